@@ -0,0 +1,8 @@
+//! X3DH *(Extended Triple Diffie-Hellman)* Key Agreement Protocol, shared by the E2EE implementations
+
+pub mod mlkem;
+mod x3dh;
+pub mod xeddsa;
+
+pub use crate::x3dh::*;
+pub use crate::xeddsa::{xeddsa_sign, xeddsa_verify, Signature};
\ No newline at end of file
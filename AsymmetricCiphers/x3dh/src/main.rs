@@ -1,29 +1,33 @@
-mod x3dh;
-use ed25519_dalek::{Signature, VerifyingKey};
-
 use x25519_dalek::PublicKey;
 
-use crate::x3dh::*;
+use x3dh::*;
+use x3dh::mlkem::{EncapsulationKey, KemCiphertext};
 
 fn main() {
     // Bob initialization
     let ikb: IdentityKey = IdentityKey::new();
     let spkb: SignedPrekey = SignedPrekey::new();
     let opkb_bundle: Vec<OneTimePrekey> = OneTimePrekey::generate_opk_bundle(10);
+    let signature: Signature = create_prekey_signature(&ikb, &spkb);
+    let pqspkb: KemPrekey = KemPrekey::new(); // PQXDH: signed ML-KEM prekey
+    let pq_signature: Signature = create_kem_prekey_signature(&ikb, &pqspkb);
+    let config: X3DHConfig = X3DHConfig::default();
     
     // Bob publishing key to the server
-    let (server_ikb, server_spk, mut server_opkb_bundle, server_bob_signature_prekey, server_verifying_key): (PublicKey, PublicKey, Vec<PublicKey>, Signature, VerifyingKey);
-    (server_ikb, server_spk, server_opkb_bundle, server_bob_signature_prekey, server_verifying_key) = create_prekey_bundle(&ikb, &spkb, &opkb_bundle);
+    let (server_ikb, server_spk, mut server_opkb_bundle, server_bob_signature_prekey): (PublicKey, PublicKey, Vec<PublicKey>, Signature);
+    (server_ikb, server_spk, server_opkb_bundle, server_bob_signature_prekey) = create_prekey_bundle(&ikb, &spkb, &opkb_bundle, signature);
+    let (server_pqspk, server_bob_signature_kem_prekey): (EncapsulationKey, Signature) = (pqspkb.get_public_key(), pq_signature);
 
     // Alice send the initial message (Ask information to the server)
     let ika: IdentityKey = IdentityKey::new();
-    let (sk_alice, eka, opkb_used): ([u8; 32], PublicKey, Option<PublicKey>);
-    let temp: Result<([u8; 32], PublicKey, Option<PublicKey>), X3DHError> = x3dh_sender(ika.clone(), server_ikb, server_spk, server_bob_signature_prekey, server_verifying_key, server_opkb_bundle.pop());
+    let (sk_alice, eka, opkb_used, kem_ciphertext): ([u8; 32], PublicKey, Option<PublicKey>, Option<KemCiphertext>);
+    let temp = x3dh_sender(&config, ika.clone(), server_ikb, server_spk, server_bob_signature_prekey, server_opkb_bundle.pop(), Some((&server_pqspk, server_bob_signature_kem_prekey)));
     match temp {
-        Ok((current_sk, current_eka, current_opkb)) => {
+        Ok((current_sk, current_eka, current_opkb, current_kem_ciphertext)) => {
             sk_alice = current_sk;
             eka = current_eka;
             opkb_used = current_opkb;
+            kem_ciphertext = current_kem_ciphertext;
         },
         Err(error) => panic!("{}", error),
     };
@@ -34,13 +38,14 @@ fn main() {
 
     // Bob receive the initial message
     let opkb_used_by_alice: Option<OneTimePrekey> = get_opk_used(opkb_used.unwrap(), opkb_bundle);
-    let sk_bob: [u8; 32] = x3dh_receiver(ika.get_public_key(), eka, ikb, spkb, opkb_used_by_alice);
+    let kem_ciphertext: KemCiphertext = kem_ciphertext.expect("PQXDH initial message without KEM ciphertext");
+    let sk_bob: [u8; 32] = x3dh_receiver(&config, ika.get_public_key(), eka, ikb, spkb, opkb_used_by_alice, Some((&pqspkb, &kem_ciphertext)));
     
     assert_eq!(sk_alice, sk_bob);
 
     // Test without opk
     /* 
-    let sk_without_opk = x3dh_sender(ika.clone(), server_ikb, server_spk, server_bob_signature_prekey, server_verifying_key, None);
+    let sk_without_opk = x3dh_sender(&config, ika.clone(), server_ikb, server_spk, server_bob_signature_prekey, None, None);
     match sk_without_opk {
         Ok(res) => println!("{:?}", res),
         Err(error) => panic!("{}", error),
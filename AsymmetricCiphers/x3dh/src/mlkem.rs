@@ -0,0 +1,519 @@
+//! ML-KEM-1024 *(Module-Lattice-Based Key-Encapsulation Mechanism, formerly Kyber)*
+//!
+//! Post-quantum KEM used by the PQXDH extension of X3DH.
+//!
+//! The implementation is based on FIPS 203: https://csrc.nist.gov/pubs/fips/203/final
+
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha3::digest::{ExtendableOutput, Update, XofReader};
+use sha3::{Digest, Sha3_256, Sha3_512, Shake128, Shake256};
+
+const N: usize = 256;
+const Q: u32 = 3329;
+const K: usize = 4;
+const ETA1: usize = 2;
+const ETA2: usize = 2;
+const DU: usize = 11;
+const DV: usize = 5;
+
+const POLY_LENGTH: usize = 384; // ByteEncode12 of one polynomial
+pub const ENCAPSULATION_KEY_LENGTH: usize = POLY_LENGTH * K + 32;
+pub const DECAPSULATION_KEY_LENGTH: usize = POLY_LENGTH * K + ENCAPSULATION_KEY_LENGTH + 64;
+pub const CIPHERTEXT_LENGTH: usize = 32 * (DU * K + DV);
+pub const SHARED_SECRET_LENGTH: usize = 32;
+
+pub type EncapsulationKey = [u8; ENCAPSULATION_KEY_LENGTH];
+pub type DecapsulationKey = [u8; DECAPSULATION_KEY_LENGTH];
+pub type KemCiphertext = [u8; CIPHERTEXT_LENGTH];
+
+type Poly = [u32; N];
+
+const BARRETT_MULTIPLIER: u64 = (1 << 32) / Q as u64; // floor(2^32 / q)
+const COMPRESS_SHIFT: u32 = 36;
+const COMPRESS_MULTIPLIER: u64 = (1u64 << COMPRESS_SHIFT).div_ceil(Q as u64); // ceil(2^36 / q)
+
+const ZETAS: [u32; 128] = zetas(1);
+const GAMMAS: [u32; 128] = zetas(2);
+
+/// `17^(BitRev7(i))` for `step = 1`, `17^(2 * BitRev7(i) + 1)` for `step = 2`
+const fn zetas(step: u32) -> [u32; 128] {
+    let mut res: [u32; 128] = [0; 128];
+    let mut i: usize = 0;
+    while i < 128 {
+        let exponent: u32 = if step == 1 { (i as u8).reverse_bits() as u32 >> 1 } else { 2 * ((i as u8).reverse_bits() as u32 >> 1) + 1 };
+        let mut value: u32 = 1;
+        let mut j: u32 = 0;
+        while j < exponent {
+            value = value * 17 % Q;
+            j += 1;
+        }
+        res[i] = value;
+        i += 1;
+    }
+    res
+}
+
+/// Generate a key pair
+///
+/// # Output
+///
+/// * `(ek, dk)` ((EncapsulationKey, DecapsulationKey)): (Encapsulation key, Decapsulation key)
+pub fn generate_key_pair() -> (EncapsulationKey, DecapsulationKey) {
+    let mut d: [u8; 32] = [0u8; 32];
+    let mut z: [u8; 32] = [0u8; 32];
+    OsRng.fill_bytes(&mut d);
+    OsRng.fill_bytes(&mut z);
+    generate_key_pair_from_seed(&d, &z)
+}
+
+/// Deterministic key generation from the seeds `d` and `z` *(ML-KEM.KeyGen_internal)*
+pub fn generate_key_pair_from_seed(d: &[u8; 32], z: &[u8; 32]) -> (EncapsulationKey, DecapsulationKey) {
+    let (ek_pke, dk_pke): (EncapsulationKey, [u8; POLY_LENGTH * K]) = pke_key_gen(d);
+
+    let mut dk: DecapsulationKey = [0u8; DECAPSULATION_KEY_LENGTH];
+    dk[..POLY_LENGTH * K].copy_from_slice(&dk_pke);
+    dk[POLY_LENGTH * K..POLY_LENGTH * K + ENCAPSULATION_KEY_LENGTH].copy_from_slice(&ek_pke);
+    dk[DECAPSULATION_KEY_LENGTH - 64..DECAPSULATION_KEY_LENGTH - 32].copy_from_slice(&h(&ek_pke));
+    dk[DECAPSULATION_KEY_LENGTH - 32..].copy_from_slice(z);
+
+    (ek_pke, dk)
+}
+
+/// Generate a shared secret and encapsulate it for the owner of `ek`
+///
+/// # Arguments
+///
+/// * `ek` (&EncapsulationKey): Encapsulation key of the receiver
+///
+/// # Output
+///
+/// * `(shared_secret, ciphertext)` (Option\<(\[u8; 32\], KemCiphertext)\>): None if `ek` is not a valid encapsulation key
+pub fn encapsulate(ek: &EncapsulationKey) -> Option<([u8; SHARED_SECRET_LENGTH], KemCiphertext)> {
+    let mut m: [u8; 32] = [0u8; 32];
+    OsRng.fill_bytes(&mut m);
+    encapsulate_with_randomness(ek, &m)
+}
+
+/// Same as `encapsulate`, with the 32 random bytes `m` chosen by the caller *(ML-KEM.Encaps_internal)*
+fn encapsulate_with_randomness(ek: &EncapsulationKey, m: &[u8; 32]) -> Option<([u8; SHARED_SECRET_LENGTH], KemCiphertext)> {
+    // Modulus check: every coefficient of the key must already be reduced
+    for i in 0..K {
+        let bytes: &[u8] = &ek[POLY_LENGTH * i..POLY_LENGTH * (i + 1)];
+        if byte_encode(&byte_decode(bytes, 12), 12) != bytes {
+            return None
+        }
+    }
+
+    let (shared_secret, r): ([u8; 32], [u8; 32]) = g(&[m, &h(ek)]);
+    Some((shared_secret, pke_encrypt(ek, m, &r)))
+}
+
+/// Recover the shared secret encapsulated in `ciphertext`
+///
+/// A ciphertext that was not produced for this key gives a pseudo-random secret instead of an error *(implicit rejection)*.
+///
+/// # Arguments
+///
+/// * `dk` (&DecapsulationKey): Decapsulation key of the receiver
+/// * `ciphertext` (&KemCiphertext): Ciphertext sent by the sender
+///
+/// # Output
+///
+/// * `shared_secret` (\[u8; 32\]): Shared secret
+pub fn decapsulate(dk: &DecapsulationKey, ciphertext: &KemCiphertext) -> [u8; SHARED_SECRET_LENGTH] {
+    let dk_pke: &[u8] = &dk[..POLY_LENGTH * K];
+    let ek_pke: &EncapsulationKey = dk[POLY_LENGTH * K..POLY_LENGTH * K + ENCAPSULATION_KEY_LENGTH].try_into().expect("Incorrect length");
+    let hash: &[u8] = &dk[DECAPSULATION_KEY_LENGTH - 64..DECAPSULATION_KEY_LENGTH - 32];
+    let z: &[u8] = &dk[DECAPSULATION_KEY_LENGTH - 32..];
+
+    let m: [u8; 32] = pke_decrypt(dk_pke, ciphertext);
+    let (shared_secret, r): ([u8; 32], [u8; 32]) = g(&[&m, hash]);
+    let mut rejection_secret: [u8; 32] = [0u8; 32];
+    let mut hasher = Shake256::default();
+    hasher.update(z);
+    hasher.update(ciphertext);
+    hasher.finalize_xof().read(&mut rejection_secret);
+
+    // Constant-time comparison and selection
+    let expected_ciphertext: KemCiphertext = pke_encrypt(ek_pke, &m, &r);
+    let difference: u8 = expected_ciphertext.iter().zip(ciphertext.iter()).fold(0, |acc, (a, b)| acc | (a ^ b));
+    let mask: u8 = ((difference as u16).wrapping_sub(1) >> 8) as u8; // 0xFF if equal, 0x00 otherwise
+    let mut res: [u8; 32] = [0u8; 32];
+    for i in 0..32 {
+        res[i] = (shared_secret[i] & mask) | (rejection_secret[i] & !mask);
+    }
+    res
+}
+
+/// K-PKE.KeyGen: returns `(ByteEncode12(t) || rho, ByteEncode12(s))`
+fn pke_key_gen(d: &[u8; 32]) -> (EncapsulationKey, [u8; POLY_LENGTH * K]) {
+    let (rho, sigma): ([u8; 32], [u8; 32]) = g(&[d, &[K as u8]]);
+    let a_hat: [[Poly; K]; K] = generate_matrix(&rho);
+
+    let mut nonce: u8 = 0;
+    let mut s_hat: [Poly; K] = [[0; N]; K];
+    let mut e_hat: [Poly; K] = [[0; N]; K];
+    for s in s_hat.iter_mut() {
+        *s = sample_poly_cbd(&prf(&sigma, nonce, ETA1), ETA1);
+        ntt(s);
+        nonce += 1;
+    }
+    for e in e_hat.iter_mut() {
+        *e = sample_poly_cbd(&prf(&sigma, nonce, ETA1), ETA1);
+        ntt(e);
+        nonce += 1;
+    }
+
+    let mut ek: EncapsulationKey = [0u8; ENCAPSULATION_KEY_LENGTH];
+    let mut dk: [u8; POLY_LENGTH * K] = [0u8; POLY_LENGTH * K];
+    for i in 0..K {
+        let mut t_hat: Poly = e_hat[i];
+        for j in 0..K {
+            add_assign(&mut t_hat, &multiply_ntts(&a_hat[i][j], &s_hat[j]));
+        }
+        ek[POLY_LENGTH * i..POLY_LENGTH * (i + 1)].copy_from_slice(&byte_encode(&t_hat, 12));
+        dk[POLY_LENGTH * i..POLY_LENGTH * (i + 1)].copy_from_slice(&byte_encode(&s_hat[i], 12));
+    }
+    ek[POLY_LENGTH * K..].copy_from_slice(&rho);
+
+    (ek, dk)
+}
+
+/// K-PKE.Encrypt
+fn pke_encrypt(ek: &EncapsulationKey, m: &[u8; 32], r: &[u8; 32]) -> KemCiphertext {
+    let mut t_hat: [Poly; K] = [[0; N]; K];
+    for (i, t) in t_hat.iter_mut().enumerate() {
+        *t = byte_decode(&ek[POLY_LENGTH * i..POLY_LENGTH * (i + 1)], 12);
+    }
+    let rho: [u8; 32] = ek[POLY_LENGTH * K..].try_into().expect("Incorrect length");
+    let a_hat: [[Poly; K]; K] = generate_matrix(&rho);
+
+    let mut nonce: u8 = 0;
+    let mut y_hat: [Poly; K] = [[0; N]; K];
+    for y in y_hat.iter_mut() {
+        *y = sample_poly_cbd(&prf(r, nonce, ETA1), ETA1);
+        ntt(y);
+        nonce += 1;
+    }
+
+    let mut ciphertext: KemCiphertext = [0u8; CIPHERTEXT_LENGTH];
+    for i in 0..K {
+        let e1: Poly = sample_poly_cbd(&prf(r, nonce, ETA2), ETA2);
+        nonce += 1;
+        let mut u: Poly = [0; N];
+        for j in 0..K {
+            add_assign(&mut u, &multiply_ntts(&a_hat[j][i], &y_hat[j]));
+        }
+        inverse_ntt(&mut u);
+        add_assign(&mut u, &e1);
+        ciphertext[32 * DU * i..32 * DU * (i + 1)].copy_from_slice(&byte_encode(&compress(&u, DU), DU));
+    }
+
+    let e2: Poly = sample_poly_cbd(&prf(r, nonce, ETA2), ETA2);
+    let mut v: Poly = [0; N];
+    for i in 0..K {
+        add_assign(&mut v, &multiply_ntts(&t_hat[i], &y_hat[i]));
+    }
+    inverse_ntt(&mut v);
+    add_assign(&mut v, &e2);
+    add_assign(&mut v, &decompress(&byte_decode(m, 1), 1));
+    ciphertext[32 * DU * K..].copy_from_slice(&byte_encode(&compress(&v, DV), DV));
+
+    ciphertext
+}
+
+/// K-PKE.Decrypt
+fn pke_decrypt(dk_pke: &[u8], ciphertext: &KemCiphertext) -> [u8; 32] {
+    let mut w: Poly = decompress(&byte_decode(&ciphertext[32 * DU * K..], DV), DV);
+    let mut product: Poly = [0; N];
+    for i in 0..K {
+        let mut u: Poly = decompress(&byte_decode(&ciphertext[32 * DU * i..32 * DU * (i + 1)], DU), DU);
+        ntt(&mut u);
+        let s_hat: Poly = byte_decode(&dk_pke[POLY_LENGTH * i..POLY_LENGTH * (i + 1)], 12);
+        add_assign(&mut product, &multiply_ntts(&s_hat, &u));
+    }
+    inverse_ntt(&mut product);
+    for (w_coefficient, product_coefficient) in w.iter_mut().zip(product.iter()) {
+        *w_coefficient = reduce_once(*w_coefficient + Q - product_coefficient);
+    }
+
+    byte_encode(&compress(&w, 1), 1).try_into().expect("Incorrect length")
+}
+
+/// `A_hat[i][j] = SampleNTT(rho || j || i)`
+fn generate_matrix(rho: &[u8; 32]) -> [[Poly; K]; K] {
+    let mut a_hat: [[Poly; K]; K] = [[[0; N]; K]; K];
+    for (i, row) in a_hat.iter_mut().enumerate() {
+        for (j, a) in row.iter_mut().enumerate() {
+            *a = sample_ntt(rho, j as u8, i as u8);
+        }
+    }
+    a_hat
+}
+
+/// SampleNTT: rejection sampling of a polynomial in the NTT domain from `SHAKE128(rho || j || i)`
+fn sample_ntt(rho: &[u8; 32], j: u8, i: u8) -> Poly {
+    let mut hasher = Shake128::default();
+    hasher.update(rho);
+    hasher.update(&[j, i]);
+    let mut reader = hasher.finalize_xof();
+
+    let mut a: Poly = [0; N];
+    let mut count: usize = 0;
+    let mut c: [u8; 3] = [0u8; 3];
+    while count < N {
+        reader.read(&mut c);
+        let d1: u32 = c[0] as u32 + 256 * (c[1] as u32 % 16);
+        let d2: u32 = c[1] as u32 / 16 + 16 * c[2] as u32;
+        if d1 < Q {
+            a[count] = d1;
+            count += 1;
+        }
+        if d2 < Q && count < N {
+            a[count] = d2;
+            count += 1;
+        }
+    }
+    a
+}
+
+/// SamplePolyCBD: centered binomial distribution of parameter `eta` from `64 * eta` bytes
+fn sample_poly_cbd(bytes: &[u8], eta: usize) -> Poly {
+    let bit = |index: usize| -> u32 { (bytes[index / 8] >> (index % 8)) as u32 & 1 };
+    let mut f: Poly = [0; N];
+    for (i, coefficient) in f.iter_mut().enumerate() {
+        let x: u32 = (0..eta).map(|j| bit(2 * i * eta + j)).sum();
+        let y: u32 = (0..eta).map(|j| bit(2 * i * eta + eta + j)).sum();
+        *coefficient = reduce_once(x + Q - y);
+    }
+    f
+}
+
+/// `PRF_eta(s, b) = SHAKE256(s || b, 64 * eta)`
+fn prf(s: &[u8; 32], b: u8, eta: usize) -> Vec<u8> {
+    let mut hasher = Shake256::default();
+    hasher.update(s);
+    hasher.update(&[b]);
+    let mut res: Vec<u8> = vec![0u8; 64 * eta];
+    hasher.finalize_xof().read(&mut res);
+    res
+}
+
+/// `G(c) = SHA3-512(c)`, split in two 32-byte halves
+fn g(parts: &[&[u8]]) -> ([u8; 32], [u8; 32]) {
+    let mut hasher = Sha3_512::new();
+    for part in parts {
+        Digest::update(&mut hasher, part);
+    }
+    let output: [u8; 64] = hasher.finalize().into();
+    (output[..32].try_into().expect("Incorrect length"), output[32..].try_into().expect("Incorrect length"))
+}
+
+/// `H(s) = SHA3-256(s)`
+fn h(s: &[u8]) -> [u8; 32] {
+    Sha3_256::digest(s).into()
+}
+
+/// Number-theoretic transform, in place
+fn ntt(f: &mut Poly) {
+    let mut i: usize = 1;
+    let mut length: usize = 128;
+    while length >= 2 {
+        for start in (0..N).step_by(2 * length) {
+            let zeta: u32 = ZETAS[i];
+            i += 1;
+            for j in start..start + length {
+                let t: u32 = reduce(zeta * f[j + length]);
+                f[j + length] = reduce_once(f[j] + Q - t);
+                f[j] = reduce_once(f[j] + t);
+            }
+        }
+        length /= 2;
+    }
+}
+
+/// Inverse of the number-theoretic transform, in place
+fn inverse_ntt(f: &mut Poly) {
+    let mut i: usize = 127;
+    let mut length: usize = 2;
+    while length <= 128 {
+        for start in (0..N).step_by(2 * length) {
+            let zeta: u32 = ZETAS[i];
+            i -= 1;
+            for j in start..start + length {
+                let t: u32 = f[j];
+                f[j] = reduce_once(t + f[j + length]);
+                f[j + length] = reduce(zeta * reduce_once(f[j + length] + Q - t));
+            }
+        }
+        length *= 2;
+    }
+    for coefficient in f.iter_mut() {
+        *coefficient = reduce(*coefficient * 3303); // 3303 = 128^-1 mod q
+    }
+}
+
+/// Product of two polynomials in the NTT domain
+fn multiply_ntts(f: &Poly, g: &Poly) -> Poly {
+    let mut h: Poly = [0; N];
+    for i in 0..128 {
+        let (a0, a1, b0, b1): (u32, u32, u32, u32) = (f[2 * i], f[2 * i + 1], g[2 * i], g[2 * i + 1]);
+        h[2 * i] = reduce(a0 * b0 + reduce(a1 * b1) * GAMMAS[i]);
+        h[2 * i + 1] = reduce(a0 * b1 + a1 * b0);
+    }
+    h
+}
+
+fn add_assign(f: &mut Poly, g: &Poly) {
+    for (a, b) in f.iter_mut().zip(g.iter()) {
+        *a = reduce_once(*a + b);
+    }
+}
+
+/// Barrett reduction: `a mod q` for `a < 2^26`
+///
+/// The coefficients depend on secret values, so no `%` or `/`: their timing varies with the operands on some CPUs *(KyberSlash)*.
+fn reduce(a: u32) -> u32 {
+    let quotient: u32 = ((a as u64 * BARRETT_MULTIPLIER) >> 32) as u32; // floor(a / q) or floor(a / q) - 1
+    reduce_once(a - quotient * Q)
+}
+
+/// `a mod q` for `a < 2q`, by a conditional subtraction without branch
+fn reduce_once(a: u32) -> u32 {
+    let res: u32 = a.wrapping_sub(Q);
+    res.wrapping_add(Q & (res >> 31).wrapping_neg())
+}
+
+/// `Compress_d(x) = round((2^d / q) * x) mod 2^d`
+///
+/// The division by q is a multiplication by `ceil(2^36 / q)` followed by a shift *(exact for every x < q, like the reference implementation)*.
+fn compress(f: &Poly, d: usize) -> Poly {
+    let mut res: Poly = [0; N];
+    for (y, x) in res.iter_mut().zip(f.iter()) {
+        let numerator: u64 = ((*x as u64) << d) + (Q as u64 - 1) / 2;
+        *y = ((numerator * COMPRESS_MULTIPLIER) >> COMPRESS_SHIFT) as u32 & ((1 << d) - 1);
+    }
+    res
+}
+
+/// `Decompress_d(y) = round((q / 2^d) * y)`
+fn decompress(f: &Poly, d: usize) -> Poly {
+    let mut res: Poly = [0; N];
+    for (x, y) in res.iter_mut().zip(f.iter()) {
+        *x = (y * Q + (1 << (d - 1))) >> d;
+    }
+    res
+}
+
+/// ByteEncode_d: packs the `d` low bits of every coefficient, least significant bit first
+fn byte_encode(f: &Poly, d: usize) -> Vec<u8> {
+    let mut res: Vec<u8> = vec![0u8; 32 * d];
+    for (i, coefficient) in f.iter().enumerate() {
+        for j in 0..d {
+            let index: usize = i * d + j;
+            res[index / 8] |= (((coefficient >> j) & 1) as u8) << (index % 8);
+        }
+    }
+    res
+}
+
+/// ByteDecode_d: inverse of `byte_encode`, coefficients are reduced modulo q for `d = 12` *(at most 4095 < 2q)*
+fn byte_decode(bytes: &[u8], d: usize) -> Poly {
+    let mut f: Poly = [0; N];
+    for (i, coefficient) in f.iter_mut().enumerate() {
+        for j in 0..d {
+            let index: usize = i * d + j;
+            *coefficient |= ((bytes[index / 8] >> (index % 8)) as u32 & 1) << j;
+        }
+        if d == 12 {
+            *coefficient = reduce_once(*coefficient);
+        }
+    }
+    f
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::Sha256;
+
+    fn decode(hex: &str) -> [u8; 32] {
+        let mut res: [u8; 32] = [0u8; 32];
+        for (i, byte) in res.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).unwrap();
+        }
+        res
+    }
+
+    fn sha256(data: &[u8]) -> [u8; 32] {
+        Sha256::digest(data).into()
+    }
+
+    // Expected values computed with OpenSSL 3.5 (seed d = 0x00.., z = 0x01.., encapsulation randomness m = 0x02..)
+    #[test]
+    fn test_deterministic_vectors() {
+        let (ek, dk): (EncapsulationKey, DecapsulationKey) = generate_key_pair_from_seed(&[0x00; 32], &[0x01; 32]);
+        let (shared_secret, ciphertext): ([u8; 32], KemCiphertext) = encapsulate_with_randomness(&ek, &[0x02; 32]).unwrap();
+
+        assert_eq!(sha256(&ek), decode("29e3692e1c08422f548ca7e683e89015482c09a5442f8d2ead471c3931a5ee76"));
+        assert_eq!(sha256(&ciphertext), decode("c54ca275cec365288576fad0928d58ab7f83b3e3ecf7b3ff3404fb1a87ec63f4"));
+        assert_eq!(shared_secret, decode("384254f36d937d86d256fe6af35ce04bcc714f137b040f529e4e8a0209d14029"));
+        assert_eq!(decapsulate(&dk, &ciphertext), shared_secret);
+
+        let mut modified_ciphertext: KemCiphertext = ciphertext;
+        modified_ciphertext[0] ^= 0x01;
+        assert_eq!(decapsulate(&dk, &modified_ciphertext), decode("14a8ff2b39526540335e12af39d8fea663415d515a13d220fae65d3be2bdd185"));
+    }
+
+    #[test]
+    fn test_encapsulate_and_decapsulate() {
+        let (ek, dk): (EncapsulationKey, DecapsulationKey) = generate_key_pair();
+        let (shared_secret, ciphertext): ([u8; 32], KemCiphertext) = encapsulate(&ek).unwrap();
+
+        assert_eq!(decapsulate(&dk, &ciphertext), shared_secret);
+
+        let (_, other_dk): (EncapsulationKey, DecapsulationKey) = generate_key_pair();
+        assert_ne!(decapsulate(&other_dk, &ciphertext), shared_secret);
+    }
+
+    #[test]
+    fn test_reject_unreduced_encapsulation_key() {
+        let (mut ek, _): (EncapsulationKey, DecapsulationKey) = generate_key_pair();
+        // First coefficient set to 4095 > q
+        ek[0] = 0xFF;
+        ek[1] |= 0x0F;
+
+        assert!(encapsulate(&ek).is_none());
+    }
+
+    #[test]
+    fn test_reductions() {
+        for a in 0..2 * Q {
+            assert_eq!(reduce_once(a), a % Q);
+        }
+        for a in (0..1 << 26).step_by(97).chain((1 << 26) - 97..1 << 26) {
+            assert_eq!(reduce(a), a % Q);
+        }
+        for d in [1, 4, 5, 10, 11] {
+            let mut f: Poly = [0; N];
+            for x in 0..Q {
+                f[0] = x;
+                assert_eq!(compress(&f, d)[0], (((x << (d + 1)) + Q) / (2 * Q)) & ((1 << d) - 1));
+            }
+        }
+    }
+
+    #[test]
+    fn test_ntt_round_trip() {
+        let mut f: Poly = [0; N];
+        for (i, coefficient) in f.iter_mut().enumerate() {
+            *coefficient = (i as u32 * 13) % Q;
+        }
+        let mut g: Poly = f;
+        ntt(&mut g);
+        inverse_ntt(&mut g);
+
+        assert_eq!(g, f);
+    }
+}
@@ -1,25 +1,103 @@
 //! X3DH *(Extended Triple Diffie-Hellman)* Key Agreement Protocol
-//! 
+//!
 //! Curve: 25519
-//! Hash: Sha512
-//! 
+//! Hash: Sha256 or Sha512 *(see `X3DHConfig`)*
+//! KEM *(optional, PQXDH)*: ML-KEM-1024
+//!
 //! The implementation is based on Signal recommendation: https://signal.org/docs/specifications/x3dh/
+//! and, for the post-quantum extension, on https://signal.org/docs/specifications/pqxdh/
 
 use std::fmt;
 use hkdf::Hkdf;
 use rand::rngs::OsRng;
-use sha2::Sha512;
-use x25519_dalek::{SharedSecret, PublicKey, ReusableSecret, EphemeralSecret, StaticSecret};
-use ed25519_dalek::{Signature, SigningKey, Signer, VerifyingKey, Verifier};
+use sha2::{Sha256, Sha512};
+use x25519_dalek::{SharedSecret, PublicKey, StaticSecret};
+use crate::mlkem::{self, DecapsulationKey, EncapsulationKey, KemCiphertext};
+use crate::xeddsa::{xeddsa_sign, xeddsa_verify, Signature};
 
-#[derive(PartialEq)]
+#[derive(Debug, PartialEq)]
 pub enum X3DHError {
     SignatureInvalid,
+    KemPrekeyInvalid,
+    KemCiphertextAbsent,
 }
 
 const F: [u8; 32] = [0xFF; 32];
-const SALT: [u8; 64] = [0x00; 64];
 const INFO: &[u8; 14] = b"RedWheelbarrow";
+pub const KEM_TYPE_ML_KEM_1024: u8 = 0x08; // Type byte of EncodeKEM, in front of the signed ML-KEM prekey
+
+/// Hash function used by the HKDF of the protocol
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HashFunction {
+    Sha256,
+    Sha512,
+}
+
+/// Parameters of the protocol *(both parties must use the same ones)*
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct X3DHConfig {
+    hash: HashFunction,
+    info: &'static [u8],
+}
+
+/// (Shared secret, public ephemeral key, public one-time prekey used, ML-KEM ciphertext if PQXDH is used) of the sender of the initial message
+pub type SenderOutput = ([u8; 32], PublicKey, Option<PublicKey>, Option<KemCiphertext>);
+
+impl X3DHConfig {
+    /// Create a configuration
+    ///
+    /// # Arguments
+    ///
+    /// * `hash` (HashFunction): Hash function used by the HKDF
+    /// * `info` (&'static \[u8\]): ASCII string identifying the application
+    pub const fn new(hash: HashFunction, info: &'static [u8]) -> Self {
+        X3DHConfig { hash, info }
+    }
+
+    pub fn get_hash(&self) -> HashFunction {
+        self.hash
+    }
+
+    pub fn get_info(&self) -> &'static [u8] {
+        self.info
+    }
+
+    /// Returns the info string of PQXDH: `info || "_CURVE25519_" || hash || "_ML-KEM-1024"`
+    ///
+    /// A PQXDH secret is never derived with the info of the classical protocol, as recommended by the PQXDH specification.
+    pub fn get_pq_info(&self) -> Vec<u8> {
+        let hash: &[u8] = match self.hash {
+            HashFunction::Sha256 => b"SHA-256",
+            HashFunction::Sha512 => b"SHA-512",
+        };
+        [self.info, b"_CURVE25519_", hash, b"_ML-KEM-1024"].concat()
+    }
+
+    /// Returns the 32-byte shared secret derived from the concatenation of the DH outputs *(and of the KEM shared secret for PQXDH)*
+    ///
+    /// `HKDF(salt = zeros of the hash output length, ikm = F || KM, info)`, with the PQXDH info string if `pq` is true
+    fn kdf(&self, km: &[u8], pq: bool) -> [u8; 32] {
+        let info: Vec<u8> = if pq { self.get_pq_info() } else { self.info.to_vec() };
+        let mut ikm: Vec<u8> = Vec::new();
+        ikm.extend_from_slice(&F);
+        ikm.extend_from_slice(km);
+
+        let mut sk: [u8; 32] = [0u8; 32];
+        match self.hash {
+            HashFunction::Sha256 => Hkdf::<Sha256>::new(Some(&[0x00; 32]), &ikm).expand(&info, &mut sk),
+            HashFunction::Sha512 => Hkdf::<Sha512>::new(Some(&[0x00; 64]), &ikm).expand(&info, &mut sk),
+        }.expect("Error during the creation of the share secret");
+
+        sk
+    }
+}
+
+impl Default for X3DHConfig {
+    /// Sha512 with the "RedWheelbarrow" info string
+    fn default() -> Self {
+        X3DHConfig::new(HashFunction::Sha512, INFO)
+    }
+}
 
 #[derive(Clone)]
 pub struct IdentityKey {
@@ -27,41 +105,85 @@ pub struct IdentityKey {
     private_key: StaticSecret,
 }
 
+impl Default for IdentityKey {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl IdentityKey {
     pub fn new() -> Self {
-        let mut csprng = OsRng;
-        let private_key: StaticSecret = StaticSecret::random_from_rng(&mut csprng);
-        IdentityKey { public_key: PublicKey::from(&private_key), private_key: private_key }
+        let private_key: StaticSecret = StaticSecret::random_from_rng(OsRng);
+        IdentityKey { public_key: PublicKey::from(&private_key), private_key }
+    }
+
+    pub fn from_bytes(private_key: [u8; 32]) -> Self {
+        let private_key: StaticSecret = StaticSecret::from(private_key);
+        IdentityKey { public_key: PublicKey::from(&private_key), private_key }
     }
 
     pub fn get_public_key(&self) -> PublicKey {
         self.public_key
     }
+
+    pub fn get_private_key(&self) -> StaticSecret {
+        self.private_key.clone()
+    }
 }
 
+#[derive(Clone)]
 pub struct SignedPrekey {
     public_key: PublicKey,
-    private_key: ReusableSecret,
+    private_key: StaticSecret,
+}
+
+impl Default for SignedPrekey {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl SignedPrekey {
     pub fn new() -> Self {
-        let mut csprng = OsRng;
-        let private_key: ReusableSecret = ReusableSecret::random_from_rng(&mut csprng);
-        SignedPrekey { public_key: PublicKey::from(&private_key), private_key: private_key }
+        let private_key: StaticSecret = StaticSecret::random_from_rng(OsRng);
+        SignedPrekey { public_key: PublicKey::from(&private_key), private_key }
+    }
+
+    pub fn from_bytes(private_key: [u8; 32]) -> Self {
+        let private_key: StaticSecret = StaticSecret::from(private_key);
+        SignedPrekey { public_key: PublicKey::from(&private_key), private_key }
+    }
+
+    pub fn get_public_key(&self) -> PublicKey {
+        self.public_key
+    }
+
+    pub fn get_private_key(&self) -> StaticSecret {
+        self.private_key.clone()
     }
 }
 
+#[derive(Clone)]
 pub struct OneTimePrekey {
     public_key: PublicKey,
-    private_key: EphemeralSecret,
+    private_key: StaticSecret,
+}
+
+impl Default for OneTimePrekey {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl OneTimePrekey {
     pub fn new() -> Self {
-        let mut csprng = OsRng;
-        let private_key: EphemeralSecret = EphemeralSecret::random_from_rng(&mut csprng);
-        OneTimePrekey { public_key: PublicKey::from(&private_key), private_key: private_key }
+        let private_key: StaticSecret = StaticSecret::random_from_rng(OsRng);
+        OneTimePrekey { public_key: PublicKey::from(&private_key), private_key }
+    }
+
+    pub fn from_bytes(private_key: [u8; 32]) -> Self {
+        let private_key: StaticSecret = StaticSecret::from(private_key);
+        OneTimePrekey { public_key: PublicKey::from(&private_key), private_key }
     }
 
     pub fn generate_opk_bundle(n: u8) -> Vec<OneTimePrekey> {
@@ -72,91 +194,192 @@ impl OneTimePrekey {
 
         opk_set
     }
+
+    pub fn get_public_key(&self) -> PublicKey {
+        self.public_key
+    }
+}
+
+/// Signed ML-KEM prekey *(PQXDH)*
+#[derive(Clone)]
+pub struct KemPrekey {
+    public_key: EncapsulationKey,
+    private_key: DecapsulationKey,
+}
+
+impl Default for KemPrekey {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl KemPrekey {
+    pub fn new() -> Self {
+        let (public_key, private_key): (EncapsulationKey, DecapsulationKey) = mlkem::generate_key_pair();
+        KemPrekey { public_key, private_key }
+    }
+
+    pub fn from_seed(d: [u8; 32], z: [u8; 32]) -> Self {
+        let (public_key, private_key): (EncapsulationKey, DecapsulationKey) = mlkem::generate_key_pair_from_seed(&d, &z);
+        KemPrekey { public_key, private_key }
+    }
+
+    pub fn get_public_key(&self) -> EncapsulationKey {
+        self.public_key
+    }
 }
 
 pub struct EphemeralKey {
     public_key: PublicKey,
-    private_key: ReusableSecret,
+    private_key: StaticSecret,
+}
+
+impl Default for EphemeralKey {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl EphemeralKey {
     pub fn new() -> Self {
-        let mut csprng = OsRng;
-        let private_key: ReusableSecret = ReusableSecret::random_from_rng(&mut csprng);
-        EphemeralKey { public_key: PublicKey::from(&private_key), private_key: private_key }
+        let private_key: StaticSecret = StaticSecret::random_from_rng(OsRng);
+        EphemeralKey { public_key: PublicKey::from(&private_key), private_key }
+    }
+
+    pub fn from_bytes(private_key: [u8; 32]) -> Self {
+        let private_key: StaticSecret = StaticSecret::from(private_key);
+        EphemeralKey { public_key: PublicKey::from(&private_key), private_key }
     }
+
+    pub fn get_public_key(&self) -> PublicKey {
+        self.public_key
+    }
+}
+
+/// Sign the signed prekey with the identity key *(XEdDSA, the signature is verified with the X25519 identity public key)*
+pub fn create_prekey_signature(ik: &IdentityKey, spk: &SignedPrekey) -> Signature {
+    xeddsa_sign(&ik.private_key, spk.public_key.as_bytes())
+}
+
+/// Sign the ML-KEM prekey with the identity key *(PQXDH, the signature covers `EncodeKEM(pqspk)`)*
+pub fn create_kem_prekey_signature(ik: &IdentityKey, pqspk: &KemPrekey) -> Signature {
+    xeddsa_sign(&ik.private_key, &encode_kem(&pqspk.public_key))
+}
+
+/// `EncodeKEM(pk) = type byte || pk`: the signature of a KEM prekey can't be taken for the signature of a key of another type
+pub fn encode_kem(pk: &EncapsulationKey) -> Vec<u8> {
+    [&[KEM_TYPE_ML_KEM_1024], pk.as_slice()].concat()
 }
 
-pub fn create_prekey_bundle(ik: &IdentityKey, spk: &SignedPrekey, opk_bundle: &Vec<OneTimePrekey>) -> (PublicKey, PublicKey, Vec<PublicKey>, Signature, VerifyingKey) {
+/// Sign any other message with the identity key *(e.g. the login challenge of a relay, verified with `xeddsa_verify`)*
+pub fn create_identity_signature(ik: &IdentityKey, message: &[u8]) -> Signature {
+    xeddsa_sign(&ik.private_key, message)
+}
+
+pub fn create_prekey_bundle(ik: &IdentityKey, spk: &SignedPrekey, opk_bundle: &Vec<OneTimePrekey>, signature: Signature) -> (PublicKey, PublicKey, Vec<PublicKey>, Signature) {
     let mut opk_public_bundle: Vec<PublicKey> = Vec::new();
     for key in opk_bundle {
         opk_public_bundle.push(key.public_key);
     };
 
-    let signing_key: SigningKey = SigningKey::from_bytes(&ik.private_key.to_bytes());
-    let signature: Signature = signing_key.sign(spk.public_key.as_bytes());
-    let verifying_key: VerifyingKey = signing_key.verifying_key();
+    (ik.public_key, spk.public_key, opk_public_bundle, signature)
+}
 
-    (ik.public_key, spk.public_key, opk_public_bundle, signature, verifying_key)
+/// Compute the sender shared secret
+///
+/// # Arguments
+///
+/// * `config` (&X3DHConfig): Parameters of the protocol
+/// * `ika` (IdentityKey): Identity key of the sender
+/// * `ikb` (PublicKey): Identity key of the receiver
+/// * `spkb` (PublicKey): Signed prekey of the receiver
+/// * `signature` (Signature): Signature of the signed prekey
+/// * `opkb` (Option\<PublicKey\>): One-time prekey of the receiver, if the bundle still had one
+/// * `pqpkb` (Option\<(&EncapsulationKey, Signature)\>): Signed ML-KEM prekey of the receiver *(PQXDH)*, None for the classical protocol
+///
+/// # Output
+///
+/// * `(shared_secret, ephemeral_key, one_time_prekey_used, kem_ciphertext)` (Result\<(\[u8; 32\], PublicKey, Option\<PublicKey\>, Option\<KemCiphertext\>), X3DHError\>): The KEM ciphertext must be sent with the initial message
+pub fn x3dh_sender(config: &X3DHConfig, ika: IdentityKey, ikb: PublicKey, spkb: PublicKey, signature: Signature, opkb: Option<PublicKey>, pqpkb: Option<(&EncapsulationKey, Signature)>) -> Result<SenderOutput, X3DHError> {
+    x3dh_sender_with_ephemeral_key(config, ika, EphemeralKey::new(), ikb, (spkb, signature), opkb, pqpkb)
 }
 
-pub fn x3dh_sender(ika: IdentityKey, ikb: PublicKey, spkb: PublicKey, signature: Signature, verifying_key: VerifyingKey, opkb: Option<PublicKey>) -> Result<([u8; 32], PublicKey, Option<PublicKey>), X3DHError> {
-    // Verify the signature
-    if verifying_key.verify(spkb.as_bytes(), &signature).is_err() {
+/// Same as `x3dh_sender`, with the ephemeral key chosen by the caller *(used by the test vectors)*
+fn x3dh_sender_with_ephemeral_key(config: &X3DHConfig, ika: IdentityKey, eka: EphemeralKey, ikb: PublicKey, (spkb, signature): (PublicKey, Signature), opkb: Option<PublicKey>, pqpkb: Option<(&EncapsulationKey, Signature)>) -> Result<SenderOutput, X3DHError> {
+    // Verify the signatures with the identity key of the receiver
+    if !xeddsa_verify(&ikb, spkb.as_bytes(), &signature) {
         return Err(X3DHError::SignatureInvalid)
     }
-    
-    // Compute the shared secret
-    let eka: EphemeralKey = EphemeralKey::new();
+    if let Some((pqpk, pq_signature)) = pqpkb {
+        if !xeddsa_verify(&ikb, &encode_kem(pqpk), &pq_signature) {
+            return Err(X3DHError::SignatureInvalid)
+        }
+    }
 
+    // Compute the shared secret
     let dh1: SharedSecret = ika.private_key.diffie_hellman(&spkb);
     let dh2: SharedSecret = eka.private_key.diffie_hellman(&ikb);
     let dh3: SharedSecret = eka.private_key.diffie_hellman(&spkb);
 
-    let mut ikm: Vec<u8> = Vec::new();
-    ikm.extend_from_slice(&F);
-    ikm.extend_from_slice(dh1.as_bytes());
-    ikm.extend_from_slice(dh2.as_bytes());
-    ikm.extend_from_slice(dh3.as_bytes());
+    let mut km: Vec<u8> = Vec::new();
+    km.extend_from_slice(dh1.as_bytes());
+    km.extend_from_slice(dh2.as_bytes());
+    km.extend_from_slice(dh3.as_bytes());
 
     // Verify that the bundle contain a one-time prekey
     if let Some(key) = opkb {
         let dh4: SharedSecret = eka.private_key.diffie_hellman(&key);
-        ikm.extend_from_slice(dh4.as_bytes())
+        km.extend_from_slice(dh4.as_bytes())
     }
 
-    let hk = Hkdf::<Sha512>::new(Some(&SALT), &ikm);
-    let mut sk: [u8; 32] = [0u8; 32];
-    hk.expand(INFO, &mut sk)
-        .expect("Error during the creation of the share secret");
-    
-    Ok((sk, eka.public_key, opkb))
+    // PQXDH: the KEM shared secret is appended after the DH outputs
+    let mut kem_ciphertext: Option<KemCiphertext> = None;
+    if let Some((pqpk, _)) = pqpkb {
+        let (ss, ciphertext): ([u8; 32], KemCiphertext) = mlkem::encapsulate(pqpk).ok_or(X3DHError::KemPrekeyInvalid)?;
+        km.extend_from_slice(&ss);
+        kem_ciphertext = Some(ciphertext);
+    }
+
+    Ok((config.kdf(&km, kem_ciphertext.is_some()), eka.public_key, opkb, kem_ciphertext))
 }
 
-pub fn x3dh_receiver(ika: PublicKey, eka: PublicKey, ikb: IdentityKey, spkb: SignedPrekey, opkb: Option<OneTimePrekey>) -> [u8; 32] {
+/// Compute the receiver shared secret
+///
+/// # Arguments
+///
+/// * `config` (&X3DHConfig): Parameters of the protocol
+/// * `ika` (PublicKey): Identity key of the sender
+/// * `eka` (PublicKey): Ephemeral key of the sender
+/// * `ikb` (IdentityKey): Identity key of the receiver
+/// * `spkb` (SignedPrekey): Signed prekey of the receiver
+/// * `opkb` (Option\<OneTimePrekey\>): One-time prekey used by the sender
+/// * `pqpkb` (Option\<(&KemPrekey, &KemCiphertext)\>): ML-KEM prekey used by the sender and the KEM ciphertext of the initial message *(PQXDH)*
+///
+/// # Output
+///
+/// * `shared_secret` (\[u8; 32\]): Shared secret
+pub fn x3dh_receiver(config: &X3DHConfig, ika: PublicKey, eka: PublicKey, ikb: IdentityKey, spkb: SignedPrekey, opkb: Option<OneTimePrekey>, pqpkb: Option<(&KemPrekey, &KemCiphertext)>) -> [u8; 32] {
     // Compute the shared secret
     let dh1: SharedSecret = spkb.private_key.diffie_hellman(&ika);
     let dh2: SharedSecret = ikb.private_key.diffie_hellman(&eka);
     let dh3: SharedSecret = spkb.private_key.diffie_hellman(&eka);
 
-    let mut ikm: Vec<u8> = Vec::new();
-    ikm.extend_from_slice(&F);
-    ikm.extend_from_slice(dh1.as_bytes());
-    ikm.extend_from_slice(dh2.as_bytes());
-    ikm.extend_from_slice(dh3.as_bytes());
+    let mut km: Vec<u8> = Vec::new();
+    km.extend_from_slice(dh1.as_bytes());
+    km.extend_from_slice(dh2.as_bytes());
+    km.extend_from_slice(dh3.as_bytes());
 
     // Verify that the bundle contain a one-time prekey
     if let Some(key) = opkb {
         let dh4: SharedSecret = key.private_key.diffie_hellman(&eka);
-        ikm.extend_from_slice(dh4.as_bytes())
+        km.extend_from_slice(dh4.as_bytes())
     }
 
-    let hk = Hkdf::<Sha512>::new(Some(&SALT), &ikm);
-    let mut sk: [u8; 32] = [0u8; 32];
-    hk.expand(INFO, &mut sk)
-        .expect("Error during the creation of the share secret");
+    if let Some((pqpk, ciphertext)) = pqpkb {
+        km.extend_from_slice(&mlkem::decapsulate(&pqpk.private_key, ciphertext));
+    }
 
-    sk
+    config.kdf(&km, pqpkb.is_some())
 }
 
 pub fn get_ad(first_ik_pk: PublicKey, second_ik_pk: PublicKey, additional_information: Option<Vec<u8>>) -> Vec<u8> {
@@ -186,6 +409,144 @@ impl fmt::Display for X3DHError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             X3DHError::SignatureInvalid => write!(f, "Verification of the signature failed"),
+            X3DHError::KemPrekeyInvalid => write!(f, "The ML-KEM prekey is not a valid encapsulation key"),
+            X3DHError::KemCiphertextAbsent => write!(f, "No ML-KEM ciphertext for the PQXDH initial message"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA256_CONFIG: X3DHConfig = X3DHConfig::new(HashFunction::Sha256, INFO);
+
+    fn decode(hex: &str) -> [u8; 32] {
+        let mut res: [u8; 32] = [0u8; 32];
+        for (i, byte) in res.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).unwrap();
         }
+        res
+    }
+
+    /// Run both sides of the protocol with fixed keys and returns the shared secret
+    fn shared_secret(config: &X3DHConfig, with_opk: bool) -> [u8; 32] {
+        let ika: IdentityKey = IdentityKey::from_bytes([0x01; 32]);
+        let ikb: IdentityKey = IdentityKey::from_bytes([0x02; 32]);
+        let spkb: SignedPrekey = SignedPrekey::from_bytes([0x03; 32]);
+        let opkb: Option<OneTimePrekey> = with_opk.then(|| OneTimePrekey::from_bytes([0x04; 32]));
+        let eka: EphemeralKey = EphemeralKey::from_bytes([0x05; 32]);
+        let signature: Signature = create_prekey_signature(&ikb, &spkb);
+
+        let (sk_sender, eka_public, _, kem_ciphertext) = x3dh_sender_with_ephemeral_key(config, ika.clone(), eka, ikb.get_public_key(), (spkb.get_public_key(), signature), opkb.as_ref().map(|key| key.get_public_key()), None).unwrap();
+        assert!(kem_ciphertext.is_none());
+        let sk_receiver: [u8; 32] = x3dh_receiver(config, ika.get_public_key(), eka_public, ikb, spkb, opkb, None);
+        assert_eq!(sk_sender, sk_receiver);
+
+        sk_sender
+    }
+
+    // Expected values computed with the previous implementations (AsymmetricCiphers/x3dh for Sha512, the E2EE copies for Sha256)
+    #[test]
+    fn test_sha512_vectors() {
+        assert_eq!(shared_secret(&X3DHConfig::default(), true), decode("281081ae2b4cfdc0ac9f5a6d03a0904482faf6f21afbe02ab6dd679873dbab9e"));
+        assert_eq!(shared_secret(&X3DHConfig::default(), false), decode("ab2b9bbb3bab4719344b15b2c8f7dd79121ae1285c84157c0f9a96e5a6d15030"));
+    }
+
+    #[test]
+    fn test_sha256_vectors() {
+        assert_eq!(shared_secret(&SHA256_CONFIG, true), decode("e1026042f4adda1974ad435c67b3085f60053f7c9f6968b8406f27e66d54a504"));
+        assert_eq!(shared_secret(&SHA256_CONFIG, false), decode("6b64449b70ab86ff9343eae6bc7fcc4ced013aa7c9d926dcae337015c7a168ec"));
+    }
+
+    #[test]
+    fn test_info_changes_shared_secret() {
+        let config: X3DHConfig = X3DHConfig::new(HashFunction::Sha512, b"AnotherApplication");
+        assert_ne!(shared_secret(&config, true), shared_secret(&X3DHConfig::default(), true));
+    }
+
+    #[test]
+    fn test_invalid_signature() {
+        let ika: IdentityKey = IdentityKey::new();
+        let ikb: IdentityKey = IdentityKey::new();
+        let spkb: SignedPrekey = SignedPrekey::new();
+        let signature: Signature = create_prekey_signature(&ikb, &SignedPrekey::new());
+
+        let result = x3dh_sender(&X3DHConfig::default(), ika.clone(), ikb.get_public_key(), spkb.get_public_key(), signature, None, None);
+        assert_eq!(result.err(), Some(X3DHError::SignatureInvalid));
+
+        // A valid signature from another identity key is not accepted either
+        let signature: Signature = create_prekey_signature(&ika, &spkb);
+        let result = x3dh_sender(&X3DHConfig::default(), ika, ikb.get_public_key(), spkb.get_public_key(), signature, None, None);
+        assert_eq!(result.err(), Some(X3DHError::SignatureInvalid));
+    }
+
+    #[test]
+    fn test_identity_signature() {
+        let ik: IdentityKey = IdentityKey::new();
+        let signature: Signature = create_identity_signature(&ik, b"challenge");
+
+        assert!(xeddsa_verify(&ik.get_public_key(), b"challenge", &signature));
+        assert!(!xeddsa_verify(&ik.get_public_key(), b"another challenge", &signature));
+        assert!(!xeddsa_verify(&IdentityKey::new().get_public_key(), b"challenge", &signature));
+    }
+
+    #[test]
+    fn test_pqxdh() {
+        let ika: IdentityKey = IdentityKey::new();
+        let ikb: IdentityKey = IdentityKey::new();
+        let spkb: SignedPrekey = SignedPrekey::new();
+        let pqspkb: KemPrekey = KemPrekey::new();
+        let signature: Signature = create_prekey_signature(&ikb, &spkb);
+        let pq_signature: Signature = create_kem_prekey_signature(&ikb, &pqspkb);
+        let eka: EphemeralKey = EphemeralKey::from_bytes([0x05; 32]);
+        let eka_public: PublicKey = eka.get_public_key();
+
+        let (sk_sender, _, _, kem_ciphertext) = x3dh_sender_with_ephemeral_key(&X3DHConfig::default(), ika.clone(), eka, ikb.get_public_key(), (spkb.get_public_key(), signature), None, Some((&pqspkb.get_public_key(), pq_signature))).unwrap();
+        let kem_ciphertext: KemCiphertext = kem_ciphertext.unwrap();
+        let sk_receiver: [u8; 32] = x3dh_receiver(&X3DHConfig::default(), ika.get_public_key(), eka_public, ikb.clone(), spkb.clone(), None, Some((&pqspkb, &kem_ciphertext)));
+        assert_eq!(sk_sender, sk_receiver);
+
+        // The KEM shared secret is part of the key material: ignoring the ciphertext gives another secret
+        let sk_classical: [u8; 32] = x3dh_receiver(&X3DHConfig::default(), ika.get_public_key(), eka_public, ikb.clone(), spkb.clone(), None, None);
+        assert_ne!(sk_sender, sk_classical);
+
+        // Same key material, but derived with the PQXDH info string
+        let mut km: Vec<u8> = Vec::new();
+        km.extend_from_slice(spkb.private_key.diffie_hellman(&ika.get_public_key()).as_bytes());
+        km.extend_from_slice(ikb.private_key.diffie_hellman(&eka_public).as_bytes());
+        km.extend_from_slice(spkb.private_key.diffie_hellman(&eka_public).as_bytes());
+        km.extend_from_slice(&mlkem::decapsulate(&pqspkb.private_key, &kem_ciphertext));
+        assert_eq!(X3DHConfig::default().kdf(&km, true), sk_sender);
+        assert_ne!(X3DHConfig::default().kdf(&km, false), sk_sender);
+        assert_eq!(X3DHConfig::default().get_pq_info(), b"RedWheelbarrow_CURVE25519_SHA-512_ML-KEM-1024");
+        assert_eq!(SHA256_CONFIG.get_pq_info(), b"RedWheelbarrow_CURVE25519_SHA-256_ML-KEM-1024");
+    }
+
+    #[test]
+    fn test_pqxdh_invalid_kem_prekey() {
+        let ika: IdentityKey = IdentityKey::new();
+        let ikb: IdentityKey = IdentityKey::new();
+        let spkb: SignedPrekey = SignedPrekey::new();
+        let pqspkb: KemPrekey = KemPrekey::new();
+        let signature: Signature = create_prekey_signature(&ikb, &spkb);
+
+        // KEM prekey signed by another identity key
+        let pq_signature: Signature = create_kem_prekey_signature(&ika, &pqspkb);
+        let result = x3dh_sender(&X3DHConfig::default(), ika.clone(), ikb.get_public_key(), spkb.get_public_key(), signature, None, Some((&pqspkb.get_public_key(), pq_signature)));
+        assert_eq!(result.err(), Some(X3DHError::SignatureInvalid));
+
+        // Signature of the key without the EncodeKEM type byte
+        let pq_signature: Signature = xeddsa_sign(&ikb.private_key, &pqspkb.get_public_key());
+        let result = x3dh_sender(&X3DHConfig::default(), ika.clone(), ikb.get_public_key(), spkb.get_public_key(), signature, None, Some((&pqspkb.get_public_key(), pq_signature)));
+        assert_eq!(result.err(), Some(X3DHError::SignatureInvalid));
+
+        // Correctly signed, but not a valid encapsulation key
+        let mut pqpk: EncapsulationKey = pqspkb.get_public_key();
+        pqpk[0] = 0xFF;
+        pqpk[1] |= 0x0F;
+        let pq_signature: Signature = xeddsa_sign(&ikb.private_key, &encode_kem(&pqpk));
+        let result = x3dh_sender(&X3DHConfig::default(), ika, ikb.get_public_key(), spkb.get_public_key(), signature, None, Some((&pqpk, pq_signature)));
+        assert_eq!(result.err(), Some(X3DHError::KemPrekeyInvalid));
     }
 }
\ No newline at end of file
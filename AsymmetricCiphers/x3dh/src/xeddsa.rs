@@ -0,0 +1,188 @@
+//! XEdDSA signatures with X25519 keys
+//!
+//! A signature can be verified directly with the X25519 public key of the signer, no separate Ed25519 key has to be published.
+//!
+//! The implementation is based on Signal specification: https://signal.org/docs/specifications/xeddsa/
+
+use curve25519_dalek::constants::ED25519_BASEPOINT_TABLE;
+use curve25519_dalek::edwards::{CompressedEdwardsY, EdwardsPoint};
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use curve25519_dalek::scalar::Scalar;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::{Digest, Sha512};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// `R || s`
+pub type Signature = [u8; 64];
+
+/// Returns the Ed25519 key pair `(A, a)` matching the X25519 private key `k`, with the sign bit of `A` set to 0
+fn calculate_key_pair(k: &StaticSecret) -> ([u8; 32], Scalar) {
+    let k_scalar: Scalar = clamp(k);
+    let mut public_key: [u8; 32] = (&k_scalar * ED25519_BASEPOINT_TABLE).compress().to_bytes();
+    let a: Scalar = if public_key[31] & 0x80 != 0 { -k_scalar } else { k_scalar };
+    public_key[31] &= 0x7F;
+
+    (public_key, a)
+}
+
+/// Returns the scalar of an X25519 private key, clamped like X25519 does so that kB corresponds to the X25519 public key
+fn clamp(k: &StaticSecret) -> Scalar {
+    let mut k_bytes: [u8; 32] = k.to_bytes();
+    k_bytes[0] &= 248;
+    k_bytes[31] &= 127;
+    k_bytes[31] |= 64;
+    Scalar::from_bytes_mod_order(k_bytes)
+}
+
+/// `hash_i(X) = SHA512(2^256 - 1 - i || X)`
+fn hash_i(i: u8, data: &[&[u8]]) -> Scalar {
+    let mut prefix: [u8; 32] = [0xFF; 32];
+    prefix[0] = 0xFF - i;
+    let mut hasher = Sha512::new();
+    hasher.update(prefix);
+    for part in data {
+        hasher.update(part);
+    }
+    Scalar::from_bytes_mod_order_wide(&hasher.finalize().into())
+}
+
+/// `SHA512(X) mod q`
+fn hash(data: &[&[u8]]) -> Scalar {
+    let mut hasher = Sha512::new();
+    for part in data {
+        hasher.update(part);
+    }
+    Scalar::from_bytes_mod_order_wide(&hasher.finalize().into())
+}
+
+/// Sign a message with an X25519 private key
+///
+/// # Arguments
+///
+/// * `private_key` (&StaticSecret): X25519 private key of the signer
+/// * `message` (&\[u8\]): Message to sign
+///
+/// # Output
+///
+/// * `signature` (Signature): XEdDSA signature
+pub fn xeddsa_sign(private_key: &StaticSecret, message: &[u8]) -> Signature {
+    let mut z: [u8; 64] = [0u8; 64];
+    OsRng.fill_bytes(&mut z);
+    xeddsa_sign_with_nonce(private_key, message, &z)
+}
+
+/// Same as `xeddsa_sign`, with the 64 random bytes `z` chosen by the caller
+fn xeddsa_sign_with_nonce(private_key: &StaticSecret, message: &[u8], z: &[u8; 64]) -> Signature {
+    let (public_key, a): ([u8; 32], Scalar) = calculate_key_pair(private_key);
+    let r: Scalar = hash_i(1, &[a.as_bytes(), message, z]);
+    let r_point: [u8; 32] = (&r * ED25519_BASEPOINT_TABLE).compress().to_bytes();
+    let h: Scalar = hash(&[&r_point, &public_key, message]);
+    let s: Scalar = r + h * a;
+
+    let mut signature: Signature = [0u8; 64];
+    signature[..32].copy_from_slice(&r_point);
+    signature[32..].copy_from_slice(s.as_bytes());
+    signature
+}
+
+/// Verify an XEdDSA signature with the X25519 public key of the signer
+///
+/// # Arguments
+///
+/// * `public_key` (&PublicKey): X25519 public key of the signer
+/// * `message` (&\[u8\]): Signed message
+/// * `signature` (&Signature): XEdDSA signature
+///
+/// # Output
+///
+/// * `valid` (bool): True if the signature is valid
+pub fn xeddsa_verify(public_key: &PublicKey, message: &[u8], signature: &Signature) -> bool {
+    let u: &[u8; 32] = public_key.as_bytes();
+    let r_bytes: [u8; 32] = signature[..32].try_into().expect("Incorrect length");
+    let s_bytes: [u8; 32] = signature[32..].try_into().expect("Incorrect length");
+    if !is_canonical_field_element(u) || s_bytes[31] & 0xE0 != 0 {
+        return false
+    }
+
+    // convert_mont(u): the Edwards point with the sign bit set to 0
+    let a_point: EdwardsPoint = match MontgomeryPoint(*u).to_edwards(0) {
+        Some(point) => point,
+        None => return false,
+    };
+    let public_key_edwards: CompressedEdwardsY = a_point.compress();
+    let h: Scalar = hash(&[&r_bytes, public_key_edwards.as_bytes(), message]);
+    let s: Scalar = Scalar::from_bytes_mod_order(s_bytes);
+    let r_check: EdwardsPoint = EdwardsPoint::vartime_double_scalar_mul_basepoint(&-h, &a_point, &s);
+
+    r_check.compress().to_bytes() == r_bytes
+}
+
+/// Check that `u` is the canonical encoding of a field element *(u < 2^255 - 19)*
+fn is_canonical_field_element(u: &[u8; 32]) -> bool {
+    if u[31] & 0x80 != 0 {
+        return false
+    }
+    // Values between 2^255 - 19 and 2^255 - 1 are 0x7FFF...FFED to 0x7FFF...FFFF in big-endian
+    !(u[31] == 0x7F && u[1..31].iter().all(|byte| *byte == 0xFF) && u[0] >= 0xED)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signature as Ed25519Signature, Verifier, VerifyingKey};
+
+    const MESSAGE: &[u8] = b"signed prekey";
+
+    #[test]
+    fn test_sign_and_verify() {
+        let private_key: StaticSecret = StaticSecret::from([0x01; 32]);
+        let public_key: PublicKey = PublicKey::from(&private_key);
+        let signature: Signature = xeddsa_sign(&private_key, MESSAGE);
+
+        assert!(xeddsa_verify(&public_key, MESSAGE, &signature));
+        assert!(!xeddsa_verify(&public_key, b"another message", &signature));
+        assert!(!xeddsa_verify(&PublicKey::from(&StaticSecret::from([0x02; 32])), MESSAGE, &signature));
+    }
+
+    #[test]
+    fn test_both_signs_of_the_edwards_key() {
+        // Half of the X25519 keys have an Edwards public key with the sign bit set, which requires negating the private scalar
+        let mut signs: Vec<bool> = Vec::new();
+        for seed in 0..16u8 {
+            let private_key: StaticSecret = StaticSecret::from([seed; 32]);
+            let (_, a): ([u8; 32], Scalar) = calculate_key_pair(&private_key);
+            signs.push(a != clamp(&private_key));
+            let signature: Signature = xeddsa_sign(&private_key, MESSAGE);
+            assert!(xeddsa_verify(&PublicKey::from(&private_key), MESSAGE, &signature), "Seed {}", seed);
+        }
+        assert!(signs.contains(&true) && signs.contains(&false));
+    }
+
+    #[test]
+    fn test_signature_is_a_valid_ed25519_signature() {
+        let private_key: StaticSecret = StaticSecret::from([0x03; 32]);
+        let (public_key, _): ([u8; 32], Scalar) = calculate_key_pair(&private_key);
+        let signature: Signature = xeddsa_sign_with_nonce(&private_key, MESSAGE, &[0x04; 64]);
+
+        let verifying_key: VerifyingKey = VerifyingKey::from_bytes(&public_key).unwrap();
+        assert!(verifying_key.verify(MESSAGE, &Ed25519Signature::from_bytes(&signature)).is_ok());
+    }
+
+    #[test]
+    fn test_reject_malformed_signature() {
+        let private_key: StaticSecret = StaticSecret::from([0x05; 32]);
+        let public_key: PublicKey = PublicKey::from(&private_key);
+        let signature: Signature = xeddsa_sign(&private_key, MESSAGE);
+
+        let mut large_s: Signature = signature;
+        large_s[63] |= 0x20;
+        let mut forged_r: Signature = signature;
+        forged_r[0] ^= 0x01;
+        let non_canonical_u: PublicKey = PublicKey::from([0xFF; 32]);
+
+        assert!(!xeddsa_verify(&public_key, MESSAGE, &large_s));
+        assert!(!xeddsa_verify(&public_key, MESSAGE, &forged_r));
+        assert!(!xeddsa_verify(&non_canonical_u, MESSAGE, &signature));
+    }
+}
\ No newline at end of file
@@ -1,17 +1,69 @@
 use crate::communication;
-use std::collections::HashMap;
-use communication::key_collection::{ClientKeyCollection, ServerKeyCollection};
-use crate::x3dh::x3dh::X3DHError;
-use crate::double_ratchet::double_ratchet::DoubleRatchet;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use communication::key_collection::{unix_time, ClientKeyCollection, ServerKeyCollection, SenderSharedSecret};
+use x3dh::{create_identity_signature, SignedPrekey, Signature, X3DHError};
+use crate::double_ratchet::double_ratchet::{DoubleRatchet, EncryptedMessage};
+use crate::double_ratchet::aead::{self, AeadAlgorithm, CryptoError, NonceMode};
 use x25519_dalek::PublicKey;
+use x3dh::mlkem::EncapsulationKey;
 
+use super::group::{new_group_id, Group, GroupError, GroupId, GroupMessage, SenderKeyDistribution};
+use super::identity_store::{IdentityError, IdentityStore, Trust};
 use super::key_collection::KeyError;
-use super::message::{Ciphertext, Header, Message};
+use super::relay::{Relay, RelayError};
+use super::safety_number;
+use super::sealed_sender::{self, SealedMessage, SealedSenderError, SenderCertificate, SENDER_CERTIFICATE_LIFETIME};
+use super::server::{login_message, Challenge, DeviceId, ServerError, SessionToken, PRIMARY_DEVICE_ID};
+use super::message::{write_bytes, Ciphertext, Content, Header, Envelope, Message, ParseError, X3DHHeader};
+
+/// Associated data and double ratchet of a session with a device
+type Session = (Vec<u8>, DoubleRatchet);
+/// Sender name, device, identity key (sealed messages), relay ids and messages of a sender device
+type SenderMessages = (String, DeviceId, Option<PublicKey>, Vec<u64>, Vec<Message>);
+/// Sender name, device, identity key, indexes in the batch and messages of a sealed sender device
+type SealedSenderMessages = (String, DeviceId, PublicKey, Vec<usize>, Vec<Message>);
+/// Sender name and plaintext of a sealed message *(see `Client::read_sealed_messages`)*
+pub type SealedPlaintext = Result<(String, Option<Vec<u8>>), ClientError>;
+
+/// Number of replaced sessions kept with each device to read the messages still in flight
+pub const MAX_PREVIOUS_SESSIONS: usize = 5;
+/// Number of ephemeral keys of initial messages remembered for each device *(the ones of the current and of the previous sessions)*
+pub const MAX_INITIAL_EKS: usize = MAX_PREVIOUS_SESSIONS + 1;
+
+#[derive(Debug)]
+pub enum ClientError {
+    X3DH(X3DHError),
+    Key(KeyError),
+    Crypto(CryptoError),
+    SessionNotFound,
+    IdentityNotFound,
+    Relay(RelayError),
+    SealedSender(SealedSenderError),
+    Group(GroupError),
+    Parse(ParseError),
+    IdentityChanged,
+    VerifiedIdentityChanged,
+}
 
 pub struct Client {
     name: String,
-    communications: HashMap<String, (Vec<u8>, DoubleRatchet)>, // Each communication has a different double ratchet (Key: username, ad) (Value: double ratchet for the communication)
+    device_id: DeviceId, // Device of the user running the client (given by the relay when the device is added)
+    communications: HashMap<(String, DeviceId), Session>, // Each communication has a different double ratchet (Key: (username, device), ad) (Value: double ratchet for the communication)
+    previous_communications: HashMap<(String, DeviceId), Vec<Session>>, // Sessions replaced by a new X3DH, kept to read the messages still in flight (the oldest one first)
+    unconfirmed_sessions: HashSet<(String, DeviceId)>, // Sessions started by the client that haven't received any message yet
+    initial_eks: HashMap<(String, DeviceId), Vec<PublicKey>>, // Ephemeral keys of the last initial messages read (the oldest one first), an initial message replayed doesn't start a new session
     keys: ClientKeyCollection,
+    relay_session: Option<SessionToken>, // Session opened on the relay by the last login
+    identities: IdentityStore, // Identity keys of the other devices trusted on first use, the sealed messages are encrypted to them
+    certificate_key: Option<PublicKey>, // Key of the relay signing the sender certificates, the messages sent are sealed once it's known
+    sender_certificate: Option<SenderCertificate>,
+    groups: HashMap<GroupId, Group>, // Groups of the client (Key: group id) (Value: members and sender keys of the group)
+    group_messages: Vec<(GroupId, String, Vec<u8>)>, // Group messages decrypted by `poll`, kept until `take_group_messages`
+    dropped_messages: Vec<(Option<String>, ClientError)>, // Messages that `poll` can never read, deleted from the relay and kept until `take_dropped_messages` (sender name if known, error)
+    aead: AeadAlgorithm, // AEAD of the sessions started by the client (the sessions started by the other devices use the AEAD of their initial message)
+    nonce_mode: NonceMode, // Nonces of the messages sent by the client
+    require_pq: bool, // PQXDH for the sessions started by the client, required for the initial messages of the other devices (off: classical X3DH for the clients without ML-KEM)
 }
 
 impl Client {
@@ -22,84 +74,202 @@ impl Client {
 
         // Create the client object
         Client {
-            name: name,
+            name,
+            device_id: PRIMARY_DEVICE_ID,
             communications: HashMap::new(),
-            keys: keys,
+            previous_communications: HashMap::new(),
+            unconfirmed_sessions: HashSet::new(),
+            initial_eks: HashMap::new(),
+            keys,
+            relay_session: None,
+            identities: IdentityStore::new(),
+            certificate_key: None,
+            sender_certificate: None,
+            groups: HashMap::new(),
+            group_messages: Vec::new(),
+            dropped_messages: Vec::new(),
+            aead: AeadAlgorithm::default(),
+            nonce_mode: NonceMode::default(),
+            require_pq: true,
         }
     }
 
     pub fn get_server_keys(&self) -> ServerKeyCollection {
-        ServerKeyCollection::from(self.keys.get_ik(), self.keys.get_spk(), self.keys.get_opk_bundle(), self.keys.get_signature(), self.keys.get_verifying_key())
+        ServerKeyCollection::from(self.keys.get_ik(), self.keys.get_spk(), self.keys.get_spk_id(), self.keys.get_opk_bundle(), self.keys.get_signature(), (self.keys.get_pqspk(), self.keys.get_pqspk_id(), self.keys.get_pq_signature()))
     }
 
     pub fn get_client_name(&self) -> String {
         self.name.clone()
     }
 
+    pub fn get_device_id(&self) -> DeviceId {
+        self.device_id
+    }
+
     pub fn get_keys(&self) -> &ClientKeyCollection {
         &self.keys
     }
 
+    /// Choose the AEAD of the next sessions started by the client *(the sessions already established keep their AEAD)*
+    pub fn set_aead(&mut self, aead: AeadAlgorithm) {
+        self.aead = aead;
+    }
+
+    pub fn get_aead(&self) -> AeadAlgorithm {
+        self.aead
+    }
+
+    /// Choose how the nonces of the messages sent are chosen, in every session *(the other devices read both modes)*
+    pub fn set_nonce_mode(&mut self, nonce_mode: NonceMode) {
+        self.nonce_mode = nonce_mode;
+        for (_, double_ratchet) in self.communications.values_mut() {
+            double_ratchet.set_nonce_mode(nonce_mode);
+        }
+    }
+
+    pub fn get_nonce_mode(&self) -> NonceMode {
+        self.nonce_mode
+    }
+
+    /// Choose whether the sessions use PQXDH *(on by default)*: off, the client starts its sessions with the classical X3DH
+    /// and reads the initial messages without ML-KEM ciphertext *(`KeyError::KemCiphertextAbsent` otherwise)*
+    pub fn set_require_pq(&mut self, require_pq: bool) {
+        self.require_pq = require_pq;
+    }
+
+    pub fn get_require_pq(&self) -> bool {
+        self.require_pq
+    }
+
+    /// Replace the signed prekey *(see `ClientKeyCollection::rotate_spk`)*
+    /// 
+    /// # Arguments
+    /// 
+    /// * `now` (u64): Current Unix time (in seconds)
+    /// 
+    /// # Output
+    /// 
+    /// * `(spk_id, spk, signature)` ((u32, PublicKey, Signature)): New signed prekey to publish with `Server::update_user_spk`
+    pub fn rotate_spk(&mut self, now: u64) -> (u32, PublicKey, Signature) {
+        self.keys.rotate_spk(now)
+    }
+
+    /// Generate a new batch of one-time prekeys if the server is running out of them *(see `ClientKeyCollection::replenish_opks`)*
+    /// 
+    /// # Arguments
+    /// 
+    /// * `opk_count` (usize): Number of one-time prekeys left on the server *(`Server::get_opk_count`)*
+    /// 
+    /// # Output
+    /// 
+    /// * `new_opks` (Option\<Vec\<(u32, PublicKey)\>\>): One-time prekeys to upload with `Server::add_user_opks`
+    pub fn replenish_opks(&mut self, opk_count: usize) -> Option<Vec<(u32, PublicKey)>> {
+        self.keys.replenish_opks(opk_count)
+    }
+
+    /// Scheduled rotation of the signed prekey *(see `ClientKeyCollection::rotate_spk_if_due`)*
+    pub fn rotate_spk_if_due(&mut self, now: u64) -> Option<(u32, PublicKey, Signature)> {
+        self.keys.rotate_spk_if_due(now)
+    }
+
+    /// Replace the ML-KEM prekey *(see `ClientKeyCollection::rotate_pqspk`)*
+    /// 
+    /// # Arguments
+    /// 
+    /// * `now` (u64): Current Unix time (in seconds)
+    /// 
+    /// # Output
+    /// 
+    /// * `(pqspk_id, pqspk, pq_signature)` ((u32, EncapsulationKey, Signature)): New ML-KEM prekey to publish with `Server::update_user_pqspk`
+    pub fn rotate_pqspk(&mut self, now: u64) -> (u32, EncapsulationKey, Signature) {
+        self.keys.rotate_pqspk(now)
+    }
+
+    /// Scheduled rotation of the ML-KEM prekey *(see `ClientKeyCollection::rotate_pqspk_if_due`)*
+    pub fn rotate_pqspk_if_due(&mut self, now: u64) -> Option<(u32, EncapsulationKey, Signature)> {
+        self.keys.rotate_pqspk_if_due(now)
+    }
+
     /// Read all the messages sent by one user
     /// 
     /// # Arguments
     /// 
-    /// * `receiver_name` (&String): Name of the person that will receive the message
+    /// * `receiver_name` (&str): Name of the person that will receive the message
+    /// * `device_id` (DeviceId): Device of the receiver
     /// * `messages` (&[u8]): Message(s) sent by the user *(can have multiple ciphertext when you are offline)*
     /// * `r_keys`: (&ServerKeyCollection)
     /// 
     /// # Output
     /// 
-    /// * `ciphertext` (Result\<((PublicKey, Option\<PublicKey\>), (Header, Ciphertext)), X3DHError\>): ((Public Ephemeral Key, Public One Time Prekey used), (Header, Ciphertext))
-    fn send_first_message(&mut self, receiver_name: &String, message: &[u8], r_keys: &ServerKeyCollection) -> Result<((PublicKey, Option<PublicKey>), (Header, Ciphertext)), X3DHError> {
-        // X3DH: Sending the initial message
-        let (sk, ad, ek_pub, opk_used): ([u8; 32], Vec<u8>, PublicKey, Option<PublicKey>);
-        (sk, ad, ek_pub, opk_used) = match self.keys.generate_sender_shared_secret(&r_keys) {
-            Ok((sk, ad, ek, opk)) => (sk, ad, ek, opk),
-            Err(error) => return Err(error)
-        };
+    /// * `ciphertext` (Result\<((PublicKey, u32, Option\<PublicKey\>, (u32, KemCiphertext)), (Header, Ciphertext)), ClientError\>): ((Public Ephemeral Key, Signed Prekey id, Public One Time Prekey used, (ML-KEM prekey id, ML-KEM ciphertext)), (Header, Ciphertext))
+    fn send_first_message(&mut self, receiver_name: &str, device_id: DeviceId, message: &[u8], r_keys: &ServerKeyCollection) -> Result<(X3DHHeader, (Header, Ciphertext)), ClientError> {
+        self.identities.check(receiver_name, device_id, &r_keys.get_ik())?;
+
+        // X3DH (PQXDH): Sending the initial message
+        let (sk, ad, ek_pub, opk_used, kem_ciphertext): SenderSharedSecret;
+        (sk, ad, ek_pub, opk_used, kem_ciphertext) = self.keys.generate_sender_shared_secret(r_keys, self.require_pq)?;
 
         // Double Ratchet
-        let mut double_ratchet: DoubleRatchet = DoubleRatchet::new();
+        let mut double_ratchet: DoubleRatchet = DoubleRatchet::with_aead(self.aead);
+        double_ratchet.set_nonce_mode(self.nonce_mode);
 
         double_ratchet.init_sender(sk, r_keys.get_spk());
         
-        let (header, ciphertext): ((PublicKey, u8, u8), (Vec<u8>, Vec<u8>));
-        (header, ciphertext) = double_ratchet.encrypt(message, &ad);
-        self.communications.insert(receiver_name.clone(), (ad, double_ratchet));
+        let (header, ciphertext): EncryptedMessage;
+        (header, ciphertext) = double_ratchet.encrypt(message, &ad)?;
+        self.communications.insert((receiver_name.to_string(), device_id), (ad, double_ratchet));
+        self.unconfirmed_sessions.insert((receiver_name.to_string(), device_id));
+        self.identities.save(receiver_name, device_id, r_keys.get_ik());
 
-        Ok(((ek_pub, opk_used), (Header::new(header.0, header.1, header.2), Ciphertext::new(ciphertext.0, ciphertext.1))))
+        Ok(((ek_pub, r_keys.get_spk_id(), opk_used, kem_ciphertext), (Header::new(header.0, header.1, header.2), Ciphertext::new(ciphertext.0, ciphertext.1))))
     }
 
     /// Read the first messages sent by one user *(Double ratchet not initialize yet)*
     /// 
     /// # Arguments
     /// 
-    /// * `sender_name` (&String): Name of the person that sent you the message
+    /// * `sender_name` (&str): Name of the person that sent you the message
+    /// * `device_id` (DeviceId): Device of the sender
     /// * `ik_sender` (PublicKey): Public Identity Key of the sender (input when you want to initialize the communication)
     /// * `messages` (& Message): Message sent by the user
     /// 
     /// # Output
     /// 
-    /// * `plaintext_received` (Result\<Vec\<Vec\<u8\>\>, KeyError\>): All the plaintext received *(can have multiple plaintext when you are offline)*
-    fn read_first_message(&mut self, sender_name: &String, ik_sender: PublicKey, message: &Message) -> Result<Vec<u8>, KeyError> {
+    /// * `plaintext_received` (Result\<Vec\<u8\>, ClientError\>): Plaintext of the first message
+    fn read_first_message(&mut self, sender_name: &str, device_id: DeviceId, ik_sender: PublicKey, message: &Message) -> Result<Vec<u8>, ClientError> {
+        self.identities.check(sender_name, device_id, &ik_sender)?;
+
         // X3DH: Receiving the initial message
-        let (sk, ad): ([u8; 32], Vec<u8>);
-        (sk, ad) = match self.keys.generate_receiver_shared_secret(ik_sender, &message) {
-            Ok((sk, ad)) => (sk, ad),
-            Err(error) => return Err(error),
-        };
+        let (sk, ad, spk): ([u8; 32], Vec<u8>, SignedPrekey);
+        (sk, ad, spk) = self.keys.generate_receiver_shared_secret(ik_sender, message, self.require_pq)?;
 
         // Double Ratchet
-        let mut double_ratchet: DoubleRatchet = DoubleRatchet::new();
+        let mut double_ratchet: DoubleRatchet = DoubleRatchet::with_aead(message.get_aead().unwrap_or_default());
+        double_ratchet.set_nonce_mode(self.nonce_mode);
 
-        double_ratchet.init_receiver(sk, (self.keys.get_spk_private(), self.keys.get_spk_public())); // Let like this to allow simple DH instead of X3DH to start
+        double_ratchet.init_receiver(sk, (spk.get_private_key(), spk.get_public_key())); // Let like this to allow simple DH instead of X3DH to start
 
         let plaintext: Vec<u8> = double_ratchet.decrypt((message.get_header().get_dh_pub(), message.get_header().get_pn(), message.get_header().get_n()), 
                     message.get_ciphertext().get_ciphertext(), 
                     message.get_ciphertext().get_nonce(), 
-                    &ad);
-        self.communications.insert(sender_name.clone(), (ad, double_ratchet));
+                    &ad)?;
+        if let Some(opk_used) = message.get_opk_used() {
+            self.keys.remove_opk(opk_used);
+        }
+        let key: (String, DeviceId) = (sender_name.to_string(), device_id);
+        if self.unconfirmed_sessions.contains(&key) && self.communications.contains_key(&key) && (self.name.as_str(), self.device_id) < (sender_name, device_id) {
+            // Crossing initial messages: both devices started a session before reading the initial message of the other one,
+            // both keep sending with the session started by the device with the lowest (name, device) and only read with the other one
+            self.keep_previous_session(sender_name, device_id, (ad, double_ratchet));
+        } else {
+            // The sender lost its session (or reset it, or won the crossing): the new session replaces the previous one
+            self.archive_session(sender_name, device_id);
+            self.communications.insert(key, (ad, double_ratchet));
+        }
+        self.identities.save(sender_name, device_id, ik_sender);
+        if let Some(ek_sender) = message.get_ek_sender() {
+            self.remember_initial_ek(sender_name, device_id, ek_sender);
+        }
 
         Ok(plaintext)
     }
@@ -108,73 +278,1727 @@ impl Client {
     /// 
     /// # Arguments
     /// 
-    /// * `receiver_name` (&String): Name of the person that will receive the message
+    /// * `receiver_name` (&str): Name of the person that will receive the message
+    /// * `device_id` (DeviceId): Device of the receiver
     /// * `messages` (&[u8]): Message(s) sent by the user *(can have multiple ciphertext when you are offline)*
     /// * `r_keys`: (&ServerKeyCollection)
     /// 
     /// # Output
     /// 
-    /// * `ciphertext` (Result\<(Option\<(PublicKey, Option<PublicKey>)>, (Header, Ciphertext)), X3DHError>): ((Public Ephemeral Key, Public One Time Prekey used), (Header, Ciphertext))
-    pub fn send_message(&mut self, receiver_name: &String, message: &[u8], r_keys: &ServerKeyCollection) -> Result<(Option<(PublicKey, Option<PublicKey>)>, (Header, Ciphertext)), X3DHError> {
+    /// * `ciphertext` (Result\<(Option\<(PublicKey, u32, Option<PublicKey>, Option<(u32, KemCiphertext)>)>, (Header, Ciphertext)), ClientError>): ((Public Ephemeral Key, Signed Prekey id, Public One Time Prekey used, (ML-KEM prekey id, ML-KEM ciphertext) if PQXDH is used), (Header, Ciphertext))
+    pub fn send_message(&mut self, receiver_name: &str, device_id: DeviceId, message: &[u8], r_keys: &ServerKeyCollection) -> Result<(Option<X3DHHeader>, (Header, Ciphertext)), ClientError> {
         // Send a message to the define user (check if the first message has already been sends, otherwise use first message instead)
-        if !self.communications.contains_key(receiver_name) {
-            match self.send_first_message(receiver_name, message, r_keys) {
-                Ok(((ek_pub, opk_used), (header, ciphertext))) => return Ok((Some((ek_pub, opk_used)), (header, ciphertext))),
-                Err(error) => return Err(error),
+        let message: &[u8] = &Content::Text(message.to_vec()).to_bytes()?;
+        if !self.communications.contains_key(&(receiver_name.to_string(), device_id)) {
+            match self.send_first_message(receiver_name, device_id, message, r_keys) {
+                Ok(((ek_pub, spk_id, opk_used, kem_ciphertext), (header, ciphertext))) => Ok((Some((ek_pub, spk_id, opk_used, kem_ciphertext)), (header, ciphertext))),
+                Err(error) => Err(error),
             }
         } else {
-            if let Some((ad, double_ratchet)) = self.communications.get_mut(receiver_name) {
-                let (header, ciphertext): ((PublicKey, u8, u8), (Vec<u8>, Vec<u8>));
-                (header, ciphertext) = double_ratchet.encrypt(message, &ad);
-                // Update communication
-                *self.communications.get_mut(receiver_name).unwrap() = (ad.clone(), double_ratchet.clone());
-                return Ok((None, (Header::new(header.0, header.1, header.2), Ciphertext::new(ciphertext.0, ciphertext.1))))
-            };
+            Ok((None, self.encrypt_message(receiver_name, device_id, message)?))
         }
+    }
 
-        panic!("User not found, which is not normal");
+    /// Encrypt a message for a user with the session already established
+    fn encrypt_message(&mut self, receiver_name: &str, device_id: DeviceId, message: &[u8]) -> Result<(Header, Ciphertext), ClientError> {
+        let (ad, double_ratchet) = self.communications.get_mut(&(receiver_name.to_string(), device_id)).ok_or(ClientError::SessionNotFound)?;
+        let (header, ciphertext): EncryptedMessage;
+        (header, ciphertext) = double_ratchet.encrypt(message, ad)?;
+        Ok((Header::new(header.0, header.1, header.2), Ciphertext::new(ciphertext.0, ciphertext.1)))
     }
-    
-    /// Read all the messages sent by one user
+
+    /// Publish the keys of the client on a relay and log in
+    pub fn register<R: Relay>(&mut self, relay: &mut R) -> Result<(), ClientError> {
+        relay.publish_keys(&self.name, self.get_server_keys())?;
+        self.login(relay)?;
+        Ok(())
+    }
+
+    /// Open a session on a relay by signing its challenge with the identity key of the device, the session is kept for the next requests
+    /// 
+    /// # Arguments
+    /// 
+    /// * `relay` (&mut R): Relay of the client
+    /// 
+    /// # Output
+    /// 
+    /// * `session` (Result\<SessionToken, ClientError\>): Token to send with the requests that need a session
+    pub fn login<R: Relay>(&mut self, relay: &mut R) -> Result<SessionToken, ClientError> {
+        let challenge: Challenge = relay.challenge(&self.name, self.device_id)?;
+        let signature: Signature = create_identity_signature(&self.keys.get_ik(), &login_message(&self.name, self.device_id, &challenge));
+        let session: SessionToken = relay.login(&self.name, self.device_id, signature)?;
+        self.relay_session = Some(session);
+        Ok(session)
+    }
+
+    /// Run a request that needs a session, logging in again if the relay doesn't know the session anymore *(e.g. after a restart)*
+    fn with_session<R: Relay, T>(&mut self, relay: &mut R, request: impl Fn(&mut R, &SessionToken) -> Result<T, RelayError>) -> Result<T, ClientError> {
+        let session: SessionToken = match self.relay_session {
+            Some(session) => session,
+            None => self.login(relay)?,
+        };
+        match request(relay, &session) {
+            Err(RelayError::Server(ServerError::NotAuthenticated)) => {
+                let session: SessionToken = self.login(relay)?;
+                Ok(request(relay, &session)?)
+            },
+            result => Ok(result?),
+        }
+    }
+
+    /// Link a new device to the user: the relay publishes its keys under a new device id, then the new device logs in
+    /// 
+    /// # Arguments
+    /// 
+    /// * `relay` (&mut R): Relay of the client
+    /// * `device` (&mut Client): Client of the new device *(created with the same username)*
+    /// 
+    /// # Output
+    /// 
+    /// * `device_id` (Result\<DeviceId, ClientError\>): Id given to the new device
+    pub fn add_device<R: Relay>(&mut self, relay: &mut R, device: &mut Client) -> Result<DeviceId, ClientError> {
+        let username: String = self.name.clone();
+        let keys: ServerKeyCollection = device.get_server_keys();
+        let device_id: DeviceId = self.with_session(relay, |relay, session| relay.add_device(&username, session, keys.clone()))?;
+        device.device_id = device_id;
+        device.login(relay)?;
+        Ok(device_id)
+    }
+
+    /// Unlink a device of the user *(the primary device can't be removed)*, its keys and its pending messages are deleted from the relay
+    /// 
+    /// # Arguments
+    /// 
+    /// * `relay` (&mut R): Relay of the client
+    /// * `device_id` (DeviceId): Device to remove
+    /// 
+    /// # Output
+    /// 
+    /// * `result` (Result\<(), ClientError\>)
+    pub fn remove_device<R: Relay>(&mut self, relay: &mut R, device_id: DeviceId) -> Result<(), ClientError> {
+        let username: String = self.name.clone();
+        self.with_session(relay, |relay, session| relay.remove_device(&username, session, device_id))?;
+        self.communications.remove(&(username.clone(), device_id));
+        self.previous_communications.remove(&(username.clone(), device_id));
+        self.identities.remove(&username, device_id);
+        Ok(())
+    }
+
+    /// Seal the messages sent from now on *(the relay only learns their receiver)* and accept the sealed messages certified by the relay
+    /// 
+    /// # Arguments
+    /// 
+    /// * `certificate_key` (PublicKey): Public key of the relay signing the sender certificates
+    pub fn enable_sealed_sender(&mut self, certificate_key: PublicKey) {
+        self.certificate_key = Some(certificate_key);
+    }
+
+    /// Encrypt a message for every device of the receiver and queue it on a relay, the other devices of the client get a copy too *(the prekey bundle of a device is only fetched to start the session)*
+    /// 
+    /// # Arguments
+    /// 
+    /// * `relay` (&mut R): Relay of the receiver
+    /// * `receiver_name` (&String): Name of the person that will receive the message
+    /// * `message` (&\[u8\]): Plaintext
+    /// 
+    /// # Output
+    /// 
+    /// * `result` (Result\<(), ClientError\>)
+    pub fn send_to<R: Relay>(&mut self, relay: &mut R, receiver_name: &String, message: &[u8]) -> Result<(), ClientError> {
+        let mut usernames: Vec<String> = vec![receiver_name.clone()];
+        if *receiver_name != self.name {
+            usernames.push(self.name.clone());
+        }
+        self.send_content(relay, &usernames, &Content::Text(message.to_vec()))
+    }
+
+    /// Encrypt a content for every device of some users *(except the device of the client)* and queue it on a relay
+    fn send_content<R: Relay>(&mut self, relay: &mut R, usernames: &[String], content: &Content) -> Result<(), ClientError> {
+        let content: Vec<u8> = content.to_bytes()?;
+        for username in usernames {
+            let devices: Vec<DeviceId> = relay.devices(username)?;
+            self.forget_removed_devices(username, &devices);
+            for device_id in devices {
+                if *username == self.name && device_id == self.device_id {
+                    continue
+                }
+                self.send_to_device(relay, username, device_id, &content)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Encrypt a message for one device and queue it on a relay
+    fn send_to_device<R: Relay>(&mut self, relay: &mut R, receiver_name: &str, device_id: DeviceId, message: &[u8]) -> Result<(), ClientError> {
+        let message: Message = if self.communications.contains_key(&(receiver_name.to_string(), device_id)) {
+            let (header, ciphertext): (Header, Ciphertext) = self.encrypt_message(receiver_name, device_id, message)?;
+            Message::new((self.name.clone(), self.device_id), (header, ciphertext), None, None, None, None, None)
+        } else {
+            let r_keys: ServerKeyCollection = relay.fetch_bundle(receiver_name, device_id)?;
+            let ((ek_pub, spk_id, opk_used, kem_ciphertext), (header, ciphertext)) = self.send_first_message(receiver_name, device_id, message, &r_keys)?;
+            Message::new((self.name.clone(), self.device_id), (header, ciphertext), Some(ek_pub), Some(spk_id), opk_used, kem_ciphertext, Some(self.aead))
+        };
+        let envelope: Envelope = match self.certificate_key {
+            Some(_) => Envelope::Sealed(self.seal_message(relay, receiver_name, device_id, &message)?),
+            None => Envelope::Plain(Box::new(message)),
+        };
+        relay.enqueue(receiver_name, device_id, envelope)?;
+        Ok(())
+    }
+
+    /// Drop the sessions held with the devices of a user that were removed from the relay
+    fn forget_removed_devices(&mut self, username: &String, devices: &[DeviceId]) {
+        self.communications.retain(|(current_username, device_id), _| current_username != username || devices.contains(device_id));
+        self.previous_communications.retain(|(current_username, device_id), _| current_username != username || devices.contains(device_id));
+        self.identities.retain_devices(username, devices);
+    }
+
+    /// Create a group and send the sender key of the client to its members, the client is the admin of the group
+    /// 
+    /// # Arguments
+    /// 
+    /// * `relay` (&mut R): Relay of the members
+    /// * `members` (Vec\<String\>): Names of the other members
+    /// 
+    /// # Output
+    /// 
+    /// * `group_id` (Result\<GroupId, ClientError\>): Id of the new group
+    pub fn create_group<R: Relay>(&mut self, relay: &mut R, mut members: Vec<String>) -> Result<GroupId, ClientError> {
+        let group_id: GroupId = new_group_id();
+        members.push(self.name.clone());
+        self.groups.insert(group_id, Group::new(group_id, members, vec![self.name.clone()]));
+        self.distribute_sender_key(relay, &group_id)?;
+        Ok(group_id)
+    }
+
+    /// Add a member to a group, the members are rekeyed *(each of them sends a new sender key before its next message)*
+    /// 
+    /// Only an admin of the group can add a member *(`GroupError::NotAdmin`)*
+    pub fn add_group_member<R: Relay>(&mut self, relay: &mut R, group_id: &GroupId, username: &str) -> Result<(), ClientError> {
+        let group: &mut Group = self.groups.get_mut(group_id).ok_or(GroupError::GroupNotFound)?;
+        if !group.is_admin(&self.name) {
+            return Err(ClientError::Group(GroupError::NotAdmin))
+        }
+        let mut members: Vec<String> = group.get_members();
+        members.push(username.to_string());
+        group.set_members(members);
+        self.distribute_sender_key(relay, group_id)
+    }
+
+    /// Remove a member from a group, the remaining members are rekeyed so that the removed member can't read their next messages
+    /// 
+    /// Only an admin of the group can remove a member *(`GroupError::NotAdmin`)*
+    pub fn remove_group_member<R: Relay>(&mut self, relay: &mut R, group_id: &GroupId, username: &str) -> Result<(), ClientError> {
+        let group: &mut Group = self.groups.get_mut(group_id).ok_or(GroupError::GroupNotFound)?;
+        if !group.is_admin(&self.name) {
+            return Err(ClientError::Group(GroupError::NotAdmin))
+        }
+        let members: Vec<String> = group.get_members();
+        if !members.iter().any(|member| member == username) {
+            return Err(ClientError::Group(GroupError::MemberNotFound))
+        }
+        group.set_members(members.into_iter().filter(|member| member != username).collect());
+        self.distribute_sender_key(relay, group_id)
+    }
+
+    /// Allow a member of a group to change its members, the other members learn it with the next sender key of the client
+    pub fn add_group_admin<R: Relay>(&mut self, relay: &mut R, group_id: &GroupId, username: &String) -> Result<(), ClientError> {
+        let group: &mut Group = self.groups.get_mut(group_id).ok_or(GroupError::GroupNotFound)?;
+        if !group.is_admin(&self.name) {
+            return Err(ClientError::Group(GroupError::NotAdmin))
+        }
+        if !group.is_member(username) {
+            return Err(ClientError::Group(GroupError::MemberNotFound))
+        }
+        let mut admins: Vec<String> = group.get_admins();
+        admins.push(username.clone());
+        group.set_admins(admins);
+        self.distribute_sender_key(relay, group_id)
+    }
+
+    /// Leave a group, the other members drop the sender keys of the client and are rekeyed
+    pub fn leave_group<R: Relay>(&mut self, relay: &mut R, group_id: &GroupId) -> Result<(), ClientError> {
+        let group: Group = self.groups.remove(group_id).ok_or(GroupError::GroupNotFound)?;
+        self.send_content(relay, &group.get_members(), &Content::GroupLeave(*group_id))
+    }
+
+    /// Returns the members of a group
+    pub fn get_group_members(&self, group_id: &GroupId) -> Option<Vec<String>> {
+        self.groups.get(group_id).map(|group| group.get_members())
+    }
+
+    /// Returns the admins of a group
+    pub fn get_group_admins(&self, group_id: &GroupId) -> Option<Vec<String>> {
+        self.groups.get(group_id).map(|group| group.get_admins())
+    }
+
+    /// Encrypt a message once with the sender key of the client and queue it for every device of the members of a group
     /// 
     /// # Arguments
     /// 
-    /// * `sender_name` (&String): Name of the person that sent you the message
-    /// * `ik_sender` (Option\<PublicKey\>): Public Identity Key of the sender (input when you want to initialize the communication)
-    /// * `messages` (mut Vec\<Message\>): Message(s) sent by the user *(can have multiple ciphertext when you are offline)*
+    /// * `relay` (&mut R): Relay of the members
+    /// * `group_id` (&GroupId): Id of the group
+    /// * `message` (&\[u8\]): Plaintext
+    /// 
+    /// # Output
+    /// 
+    /// * `result` (Result\<(), ClientError\>)
+    pub fn send_group_message<R: Relay>(&mut self, relay: &mut R, group_id: &GroupId, message: &[u8]) -> Result<(), ClientError> {
+        let group: &mut Group = self.groups.get_mut(group_id).ok_or(GroupError::GroupNotFound)?;
+        if group.needs_sender_key() {
+            self.distribute_sender_key(relay, group_id)?;
+        }
+        let group: &mut Group = self.groups.get_mut(group_id).ok_or(GroupError::GroupNotFound)?;
+        let group_message: GroupMessage = group.encrypt(&self.name, self.device_id, message)?;
+        for username in group.get_members() {
+            for device_id in relay.devices(&username)? {
+                if username == self.name && device_id == self.device_id {
+                    continue
+                }
+                relay.enqueue(&username, device_id, Envelope::Group(group_message.clone()))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the group messages decrypted by `poll` since the last call
+    /// 
+    /// # Output
+    /// 
+    /// * `group_messages` (Vec\<(GroupId, String, Vec\<u8\>)\>): (Group id, sender name, plaintext) in their order of arrival
+    pub fn take_group_messages(&mut self) -> Vec<(GroupId, String, Vec<u8>)> {
+        std::mem::take(&mut self.group_messages)
+    }
+
+    /// Returns the messages that `poll` deleted from the relay because they can never be read *(see `ClientError::is_permanent`)*
     /// 
     /// # Output
     /// 
-    /// * `plaintext_received` (Result\<Vec\<Vec\<u8\>\>, KeyError\>): All the plaintext received *(can have multiple plaintext when you are offline)*
-    pub fn read_messages(&mut self, sender_name: &String, ik_sender: Option<PublicKey>, mut messages: Vec<Message>) -> Result<Vec<Vec<u8>>, KeyError> {
-        // If it's the first message init the double ratchet with X3DH
-        let mut plaintext_received: Vec<Vec<u8>> = Vec::new();
-        if messages.len() > 0 { 
-            if !self.communications.contains_key(sender_name) {
-                if let Some(ik) = ik_sender {
-                    let first_message: Message = messages.pop().unwrap();
-                    match self.read_first_message(sender_name, ik, &first_message) {
-                        Ok(plaintext) => plaintext_received.push(plaintext),
-                        Err(error) => return Err(error),
-                    };
-                } else {
-                    return Err(KeyError::IdentityKeyAbsent)
+    /// * `dropped_messages` (Vec\<(Option\<String\>, ClientError)\>): (Sender name if known, error) in their order of arrival
+    pub fn take_dropped_messages(&mut self) -> Vec<(Option<String>, ClientError)> {
+        std::mem::take(&mut self.dropped_messages)
+    }
+
+    /// Generate a new sender key for a group and send it to every device of its members over the pairwise sessions
+    fn distribute_sender_key<R: Relay>(&mut self, relay: &mut R, group_id: &GroupId) -> Result<(), ClientError> {
+        let group: &mut Group = self.groups.get_mut(group_id).ok_or(GroupError::GroupNotFound)?;
+        let distribution: SenderKeyDistribution = group.rotate_sender_key();
+        let members: Vec<String> = group.get_members();
+        self.send_content(relay, &members, &Content::SenderKey(distribution))
+    }
+
+    /// Apply a content received over a pairwise session, returns the text of the user *(the group updates are only applied)*
+    fn read_content(&mut self, sender_name: &String, device_id: DeviceId, content: &[u8]) -> Result<Option<Vec<u8>>, ClientError> {
+        match Content::from_bytes(content)? {
+            Content::Text(text) => Ok(Some(text)),
+            Content::SenderKey(distribution) => {
+                let group_id: GroupId = distribution.get_group_id();
+                let members: Vec<String> = distribution.get_members();
+                if !members.contains(sender_name) {
+                    return Ok(None)
                 }
-                
+                match self.groups.get_mut(&group_id) {
+                    Some(group) => {
+                        if !group.is_member(sender_name) {
+                            return Ok(None)
+                        }
+                        // Only an admin can change the members, the sender keys of the other members are still stored
+                        if group.is_admin(sender_name) {
+                            if !members.contains(&self.name) {
+                                // The client has been removed from the group
+                                self.groups.remove(&group_id);
+                                return Ok(None)
+                            }
+                            group.set_members(members);
+                            group.set_admins(distribution.get_admins());
+                        }
+                    },
+                    None => {
+                        // A group is learned from the sender key of an admin *(the creator sends it first)*
+                        if !members.contains(&self.name) || !distribution.get_admins().contains(sender_name) {
+                            return Ok(None)
+                        }
+                        self.groups.insert(group_id, Group::new(group_id, members, distribution.get_admins()));
+                    },
+                }
+                let group: &mut Group = self.groups.get_mut(&group_id).ok_or(GroupError::GroupNotFound)?;
+                group.process_distribution(sender_name, device_id, &distribution, unix_time());
+                Ok(None)
+            },
+            Content::GroupLeave(group_id) => {
+                if *sender_name == self.name {
+                    // Another device of the client left the group
+                    self.groups.remove(&group_id);
+                } else if let Some(group) = self.groups.get_mut(&group_id) {
+                    group.remove_member(sender_name);
+                }
+                Ok(None)
+            },
+        }
+    }
+
+    /// Decrypt a group message with the sender key of its sender device
+    fn read_group_message(&mut self, group_message: &GroupMessage) -> Result<Vec<u8>, ClientError> {
+        let group: &mut Group = self.groups.get_mut(&group_message.get_group_id()).ok_or(GroupError::GroupNotFound)?;
+        Ok(group.decrypt(group_message, unix_time())?)
+    }
+
+    /// Returns the safety number of the client and a device of a contact *(see `safety_number`)*, the users compare it to check their identity keys
+    /// 
+    /// # Arguments
+    /// 
+    /// * `username` (&String): Name of the contact
+    /// * `device_id` (DeviceId): Device of the contact
+    /// 
+    /// # Output
+    /// 
+    /// * `safety_number` (Result\<String, ClientError\>): 60 digits, the same on both sides *(error if no identity key is known for the device)*
+    pub fn safety_number(&self, username: &String, device_id: DeviceId) -> Result<String, ClientError> {
+        let ik: PublicKey = self.known_identity_key(username, device_id)?;
+        Ok(safety_number::safety_number(&self.name, &self.keys.get_ik().get_public_key(), username, &ik))
+    }
+
+    /// Returns the payload of the QR code to show to a contact *(see `verify_scannable_payload`)*
+    pub fn scannable_payload(&self, username: &String, device_id: DeviceId) -> Result<Vec<u8>, ClientError> {
+        let ik: PublicKey = self.known_identity_key(username, device_id)?;
+        Ok(safety_number::scannable_payload(&self.name, &self.keys.get_ik().get_public_key(), username, &ik))
+    }
+
+    /// Check the payload scanned from the QR code of a contact, the contact is marked as verified if the identity keys match
+    /// 
+    /// # Arguments
+    /// 
+    /// * `username` (&String): Name of the contact
+    /// * `device_id` (DeviceId): Device of the contact
+    /// * `payload` (&\[u8\]): Payload scanned
+    /// 
+    /// # Output
+    /// 
+    /// * `matching` (Result\<bool, ClientError\>): True if both users have the same identity keys
+    pub fn verify_scannable_payload(&mut self, username: &String, device_id: DeviceId, payload: &[u8]) -> Result<bool, ClientError> {
+        let ik: PublicKey = self.known_identity_key(username, device_id)?;
+        let matching: bool = safety_number::verify_scannable_payload(payload, &self.name, &self.keys.get_ik().get_public_key(), username, &ik)?;
+        if matching {
+            self.identities.verify(username, device_id);
+        }
+        Ok(matching)
+    }
+
+    /// Mark a device of a contact as verified *(after comparing the safety numbers)*
+    pub fn mark_verified(&mut self, username: &str, device_id: DeviceId) -> Result<(), ClientError> {
+        match self.identities.verify(username, device_id) {
+            true => Ok(()),
+            false => Err(ClientError::IdentityNotFound),
+        }
+    }
+
+    pub fn is_verified(&self, username: &str, device_id: DeviceId) -> bool {
+        self.identities.get_trust(username, device_id) == Some(Trust::Verified)
+    }
+
+    /// Returns the identity keys known by the client *(see `IdentityStore::get_identities`)*
+    pub fn get_identity_store(&self) -> &IdentityStore {
+        &self.identities
+    }
+
+    /// Trust the new identity key refused for a device of a contact, the session with this device is dropped so that a new one is started with the new key
+    /// 
+    /// # Arguments
+    /// 
+    /// * `username` (&str): Name of the contact
+    /// * `device_id` (DeviceId): Device of the contact
+    /// 
+    /// # Output
+    /// 
+    /// * `result` (Result\<(), ClientError\>): Error if no key was refused for this device
+    pub fn accept_identity_key(&mut self, username: &str, device_id: DeviceId) -> Result<(), ClientError> {
+        if !self.identities.accept_changed_key(username, device_id) {
+            return Err(ClientError::Key(KeyError::IdentityKeyAbsent))
+        }
+        self.communications.remove(&(username.to_string(), device_id));
+        self.previous_communications.remove(&(username.to_string(), device_id));
+        Ok(())
+    }
+
+    /// Fetch the identity key of a device of a contact from a relay, and check it against the key trusted by the client *(trusted on first use)*
+    /// 
+    /// # Arguments
+    /// 
+    /// * `relay` (&mut R): Relay of the contact
+    /// * `username` (&str): Name of the contact
+    /// * `device_id` (DeviceId): Device of the contact
+    /// 
+    /// # Output
+    /// 
+    /// * `ik` (Result\<PublicKey, ClientError\>): Identity key on the relay *(`ClientError::IdentityChanged` or `ClientError::VerifiedIdentityChanged` if it isn't the trusted one)*
+    pub fn check_identity_key<R: Relay>(&mut self, relay: &mut R, username: &str, device_id: DeviceId) -> Result<PublicKey, ClientError> {
+        let ik: PublicKey = relay.identity_key(username, device_id)?;
+        self.identities.check(username, device_id, &ik)?;
+        self.identities.save(username, device_id, ik);
+        Ok(ik)
+    }
+
+    /// Returns the identity key known for a device of a contact
+    fn known_identity_key(&self, username: &str, device_id: DeviceId) -> Result<PublicKey, ClientError> {
+        self.identities.get(username, device_id).ok_or(ClientError::IdentityNotFound)
+    }
+
+    /// Seal a message so that only its receiver learns who sent it *(the certificate of the client is renewed before it expires)*
+    fn seal_message<R: Relay>(&mut self, relay: &mut R, receiver_name: &str, device_id: DeviceId, message: &Message) -> Result<SealedMessage, ClientError> {
+        let certificate: SenderCertificate = match &self.sender_certificate {
+            Some(certificate) if certificate.get_expiration() > unix_time() + SENDER_CERTIFICATE_LIFETIME / 2 => certificate.clone(),
+            _ => {
+                let username: String = self.name.clone();
+                self.with_session(relay, |relay, session| relay.sender_certificate(&username, session))?
+            },
+        };
+        self.sender_certificate = Some(certificate.clone());
+        let ik_receiver: PublicKey = match self.identities.get(receiver_name, device_id) {
+            Some(ik) => ik,
+            None => relay.identity_key(receiver_name, device_id)?,
+        };
+        self.identities.save(receiver_name, device_id, ik_receiver);
+
+        Ok(sealed_sender::seal(&self.keys.get_ik(), &ik_receiver, &certificate, message)?)
+    }
+
+    /// Open a sealed message, returns the name, the device and the identity key of its sender with the message
+    fn unseal_message(&self, sealed_message: &SealedMessage) -> Result<(String, DeviceId, PublicKey, Message), ClientError> {
+        let certificate_key: PublicKey = self.certificate_key.ok_or(SealedSenderError::CertificateKeyUnknown)?;
+        Ok(sealed_sender::unseal(&self.keys.get_ik(), &certificate_key, sealed_message, unix_time())?)
+    }
+
+    /// Fetch the messages queued for the client on a relay, decrypt them and acknowledge the ones decrypted
+    /// 
+    /// A message that can never be read *(forged, replayed, for a session or a sender key no longer known, see `ClientError::is_permanent`)* is acknowledged too and kept with its error until `take_dropped_messages`.
+    /// The other messages that can't be read yet *(relay unreachable, identity key changed, certificate key unknown)* stay on the relay and are fetched again at the next poll, the other messages of their sender are still read.
+    /// The copies of the messages sent by the other devices of the client are returned with the name of the client.
+    /// The group messages are kept until `take_group_messages`.
+    /// 
+    /// # Arguments
+    /// 
+    /// * `relay` (&mut R): Relay of the client
+    /// 
+    /// # Output
+    /// 
+    /// * `plaintext_received` (Result\<Vec\<(String, Vec\<u8\>)\>, ClientError\>): (Sender name, plaintext) of every message decrypted, grouped by sender device
+    pub fn poll<R: Relay>(&mut self, relay: &mut R) -> Result<Vec<(String, Vec<u8>)>, ClientError> {
+        // Open the sealed messages and group the messages by sender device, keeping their order of arrival
+        let mut messages_by_sender: Vec<SenderMessages> = Vec::new();
+        let mut group_messages: Vec<(u64, GroupMessage)> = Vec::new();
+        let mut dropped_ids: Vec<u64> = Vec::new();
+        let username: String = self.name.clone();
+        for (id, envelope) in self.with_session(relay, |relay, session| relay.fetch(&username, session))? {
+            let (sender_name, device_id, ik_sender, message): (String, DeviceId, Option<PublicKey>, Message) = match envelope {
+                Envelope::Plain(message) => (message.get_username(), message.get_device_id(), None, *message),
+                Envelope::Sealed(sealed_message) => match self.unseal_message(&sealed_message) {
+                    Ok((sender_name, device_id, ik_sender, message)) => (sender_name, device_id, Some(ik_sender), message),
+                    Err(error) => {
+                        if error.is_permanent() {
+                            self.dropped_messages.push((None, error));
+                            dropped_ids.push(id);
+                        }
+                        continue
+                    },
+                },
+                Envelope::Group(group_message) => {
+                    group_messages.push((id, group_message));
+                    continue
+                },
+            };
+            match messages_by_sender.iter_mut().find(|(current_sender_name, current_device_id, _, _, _)| *current_sender_name == sender_name && *current_device_id == device_id) {
+                Some((_, _, current_ik_sender, ids, messages)) => {
+                    *current_ik_sender = current_ik_sender.or(ik_sender);
+                    ids.push(id);
+                    messages.push(message);
+                },
+                None => messages_by_sender.push((sender_name, device_id, ik_sender, vec![id], vec![message])),
             }
-            
-            if let Some((ad, double_ratchet)) = self.communications.get_mut(sender_name) {
-                for message in messages {
-                    let current_plaintext: Vec<u8> = double_ratchet.decrypt((message.get_header().get_dh_pub(), message.get_header().get_pn(), message.get_header().get_n()), 
-                        message.get_ciphertext().get_ciphertext(), 
-                        message.get_ciphertext().get_nonce(), 
-                        ad);
-                    plaintext_received.push(current_plaintext);                    
+        }
+
+        let mut plaintext_received: Vec<(String, Vec<u8>)> = Vec::new();
+        for (sender_name, device_id, ik_sender, ids, messages) in messages_by_sender {
+            // The identity key of the sender is only needed to start a session, a sealed message already holds it
+            let ik_sender: Option<PublicKey> = if messages.iter().all(|message| message.get_ek_sender().is_none()) {
+                None
+            } else if ik_sender.is_some() {
+                ik_sender
+            } else {
+                match relay.identity_key(&sender_name, device_id) {
+                    Ok(ik) => Some(ik),
+                    Err(_) => continue,
+                }
+            };
+            let plaintexts: Vec<Result<Option<Vec<u8>>, ClientError>> = self.read_messages(&sender_name, device_id, ik_sender, messages);
+
+            // The messages are only deleted from the relay once they have been decrypted, or if they can never be
+            let mut read_ids: Vec<u64> = Vec::new();
+            for (id, plaintext) in ids.into_iter().zip(plaintexts) {
+                match plaintext {
+                    Ok(Some(plaintext)) => plaintext_received.push((sender_name.clone(), plaintext)),
+                    Ok(None) => (),
+                    Err(error) if error.is_permanent() => self.dropped_messages.push((Some(sender_name.clone()), error)),
+                    Err(_) => continue,
                 }
-                *self.communications.get_mut(sender_name).unwrap() = (ad.clone(), double_ratchet.clone());
+                read_ids.push(id);
+            }
+            if !read_ids.is_empty() {
+                self.with_session(relay, |relay, session| relay.acknowledge(&username, session, &read_ids))?;
             }
         }
 
+        // The group messages are read once the sender keys sent with the pairwise messages are known
+        for (id, group_message) in group_messages {
+            match self.read_group_message(&group_message) {
+                Ok(plaintext) => self.group_messages.push((group_message.get_group_id(), group_message.get_username(), plaintext)),
+                // The messages of a group the client left are dropped
+                Err(ClientError::Group(GroupError::GroupNotFound)) => (),
+                Err(error) if error.is_permanent() => self.dropped_messages.push((Some(group_message.get_username()), error)),
+                Err(_) => continue,
+            }
+            dropped_ids.push(id);
+        }
+        if !dropped_ids.is_empty() {
+            self.with_session(relay, |relay, session| relay.acknowledge(&username, session, &dropped_ids))?;
+        }
         Ok(plaintext_received)
     }
+    
+    /// Read all the messages sent by one user
+    /// 
+    /// # Arguments
+    /// 
+    /// * `sender_name` (&str): Name of the person that sent you the message
+    /// * `device_id` (DeviceId): Device of the sender
+    /// * `ik_sender` (Option\<PublicKey\>): Public Identity Key of the sender, only used by the initial messages *(`None`: the key of the identity store)*
+    /// * `messages` (Vec\<Message\>): Message(s) sent by the user, in any order *(can have multiple ciphertext when you are offline)*
+    /// 
+    /// The identity key of a sender can't be read from a plain message, that only names its sender: `poll` fetches it from the relay,
+    /// and `read_sealed_messages` takes it from the sender certificate. It's still a parameter for the first message of a device
+    /// unknown to the identity store, or to check a new key of the sender *(`ClientError::IdentityChanged`)*
+    /// 
+    /// # Output
+    /// 
+    /// * `plaintext_received` (Vec\<Result\<Option\<Vec\<u8\>\>, ClientError\>\>): Result of each message, in the order of `messages` *(`None` for the group updates, they are only applied)*
+    pub fn read_messages(&mut self, sender_name: &String, device_id: DeviceId, ik_sender: Option<PublicKey>, messages: Vec<Message>) -> Vec<Result<Option<Vec<u8>>, ClientError>> {
+        let ik_sender: Option<PublicKey> = ik_sender.or_else(|| self.identities.get(sender_name, device_id));
+        let mut plaintext_received: Vec<Option<Result<Vec<u8>, ClientError>>> = messages.iter().map(|_| None).collect();
+
+        // Init the double ratchet with X3DH from each initial message not read yet *(holding X3DH keys)*, wherever it is in the backlog
+        // An initial message from a device with a session means that the sender lost its session, the previous session is kept for the messages in flight
+        for (index, message) in messages.iter().enumerate() {
+            match message.get_ek_sender() {
+                Some(ek_sender) if !self.initial_eks.get(&(sender_name.clone(), device_id)).is_some_and(|initial_eks| initial_eks.contains(&ek_sender)) => (),
+                _ => continue,
+            }
+            plaintext_received[index] = Some(match ik_sender {
+                Some(ik) => self.read_first_message(sender_name, device_id, ik, message),
+                None => Err(ClientError::Key(KeyError::IdentityKeyAbsent)),
+            });
+        }
+
+        // The other messages are decrypted with the sessions, the keys of the messages skipped are kept for the ones arriving later
+        for (index, message) in messages.iter().enumerate() {
+            if plaintext_received[index].is_none() {
+                plaintext_received[index] = Some(self.decrypt_message(sender_name, device_id, message));
+            }
+        }
+
+        plaintext_received.into_iter()
+            .map(|plaintext| match plaintext.expect("Every message is read") {
+                Ok(content) => self.read_content(sender_name, device_id, &content),
+                Err(error) => Err(error),
+            })
+            .collect()
+    }
+
+    /// Decrypt a message with the current session, or with the previous sessions for a message sent before a reset *(a session is left untouched if the message can't be decrypted)*
+    fn decrypt_message(&mut self, sender_name: &str, device_id: DeviceId, message: &Message) -> Result<Vec<u8>, ClientError> {
+        let key: (String, DeviceId) = (sender_name.to_string(), device_id);
+        let current_session: bool = self.communications.contains_key(&key);
+        let mut sessions: Vec<&mut Session> = self.communications.get_mut(&key).into_iter().collect();
+        if let Some(previous_sessions) = self.previous_communications.get_mut(&key) {
+            sessions.extend(previous_sessions.iter_mut().rev());
+        }
+
+        let mut error: ClientError = ClientError::SessionNotFound;
+        for (index, (ad, double_ratchet)) in sessions.into_iter().enumerate() {
+            match double_ratchet.decrypt((message.get_header().get_dh_pub(), message.get_header().get_pn(), message.get_header().get_n()), 
+                message.get_ciphertext().get_ciphertext(), 
+                message.get_ciphertext().get_nonce(), 
+                ad) {
+                Ok(plaintext) => {
+                    // The other device read the initial message of the current session
+                    if index == 0 && current_session {
+                        self.unconfirmed_sessions.remove(&key);
+                    }
+                    return Ok(plaintext)
+                },
+                // The error of the current session is the one returned
+                Err(current_error) if matches!(error, ClientError::SessionNotFound) => error = ClientError::Crypto(current_error),
+                Err(_) => (),
+            }
+        }
+        Err(error)
+    }
+
+    /// Remember the ephemeral key of an initial message read, the oldest one is forgotten past `MAX_INITIAL_EKS`
+    fn remember_initial_ek(&mut self, username: &str, device_id: DeviceId, ek: PublicKey) {
+        let initial_eks: &mut Vec<PublicKey> = self.initial_eks.entry((username.to_string(), device_id)).or_default();
+        initial_eks.retain(|initial_ek| *initial_ek != ek);
+        initial_eks.push(ek);
+        if initial_eks.len() > MAX_INITIAL_EKS {
+            initial_eks.remove(0);
+        }
+    }
+
+    /// Set the current session with a device aside to read the messages still in flight, a new session *(X3DH)* takes its place
+    fn archive_session(&mut self, username: &str, device_id: DeviceId) {
+        self.unconfirmed_sessions.remove(&(username.to_string(), device_id));
+        if let Some(session) = self.communications.remove(&(username.to_string(), device_id)) {
+            self.keep_previous_session(username, device_id, session);
+        }
+    }
+
+    /// Keep a session only used to read messages, the oldest previous session is dropped past `MAX_PREVIOUS_SESSIONS`
+    fn keep_previous_session(&mut self, username: &str, device_id: DeviceId, session: Session) {
+        let previous_sessions: &mut Vec<Session> = self.previous_communications.entry((username.to_string(), device_id)).or_default();
+        previous_sessions.push(session);
+        if previous_sessions.len() > MAX_PREVIOUS_SESSIONS {
+            previous_sessions.remove(0);
+        }
+    }
+
+    /// Reset the sessions with every device of a user *(e.g. after a decryption failure)*, the next message sent to this user starts new sessions with X3DH
+    /// 
+    /// The previous sessions are kept to read the messages still in flight, the devices of the user start using the new sessions once they read the new initial messages.
+    /// 
+    /// # Arguments
+    /// 
+    /// * `username` (&String): Name of the user
+    /// 
+    /// # Output
+    /// 
+    /// * `result` (Result\<(), ClientError\>): Error if there is no session with this user
+    pub fn reset_session(&mut self, username: &String) -> Result<(), ClientError> {
+        let devices: Vec<DeviceId> = self.communications.keys()
+            .filter(|(current_username, _)| current_username == username)
+            .map(|(_, device_id)| *device_id)
+            .collect();
+        if devices.is_empty() {
+            return Err(ClientError::SessionNotFound)
+        }
+        for device_id in devices {
+            self.archive_session(username, device_id);
+        }
+        Ok(())
+    }
+
+    /// Read sealed messages, the name, the device and the identity key of each sender come from its sealed message
+    /// 
+    /// # Arguments
+    /// 
+    /// * `sealed_messages` (Vec\<SealedMessage\>): Sealed messages, in their order of arrival
+    /// 
+    /// # Output
+    /// 
+    /// * `plaintext_received` (Vec\<Result\<(String, Option\<Vec\<u8\>\>), ClientError\>\>): (Sender name, plaintext) of each message, in the order of `sealed_messages` *(see `read_messages`)*
+    pub fn read_sealed_messages(&mut self, sealed_messages: Vec<SealedMessage>) -> Vec<SealedPlaintext> {
+        let mut plaintext_received: Vec<Option<SealedPlaintext>> = sealed_messages.iter().map(|_| None).collect();
+        let mut messages_by_sender: Vec<SealedSenderMessages> = Vec::new();
+        for (index, sealed_message) in sealed_messages.iter().enumerate() {
+            let (sender_name, device_id, ik_sender, message): (String, DeviceId, PublicKey, Message) = match self.unseal_message(sealed_message) {
+                Ok(unsealed_message) => unsealed_message,
+                Err(error) => {
+                    plaintext_received[index] = Some(Err(error));
+                    continue
+                },
+            };
+            match messages_by_sender.iter_mut().find(|(current_sender_name, current_device_id, _, _, _)| *current_sender_name == sender_name && *current_device_id == device_id) {
+                Some((_, _, _, indexes, messages)) => {
+                    indexes.push(index);
+                    messages.push(message);
+                },
+                None => messages_by_sender.push((sender_name, device_id, ik_sender, vec![index], vec![message])),
+            }
+        }
+
+        for (sender_name, device_id, ik_sender, indexes, messages) in messages_by_sender {
+            for (index, plaintext) in indexes.into_iter().zip(self.read_messages(&sender_name, device_id, Some(ik_sender), messages)) {
+                plaintext_received[index] = Some(plaintext.map(|plaintext| (sender_name.clone(), plaintext)));
+            }
+        }
+        plaintext_received.into_iter().map(|plaintext| plaintext.expect("Every message is read")).collect()
+    }
+
+    /// Export the whole session held with one device, sealed with a storage key so that it can be written to a file
+    /// 
+    /// The ephemeral keys of the initial messages read from the device are exported with it, so that they can't be replayed after an import.
+    /// 
+    /// # Arguments
+    /// 
+    /// * `username` (&String): Name of the other user of the session
+    /// * `device_id` (DeviceId): Device of the other user
+    /// * `storage_key` (\[u8; 32\]): Key used to seal the session
+    /// 
+    /// # Output
+    /// 
+    /// * `sealed_session` (Result\<Vec\<u8\>, ClientError\>): Sealed session *(nonce || ciphertext)*
+    pub fn export_session(&self, username: &String, device_id: DeviceId, storage_key: [u8; 32]) -> Result<Vec<u8>, ClientError> {
+        let (ad, double_ratchet) = self.communications.get(&(username.clone(), device_id)).ok_or(ClientError::SessionNotFound)?;
+        let ad_length: u32 = ad.len().try_into().map_err(|_| CryptoError::InvalidSession)?;
+        let initial_eks: &[PublicKey] = self.initial_eks.get(&(username.clone(), device_id)).map(Vec::as_slice).unwrap_or_default();
+
+        // ad length (4) || ad || initial ephemeral keys count (1) || initial ephemeral keys (32 × count) || double ratchet state
+        let mut session: Vec<u8> = Vec::new();
+        session.extend_from_slice(&ad_length.to_be_bytes());
+        session.extend_from_slice(ad);
+        session.push(initial_eks.len() as u8);
+        for initial_ek in initial_eks {
+            session.extend_from_slice(initial_ek.as_bytes());
+        }
+        session.extend(double_ratchet.to_bytes());
+
+        // The username and the device are authenticated so that a session can't be imported for another device
+        Ok(aead::seal(storage_key, &session, &session_ad(username, device_id)?)?)
+    }
+
+    /// Import a session exported with `export_session`, replacing any existing session with this device
+    /// 
+    /// # Arguments
+    /// 
+    /// * `username` (&String): Name of the other user of the session
+    /// * `device_id` (DeviceId): Device of the other user
+    /// * `sealed_session` (&\[u8\]): Sealed session
+    /// * `storage_key` (\[u8; 32\]): Key used to seal the session
+    /// 
+    /// # Output
+    /// 
+    /// * `result` (Result\<(), ClientError\>): Error if the session can't be unsealed or is malformed
+    pub fn import_session(&mut self, username: &String, device_id: DeviceId, sealed_session: &[u8], storage_key: [u8; 32]) -> Result<(), ClientError> {
+        let session: Vec<u8> = aead::open(storage_key, sealed_session, &session_ad(username, device_id)?)?;
+
+        if session.len() < 4 {
+            return Err(ClientError::Crypto(CryptoError::InvalidSession))
+        }
+        let (ad_length, rest) = session.split_at(4);
+        let ad_length: usize = u32::from_be_bytes(ad_length.try_into().expect("Incorrect length")) as usize;
+        if rest.len() < ad_length {
+            return Err(ClientError::Crypto(CryptoError::InvalidSession))
+        }
+        let (ad, rest) = rest.split_at(ad_length);
+        let (initial_eks_count, rest) = rest.split_first().ok_or(CryptoError::InvalidSession)?;
+        let initial_eks_length: usize = *initial_eks_count as usize * 32;
+        if (*initial_eks_count as usize) > MAX_INITIAL_EKS || rest.len() < initial_eks_length {
+            return Err(ClientError::Crypto(CryptoError::InvalidSession))
+        }
+        let (initial_eks, double_ratchet) = rest.split_at(initial_eks_length);
+        let initial_eks: Vec<PublicKey> = initial_eks.chunks_exact(32)
+            .map(|initial_ek| PublicKey::from(<[u8; 32]>::try_from(initial_ek).expect("Incorrect length")))
+            .collect();
+        let double_ratchet: DoubleRatchet = DoubleRatchet::from_bytes(double_ratchet)?;
+
+        self.communications.insert((username.clone(), device_id), (ad.to_vec(), double_ratchet));
+        self.initial_eks.insert((username.clone(), device_id), initial_eks);
+        Ok(())
+    }
+
+    /// Export the identity keys trusted by the client, sealed with a storage key so that they can be written to a file
+    /// 
+    /// # Arguments
+    /// 
+    /// * `storage_key` (\[u8; 32\]): Key used to seal the identity keys
+    /// 
+    /// # Output
+    /// 
+    /// * `sealed_identities` (Result\<Vec\<u8\>, ClientError\>): Sealed identity store *(nonce || ciphertext)*
+    pub fn export_identity_store(&self, storage_key: [u8; 32]) -> Result<Vec<u8>, ClientError> {
+        Ok(aead::seal(storage_key, &self.identities.to_bytes()?, &identity_store_ad(&self.name, self.device_id)?)?)
+    }
+
+    /// Import the identity keys exported with `export_identity_store`, replacing the ones known by the client
+    /// 
+    /// # Arguments
+    /// 
+    /// * `sealed_identities` (&\[u8\]): Sealed identity store
+    /// * `storage_key` (\[u8; 32\]): Key used to seal the identity keys
+    /// 
+    /// # Output
+    /// 
+    /// * `result` (Result\<(), ClientError\>): Error if the identity store can't be unsealed or is malformed
+    pub fn import_identity_store(&mut self, sealed_identities: &[u8], storage_key: [u8; 32]) -> Result<(), ClientError> {
+        let identities: Vec<u8> = aead::open(storage_key, sealed_identities, &identity_store_ad(&self.name, self.device_id)?)?;
+        self.identities = IdentityStore::from_bytes(&identities)?;
+        Ok(())
+    }
+}
+
+/// Associated data of an exported session: username (4 + len) || device id (4)
+fn session_ad(username: &String, device_id: DeviceId) -> Result<Vec<u8>, ParseError> {
+    let mut ad: Vec<u8> = Vec::new();
+    write_bytes(&mut ad, username.as_bytes())?;
+    ad.extend_from_slice(&device_id.to_be_bytes());
+    Ok(ad)
+}
+
+/// Associated data of an exported identity store: "identities" (4 + len) || username (4 + len) || device id (4) *(so that it can't be mistaken for a session)*
+fn identity_store_ad(username: &String, device_id: DeviceId) -> Result<Vec<u8>, ParseError> {
+    let mut ad: Vec<u8> = Vec::new();
+    write_bytes(&mut ad, b"identities")?;
+    ad.extend_from_slice(&session_ad(username, device_id)?);
+    Ok(ad)
+}
+
+impl ClientError {
+    /// Returns true if reading the message again can't succeed *(the message is dropped by `poll`)*
+    /// 
+    /// The errors of the relay, a changed identity key *(until the user accepts it)* and an unknown certificate key *(until `enable_sealed_sender`)* can be solved.
+    pub fn is_permanent(&self) -> bool {
+        !matches!(self, ClientError::Relay(_) | ClientError::IdentityChanged | ClientError::VerifiedIdentityChanged | ClientError::SealedSender(SealedSenderError::CertificateKeyUnknown))
+    }
+}
+
+impl From<X3DHError> for ClientError {
+    fn from(error: X3DHError) -> Self {
+        ClientError::X3DH(error)
+    }
+}
+
+impl From<KeyError> for ClientError {
+    fn from(error: KeyError) -> Self {
+        ClientError::Key(error)
+    }
+}
+
+impl From<RelayError> for ClientError {
+    fn from(error: RelayError) -> Self {
+        ClientError::Relay(error)
+    }
+}
+
+impl From<SealedSenderError> for ClientError {
+    fn from(error: SealedSenderError) -> Self {
+        ClientError::SealedSender(error)
+    }
+}
+
+impl From<GroupError> for ClientError {
+    fn from(error: GroupError) -> Self {
+        ClientError::Group(error)
+    }
+}
+
+impl From<IdentityError> for ClientError {
+    fn from(error: IdentityError) -> Self {
+        match error {
+            IdentityError::Changed => ClientError::IdentityChanged,
+            IdentityError::VerifiedChanged => ClientError::VerifiedIdentityChanged,
+        }
+    }
+}
+
+impl From<ParseError> for ClientError {
+    fn from(error: ParseError) -> Self {
+        ClientError::Parse(error)
+    }
+}
+
+impl From<CryptoError> for ClientError {
+    fn from(error: CryptoError) -> Self {
+        ClientError::Crypto(error)
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientError::X3DH(error) => write!(f, "{}", error),
+            ClientError::Key(error) => write!(f, "{}", error),
+            ClientError::Crypto(error) => write!(f, "{}", error),
+            ClientError::SessionNotFound => write!(f, "No session with this user"),
+            ClientError::IdentityNotFound => write!(f, "No identity key known for this device"),
+            ClientError::Relay(error) => write!(f, "{}", error),
+            ClientError::SealedSender(error) => write!(f, "{}", error),
+            ClientError::Group(error) => write!(f, "{}", error),
+            ClientError::Parse(error) => write!(f, "{}", error),
+            ClientError::IdentityChanged => write!(f, "The identity key of a contact has changed"),
+            ClientError::VerifiedIdentityChanged => write!(f, "The identity key of a verified contact has changed"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x3dh::mlkem::KemCiphertext;
+    use crate::communication::server::Server;
+    use crate::communication::key_collection::{SPK_GRACE_PERIOD, SPK_ROTATION_PERIOD};
+
+    const STORAGE_KEY: [u8; 32] = [0x45; 32];
+    const NOW: u64 = 1_700_000_000;
+
+    fn send(server: &mut Server, sender: &mut Client, receiver_name: &str, plaintext: &[u8]) -> Message {
+        let r_keys: ServerKeyCollection = server.fetch_prekey_bundle(receiver_name, PRIMARY_DEVICE_ID).unwrap();
+        let (x3dh_keys, (header, ciphertext)) = sender.send_message(receiver_name, PRIMARY_DEVICE_ID, plaintext, &r_keys).unwrap();
+        let (ek_sender, spk_id, opk_used, kem_ciphertext) = match x3dh_keys {
+            Some((ek_sender, spk_id, opk_used, kem_ciphertext)) => (Some(ek_sender), Some(spk_id), opk_used, kem_ciphertext),
+            None => (None, None, None, None),
+        };
+        Message::new((sender.get_client_name(), sender.get_device_id()), (header, ciphertext), ek_sender, spk_id, opk_used, kem_ciphertext, ek_sender.map(|_| sender.get_aead()))
+    }
+
+    /// Unwrap the result of each message read and keep the texts
+    fn texts(plaintext_received: Vec<Result<Option<Vec<u8>>, ClientError>>) -> Vec<Vec<u8>> {
+        plaintext_received.into_iter().filter_map(|plaintext| plaintext.unwrap()).collect()
+    }
+
+    #[test]
+    fn test_session_survives_export_and_import() {
+        let alice_name: String = "Alice".to_string();
+        let bob_name: String = "Bob".to_string();
+        let mut alice: Client = Client::new(alice_name.clone());
+        let mut bob: Client = Client::new(bob_name.clone());
+        let mut server: Server = Server::new();
+        server.add_user(alice_name.clone(), alice.get_server_keys()).unwrap();
+        server.add_user(bob_name.clone(), bob.get_server_keys()).unwrap();
+
+        let first_message: Message = send(&mut server, &mut alice, &bob_name, b"first");
+        texts(bob.read_messages(&alice_name, PRIMARY_DEVICE_ID, Some(alice.get_server_keys().get_ik()), vec![first_message.clone()]));
+        let reply: Message = send(&mut server, &mut bob, &alice_name, b"reply");
+        texts(alice.read_messages(&bob_name, PRIMARY_DEVICE_ID, None, vec![reply]));
+
+        // The first message is delayed so that the exported session holds a skipped message key
+        let delayed_message: Message = send(&mut server, &mut alice, &bob_name, b"delayed");
+        let message: Message = send(&mut server, &mut alice, &bob_name, b"message");
+        texts(bob.read_messages(&alice_name, PRIMARY_DEVICE_ID, None, vec![message]));
+
+        let path: std::path::PathBuf = std::env::temp_dir().join(format!("session-{}.bin", std::process::id()));
+        std::fs::write(&path, bob.export_session(&alice_name, PRIMARY_DEVICE_ID, STORAGE_KEY).unwrap()).unwrap();
+        let sealed_session: Vec<u8> = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut restored_bob: Client = Client::new(bob_name.clone());
+        restored_bob.import_session(&alice_name, PRIMARY_DEVICE_ID, &sealed_session, STORAGE_KEY).unwrap();
+        // The initial message can't be replayed after the import
+        let expected_value: Vec<PublicKey> = vec![first_message.get_ek_sender().unwrap()];
+        assert_eq!(restored_bob.initial_eks[&(alice_name.clone(), PRIMARY_DEVICE_ID)], expected_value);
+        assert!(restored_bob.read_messages(&alice_name, PRIMARY_DEVICE_ID, Some(alice.get_server_keys().get_ik()), vec![first_message])[0].is_err());
+
+        let next_message: Message = send(&mut server, &mut alice, &bob_name, b"after restart");
+        let expected_value: Vec<Vec<u8>> = vec![b"delayed".to_vec(), b"after restart".to_vec()];
+        assert_eq!(texts(restored_bob.read_messages(&alice_name, PRIMARY_DEVICE_ID, None, vec![delayed_message, next_message])), expected_value);
+
+        let answer: Message = send(&mut server, &mut restored_bob, &alice_name, b"answer");
+        assert_eq!(texts(alice.read_messages(&bob_name, PRIMARY_DEVICE_ID, None, vec![answer])), vec![b"answer".to_vec()]);
+    }
+
+    #[test]
+    fn test_session_reset() {
+        let alice_name: String = "Alice".to_string();
+        let bob_name: String = "Bob".to_string();
+        let mut alice: Client = Client::new(alice_name.clone());
+        let mut bob: Client = Client::new(bob_name.clone());
+        let mut server: Server = Server::new();
+        server.add_user(alice_name.clone(), alice.get_server_keys()).unwrap();
+        server.add_user(bob_name.clone(), bob.get_server_keys()).unwrap();
+        let ik_alice: PublicKey = alice.get_server_keys().get_ik();
+        assert!(matches!(alice.reset_session(&bob_name), Err(ClientError::SessionNotFound)));
+
+        let first_message: Message = send(&mut server, &mut alice, &bob_name, b"first");
+        assert_eq!(texts(bob.read_messages(&alice_name, PRIMARY_DEVICE_ID, Some(ik_alice), vec![first_message])), vec![b"first".to_vec()]);
+        let reply: Message = send(&mut server, &mut bob, &alice_name, b"reply");
+        assert_eq!(texts(alice.read_messages(&bob_name, PRIMARY_DEVICE_ID, None, vec![reply])), vec![b"reply".to_vec()]);
+
+        // Messages still in flight when Alice resets the session
+        let alice_in_flight: Message = send(&mut server, &mut alice, &bob_name, b"Alice in flight");
+        let bob_in_flight: Message = send(&mut server, &mut bob, &alice_name, b"Bob in flight");
+        alice.reset_session(&bob_name).unwrap();
+        let restart: Message = send(&mut server, &mut alice, &bob_name, b"restart");
+        assert!(restart.get_ek_sender().is_some());
+
+        // The identity key of Alice is already in the identity store of Bob
+        assert_eq!(texts(bob.read_messages(&alice_name, PRIMARY_DEVICE_ID, None, vec![alice_in_flight, restart.clone()])), vec![b"Alice in flight".to_vec(), b"restart".to_vec()]);
+        assert_eq!(texts(alice.read_messages(&bob_name, PRIMARY_DEVICE_ID, None, vec![bob_in_flight])), vec![b"Bob in flight".to_vec()]);
+
+        // A replayed initial message doesn't start another session
+        assert!(bob.read_messages(&alice_name, PRIMARY_DEVICE_ID, Some(ik_alice), vec![restart])[0].is_err());
+        // Only the ephemeral keys of the last sessions are remembered
+        let eks: Vec<PublicKey> = (0..MAX_INITIAL_EKS + 2).map(|n| PublicKey::from([n as u8; 32])).collect();
+        for ek in &eks {
+            bob.remember_initial_ek(&alice_name, PRIMARY_DEVICE_ID, *ek);
+        }
+        bob.remember_initial_ek(&alice_name, PRIMARY_DEVICE_ID, eks[MAX_INITIAL_EKS]);
+        let expected_value: Vec<PublicKey> = [&eks[2..MAX_INITIAL_EKS], &eks[MAX_INITIAL_EKS + 1..], &eks[MAX_INITIAL_EKS..MAX_INITIAL_EKS + 1]].concat();
+        assert_eq!(bob.initial_eks[&(alice_name.clone(), PRIMARY_DEVICE_ID)], expected_value);
+        let answer: Message = send(&mut server, &mut bob, &alice_name, b"answer");
+        assert!(answer.get_ek_sender().is_none());
+        assert_eq!(texts(alice.read_messages(&bob_name, PRIMARY_DEVICE_ID, None, vec![answer])), vec![b"answer".to_vec()]);
+    }
+
+    #[test]
+    fn test_crossing_initial_messages() {
+        let alice_name: String = "Alice".to_string();
+        let bob_name: String = "Bob".to_string();
+        let mut alice: Client = Client::new(alice_name.clone());
+        let mut bob: Client = Client::new(bob_name.clone());
+        let mut server: Server = Server::new();
+        for client in [&mut alice, &mut bob] {
+            client.register(&mut server).unwrap();
+        }
+
+        // Both start a session before reading anything
+        alice.send_to(&mut server, &bob_name, b"A1").unwrap();
+        bob.send_to(&mut server, &alice_name, b"B1").unwrap();
+        assert_eq!(alice.poll(&mut server).unwrap(), vec![(bob_name.clone(), b"B1".to_vec())]);
+        assert_eq!(bob.poll(&mut server).unwrap(), vec![(alice_name.clone(), b"A1".to_vec())]);
+
+        alice.send_to(&mut server, &bob_name, b"A2").unwrap();
+        bob.send_to(&mut server, &alice_name, b"B2").unwrap();
+        assert_eq!(bob.poll(&mut server).unwrap(), vec![(alice_name.clone(), b"A2".to_vec())]);
+        assert_eq!(alice.poll(&mut server).unwrap(), vec![(bob_name.clone(), b"B2".to_vec())]);
+
+        // Both send with the session started by Alice: it's enough without the previous sessions
+        let mut restored_alice: Client = Client::new(alice_name.clone());
+        let mut restored_bob: Client = Client::new(bob_name.clone());
+        restored_alice.import_session(&bob_name, PRIMARY_DEVICE_ID, &alice.export_session(&bob_name, PRIMARY_DEVICE_ID, STORAGE_KEY).unwrap(), STORAGE_KEY).unwrap();
+        restored_bob.import_session(&alice_name, PRIMARY_DEVICE_ID, &bob.export_session(&alice_name, PRIMARY_DEVICE_ID, STORAGE_KEY).unwrap(), STORAGE_KEY).unwrap();
+        let a3: Message = send(&mut server, &mut restored_alice, &bob_name, b"A3");
+        let b3: Message = send(&mut server, &mut restored_bob, &alice_name, b"B3");
+        assert_eq!(texts(restored_bob.read_messages(&alice_name, PRIMARY_DEVICE_ID, None, vec![a3])), vec![b"A3".to_vec()]);
+        assert_eq!(texts(restored_alice.read_messages(&bob_name, PRIMARY_DEVICE_ID, None, vec![b3])), vec![b"B3".to_vec()]);
+    }
+
+    #[test]
+    fn test_aead_chosen_per_session() {
+        let alice_name: String = "Alice".to_string();
+        let bob_name: String = "Bob".to_string();
+        let mut alice: Client = Client::new(alice_name.clone());
+        let mut bob: Client = Client::new(bob_name.clone());
+        let mut server: Server = Server::new();
+        server.add_user(alice_name.clone(), alice.get_server_keys()).unwrap();
+        server.add_user(bob_name.clone(), bob.get_server_keys()).unwrap();
+        let ik_alice: PublicKey = alice.get_server_keys().get_ik();
+
+        // Bob uses the AEAD of the initial message whatever his own choice, and sends without nonces from the start
+        alice.set_aead(AeadAlgorithm::ChaCha20Poly1305);
+        bob.set_nonce_mode(NonceMode::Derived);
+        let first_message: Message = send(&mut server, &mut alice, &bob_name, b"first");
+        assert_eq!(first_message.get_aead(), Some(AeadAlgorithm::ChaCha20Poly1305));
+        assert_eq!(texts(bob.read_messages(&alice_name, PRIMARY_DEVICE_ID, Some(ik_alice), vec![first_message])), vec![b"first".to_vec()]);
+        let reply: Message = send(&mut server, &mut bob, &alice_name, b"reply");
+        assert_eq!(reply.get_aead(), None);
+        assert!(reply.get_ciphertext().get_nonce().is_empty());
+        assert_eq!(texts(alice.read_messages(&bob_name, PRIMARY_DEVICE_ID, None, vec![reply])), vec![b"reply".to_vec()]);
+
+        // The session keeps its AEAD, and Alice switches to derived nonces once it is established
+        alice.set_aead(AeadAlgorithm::Aes256CbcHmacSha256);
+        let random_message: Message = send(&mut server, &mut alice, &bob_name, b"same length");
+        assert_eq!(random_message.get_ciphertext().get_nonce().len(), 12);
+        alice.set_nonce_mode(NonceMode::Derived);
+        let derived_message: Message = send(&mut server, &mut alice, &bob_name, b"same length");
+        assert_eq!(random_message.to_bytes().unwrap().len() - derived_message.to_bytes().unwrap().len(), 12);
+        assert_eq!(texts(bob.read_messages(&alice_name, PRIMARY_DEVICE_ID, None, vec![derived_message, random_message])), vec![b"same length".to_vec(), b"same length".to_vec()]);
+    }
+
+    #[test]
+    fn test_first_message_requires_kem_ciphertext() {
+        let alice_name: String = "Alice".to_string();
+        let bob_name: String = "Bob".to_string();
+        let mut alice: Client = Client::new(alice_name.clone());
+        let mut bob: Client = Client::new(bob_name.clone());
+        let mut server: Server = Server::new();
+        server.add_user(bob_name.clone(), bob.get_server_keys()).unwrap();
+
+        let first_message: Message = send(&mut server, &mut alice, &bob_name, b"first");
+        assert!(first_message.get_kem_ciphertext().is_some());
+
+        // Stripping the ML-KEM ciphertext must not downgrade the session to the classical X3DH
+        let classical_message: Message = Message::new((alice_name.clone(), first_message.get_device_id()), (first_message.get_header(), first_message.get_ciphertext()), first_message.get_ek_sender(), first_message.get_spk_id(), first_message.get_opk_used(), None, first_message.get_aead());
+        let result = bob.read_messages(&alice_name, PRIMARY_DEVICE_ID, Some(alice.get_server_keys().get_ik()), vec![classical_message]);
+        assert!(matches!(result.as_slice(), [Err(ClientError::Key(KeyError::KemCiphertextAbsent))]));
+
+        // The ML-KEM prekey id must be the one of a prekey of Bob
+        let (pqspk_id, kem_ciphertext): (u32, KemCiphertext) = first_message.get_kem_ciphertext().unwrap();
+        assert_eq!(pqspk_id, bob.get_keys().get_pqspk_id());
+        let unknown_kem_message: Message = Message::new((alice_name.clone(), first_message.get_device_id()), (first_message.get_header(), first_message.get_ciphertext()), first_message.get_ek_sender(), first_message.get_spk_id(), first_message.get_opk_used(), Some((pqspk_id + 1, kem_ciphertext)), first_message.get_aead());
+        let result = bob.read_messages(&alice_name, PRIMARY_DEVICE_ID, Some(alice.get_server_keys().get_ik()), vec![unknown_kem_message]);
+        assert!(matches!(result.as_slice(), [Err(ClientError::Key(KeyError::KemPrekeyUnknown))]));
+
+        assert_eq!(texts(bob.read_messages(&alice_name, PRIMARY_DEVICE_ID, Some(alice.get_server_keys().get_ik()), vec![first_message])), vec![b"first".to_vec()]);
+    }
+
+    #[test]
+    fn test_classical_x3dh_without_require_pq() {
+        let alice_name: String = "Alice".to_string();
+        let bob_name: String = "Bob".to_string();
+        let charlie_name: String = "Charlie".to_string();
+        let mut alice: Client = Client::new(alice_name.clone());
+        let mut bob: Client = Client::new(bob_name.clone());
+        let mut charlie: Client = Client::new(charlie_name.clone());
+        let mut server: Server = Server::new();
+        server.add_user(alice_name.clone(), alice.get_server_keys()).unwrap();
+        server.add_user(bob_name.clone(), bob.get_server_keys()).unwrap();
+        let ik_alice: PublicKey = alice.get_server_keys().get_ik();
+        assert!(bob.get_require_pq());
+
+        // Alice starts the session with the classical X3DH, Bob only reads it once he doesn't require PQXDH either
+        alice.set_require_pq(false);
+        let first_message: Message = send(&mut server, &mut alice, &bob_name, b"classical");
+        assert!(first_message.get_kem_ciphertext().is_none());
+        let result = bob.read_messages(&alice_name, PRIMARY_DEVICE_ID, Some(ik_alice), vec![first_message.clone()]);
+        assert!(matches!(result.as_slice(), [Err(ClientError::Key(KeyError::KemCiphertextAbsent))]));
+        bob.set_require_pq(false);
+        assert_eq!(texts(bob.read_messages(&alice_name, PRIMARY_DEVICE_ID, Some(ik_alice), vec![first_message])), vec![b"classical".to_vec()]);
+        let reply: Message = send(&mut server, &mut bob, &alice_name, b"reply");
+        assert_eq!(texts(alice.read_messages(&bob_name, PRIMARY_DEVICE_ID, None, vec![reply])), vec![b"reply".to_vec()]);
+
+        // Alice still reads the PQXDH initial messages
+        let pq_message: Message = send(&mut server, &mut charlie, &alice_name, b"post-quantum");
+        assert!(pq_message.get_kem_ciphertext().is_some());
+        assert_eq!(texts(alice.read_messages(&charlie_name, PRIMARY_DEVICE_ID, Some(charlie.get_server_keys().get_ik()), vec![pq_message])), vec![b"post-quantum".to_vec()]);
+    }
+
+    #[test]
+    fn test_delayed_first_message_after_spk_rotation() {
+        let alice_name: String = "Alice".to_string();
+        let bob_name: String = "Bob".to_string();
+        let charlie_name: String = "Charlie".to_string();
+        let mut alice: Client = Client::new(alice_name.clone());
+        let mut bob: Client = Client::new(bob_name.clone());
+        let mut charlie: Client = Client::new(charlie_name.clone());
+        let mut server: Server = Server::new();
+        server.add_user(bob_name.clone(), bob.get_server_keys()).unwrap();
+
+        let alice_message: Message = send(&mut server, &mut alice, &bob_name, b"from Alice");
+        let charlie_message: Message = send(&mut server, &mut charlie, &bob_name, b"from Charlie");
+        assert_eq!(alice_message.get_spk_id(), Some(bob.get_keys().get_spk_id()));
+
+        // Bob rotates his signed prekey before reading the first messages
+        let bob_session: SessionToken = bob.login(&mut server).unwrap();
+        let (spk_id, spk, signature): (u32, PublicKey, Signature) = bob.rotate_spk(NOW);
+        server.update_user_spk(&bob_name, &bob_session, spk_id, spk, signature).unwrap();
+        assert_eq!(server.get_user_keys(&bob_name, PRIMARY_DEVICE_ID).unwrap().get_spk_id(), spk_id);
+        assert_ne!(alice_message.get_spk_id(), Some(spk_id));
+
+        // Still in the grace period
+        assert_eq!(texts(bob.read_messages(&alice_name, PRIMARY_DEVICE_ID, Some(alice.get_server_keys().get_ik()), vec![alice_message])), vec![b"from Alice".to_vec()]);
+
+        // After the grace period the replaced signed prekey is deleted
+        assert!(bob.rotate_spk_if_due(NOW + SPK_GRACE_PERIOD).is_some());
+        let result = bob.read_messages(&charlie_name, PRIMARY_DEVICE_ID, Some(charlie.get_server_keys().get_ik()), vec![charlie_message]);
+        assert!(matches!(result.as_slice(), [Err(ClientError::Key(KeyError::SignedPrekeyUnknown))]));
+    }
+
+    #[test]
+    fn test_delayed_first_message_after_pqspk_rotation() {
+        let alice_name: String = "Alice".to_string();
+        let bob_name: String = "Bob".to_string();
+        let charlie_name: String = "Charlie".to_string();
+        let mut alice: Client = Client::new(alice_name.clone());
+        let mut bob: Client = Client::new(bob_name.clone());
+        let mut charlie: Client = Client::new(charlie_name.clone());
+        let mut server: Server = Server::new();
+        server.add_user(bob_name.clone(), bob.get_server_keys()).unwrap();
+
+        let alice_message: Message = send(&mut server, &mut alice, &bob_name, b"from Alice");
+        let charlie_message: Message = send(&mut server, &mut charlie, &bob_name, b"from Charlie");
+        assert_eq!(alice_message.get_kem_ciphertext().map(|(pqspk_id, _)| pqspk_id), Some(bob.get_keys().get_pqspk_id()));
+
+        // Bob rotates his ML-KEM prekey before reading the first messages
+        let bob_session: SessionToken = bob.login(&mut server).unwrap();
+        let (pqspk_id, pqspk, pq_signature): (u32, EncapsulationKey, Signature) = bob.rotate_pqspk(NOW);
+        server.update_user_pqspk(&bob_name, &bob_session, pqspk_id, pqspk, pq_signature).unwrap();
+        assert_eq!(server.get_user_keys(&bob_name, PRIMARY_DEVICE_ID).unwrap().get_pqspk_id(), pqspk_id);
+
+        // A new session uses the new ML-KEM prekey
+        let mut dave: Client = Client::new("Dave".to_string());
+        let dave_message: Message = send(&mut server, &mut dave, &bob_name, b"from Dave");
+        assert_eq!(dave_message.get_kem_ciphertext().map(|(pqspk_id, _)| pqspk_id), Some(pqspk_id));
+        assert_eq!(texts(bob.read_messages(&"Dave".to_string(), PRIMARY_DEVICE_ID, Some(dave.get_server_keys().get_ik()), vec![dave_message])), vec![b"from Dave".to_vec()]);
+
+        // Still in the grace period
+        assert_eq!(texts(bob.read_messages(&alice_name, PRIMARY_DEVICE_ID, Some(alice.get_server_keys().get_ik()), vec![alice_message])), vec![b"from Alice".to_vec()]);
+
+        // After the grace period the replaced ML-KEM prekey is deleted
+        assert!(bob.rotate_pqspk_if_due(NOW + SPK_GRACE_PERIOD).is_some());
+        let result = bob.read_messages(&charlie_name, PRIMARY_DEVICE_ID, Some(charlie.get_server_keys().get_ik()), vec![charlie_message]);
+        assert!(matches!(result.as_slice(), [Err(ClientError::Key(KeyError::KemPrekeyUnknown))]));
+    }
+
+    #[test]
+    fn test_offline_backlog_in_any_order() {
+        let alice_name: String = "Alice".to_string();
+        let bob_name: String = "Bob".to_string();
+        let charlie_name: String = "Charlie".to_string();
+        let mut alice: Client = Client::new(alice_name.clone());
+        let mut bob: Client = Client::new(bob_name.clone());
+        let mut charlie: Client = Client::new(charlie_name.clone());
+        let mut server: Server = Server::new();
+        for client in [&mut bob, &mut charlie] {
+            client.register(&mut server).unwrap();
+        }
+
+        // Alice sends several messages before Bob comes online, only the first one holds the X3DH keys
+        let a1: Message = send(&mut server, &mut alice, &bob_name, b"A1");
+        let a2: Message = send(&mut server, &mut alice, &bob_name, b"A2");
+        let a3: Message = send(&mut server, &mut alice, &bob_name, b"A3");
+        assert!(a1.get_ek_sender().is_some() && a3.get_ek_sender().is_none());
+
+        let result = bob.read_messages(&alice_name, PRIMARY_DEVICE_ID, None, vec![a3.clone(), a1.clone()]);
+        assert!(matches!(result.as_slice(), [Err(ClientError::SessionNotFound), Err(ClientError::Key(KeyError::IdentityKeyAbsent))]));
+
+        // The copy of the first message can't be decrypted again, the other messages are still read
+        let result = bob.read_messages(&alice_name, PRIMARY_DEVICE_ID, Some(alice.get_server_keys().get_ik()), vec![a3, a1.clone(), a2, a1]);
+        assert_eq!(result.len(), 4);
+        assert!(matches!(&result[0], Ok(Some(text)) if text == b"A3"));
+        assert!(matches!(&result[1], Ok(Some(text)) if text == b"A1"));
+        assert!(matches!(&result[2], Ok(Some(text)) if text == b"A2"));
+        assert!(result[3].is_err());
+
+        // Same backlog through the relay
+        for message in [b"C1", b"C2", b"C3"] {
+            charlie.send_to(&mut server, &bob_name, message).unwrap();
+        }
+        let expected_value: Vec<(String, Vec<u8>)> = vec![(charlie_name.clone(), b"C1".to_vec()), (charlie_name.clone(), b"C2".to_vec()), (charlie_name.clone(), b"C3".to_vec())];
+        assert_eq!(bob.poll(&mut server).unwrap(), expected_value);
+        assert_eq!(bob.poll(&mut server).unwrap(), Vec::<(String, Vec<u8>)>::new());
+    }
+
+    #[test]
+    fn test_spk_rotation_schedule() {
+        let mut bob: Client = Client::new("Bob".to_string());
+        let (spk_id, _, _): (u32, PublicKey, Signature) = bob.rotate_spk(NOW);
+
+        assert!(bob.rotate_spk_if_due(NOW + SPK_ROTATION_PERIOD - 1).is_none());
+        let (next_spk_id, spk, _): (u32, PublicKey, Signature) = bob.rotate_spk_if_due(NOW + SPK_ROTATION_PERIOD).unwrap();
+        assert_eq!(next_spk_id, spk_id + 1);
+        assert_eq!(bob.get_server_keys().get_spk(), spk);
+        assert!(bob.get_keys().get_spk_by_id(spk_id).is_some());
+
+        let (pqspk_id, _, _): (u32, EncapsulationKey, Signature) = bob.rotate_pqspk(NOW);
+        assert!(bob.rotate_pqspk_if_due(NOW + SPK_ROTATION_PERIOD - 1).is_none());
+        let (next_pqspk_id, pqspk, _): (u32, EncapsulationKey, Signature) = bob.rotate_pqspk_if_due(NOW + SPK_ROTATION_PERIOD).unwrap();
+        assert_eq!(next_pqspk_id, pqspk_id + 1);
+        assert_eq!(bob.get_server_keys().get_pqspk(), pqspk);
+        assert!(bob.get_keys().get_pqspk_by_id(pqspk_id).is_some());
+        assert!(bob.get_keys().get_pqspk_by_id(pqspk_id - 1).is_some());
+        assert!(bob.rotate_pqspk_if_due(NOW + SPK_GRACE_PERIOD).is_some());
+        assert!(bob.get_keys().get_pqspk_by_id(pqspk_id - 1).is_none());
+        assert!(bob.get_keys().get_pqspk_by_id(pqspk_id).is_some());
+    }
+
+    #[test]
+    fn test_opk_handed_out_once() {
+        let alice_name: String = "Alice".to_string();
+        let bob_name: String = "Bob".to_string();
+        let charlie_name: String = "Charlie".to_string();
+        let mut alice: Client = Client::new(alice_name.clone());
+        let mut bob: Client = Client::new(bob_name.clone());
+        let mut charlie: Client = Client::new(charlie_name.clone());
+        let mut server: Server = Server::new();
+        server.add_user(bob_name.clone(), bob.get_server_keys()).unwrap();
+        let opk_count: usize = server.get_opk_count(&bob_name, PRIMARY_DEVICE_ID).unwrap();
+
+        let alice_message: Message = send(&mut server, &mut alice, &bob_name, b"from Alice");
+        let charlie_message: Message = send(&mut server, &mut charlie, &bob_name, b"from Charlie");
+        assert!(alice_message.get_opk_used().is_some());
+        assert_ne!(alice_message.get_opk_used(), charlie_message.get_opk_used());
+        assert_eq!(server.get_opk_count(&bob_name, PRIMARY_DEVICE_ID).unwrap(), opk_count - 2);
+
+        assert_eq!(texts(bob.read_messages(&alice_name, PRIMARY_DEVICE_ID, Some(alice.get_server_keys().get_ik()), vec![alice_message])), vec![b"from Alice".to_vec()]);
+        assert_eq!(texts(bob.read_messages(&charlie_name, PRIMARY_DEVICE_ID, Some(charlie.get_server_keys().get_ik()), vec![charlie_message])), vec![b"from Charlie".to_vec()]);
+    }
+
+    #[test]
+    fn test_opk_kept_until_first_message_decrypted() {
+        let alice_name: String = "Alice".to_string();
+        let bob_name: String = "Bob".to_string();
+        let mut alice: Client = Client::new(alice_name.clone());
+        let mut bob: Client = Client::new(bob_name.clone());
+        let mut server: Server = Server::new();
+        server.add_user(bob_name.clone(), bob.get_server_keys()).unwrap();
+
+        let first_message: Message = send(&mut server, &mut alice, &bob_name, b"first");
+        let opk_used: PublicKey = first_message.get_opk_used().unwrap();
+        let has_opk = |client: &Client| client.get_keys().get_opk_used(opk_used).is_some();
+
+        // A one-time prekey Bob does not have (anymore) is rejected
+        let unknown_opk_message: Message = Message::new((alice_name.clone(), first_message.get_device_id()), (first_message.get_header(), first_message.get_ciphertext()), first_message.get_ek_sender(), first_message.get_spk_id(), Some(alice.get_server_keys().get_ik()), first_message.get_kem_ciphertext(), first_message.get_aead());
+        let result = bob.read_messages(&alice_name, PRIMARY_DEVICE_ID, Some(alice.get_server_keys().get_ik()), vec![unknown_opk_message]);
+        assert!(matches!(result.as_slice(), [Err(ClientError::Key(KeyError::OneTimePrekeyUnknown))]));
+
+        // A tampered first message must not consume the one-time prekey of the genuine one
+        let mut tampered_ciphertext: Vec<u8> = first_message.get_ciphertext().get_ciphertext();
+        tampered_ciphertext[0] ^= 1;
+        let tampered_message: Message = Message::new((alice_name.clone(), first_message.get_device_id()), (first_message.get_header(), Ciphertext::new(tampered_ciphertext, first_message.get_ciphertext().get_nonce())), first_message.get_ek_sender(), first_message.get_spk_id(), first_message.get_opk_used(), first_message.get_kem_ciphertext(), first_message.get_aead());
+        let result = bob.read_messages(&alice_name, PRIMARY_DEVICE_ID, Some(alice.get_server_keys().get_ik()), vec![tampered_message]);
+        assert!(matches!(result.as_slice(), [Err(_)]));
+        assert!(has_opk(&bob));
+
+        assert_eq!(texts(bob.read_messages(&alice_name, PRIMARY_DEVICE_ID, Some(alice.get_server_keys().get_ik()), vec![first_message])), vec![b"first".to_vec()]);
+        assert!(!has_opk(&bob));
+    }
+
+    #[test]
+    fn test_opk_replenishment() {
+        let bob_name: String = "Bob".to_string();
+        let mut bob: Client = Client::new(bob_name.clone());
+        let mut server: Server = Server::new();
+        server.add_user(bob_name.clone(), bob.get_server_keys()).unwrap();
+        let opk_count: usize = server.get_opk_count(&bob_name, PRIMARY_DEVICE_ID).unwrap();
+        assert!(bob.replenish_opks(opk_count).is_none());
+
+        while !server.has_low_opk_stock(&bob_name, PRIMARY_DEVICE_ID).unwrap() {
+            server.fetch_prekey_bundle(&bob_name, PRIMARY_DEVICE_ID).unwrap();
+        }
+        let low_count: usize = server.get_opk_count(&bob_name, PRIMARY_DEVICE_ID).unwrap();
+        let new_opks: Vec<(u32, PublicKey)> = bob.replenish_opks(low_count).unwrap();
+        assert_eq!(new_opks.len(), opk_count - low_count);
+        let ids_on_server: Vec<u32> = server.get_user_keys(&bob_name, PRIMARY_DEVICE_ID).unwrap().get_opk_bundle().iter().map(|(id, _)| *id).collect();
+        assert!(new_opks.iter().all(|(id, _)| !ids_on_server.contains(id)));
+
+        let bob_session: SessionToken = bob.login(&mut server).unwrap();
+        server.add_user_opks(&bob_name, &bob_session, new_opks.clone()).unwrap();
+        assert_eq!(server.get_opk_count(&bob_name, PRIMARY_DEVICE_ID).unwrap(), opk_count);
+        // Uploading the same batch twice doesn't duplicate the keys
+        server.add_user_opks(&bob_name, &bob_session, new_opks).unwrap();
+        assert_eq!(server.get_opk_count(&bob_name, PRIMARY_DEVICE_ID).unwrap(), opk_count);
+
+        // Once the stock is exhausted, the bundle has no one-time prekey anymore
+        for _ in 0..opk_count {
+            assert_eq!(server.fetch_prekey_bundle(&bob_name, PRIMARY_DEVICE_ID).unwrap().get_opk_bundle().len(), 1);
+        }
+        assert!(server.fetch_prekey_bundle(&bob_name, PRIMARY_DEVICE_ID).unwrap().get_opk_bundle().is_empty());
+    }
+
+    #[test]
+    fn test_send_to_and_poll() {
+        let alice_name: String = "Alice".to_string();
+        let bob_name: String = "Bob".to_string();
+        let charlie_name: String = "Charlie".to_string();
+        let mut alice: Client = Client::new(alice_name.clone());
+        let mut bob: Client = Client::new(bob_name.clone());
+        let mut charlie: Client = Client::new(charlie_name.clone());
+        let mut server: Server = Server::new();
+        for client in [&mut alice, &mut bob, &mut charlie] {
+            client.register(&mut server).unwrap();
+        }
+        let opk_count: usize = server.get_opk_count(&bob_name, PRIMARY_DEVICE_ID).unwrap();
+
+        alice.send_to(&mut server, &bob_name, b"A1").unwrap();
+        charlie.send_to(&mut server, &bob_name, b"C1").unwrap();
+        assert_eq!(bob.poll(&mut server).unwrap(), vec![(alice_name.clone(), b"A1".to_vec()), (charlie_name.clone(), b"C1".to_vec())]);
+
+        bob.send_to(&mut server, &alice_name, b"B1").unwrap();
+        alice.send_to(&mut server, &bob_name, b"A2").unwrap();
+        assert_eq!(alice.poll(&mut server).unwrap(), vec![(bob_name.clone(), b"B1".to_vec())]);
+        assert_eq!(bob.poll(&mut server).unwrap(), vec![(alice_name.clone(), b"A2".to_vec())]);
+        assert!(bob.poll(&mut server).unwrap().is_empty());
+        // Only the first messages used a one-time prekey
+        assert_eq!(server.get_opk_count(&bob_name, PRIMARY_DEVICE_ID).unwrap(), opk_count - 2);
+
+        let result = alice.send_to(&mut server, &"Dave".to_string(), b"A1");
+        assert!(matches!(result, Err(ClientError::Relay(RelayError::Server(ServerError::UserDoesNotExist)))));
+    }
+
+    #[test]
+    fn test_undecryptable_message_dropped() {
+        let alice_name: String = "Alice".to_string();
+        let bob_name: String = "Bob".to_string();
+        let mut alice: Client = Client::new(alice_name.clone());
+        let mut bob: Client = Client::new(bob_name.clone());
+        let mut server: Server = Server::new();
+        for client in [&mut alice, &mut bob] {
+            client.register(&mut server).unwrap();
+        }
+        alice.send_to(&mut server, &bob_name, b"A1").unwrap();
+        let bob_session: SessionToken = bob.login(&mut server).unwrap();
+        let replayed_message: Envelope = server.get_user_messages(&bob_name, &bob_session).unwrap().remove(0).1;
+        assert_eq!(bob.poll(&mut server).unwrap(), vec![(alice_name.clone(), b"A1".to_vec())]);
+
+        // A replayed message can never be decrypted again: it's deleted from the relay and reported once
+        server.add_message_to(&bob_name, PRIMARY_DEVICE_ID, replayed_message).unwrap();
+        alice.send_to(&mut server, &bob_name, b"A2").unwrap();
+        assert_eq!(bob.poll(&mut server).unwrap(), vec![(alice_name.clone(), b"A2".to_vec())]);
+        let dropped_messages: Vec<(Option<String>, ClientError)> = bob.take_dropped_messages();
+        assert_eq!(dropped_messages.len(), 1);
+        assert!(matches!(&dropped_messages[0], (Some(sender_name), ClientError::Crypto(_)) if *sender_name == alice_name));
+
+        let bob_session: SessionToken = bob.login(&mut server).unwrap();
+        assert!(server.get_user_messages(&bob_name, &bob_session).unwrap().is_empty());
+        assert!(bob.poll(&mut server).unwrap().is_empty());
+        assert!(bob.take_dropped_messages().is_empty());
+    }
+
+    #[test]
+    fn test_sealed_sender() {
+        let alice_name: String = "Alice".to_string();
+        let bob_name: String = "Bob".to_string();
+        let mut alice: Client = Client::new(alice_name.clone());
+        let mut bob: Client = Client::new(bob_name.clone());
+        let mut server: Server = Server::new();
+        for client in [&mut alice, &mut bob] {
+            client.register(&mut server).unwrap();
+            client.enable_sealed_sender(server.get_certificate_key());
+        }
+
+        // The server only sees a sealed message, Bob learns its sender when he opens it
+        alice.send_to(&mut server, &bob_name, b"A1").unwrap();
+        let bob_session: SessionToken = bob.login(&mut server).unwrap();
+        let (ids, sealed_messages): (Vec<u64>, Vec<SealedMessage>) = server.get_user_messages(&bob_name, &bob_session).unwrap().into_iter()
+            .map(|(id, envelope)| match envelope {
+                Envelope::Sealed(sealed_message) => (id, sealed_message),
+                _ => panic!("The message isn't sealed"),
+            })
+            .unzip();
+        let plaintext_received: Vec<(String, Option<Vec<u8>>)> = bob.read_sealed_messages(sealed_messages).into_iter().map(|plaintext| plaintext.unwrap()).collect();
+        assert_eq!(plaintext_received, vec![(alice_name.clone(), Some(b"A1".to_vec()))]);
+        server.acknowledge_messages(&bob_name, &bob_session, &ids).unwrap();
+
+        bob.send_to(&mut server, &alice_name, b"B1").unwrap();
+        assert_eq!(alice.poll(&mut server).unwrap(), vec![(bob_name.clone(), b"B1".to_vec())]);
+
+        // A sealed message can't be opened without the key of the server
+        bob.certificate_key = None;
+        alice.send_to(&mut server, &bob_name, b"A2").unwrap();
+        assert!(bob.poll(&mut server).unwrap().is_empty());
+        bob.enable_sealed_sender(server.get_certificate_key());
+        assert_eq!(bob.poll(&mut server).unwrap(), vec![(alice_name, b"A2".to_vec())]);
+    }
+
+    #[test]
+    fn test_group_with_member_leaving() {
+        let alice_name: String = "Alice".to_string();
+        let bob_name: String = "Bob".to_string();
+        let charlie_name: String = "Charlie".to_string();
+        let mut alice: Client = Client::new(alice_name.clone());
+        let mut bob: Client = Client::new(bob_name.clone());
+        let mut charlie: Client = Client::new(charlie_name.clone());
+        let mut server: Server = Server::new();
+        for client in [&mut alice, &mut bob, &mut charlie] {
+            client.register(&mut server).unwrap();
+        }
+
+        // The sender keys are sent over the pairwise sessions, they don't show up as texts
+        let group_id: GroupId = alice.create_group(&mut server, vec![bob_name.clone(), charlie_name.clone()]).unwrap();
+        alice.send_group_message(&mut server, &group_id, b"A1").unwrap();
+        for client in [&mut bob, &mut charlie] {
+            assert!(client.poll(&mut server).unwrap().is_empty());
+            assert_eq!(client.take_group_messages(), vec![(group_id, alice_name.clone(), b"A1".to_vec())]);
+            assert_eq!(client.get_group_members(&group_id), Some(vec![alice_name.clone(), bob_name.clone(), charlie_name.clone()]));
+        }
+        bob.send_group_message(&mut server, &group_id, b"B1").unwrap();
+        for client in [&mut alice, &mut charlie] {
+            assert!(client.poll(&mut server).unwrap().is_empty());
+            assert_eq!(client.take_group_messages(), vec![(group_id, bob_name.clone(), b"B1".to_vec())]);
+        }
+        assert!(alice.take_group_messages().is_empty());
+
+        // Once Charlie has left, Alice and Bob send a new sender key before their next message
+        charlie.leave_group(&mut server, &group_id).unwrap();
+        assert_eq!(charlie.get_group_members(&group_id), None);
+        for client in [&mut alice, &mut bob] {
+            assert!(client.poll(&mut server).unwrap().is_empty());
+            assert_eq!(client.get_group_members(&group_id), Some(vec![alice_name.clone(), bob_name.clone()]));
+        }
+        alice.send_group_message(&mut server, &group_id, b"A2").unwrap();
+        bob.send_group_message(&mut server, &group_id, b"B2").unwrap();
+        assert!(bob.poll(&mut server).unwrap().is_empty());
+        assert_eq!(bob.take_group_messages(), vec![(group_id, alice_name.clone(), b"A2".to_vec())]);
+        assert!(alice.poll(&mut server).unwrap().is_empty());
+        assert_eq!(alice.take_group_messages(), vec![(group_id, bob_name.clone(), b"B2".to_vec())]);
+        assert!(charlie.poll(&mut server).unwrap().is_empty());
+        assert!(charlie.take_group_messages().is_empty());
+        assert!(matches!(charlie.send_group_message(&mut server, &group_id, b"C1"), Err(ClientError::Group(GroupError::GroupNotFound))));
+
+        // The pairwise conversations still work
+        charlie.send_to(&mut server, &alice_name, b"C1").unwrap();
+        assert_eq!(alice.poll(&mut server).unwrap(), vec![(charlie_name, b"C1".to_vec())]);
+    }
+
+    #[test]
+    fn test_group_membership_changed_by_admins() {
+        let alice_name: String = "Alice".to_string();
+        let bob_name: String = "Bob".to_string();
+        let charlie_name: String = "Charlie".to_string();
+        let dave_name: String = "Dave".to_string();
+        let mut alice: Client = Client::new(alice_name.clone());
+        let mut bob: Client = Client::new(bob_name.clone());
+        let mut charlie: Client = Client::new(charlie_name.clone());
+        let mut dave: Client = Client::new(dave_name.clone());
+        let mut server: Server = Server::new();
+        for client in [&mut alice, &mut bob, &mut charlie, &mut dave] {
+            client.register(&mut server).unwrap();
+        }
+        let group_id: GroupId = alice.create_group(&mut server, vec![bob_name.clone(), charlie_name.clone()]).unwrap();
+        for client in [&mut bob, &mut charlie] {
+            assert!(client.poll(&mut server).unwrap().is_empty());
+            assert_eq!(client.get_group_admins(&group_id), Some(vec![alice_name.clone()]));
+        }
+        assert!(matches!(bob.add_group_member(&mut server, &group_id, &dave_name), Err(ClientError::Group(GroupError::NotAdmin))));
+
+        // Bob isn't an admin: the members he sends with his sender key are ignored, his sender key is still used
+        let bob_group: &mut Group = bob.groups.get_mut(&group_id).unwrap();
+        bob_group.set_members(vec![alice_name.clone(), bob_name.clone()]);
+        let distribution: SenderKeyDistribution = bob_group.rotate_sender_key();
+        bob.send_content(&mut server, &[alice_name.clone(), charlie_name.clone()], &Content::SenderKey(distribution)).unwrap();
+        bob.send_group_message(&mut server, &group_id, b"B1").unwrap();
+        assert!(alice.poll(&mut server).unwrap().is_empty());
+        assert_eq!(alice.take_group_messages(), vec![(group_id, bob_name.clone(), b"B1".to_vec())]);
+        for client in [&mut alice, &mut charlie] {
+            assert!(client.poll(&mut server).unwrap().is_empty());
+            assert_eq!(client.get_group_members(&group_id), Some(vec![alice_name.clone(), bob_name.clone(), charlie_name.clone()]));
+        }
+
+        // A message sent before a rekey is still read with the previous sender key
+        alice.send_group_message(&mut server, &group_id, b"A1").unwrap();
+        alice.add_group_admin(&mut server, &group_id, &charlie_name).unwrap();
+        alice.send_group_message(&mut server, &group_id, b"A2").unwrap();
+        assert!(charlie.poll(&mut server).unwrap().is_empty());
+        assert_eq!(charlie.take_group_messages(), vec![(group_id, alice_name.clone(), b"A1".to_vec()), (group_id, alice_name.clone(), b"A2".to_vec())]);
+        assert!(charlie.take_dropped_messages().is_empty());
+        assert_eq!(charlie.get_group_admins(&group_id), Some(vec![alice_name.clone(), charlie_name.clone()]));
+
+        // Charlie is now an admin and adds Dave
+        charlie.add_group_member(&mut server, &group_id, &dave_name).unwrap();
+        charlie.send_group_message(&mut server, &group_id, b"C1").unwrap();
+        for client in [&mut alice, &mut dave] {
+            assert!(client.poll(&mut server).unwrap().is_empty());
+            assert_eq!(client.take_group_messages(), vec![(group_id, charlie_name.clone(), b"C1".to_vec())]);
+            assert_eq!(client.get_group_members(&group_id), Some(vec![alice_name.clone(), bob_name.clone(), charlie_name.clone(), dave_name.clone()]));
+        }
+    }
+
+    #[test]
+    fn test_group_member_removed_by_admin() {
+        let alice_name: String = "Alice".to_string();
+        let bob_name: String = "Bob".to_string();
+        let charlie_name: String = "Charlie".to_string();
+        let mut alice: Client = Client::new(alice_name.clone());
+        let mut bob: Client = Client::new(bob_name.clone());
+        let mut charlie: Client = Client::new(charlie_name.clone());
+        let mut server: Server = Server::new();
+        for client in [&mut alice, &mut bob, &mut charlie] {
+            client.register(&mut server).unwrap();
+        }
+        let group_id: GroupId = alice.create_group(&mut server, vec![bob_name.clone(), charlie_name.clone()]).unwrap();
+        alice.send_group_message(&mut server, &group_id, b"A1").unwrap();
+        for client in [&mut bob, &mut charlie] {
+            assert!(client.poll(&mut server).unwrap().is_empty());
+            assert_eq!(client.take_group_messages(), vec![(group_id, alice_name.clone(), b"A1".to_vec())]);
+        }
+        assert!(matches!(bob.remove_group_member(&mut server, &group_id, &charlie_name), Err(ClientError::Group(GroupError::NotAdmin))));
+
+        // Alice sends a new sender key to Bob only before her next message
+        alice.remove_group_member(&mut server, &group_id, &charlie_name).unwrap();
+        assert_eq!(alice.get_group_members(&group_id), Some(vec![alice_name.clone(), bob_name.clone()]));
+        assert!(matches!(alice.remove_group_member(&mut server, &group_id, &charlie_name), Err(ClientError::Group(GroupError::MemberNotFound))));
+        alice.send_group_message(&mut server, &group_id, b"A2").unwrap();
+        assert!(bob.poll(&mut server).unwrap().is_empty());
+        assert_eq!(bob.take_group_messages(), vec![(group_id, alice_name.clone(), b"A2".to_vec())]);
+        assert_eq!(bob.get_group_members(&group_id), Some(vec![alice_name.clone(), bob_name.clone()]));
+        assert!(charlie.poll(&mut server).unwrap().is_empty());
+        assert!(charlie.take_group_messages().is_empty());
+
+        // Even if it reaches Charlie, the next message of the group can't be read with the sender key he has
+        let group_message: GroupMessage = alice.groups.get_mut(&group_id).unwrap().encrypt(&alice_name, PRIMARY_DEVICE_ID, b"A3").unwrap();
+        assert!(charlie.read_group_message(&group_message).is_err());
+        assert!(bob.read_group_message(&group_message).is_ok());
+    }
+
+    #[test]
+    fn test_safety_number_verification() {
+        let alice_name: String = "Alice".to_string();
+        let bob_name: String = "Bob".to_string();
+        let mut alice: Client = Client::new(alice_name.clone());
+        let mut bob: Client = Client::new(bob_name.clone());
+        let charlie: Client = Client::new("Charlie".to_string());
+        let mut server: Server = Server::new();
+        for client in [&mut alice, &mut bob] {
+            client.register(&mut server).unwrap();
+        }
+        assert!(matches!(alice.safety_number(&bob_name, PRIMARY_DEVICE_ID), Err(ClientError::IdentityNotFound)));
+        assert!(matches!(alice.mark_verified(&bob_name, PRIMARY_DEVICE_ID), Err(ClientError::IdentityNotFound)));
+        alice.send_to(&mut server, &bob_name, b"A1").unwrap();
+        bob.poll(&mut server).unwrap();
+
+        let safety_number: String = alice.safety_number(&bob_name, PRIMARY_DEVICE_ID).unwrap();
+        assert_eq!(bob.safety_number(&alice_name, PRIMARY_DEVICE_ID).unwrap(), safety_number);
+
+        // Alice scans the QR code of Bob
+        let payload: Vec<u8> = bob.scannable_payload(&alice_name, PRIMARY_DEVICE_ID).unwrap();
+        let forged_payload: Vec<u8> = safety_number::scannable_payload(&bob_name, &charlie.get_server_keys().get_ik(), &alice_name, &alice.get_server_keys().get_ik());
+        assert!(!alice.verify_scannable_payload(&bob_name, PRIMARY_DEVICE_ID, &forged_payload).unwrap());
+        assert!(!alice.is_verified(&bob_name, PRIMARY_DEVICE_ID));
+        assert!(alice.verify_scannable_payload(&bob_name, PRIMARY_DEVICE_ID, &payload).unwrap());
+        assert!(alice.is_verified(&bob_name, PRIMARY_DEVICE_ID));
+        bob.mark_verified(&alice_name, PRIMARY_DEVICE_ID).unwrap();
+        assert_eq!(alice.check_identity_key(&mut server, &bob_name, PRIMARY_DEVICE_ID).unwrap(), bob.get_server_keys().get_ik());
+
+        // Bob's keys are replaced on the server (e.g. by someone holding his session)
+        let bob_session: SessionToken = bob.login(&mut server).unwrap();
+        server.replace_user_keys(&bob_name, &bob_session, charlie.get_server_keys()).unwrap();
+        assert!(matches!(alice.check_identity_key(&mut server, &bob_name, PRIMARY_DEVICE_ID), Err(ClientError::VerifiedIdentityChanged)));
+        let r_keys: ServerKeyCollection = server.fetch_prekey_bundle(&bob_name, PRIMARY_DEVICE_ID).unwrap();
+        assert!(matches!(alice.send_first_message(&bob_name, PRIMARY_DEVICE_ID, b"A2", &r_keys), Err(ClientError::VerifiedIdentityChanged)));
+        assert_eq!(alice.safety_number(&bob_name, PRIMARY_DEVICE_ID).unwrap(), safety_number);
+    }
+
+    #[test]
+    fn test_identity_trusted_on_first_use() {
+        let bob_name: String = "Bob".to_string();
+        let charlie_name: String = "Charlie".to_string();
+        let mut bob: Client = Client::new(bob_name.clone());
+        let mut charlie: Client = Client::new(charlie_name.clone());
+        let mut mallory: Client = Client::new(bob_name.clone());
+        let mut server: Server = Server::new();
+        for client in [&mut bob, &mut charlie] {
+            client.register(&mut server).unwrap();
+        }
+        let ik_bob: PublicKey = charlie.check_identity_key(&mut server, &bob_name, PRIMARY_DEVICE_ID).unwrap();
+        assert_eq!(charlie.get_identity_store().get_trust(&bob_name, PRIMARY_DEVICE_ID), Some(Trust::FirstUse));
+
+        // Mallory replaces the keys of Bob on the relay
+        let bob_session: SessionToken = bob.login(&mut server).unwrap();
+        server.replace_user_keys(&bob_name, &bob_session, mallory.get_server_keys()).unwrap();
+        assert!(matches!(charlie.send_to(&mut server, &bob_name, b"C1"), Err(ClientError::IdentityChanged)));
+        mallory.send_to(&mut server, &charlie_name, b"M1").unwrap();
+        assert_eq!(charlie.poll(&mut server).unwrap(), Vec::<(String, Vec<u8>)>::new());
+        assert_eq!(charlie.get_identity_store().get(&bob_name, PRIMARY_DEVICE_ID), Some(ik_bob));
+        assert_eq!(charlie.get_identity_store().get_changed_key(&bob_name, PRIMARY_DEVICE_ID), Some(mallory.get_server_keys().get_ik()));
+
+        // The identity store is kept across restarts
+        let sealed_identities: Vec<u8> = charlie.export_identity_store(STORAGE_KEY).unwrap();
+        let mut restarted_charlie: Client = Client::new(charlie_name.clone());
+        assert!(restarted_charlie.import_identity_store(&sealed_identities, [0x00; 32]).is_err());
+        restarted_charlie.import_identity_store(&sealed_identities, STORAGE_KEY).unwrap();
+        assert_eq!(restarted_charlie.get_identity_store(), charlie.get_identity_store());
+
+        // Once the user accepts the new key, the message queued is read
+        charlie.accept_identity_key(&bob_name, PRIMARY_DEVICE_ID).unwrap();
+        assert!(charlie.accept_identity_key(&bob_name, PRIMARY_DEVICE_ID).is_err());
+        assert_eq!(charlie.poll(&mut server).unwrap(), vec![(bob_name.clone(), b"M1".to_vec())]);
+    }
+
+    #[test]
+    fn test_import_session_wrong_key_or_user() {
+        let alice_name: String = "Alice".to_string();
+        let bob_name: String = "Bob".to_string();
+        let mut alice: Client = Client::new(alice_name.clone());
+        let bob: Client = Client::new(bob_name.clone());
+        let mut server: Server = Server::new();
+        server.add_user(bob_name.clone(), bob.get_server_keys()).unwrap();
+
+        send(&mut server, &mut alice, &bob_name, b"first");
+        let sealed_session: Vec<u8> = alice.export_session(&bob_name, PRIMARY_DEVICE_ID, STORAGE_KEY).unwrap();
+
+        let mut restored_alice: Client = Client::new(alice_name.clone());
+        assert!(matches!(restored_alice.import_session(&bob_name, PRIMARY_DEVICE_ID, &sealed_session, [0x46; 32]), Err(ClientError::Crypto(CryptoError::DecryptionError))));
+        assert!(matches!(restored_alice.import_session(&"Charlie".to_string(), PRIMARY_DEVICE_ID, &sealed_session, STORAGE_KEY), Err(ClientError::Crypto(CryptoError::DecryptionError))));
+        assert!(matches!(restored_alice.export_session(&bob_name, PRIMARY_DEVICE_ID, STORAGE_KEY), Err(ClientError::SessionNotFound)));
+
+        // The username is length-prefixed, a session can't be mistaken for an identity store
+        assert_ne!(session_ad(&"identitiesBob".to_string(), PRIMARY_DEVICE_ID), identity_store_ad(&bob_name, PRIMARY_DEVICE_ID));
+        assert_ne!(session_ad(&"Bob".to_string(), 0x4142_4344).unwrap(), session_ad(&"BobABCD".to_string(), 0).unwrap());
+    }
 }
\ No newline at end of file
@@ -1,72 +1,205 @@
-use crate::x3dh::x3dh::{IdentityKey, SignedPrekey, OneTimePrekey,  x3dh_sender, x3dh_receiver, create_prekey_signature, create_prekey_bundle, X3DHError, get_ad};
-use ed25519_dalek::{Signature, VerifyingKey};
-use x25519_dalek::{PublicKey, ReusableSecret};
+use x3dh::{IdentityKey, SignedPrekey, OneTimePrekey, KemPrekey, HashFunction, X3DHConfig, Signature, x3dh_sender, x3dh_receiver, create_prekey_signature, create_kem_prekey_signature, create_prekey_bundle, X3DHError, get_ad};
+use x3dh::mlkem::{EncapsulationKey, KemCiphertext, ENCAPSULATION_KEY_LENGTH};
+use x25519_dalek::PublicKey;
 use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use super::message::Message;
+use super::message::{Message, ParseError, Reader};
 
 const BASIC_AMOUNT_OF_OPK: u8 = 50; // Change base on the average user behaviour
+/// (Shared secret, associated data, public ephemeral key, public one-time prekey used, (ML-KEM prekey id, ML-KEM ciphertext)) of the sender of the first message
+pub type SenderSharedSecret = ([u8; 32], Vec<u8>, PublicKey, Option<PublicKey>, Option<(u32, KemCiphertext)>);
+pub const OPK_LOW_STOCK: usize = 10; // Below this number of one-time prekeys left on the server, a new batch is uploaded
+const X3DH_CONFIG: X3DHConfig = X3DHConfig::new(HashFunction::Sha256, b"RedWheelbarrow");
+pub const SPK_ROTATION_PERIOD: u64 = 7 * 24 * 60 * 60; // Age (in seconds) after which a new signed prekey is generated
+pub const SPK_GRACE_PERIOD: u64 = 14 * 24 * 60 * 60; // Time (in seconds) a replaced signed prekey is kept to read the delayed first messages (also used for the ML-KEM prekey)
 
+#[derive(Debug)]
 pub enum KeyError {
     EphemeralKeyAbsent,
     IdentityKeyAbsent,
+    KemCiphertextAbsent,
+    SignedPrekeyUnknown,
+    KemPrekeyUnknown,
+    OneTimePrekeyUnknown,
 }
 
 pub struct ClientKeyCollection {
     ik: IdentityKey,
     spk: SignedPrekey,
-    opk_bundle: Vec<OneTimePrekey>,
+    spk_id: u32,
+    spk_created_at: u64, // Unix time (in seconds)
+    previous_spks: Vec<(u32, SignedPrekey, u64)>, // Replaced signed prekeys still accepted (id, key, Unix time of the replacement)
+    opk_bundle: Vec<(u32, OneTimePrekey)>, // One-time prekeys tagged with their id
+    next_opk_id: u32,
     signature: Signature,
-    verifying_key: VerifyingKey,
+    pqspk: KemPrekey, // PQXDH: signed ML-KEM prekey
+    pqspk_id: u32,
+    pqspk_created_at: u64, // Unix time (in seconds)
+    previous_pqspks: Vec<(u32, KemPrekey, u64)>, // Replaced ML-KEM prekeys still accepted (id, key, Unix time of the replacement)
+    pq_signature: Signature,
 }
 
+#[derive(Clone, Debug, PartialEq)]
 pub struct ServerKeyCollection {
     ik: PublicKey,
     spk: PublicKey,
-    opk_bundle: Vec<PublicKey>,
+    spk_id: u32,
+    opk_bundle: Vec<(u32, PublicKey)>,
     signature: Signature,
-    verifying_key: VerifyingKey,
+    pqspk: EncapsulationKey,
+    pqspk_id: u32,
+    pq_signature: Signature,
+}
+
+impl Default for ClientKeyCollection {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ClientKeyCollection {
     pub fn new() -> Self {
         let ik: IdentityKey = IdentityKey::new();
         let spk: SignedPrekey = SignedPrekey::new();
-        let opk_bundle: Vec<OneTimePrekey> = OneTimePrekey::generate_opk_bundle(BASIC_AMOUNT_OF_OPK);
-        let (signature, verification_key): (Signature, VerifyingKey) = create_prekey_signature(&ik, &spk);
+        let opk_bundle: Vec<(u32, OneTimePrekey)> = (0..).zip(OneTimePrekey::generate_opk_bundle(BASIC_AMOUNT_OF_OPK)).collect();
+        let signature: Signature = create_prekey_signature(&ik, &spk);
+        let pqspk: KemPrekey = KemPrekey::new();
+        let pq_signature: Signature = create_kem_prekey_signature(&ik, &pqspk);
+        
+        let now: u64 = unix_time();
         
-        ClientKeyCollection { ik: ik, spk: spk, opk_bundle: opk_bundle, signature: signature, verifying_key: verification_key }
+        ClientKeyCollection { ik, spk, spk_id: 0, spk_created_at: now, previous_spks: Vec::new(), opk_bundle, next_opk_id: BASIC_AMOUNT_OF_OPK as u32, signature, pqspk, pqspk_id: 0, pqspk_created_at: now, previous_pqspks: Vec::new(), pq_signature }
+    }
+
+    /// Replace the signed prekey by a new one, the previous one is kept for `SPK_GRACE_PERIOD`
+    /// 
+    /// # Arguments
+    /// 
+    /// * `now` (u64): Current Unix time (in seconds)
+    /// 
+    /// # Output
+    /// 
+    /// * `(spk_id, spk, signature)` ((u32, PublicKey, Signature)): New signed prekey to publish on the server
+    pub fn rotate_spk(&mut self, now: u64) -> (u32, PublicKey, Signature) {
+        let spk: SignedPrekey = SignedPrekey::new();
+        let signature: Signature = create_prekey_signature(&self.ik, &spk);
+        let previous_spk: SignedPrekey = std::mem::replace(&mut self.spk, spk);
+        self.previous_spks.push((self.spk_id, previous_spk, now));
+        self.spk_id = self.spk_id.wrapping_add(1);
+        self.spk_created_at = now;
+        self.signature = signature;
+        self.purge_expired_spks(now);
+
+        (self.spk_id, self.spk.get_public_key(), self.signature)
+    }
+
+    /// Scheduled rotation: replace the signed prekey if it is older than `SPK_ROTATION_PERIOD` and delete the expired ones
+    /// 
+    /// # Arguments
+    /// 
+    /// * `now` (u64): Current Unix time (in seconds)
+    /// 
+    /// # Output
+    /// 
+    /// * `new_spk` (Option\<(u32, PublicKey, Signature)\>): New signed prekey to publish on the server, if it was rotated
+    pub fn rotate_spk_if_due(&mut self, now: u64) -> Option<(u32, PublicKey, Signature)> {
+        self.purge_expired_spks(now);
+        if now.saturating_sub(self.spk_created_at) < SPK_ROTATION_PERIOD {
+            return None
+        }
+        Some(self.rotate_spk(now))
+    }
+
+    /// Replace the ML-KEM prekey by a new one, the previous one is kept for `SPK_GRACE_PERIOD` *(same schedule as the signed prekey)*
+    /// 
+    /// # Arguments
+    /// 
+    /// * `now` (u64): Current Unix time (in seconds)
+    /// 
+    /// # Output
+    /// 
+    /// * `(pqspk_id, pqspk, pq_signature)` ((u32, EncapsulationKey, Signature)): New ML-KEM prekey to publish on the server
+    pub fn rotate_pqspk(&mut self, now: u64) -> (u32, EncapsulationKey, Signature) {
+        let pqspk: KemPrekey = KemPrekey::new();
+        let pq_signature: Signature = create_kem_prekey_signature(&self.ik, &pqspk);
+        let previous_pqspk: KemPrekey = std::mem::replace(&mut self.pqspk, pqspk);
+        self.previous_pqspks.push((self.pqspk_id, previous_pqspk, now));
+        self.pqspk_id = self.pqspk_id.wrapping_add(1);
+        self.pqspk_created_at = now;
+        self.pq_signature = pq_signature;
+        self.purge_expired_spks(now);
+
+        (self.pqspk_id, self.pqspk.get_public_key(), self.pq_signature)
+    }
+
+    /// Scheduled rotation: replace the ML-KEM prekey if it is older than `SPK_ROTATION_PERIOD` and delete the expired ones
+    /// 
+    /// # Arguments
+    /// 
+    /// * `now` (u64): Current Unix time (in seconds)
+    /// 
+    /// # Output
+    /// 
+    /// * `new_pqspk` (Option\<(u32, EncapsulationKey, Signature)\>): New ML-KEM prekey to publish on the server, if it was rotated
+    pub fn rotate_pqspk_if_due(&mut self, now: u64) -> Option<(u32, EncapsulationKey, Signature)> {
+        self.purge_expired_spks(now);
+        if now.saturating_sub(self.pqspk_created_at) < SPK_ROTATION_PERIOD {
+            return None
+        }
+        Some(self.rotate_pqspk(now))
+    }
+
+    /// Generate a new batch of one-time prekeys if the server is running out of them
+    /// 
+    /// # Arguments
+    /// 
+    /// * `opk_count` (usize): Number of one-time prekeys left on the server
+    /// 
+    /// # Output
+    /// 
+    /// * `new_opks` (Option\<Vec\<(u32, PublicKey)\>\>): One-time prekeys tagged with their id to upload, None if the stock is still above `OPK_LOW_STOCK`
+    pub fn replenish_opks(&mut self, opk_count: usize) -> Option<Vec<(u32, PublicKey)>> {
+        if opk_count >= OPK_LOW_STOCK {
+            return None
+        }
+
+        let amount: u8 = (BASIC_AMOUNT_OF_OPK as usize - opk_count) as u8;
+        let mut new_opks: Vec<(u32, PublicKey)> = Vec::new();
+        for opk in OneTimePrekey::generate_opk_bundle(amount) {
+            new_opks.push((self.next_opk_id, opk.get_public_key()));
+            self.opk_bundle.push((self.next_opk_id, opk));
+            self.next_opk_id = self.next_opk_id.wrapping_add(1);
+        }
+        Some(new_opks)
     }
 
-    pub fn from(ik: IdentityKey, spk: SignedPrekey, opk_bundle: Vec<OneTimePrekey>, signature: Signature, verifying_key: VerifyingKey) -> Self {
-        ClientKeyCollection { ik: ik, spk: spk, opk_bundle: opk_bundle, signature: signature, verifying_key: verifying_key }
+    /// Delete the replaced signed prekeys and ML-KEM prekeys older than `SPK_GRACE_PERIOD`
+    pub fn purge_expired_spks(&mut self, now: u64) {
+        self.previous_spks.retain(|(_, _, replaced_at)| now.saturating_sub(*replaced_at) < SPK_GRACE_PERIOD);
+        self.previous_pqspks.retain(|(_, _, replaced_at)| now.saturating_sub(*replaced_at) < SPK_GRACE_PERIOD);
     }
 
     /// Generate the sender shared secret
     /// 
     /// # Arguments
     /// 
-    /// * `r_keys` (&ServerKeyCollection): All the public key of the receiver on the server
+    /// * `r_keys` (&ServerKeyCollection): Prekey bundle of the receiver *(from `Server::fetch_prekey_bundle`)*
+    /// * `use_pq` (bool): Use PQXDH *(encapsulate to the ML-KEM prekey of the receiver)*, or the classical X3DH
     /// 
     /// # Output
     /// 
-    /// * `(shared_secret, associated_data, ephemeral_key_sender, one_time_prekey_used` (Result\<([u8; 32], Vec\<u8\>, PublicKey, Option\<PublicKey\>), X3DHError\>): (Shared Secret, Associated Data, EphemeralKey sender, OneTimePrekey used)
-    pub fn generate_sender_shared_secret(&self, r_keys: &ServerKeyCollection) -> Result<([u8; 32], Vec<u8>, PublicKey, Option<PublicKey>), X3DHError> {
-        let sk: [u8; 32];
-        let eka: PublicKey;
-        let opk_used: Option<PublicKey>;
-        match x3dh_sender(self.get_ik(), r_keys.get_ik(), r_keys.get_spk(), r_keys.signature, r_keys.verifying_key, r_keys.get_opk_bundle().pop()) {
-            Ok((current_sk, current_eka, current_opkb)) => {
-                sk = current_sk;
-                eka = current_eka;
-                opk_used = current_opkb;
-            },
-            Err(error) => return Err(error),
-        };
+    /// * `(shared_secret, associated_data, ephemeral_key_sender, one_time_prekey_used, kem_ciphertext)` (Result\<([u8; 32], Vec\<u8\>, PublicKey, Option\<PublicKey\>, Option\<(u32, KemCiphertext)\>), X3DHError\>): (Shared Secret, Associated Data, EphemeralKey sender, OneTimePrekey used, (ML-KEM prekey id, ML-KEM ciphertext) if PQXDH is used)
+    pub fn generate_sender_shared_secret(&self, r_keys: &ServerKeyCollection, use_pq: bool) -> Result<SenderSharedSecret, X3DHError> {
+        let (sk, eka, opk_used, kem_ciphertext): ([u8; 32], PublicKey, Option<PublicKey>, Option<KemCiphertext>);
+        (sk, eka, opk_used, kem_ciphertext) = x3dh_sender(&X3DH_CONFIG, self.get_ik(), r_keys.get_ik(), r_keys.get_spk(), r_keys.signature, r_keys.get_opk_bundle().pop().map(|(_, opk)| opk), use_pq.then_some((&r_keys.pqspk, r_keys.pq_signature)))?;
+        if use_pq && kem_ciphertext.is_none() {
+            return Err(X3DHError::KemCiphertextAbsent)
+        }
 
         let ad: Vec<u8> = get_ad(self.get_ik_public(), r_keys.get_ik(), None);
 
-        Ok((sk, ad, eka, opk_used))
+        Ok((sk, ad, eka, opk_used, kem_ciphertext.map(|kem_ciphertext| (r_keys.pqspk_id, kem_ciphertext))))
     }
 
     /// Generate the receiver shared secret
@@ -75,21 +208,30 @@ impl ClientKeyCollection {
     /// 
     /// * `ik_sender` (PublicKey): Public Identity Key of the sender
     /// * `message` (&Message): Ciphertext
+    /// * `require_pq` (bool): Reject the initial messages without ML-KEM ciphertext *(otherwise they use the classical X3DH)*
     /// 
     /// # Output
     /// 
-    /// * `(shared_secret, associated_data)` (Result\<([u8; 32], Vec\<u8\>): (Shared Secret, Associated Data)
-    pub fn generate_receiver_shared_secret(&mut self, ik_sender: PublicKey, message: &Message) -> Result<([u8; 32], Vec<u8>), KeyError>  {
+    /// * `(shared_secret, associated_data, signed_prekey)` (Result\<([u8; 32], Vec\<u8\>, SignedPrekey), KeyError\>): (Shared Secret, Associated Data, SignedPrekey used by the sender)
+    pub fn generate_receiver_shared_secret(&self, ik_sender: PublicKey, message: &Message, require_pq: bool) -> Result<([u8; 32], Vec<u8>, SignedPrekey), KeyError>  {
         let ek_sender: PublicKey = message.get_ek_sender().ok_or(KeyError::EphemeralKeyAbsent)?;
-        let mut opk_used: Option<OneTimePrekey> = None;
-        if message.get_opk_used().is_some() {
-            opk_used = self.get_opk_used(message.get_opk_used().unwrap());
-        }
+        let spk: SignedPrekey = message.get_spk_id().and_then(|spk_id| self.get_spk_by_id(spk_id)).ok_or(KeyError::SignedPrekeyUnknown)?;
+        // An ML-KEM prekey is always published, so the KEM ciphertext is only left out by the senders not using PQXDH (or by a downgrade)
+        let pq: Option<(KemPrekey, KemCiphertext)> = match message.get_kem_ciphertext() {
+            Some((pqspk_id, kem_ciphertext)) => Some((self.get_pqspk_by_id(pqspk_id).ok_or(KeyError::KemPrekeyUnknown)?, kem_ciphertext)),
+            None if require_pq => return Err(KeyError::KemCiphertextAbsent),
+            None => None,
+        };
+        // The one-time prekey is only removed once the first message is decrypted (see `remove_opk`)
+        let opk_used: Option<OneTimePrekey> = match message.get_opk_used() {
+            Some(opkb_used) => Some(self.get_opk_used(opkb_used).ok_or(KeyError::OneTimePrekeyUnknown)?),
+            None => None,
+        };
         
-        let sk: [u8; 32] = x3dh_receiver(ik_sender, ek_sender, self.get_ik(), self.get_spk(), opk_used);
+        let sk: [u8; 32] = x3dh_receiver(&X3DH_CONFIG, ik_sender, ek_sender, self.get_ik(), spk.clone(), opk_used, pq.as_ref().map(|(pqspk, kem_ciphertext)| (pqspk, kem_ciphertext)));
         let ad: Vec<u8> = get_ad(ik_sender, self.get_ik_public(), None);
 
-        Ok((sk, ad))
+        Ok((sk, ad, spk))
     }
 
     pub fn get_ik(&self) -> IdentityKey {
@@ -104,15 +246,21 @@ impl ClientKeyCollection {
         self.spk.clone()
     }
 
-    pub fn get_spk_public(&self) -> PublicKey {
-        self.spk.get_public_key()
+    pub fn get_spk_id(&self) -> u32 {
+        self.spk_id
     }
 
-    pub fn get_spk_private(&self) -> ReusableSecret {
-        self.spk.get_private_key()
+    /// Returns the current signed prekey or a replaced one still in its grace period
+    pub fn get_spk_by_id(&self, spk_id: u32) -> Option<SignedPrekey> {
+        if spk_id == self.spk_id {
+            return Some(self.spk.clone())
+        }
+        self.previous_spks.iter()
+            .find(|(id, _, _)| *id == spk_id)
+            .map(|(_, spk, _)| spk.clone())
     }
 
-    pub fn get_opk_bundle(&self) -> &Vec<OneTimePrekey> {
+    pub fn get_opk_bundle(&self) -> &Vec<(u32, OneTimePrekey)> {
         &self.opk_bundle
     }
 
@@ -120,23 +268,47 @@ impl ClientKeyCollection {
         self.signature
     }
 
-    pub fn get_verifying_key(&self) -> VerifyingKey {
-        self.verifying_key
+    pub fn get_pqspk(&self) -> &KemPrekey {
+        &self.pqspk
+    }
+
+    pub fn get_pqspk_id(&self) -> u32 {
+        self.pqspk_id
+    }
+
+    /// Returns the current ML-KEM prekey or a replaced one still in its grace period
+    pub fn get_pqspk_by_id(&self, pqspk_id: u32) -> Option<KemPrekey> {
+        if pqspk_id == self.pqspk_id {
+            return Some(self.pqspk.clone())
+        }
+        self.previous_pqspks.iter()
+            .find(|(id, _, _)| *id == pqspk_id)
+            .map(|(_, pqspk, _)| pqspk.clone())
+    }
+
+    pub fn get_pq_signature(&self) -> Signature {
+        self.pq_signature
     }
 
-    pub fn get_opk_used(&mut self, opkb_used: PublicKey) -> Option<OneTimePrekey> {
-        if let Some(index) = self.opk_bundle.iter().position(|key| key.get_public_key() == opkb_used) {
-            // Item found, remove it and return it
-            return Some(self.opk_bundle.swap_remove(index));
+    pub fn get_opk_used(&self, opkb_used: PublicKey) -> Option<OneTimePrekey> {
+        self.opk_bundle.iter()
+            .find(|(_, key)| key.get_public_key() == opkb_used)
+            .map(|(_, key)| key.clone())
+    }
+
+    /// Remove a one-time prekey once the first message that used it has been decrypted
+    pub fn remove_opk(&mut self, opkb_used: PublicKey) {
+        if let Some(index) = self.opk_bundle.iter().position(|(_, key)| key.get_public_key() == opkb_used) {
+            self.opk_bundle.swap_remove(index);
         }
-        None
     }
 }
 
 impl ServerKeyCollection {
-    pub fn from(ik: IdentityKey, spk: SignedPrekey, opk_bundle: &Vec<OneTimePrekey>, signature: Signature, verifying_key: VerifyingKey) -> Self {
-        let (ik_server, spk_server, opk_bundle_server, signature_server, verifying_key_server): (PublicKey, PublicKey, Vec<PublicKey>, Signature, VerifyingKey) = create_prekey_bundle(&ik, &spk, opk_bundle, signature, verifying_key);
-        ServerKeyCollection { ik: ik_server, spk: spk_server, opk_bundle: opk_bundle_server, signature: signature_server, verifying_key: verifying_key_server }
+    pub fn from(ik: IdentityKey, spk: SignedPrekey, spk_id: u32, opk_bundle: &[(u32, OneTimePrekey)], signature: Signature, (pqspk, pqspk_id, pq_signature): (&KemPrekey, u32, Signature)) -> Self {
+        let (opk_ids, opk_keys): (Vec<u32>, Vec<OneTimePrekey>) = opk_bundle.iter().cloned().unzip();
+        let (ik_server, spk_server, opk_bundle_server, signature_server): (PublicKey, PublicKey, Vec<PublicKey>, Signature) = create_prekey_bundle(&ik, &spk, &opk_keys, signature);
+        ServerKeyCollection { ik: ik_server, spk: spk_server, spk_id, opk_bundle: opk_ids.into_iter().zip(opk_bundle_server).collect(), signature: signature_server, pqspk: pqspk.get_public_key(), pqspk_id, pq_signature }
     }
 
     pub fn get_ik(&self) -> PublicKey {
@@ -147,9 +319,97 @@ impl ServerKeyCollection {
         self.spk
     }
 
-    pub fn get_opk_bundle(&self) -> Vec<PublicKey> {
+    pub fn get_spk_id(&self) -> u32 {
+        self.spk_id
+    }
+
+    pub fn get_pqspk(&self) -> EncapsulationKey {
+        self.pqspk
+    }
+
+    pub fn get_pqspk_id(&self) -> u32 {
+        self.pqspk_id
+    }
+
+    /// Replace the published signed prekey *(rotation)*
+    pub fn set_spk(&mut self, spk_id: u32, spk: PublicKey, signature: Signature) {
+        self.spk_id = spk_id;
+        self.spk = spk;
+        self.signature = signature;
+    }
+
+    /// Replace the published ML-KEM prekey *(rotation)*
+    pub fn set_pqspk(&mut self, pqspk_id: u32, pqspk: EncapsulationKey, pq_signature: Signature) {
+        self.pqspk_id = pqspk_id;
+        self.pqspk = pqspk;
+        self.pq_signature = pq_signature;
+    }
+
+    pub fn get_opk_bundle(&self) -> Vec<(u32, PublicKey)> {
         self.opk_bundle.clone()
     }
+
+    /// Returns a copy of the bundle with at most one one-time prekey, which is removed from this collection so that it's never handed out twice
+    pub fn take_bundle(&mut self) -> ServerKeyCollection {
+        let opk_bundle: Vec<(u32, PublicKey)> = if self.opk_bundle.is_empty() { Vec::new() } else { vec![self.opk_bundle.remove(0)] };
+        ServerKeyCollection { ik: self.ik, spk: self.spk, spk_id: self.spk_id, opk_bundle, signature: self.signature, pqspk: self.pqspk, pqspk_id: self.pqspk_id, pq_signature: self.pq_signature }
+    }
+
+    /// Returns the wire encoding of the keys
+    ///
+    /// `ik (32) || spk (32) || spk_id (4) || signature (64) || pqspk (1568) || pqspk_id (4) || pq_signature (64) || opk count (4) || (opk id (4) || opk (32))*`
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::with_capacity(1772 + 36 * self.opk_bundle.len());
+        bytes.extend_from_slice(self.ik.as_bytes());
+        bytes.extend_from_slice(self.spk.as_bytes());
+        bytes.extend_from_slice(&self.spk_id.to_be_bytes());
+        bytes.extend_from_slice(&self.signature);
+        bytes.extend_from_slice(&self.pqspk);
+        bytes.extend_from_slice(&self.pqspk_id.to_be_bytes());
+        bytes.extend_from_slice(&self.pq_signature);
+        let opk_count: u32 = self.opk_bundle.len().try_into().expect("Too many one-time prekeys");
+        bytes.extend_from_slice(&opk_count.to_be_bytes());
+        for (id, opk) in &self.opk_bundle {
+            bytes.extend_from_slice(&id.to_be_bytes());
+            bytes.extend_from_slice(opk.as_bytes());
+        }
+        bytes
+    }
+
+    /// Parse the keys from their wire encoding
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ParseError> {
+        let mut reader: Reader = Reader::new(bytes);
+        let ik: PublicKey = PublicKey::from(reader.read_array::<32>()?);
+        let spk: PublicKey = PublicKey::from(reader.read_array::<32>()?);
+        let spk_id: u32 = u32::from_be_bytes(reader.read_array::<4>()?);
+        let signature: Signature = reader.read_array::<64>()?;
+        let pqspk: EncapsulationKey = reader.read_array::<ENCAPSULATION_KEY_LENGTH>()?;
+        let pqspk_id: u32 = u32::from_be_bytes(reader.read_array::<4>()?);
+        let pq_signature: Signature = reader.read_array::<64>()?;
+        let opk_count: u32 = u32::from_be_bytes(reader.read_array::<4>()?);
+        let mut opk_bundle: Vec<(u32, PublicKey)> = Vec::new();
+        for _ in 0..opk_count {
+            let id: u32 = u32::from_be_bytes(reader.read_array::<4>()?);
+            opk_bundle.push((id, PublicKey::from(reader.read_array::<32>()?)));
+        }
+        reader.finish()?;
+
+        Ok(ServerKeyCollection { ik, spk, spk_id, opk_bundle, signature, pqspk, pqspk_id, pq_signature })
+    }
+
+    /// Add newly uploaded one-time prekeys, ignoring the ids already present
+    pub fn add_opks(&mut self, opks: Vec<(u32, PublicKey)>) {
+        for (id, opk) in opks {
+            if !self.opk_bundle.iter().any(|(current_id, _)| *current_id == id) {
+                self.opk_bundle.push((id, opk));
+            }
+        }
+    }
+}
+
+/// Current Unix time (in seconds)
+pub fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0)
 }
 
 impl fmt::Display for KeyError {
@@ -157,6 +417,43 @@ impl fmt::Display for KeyError {
         match self {
             KeyError::EphemeralKeyAbsent => write!(f, "No ephemeral key to initialize the receiver X3DH"),
             KeyError::IdentityKeyAbsent => write!(f, "No identity key to initialize the receiver X3DH"),
+            KeyError::KemCiphertextAbsent => write!(f, "No ML-KEM ciphertext to initialize the receiver PQXDH"),
+            KeyError::SignedPrekeyUnknown => write!(f, "The signed prekey used by the sender is unknown or expired"),
+            KeyError::KemPrekeyUnknown => write!(f, "The ML-KEM prekey used by the sender is unknown or expired"),
+            KeyError::OneTimePrekeyUnknown => write!(f, "The one-time prekey used by the sender is unknown or already used"),
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server_keys() -> ServerKeyCollection {
+        let keys: ClientKeyCollection = ClientKeyCollection::new();
+        ServerKeyCollection::from(keys.get_ik(), keys.get_spk(), keys.get_spk_id(), keys.get_opk_bundle(), keys.get_signature(), (keys.get_pqspk(), keys.get_pqspk_id(), keys.get_pq_signature()))
+    }
+
+    #[test]
+    fn test_server_keys_round_trip() {
+        let keys: ServerKeyCollection = server_keys();
+        let bundle: ServerKeyCollection = keys.clone().take_bundle();
+        let empty_bundle: ServerKeyCollection = ServerKeyCollection { opk_bundle: Vec::new(), ..keys.clone() };
+
+        for expected_value in [keys, bundle, empty_bundle] {
+            assert_eq!(ServerKeyCollection::from_bytes(&expected_value.to_bytes()), Ok(expected_value));
+        }
+    }
+
+    #[test]
+    fn test_server_keys_truncated_or_extended() {
+        let bytes: Vec<u8> = server_keys().take_bundle().to_bytes();
+        let mut extended_bytes: Vec<u8> = bytes.clone();
+        extended_bytes.push(0);
+
+        for length in 0..bytes.len() {
+            assert_eq!(ServerKeyCollection::from_bytes(&bytes[..length]), Err(ParseError::UnexpectedEnd));
+        }
+        assert_eq!(ServerKeyCollection::from_bytes(&extended_bytes), Err(ParseError::TrailingBytes));
+    }
+}
@@ -0,0 +1,453 @@
+//! Queues of the messages waiting for their receiver
+//!
+//! Each device of a user has its own queue, a message stays in it until the device acknowledges it *(after decrypting it)*.
+//! On disk, each device has an append-only log `<hex(username)>.<device_id>.log` made of the records:
+//! - `RECORD_MESSAGE (1) || id (8) || length (4) || message`
+//! - `RECORD_ACKNOWLEDGEMENT (1) || count (4) || ids (8 × count)` *(one record per batch of acknowledged messages)*
+//! - `RECORD_NEXT_ID (1) || id (8)` *(first record of a compacted log, so that the ids are never reused)*
+//!
+//! Every record is synced before the operation returns, and a record that fails to be written is cut from the log.
+//! A log is read up to its first incomplete or invalid record *(cut by a crash, or corrupted)* and truncated there, so the relay always starts.
+//! When the acknowledged messages take most of a log, it's compacted: the pending messages are written to a new log that replaces the old one.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use super::message::{write_bytes, Envelope};
+use super::server::DeviceId;
+
+const RECORD_MESSAGE: u8 = 0x01;
+const RECORD_ACKNOWLEDGEMENT: u8 = 0x02;
+const RECORD_NEXT_ID: u8 = 0x03;
+const LOG_EXTENSION: &str = "log";
+const COMPACTION_THRESHOLD: usize = 64; // Minimum number of acknowledged messages in a log before it's compacted
+
+pub struct Mailbox {
+    directory: Option<PathBuf>, // None: the messages are only kept in memory
+    queues: HashMap<(String, DeviceId), Queue>,
+}
+
+struct Queue {
+    pending: Vec<(u64, Envelope)>, // (id, message) in their order of arrival
+    next_id: u64,
+    acknowledged: usize, // Acknowledged messages still in the log
+    log: Option<File>,
+}
+
+impl Mailbox {
+    /// Mailbox lost when the relay stops
+    pub fn in_memory() -> Self {
+        Mailbox { directory: None, queues: HashMap::new() }
+    }
+
+    /// Open (or create) a mailbox stored in a directory and read the messages still pending
+    ///
+    /// # Arguments
+    ///
+    /// * `directory` (&Path): Directory of the logs
+    ///
+    /// # Output
+    ///
+    /// * `mailbox` (io::Result\<Mailbox\>)
+    pub fn open(directory: &Path) -> io::Result<Self> {
+        fs::create_dir_all(directory)?;
+        let mut mailbox: Mailbox = Mailbox { directory: Some(directory.to_path_buf()), queues: HashMap::new() };
+        for entry in fs::read_dir(directory)? {
+            let path: PathBuf = entry?.path();
+            if path.extension().and_then(|extension| extension.to_str()) != Some(LOG_EXTENSION) {
+                continue;
+            }
+            let (username, device_id): (String, DeviceId) = match path.file_stem().and_then(|stem| stem.to_str()).and_then(decode_log_name) {
+                Some(address) => address,
+                None => continue,
+            };
+            let queue: Queue = Queue::read_log(&path)?;
+            mailbox.queues.insert((username.clone(), device_id), queue);
+            // Drop the acknowledged messages
+            mailbox.compact(&username, device_id)?;
+        }
+        Ok(mailbox)
+    }
+
+    /// Add a message to the queue of a device
+    ///
+    /// # Output
+    ///
+    /// * `id` (io::Result\<u64\>): Id used to acknowledge the message
+    pub fn enqueue(&mut self, username: &str, device_id: DeviceId, message: Envelope) -> io::Result<u64> {
+        let queue: &mut Queue = self.queue(username, device_id)?;
+        let id: u64 = queue.next_id;
+        let next_id: u64 = id.checked_add(1).ok_or_else(|| io::Error::other("No message id left"))?;
+        let message_bytes: Vec<u8> = message.to_bytes().map_err(|error| io::Error::other(error.to_string()))?;
+        let mut record: Vec<u8> = Vec::with_capacity(13 + message_bytes.len());
+        record.push(RECORD_MESSAGE);
+        record.extend_from_slice(&id.to_be_bytes());
+        write_bytes(&mut record, &message_bytes).map_err(|error| io::Error::other(error.to_string()))?;
+        queue.append(&record)?;
+        queue.pending.push((id, message));
+        queue.next_id = next_id;
+        Ok(id)
+    }
+
+    /// Returns the messages of a device not acknowledged yet, in their order of arrival
+    pub fn pending(&self, username: &str, device_id: DeviceId) -> Vec<(u64, Envelope)> {
+        self.queues.get(&(username.to_string(), device_id)).map(|queue| queue.pending.clone()).unwrap_or_default()
+    }
+
+    /// Delete messages of a device once they have been read, the unknown ids are ignored
+    pub fn acknowledge(&mut self, username: &str, device_id: DeviceId, ids: &[u64]) -> io::Result<()> {
+        let queue: &mut Queue = match self.queues.get_mut(&(username.to_string(), device_id)) {
+            Some(queue) => queue,
+            None => return Ok(()),
+        };
+        let ids: HashSet<u64> = ids.iter().copied().collect();
+        let acknowledged_ids: Vec<u64> = queue.pending.iter().map(|(id, _)| *id).filter(|id| ids.contains(id)).collect();
+        if acknowledged_ids.is_empty() {
+            return Ok(())
+        }
+        // One record, synced once, for the whole batch
+        let mut record: Vec<u8> = Vec::with_capacity(5 + 8 * acknowledged_ids.len());
+        record.push(RECORD_ACKNOWLEDGEMENT);
+        record.extend_from_slice(&(acknowledged_ids.len() as u32).to_be_bytes());
+        for id in &acknowledged_ids {
+            record.extend_from_slice(&id.to_be_bytes());
+        }
+        queue.append(&record)?;
+        queue.pending.retain(|(id, _)| !ids.contains(id));
+        queue.acknowledged += acknowledged_ids.len();
+        if queue.acknowledged >= COMPACTION_THRESHOLD && queue.acknowledged > queue.pending.len() {
+            self.compact(username, device_id)?;
+        }
+        Ok(())
+    }
+
+    /// Delete the queue of a removed device with its log
+    pub fn remove(&mut self, username: &str, device_id: DeviceId) -> io::Result<()> {
+        if self.queues.remove(&(username.to_string(), device_id)).is_some() {
+            if let Some(path) = self.log_path(username, device_id) {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Rewrite the log of a device with only its pending messages
+    pub fn compact(&mut self, username: &str, device_id: DeviceId) -> io::Result<()> {
+        let path: Option<PathBuf> = self.log_path(username, device_id);
+        let queue: &mut Queue = match self.queues.get_mut(&(username.to_string(), device_id)) {
+            Some(queue) => queue,
+            None => return Ok(()),
+        };
+        let path: PathBuf = match path {
+            Some(path) => path,
+            None => {
+                queue.acknowledged = 0;
+                return Ok(())
+            },
+        };
+
+        let mut bytes: Vec<u8> = vec![RECORD_NEXT_ID];
+        bytes.extend_from_slice(&queue.next_id.to_be_bytes());
+        for (id, message) in &queue.pending {
+            bytes.push(RECORD_MESSAGE);
+            bytes.extend_from_slice(&id.to_be_bytes());
+            write_bytes(&mut bytes, &message.to_bytes().map_err(|error| io::Error::other(error.to_string()))?).map_err(|error| io::Error::other(error.to_string()))?;
+        }
+        // The new log replaces the old one in a single step, a crash leaves one of them intact
+        let compacted_path: PathBuf = path.with_extension("compact");
+        let mut compacted_log: File = File::create(&compacted_path)?;
+        compacted_log.write_all(&bytes)?;
+        compacted_log.sync_all()?;
+        fs::rename(&compacted_path, &path)?;
+
+        queue.log = Some(OpenOptions::new().append(true).open(&path)?);
+        queue.acknowledged = 0;
+        Ok(())
+    }
+
+    /// Returns the queue of a device, with its log opened
+    fn queue(&mut self, username: &str, device_id: DeviceId) -> io::Result<&mut Queue> {
+        let path: Option<PathBuf> = self.log_path(username, device_id);
+        let queue: &mut Queue = self.queues.entry((username.to_string(), device_id)).or_insert_with(|| Queue { pending: Vec::new(), next_id: 0, acknowledged: 0, log: None });
+        if let (None, Some(path)) = (&queue.log, path) {
+            queue.log = Some(OpenOptions::new().create(true).append(true).open(path)?);
+        }
+        Ok(queue)
+    }
+
+    fn log_path(&self, username: &str, device_id: DeviceId) -> Option<PathBuf> {
+        self.directory.as_ref().map(|directory| directory.join(format!("{}.{}.{}", encode_username(username), device_id, LOG_EXTENSION)))
+    }
+}
+
+impl Queue {
+    /// Replay a log, it's truncated at its first incomplete or invalid record
+    fn read_log(path: &Path) -> io::Result<Self> {
+        let mut log: File = OpenOptions::new().read(true).write(true).open(path)?;
+        let mut bytes: Vec<u8> = Vec::new();
+        log.read_to_end(&mut bytes)?;
+
+        let mut queue: Queue = Queue { pending: Vec::new(), next_id: 0, acknowledged: 0, log: None };
+        let mut position: usize = 0;
+        while position < bytes.len() {
+            match queue.replay(&bytes[position..]) {
+                Some(length) => position += length,
+                None => break,
+            }
+        }
+        if position < bytes.len() {
+            log.set_len(position as u64)?;
+            log.sync_all()?;
+        }
+        Ok(queue)
+    }
+
+    /// Apply the record at the start of `bytes` to the queue
+    ///
+    /// # Output
+    ///
+    /// * `length` (Option\<usize\>): Length of the record, None if it's incomplete or invalid *(the queue is left unchanged)*
+    fn replay(&mut self, bytes: &[u8]) -> Option<usize> {
+        let id_at = |position: usize| bytes.get(position..position + 8).map(|id| u64::from_be_bytes(id.try_into().expect("Incorrect length")));
+        match *bytes.first()? {
+            RECORD_MESSAGE => {
+                let id: u64 = id_at(1)?;
+                let length: usize = u32::from_be_bytes(bytes.get(9..13)?.try_into().expect("Incorrect length")) as usize;
+                let message: Envelope = Envelope::from_bytes(bytes.get(13..13 + length)?).ok()?;
+                let next_id: u64 = id.checked_add(1)?;
+                self.pending.push((id, message));
+                self.next_id = self.next_id.max(next_id);
+                Some(13 + length)
+            },
+            RECORD_NEXT_ID => {
+                self.next_id = self.next_id.max(id_at(1)?);
+                Some(9)
+            },
+            RECORD_ACKNOWLEDGEMENT => {
+                let count: usize = u32::from_be_bytes(bytes.get(1..5)?.try_into().expect("Incorrect length")) as usize;
+                let ids: HashSet<u64> = (0..count).map(|index| id_at(5 + 8 * index)).collect::<Option<HashSet<u64>>>()?;
+                let pending_length: usize = self.pending.len();
+                self.pending.retain(|(id, _)| !ids.contains(id));
+                self.acknowledged += pending_length - self.pending.len();
+                Some(5 + 8 * count)
+            },
+            _ => None,
+        }
+    }
+
+    /// Write a record at the end of the log and wait for it to be on disk, a record not fully written is cut from the log
+    fn append(&mut self, record: &[u8]) -> io::Result<()> {
+        if let Some(log) = &mut self.log {
+            let length: u64 = log.metadata()?.len();
+            if let Err(error) = log.write_all(record).and_then(|_| log.sync_data()) {
+                // The next records must not follow a partial one
+                log.set_len(length)?;
+                return Err(error)
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The username is hex encoded so that any name gives a valid file name
+pub(crate) fn encode_username(username: &str) -> String {
+    username.bytes().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Returns the user and the device of a log from its file stem `<hex(username)>.<device_id>`
+fn decode_log_name(stem: &str) -> Option<(String, DeviceId)> {
+    let (username, device_id) = stem.split_once('.')?;
+    Some((decode_username(username)?, device_id.parse().ok()?))
+}
+
+pub(crate) fn decode_username(encoded: &str) -> Option<String> {
+    if !encoded.len().is_multiple_of(2) {
+        return None
+    }
+    let bytes: Option<Vec<u8>> = (0..encoded.len()).step_by(2).map(|index| u8::from_str_radix(encoded.get(index..index + 2)?, 16).ok()).collect();
+    String::from_utf8(bytes?).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::communication::message::{Ciphertext, Header, Message};
+    use crate::communication::server::PRIMARY_DEVICE_ID;
+    use x25519_dalek::PublicKey;
+    use std::env;
+    use std::process;
+
+    fn message(n: u8) -> Envelope {
+        let header: Header = Header::new(PublicKey::from([0x01; 32]), 0, n as u32);
+        Envelope::Plain(Box::new(Message::new(("Alice".to_string(), PRIMARY_DEVICE_ID), (header, Ciphertext::new(vec![n; 26], vec![n; 12])), None, None, None, None, None)))
+    }
+
+    fn directory(name: &str) -> PathBuf {
+        let directory: PathBuf = env::temp_dir().join(format!("double-ratchet-mailbox-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&directory);
+        directory
+    }
+
+    fn log_path(directory: &Path, username: &str) -> PathBuf {
+        directory.join(format!("{}.{}.{}", encode_username(username), PRIMARY_DEVICE_ID, LOG_EXTENSION))
+    }
+
+    fn log_length(directory: &Path, username: &str) -> u64 {
+        fs::metadata(log_path(directory, username)).unwrap().len()
+    }
+
+    #[test]
+    fn test_messages_survive_a_restart() {
+        let directory: PathBuf = directory("restart");
+        let bob: String = "Bob".to_string();
+        let mut mailbox: Mailbox = Mailbox::open(&directory).unwrap();
+        let first_id: u64 = mailbox.enqueue(&bob, PRIMARY_DEVICE_ID, message(1)).unwrap();
+        mailbox.enqueue(&bob, PRIMARY_DEVICE_ID, message(2)).unwrap();
+        mailbox.acknowledge(&bob, PRIMARY_DEVICE_ID, &[first_id]).unwrap();
+        drop(mailbox);
+
+        let mut mailbox: Mailbox = Mailbox::open(&directory).unwrap();
+        assert_eq!(mailbox.pending(&bob, PRIMARY_DEVICE_ID), vec![(1, message(2))]);
+        // Ids are never reused, even once all the messages are acknowledged
+        assert_eq!(mailbox.enqueue(&bob, PRIMARY_DEVICE_ID, message(3)).unwrap(), 2);
+        mailbox.acknowledge(&bob, PRIMARY_DEVICE_ID, &[1, 2]).unwrap();
+        drop(mailbox);
+        let mut mailbox: Mailbox = Mailbox::open(&directory).unwrap();
+        assert!(mailbox.pending(&bob, PRIMARY_DEVICE_ID).is_empty());
+        assert_eq!(mailbox.enqueue(&bob, PRIMARY_DEVICE_ID, message(4)).unwrap(), 3);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_record_cut_by_a_crash() {
+        let directory: PathBuf = directory("crash");
+        let bob: String = "Bob".to_string();
+        let mut mailbox: Mailbox = Mailbox::open(&directory).unwrap();
+        mailbox.enqueue(&bob, PRIMARY_DEVICE_ID, message(1)).unwrap();
+        drop(mailbox);
+        drop(Mailbox::open(&directory).unwrap());
+        let length: u64 = log_length(&directory, &bob);
+        let mut log: File = OpenOptions::new().append(true).open(log_path(&directory, &bob)).unwrap();
+        log.write_all(&[RECORD_MESSAGE, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0x10]).unwrap();
+
+        let mailbox: Mailbox = Mailbox::open(&directory).unwrap();
+        assert_eq!(mailbox.pending(&bob, PRIMARY_DEVICE_ID), vec![(0, message(1))]);
+        assert_eq!(log_length(&directory, &bob), length);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_end_of_log() {
+        let directory: PathBuf = directory("invalid");
+        let bob: String = "Bob".to_string();
+        let mut mailbox: Mailbox = Mailbox::open(&directory).unwrap();
+        mailbox.enqueue(&bob, PRIMARY_DEVICE_ID, message(1)).unwrap();
+        drop(mailbox);
+        drop(Mailbox::open(&directory).unwrap());
+        let length: u64 = log_length(&directory, &bob);
+
+        // Unknown record, then a message whose id can't be followed by another one
+        for garbage in [vec![0xFF; 20], [&[RECORD_MESSAGE][..], &[0xFF; 8], &[0, 0, 0, 0]].concat()] {
+            let mut log: File = OpenOptions::new().append(true).open(log_path(&directory, &bob)).unwrap();
+            log.write_all(&garbage).unwrap();
+            log.write_all(&[RECORD_NEXT_ID, 0, 0, 0, 0, 0, 0, 0, 9]).unwrap();
+            drop(log);
+
+            let mut mailbox: Mailbox = Mailbox::open(&directory).unwrap();
+            assert_eq!(mailbox.pending(&bob, PRIMARY_DEVICE_ID), vec![(0, message(1))]);
+            assert_eq!(log_length(&directory, &bob), length);
+            // The records after the invalid one are dropped with it, the log is still appended to
+            let id: u64 = mailbox.enqueue(&bob, PRIMARY_DEVICE_ID, message(2)).unwrap();
+            assert!(id < 9);
+            mailbox.acknowledge(&bob, PRIMARY_DEVICE_ID, &[id]).unwrap();
+        }
+        assert_eq!(Mailbox::open(&directory).unwrap().pending(&bob, PRIMARY_DEVICE_ID), vec![(0, message(1))]);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_batch_acknowledgement() {
+        let directory: PathBuf = directory("batch");
+        let bob: String = "Bob".to_string();
+        let mut mailbox: Mailbox = Mailbox::open(&directory).unwrap();
+        let ids: Vec<u64> = (0..10).map(|n| mailbox.enqueue(&bob, PRIMARY_DEVICE_ID, message(n)).unwrap()).collect();
+        let length: u64 = log_length(&directory, &bob);
+
+        // A single record for the known ids of the batch, nothing for unknown ones
+        mailbox.acknowledge(&bob, PRIMARY_DEVICE_ID, &[ids[2], ids[5], ids[2], 42]).unwrap();
+        assert_eq!(log_length(&directory, &bob), length + 5 + 2 * 8);
+        mailbox.acknowledge(&bob, PRIMARY_DEVICE_ID, &[42]).unwrap();
+        assert_eq!(log_length(&directory, &bob), length + 5 + 2 * 8);
+        drop(mailbox);
+
+        let mailbox: Mailbox = Mailbox::open(&directory).unwrap();
+        let expected_value: Vec<u64> = vec![0, 1, 3, 4, 6, 7, 8, 9];
+        assert_eq!(mailbox.pending(&bob, PRIMARY_DEVICE_ID).iter().map(|(id, _)| *id).collect::<Vec<u64>>(), expected_value);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_compaction() {
+        let directory: PathBuf = directory("compaction");
+        let bob: String = "Bob".to_string();
+        let mut mailbox: Mailbox = Mailbox::open(&directory).unwrap();
+        let ids: Vec<u64> = (0..COMPACTION_THRESHOLD as u8).map(|n| mailbox.enqueue(&bob, PRIMARY_DEVICE_ID, message(n)).unwrap()).collect();
+        mailbox.enqueue(&bob, PRIMARY_DEVICE_ID, message(0xFF)).unwrap();
+        let full_length: u64 = log_length(&directory, &bob);
+
+        mailbox.acknowledge(&bob, PRIMARY_DEVICE_ID, &ids).unwrap();
+        assert!(log_length(&directory, &bob) < full_length / 2);
+        assert_eq!(mailbox.pending(&bob, PRIMARY_DEVICE_ID), vec![(COMPACTION_THRESHOLD as u64, message(0xFF))]);
+        // The compacted log is still appended to
+        mailbox.enqueue(&bob, PRIMARY_DEVICE_ID, message(0xFE)).unwrap();
+        drop(mailbox);
+        assert_eq!(Mailbox::open(&directory).unwrap().pending(&bob, PRIMARY_DEVICE_ID).len(), 2);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_in_memory() {
+        let bob: String = "Bob".to_string();
+        let mut mailbox: Mailbox = Mailbox::in_memory();
+        let id: u64 = mailbox.enqueue(&bob, PRIMARY_DEVICE_ID, message(1)).unwrap();
+        mailbox.enqueue(&bob, PRIMARY_DEVICE_ID, message(2)).unwrap();
+        mailbox.acknowledge(&bob, PRIMARY_DEVICE_ID, &[id, 42]).unwrap();
+
+        assert_eq!(mailbox.pending(&bob, PRIMARY_DEVICE_ID), vec![(1, message(2))]);
+        assert!(mailbox.pending("Alice", PRIMARY_DEVICE_ID).is_empty());
+    }
+
+    #[test]
+    fn test_device_queues() {
+        let directory: PathBuf = directory("devices");
+        let bob: String = "Bob".to_string();
+        let mut mailbox: Mailbox = Mailbox::open(&directory).unwrap();
+        mailbox.enqueue(&bob, PRIMARY_DEVICE_ID, message(1)).unwrap();
+        mailbox.enqueue(&bob, 2, message(2)).unwrap();
+        mailbox.enqueue(&bob, 3, message(3)).unwrap();
+        drop(mailbox);
+
+        let mut mailbox: Mailbox = Mailbox::open(&directory).unwrap();
+        assert_eq!(mailbox.pending(&bob, PRIMARY_DEVICE_ID), vec![(0, message(1))]);
+        assert_eq!(mailbox.pending(&bob, 2), vec![(0, message(2))]);
+        // The log of a removed device is deleted
+        mailbox.remove(&bob, 3).unwrap();
+        mailbox.remove(&bob, 4).unwrap();
+        assert!(mailbox.pending(&bob, 3).is_empty());
+        drop(mailbox);
+        assert!(Mailbox::open(&directory).unwrap().pending(&bob, 3).is_empty());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_username_encoding() {
+        for username in ["Bob", "../etc/passwd", "Zoë"] {
+            assert_eq!(decode_username(&encode_username(username)), Some(username.to_string()));
+        }
+        assert_eq!(decode_username("4"), None);
+        assert_eq!(decode_log_name(&format!("{}.{}", encode_username("Bob"), 2)), Some(("Bob".to_string(), 2)));
+        assert_eq!(decode_log_name(&encode_username("Bob")), None);
+    }
+}
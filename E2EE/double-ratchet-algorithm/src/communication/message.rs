@@ -1,23 +1,61 @@
+use std::fmt;
 use x25519_dalek::PublicKey;
+use x3dh::mlkem::{KemCiphertext, CIPHERTEXT_LENGTH};
 
-#[derive(Clone, Debug)]
+/// (Public ephemeral key, signed prekey id, public one-time prekey used, (ML-KEM prekey id, ML-KEM ciphertext)) sent with the first message to run X3DH
+pub type X3DHHeader = (PublicKey, u32, Option<PublicKey>, Option<(u32, KemCiphertext)>);
+
+use super::group::{GroupId, GroupMessage, SenderKeyDistribution, GROUP_WIRE_VERSION};
+use super::sealed_sender::{SealedMessage, SEALED_WIRE_VERSION};
+use super::server::DeviceId;
+use crate::double_ratchet::aead::AeadAlgorithm;
+
+const WIRE_VERSION: u8 = 7;
+const FLAG_ABSENT: u8 = 0x00;
+const FLAG_PRESENT: u8 = 0x01;
+const CONTENT_TEXT: u8 = 0x00;
+const CONTENT_SENDER_KEY: u8 = 0x01;
+const CONTENT_GROUP_LEAVE: u8 = 0x02;
+
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+    UnsupportedVersion(u8),
+    UnexpectedEnd,
+    TrailingBytes,
+    InvalidFlag(u8),
+    InvalidUsername,
+    UnknownOperation(u8),
+    UnknownContentType(u8),
+    UnknownAead(u8),
+    FieldTooLong,
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct Message {
     username: String,
-    header: Header, 
-    ciphertext: Ciphertext, 
-    ek_sender: Option<PublicKey>, 
+    device_id: DeviceId, // Device of the sender
+    header: Header,
+    ciphertext: Ciphertext,
+    ek_sender: Option<PublicKey>,
+    spk_id: Option<u32>, // Signed prekey used by the sender, only in the initial message
     opk_used: Option<PublicKey>,
+    kem_ciphertext: Option<(u32, KemCiphertext)>, // PQXDH: (ML-KEM prekey used by the sender, ciphertext), only in the initial message
+    aead: Option<AeadAlgorithm>, // AEAD of the session, only in the initial message (AES-GCM-SIV if absent)
 }
 
 impl Message {
-    pub fn new(username: String, header: Header, ciphertext: Ciphertext, ek_sender: Option<PublicKey>, opk_used: Option<PublicKey>) -> Self {
-        Message { username: username, header: header, ciphertext: ciphertext, ek_sender: ek_sender, opk_used: opk_used}
+    pub fn new((username, device_id): (String, DeviceId), (header, ciphertext): (Header, Ciphertext), ek_sender: Option<PublicKey>, spk_id: Option<u32>, opk_used: Option<PublicKey>, kem_ciphertext: Option<(u32, KemCiphertext)>, aead: Option<AeadAlgorithm>) -> Self {
+        Message { username, device_id, header, ciphertext, ek_sender, spk_id, opk_used, kem_ciphertext, aead }
     }
 
     pub fn get_username(&self) -> String {
         self.username.clone()
     }
 
+    pub fn get_device_id(&self) -> DeviceId {
+        self.device_id
+    }
+
     pub fn get_header(&self) -> Header {
         self.header.clone()
     }
@@ -30,12 +68,173 @@ impl Message {
         self.ek_sender
     }
 
+    pub fn get_spk_id(&self) -> Option<u32> {
+        self.spk_id
+    }
+
     pub fn get_opk_used(&self) -> Option<PublicKey> {
         self.opk_used
     }
+
+    pub fn get_kem_ciphertext(&self) -> Option<(u32, KemCiphertext)> {
+        self.kem_ciphertext
+    }
+
+    pub fn get_aead(&self) -> Option<AeadAlgorithm> {
+        self.aead
+    }
+
+    /// Returns the wire encoding of the message
+    ///
+    /// `version (1) || username (4 + len) || device_id (4) || header (4 + len) || ciphertext (4 + len) || ek_sender (1 [+ 32]) || spk_id (1 [+ 4]) || opk_used (1 [+ 32]) || kem_ciphertext (1 [+ 4 + 1568]) || aead (1 [+ 1])`
+    ///
+    /// Every length prefix is a big-endian `u32`, and the optional X3DH fields are preceded by a presence flag.
+    ///
+    /// # Output
+    ///
+    /// * `bytes` (Result\<Vec\<u8\>, ParseError\>): Encoded message, `ParseError::FieldTooLong` if a field doesn't fit its length prefix
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ParseError> {
+        let mut bytes: Vec<u8> = vec![WIRE_VERSION];
+        write_bytes(&mut bytes, self.username.as_bytes())?;
+        bytes.extend_from_slice(&self.device_id.to_be_bytes());
+        write_bytes(&mut bytes, &self.header.to_bytes())?;
+        write_bytes(&mut bytes, &self.ciphertext.to_bytes()?)?;
+        write_optional_key(&mut bytes, self.ek_sender);
+        match self.spk_id {
+            Some(spk_id) => {
+                bytes.push(FLAG_PRESENT);
+                bytes.extend_from_slice(&spk_id.to_be_bytes());
+            },
+            None => bytes.push(FLAG_ABSENT),
+        }
+        write_optional_key(&mut bytes, self.opk_used);
+        match &self.kem_ciphertext {
+            Some((pqspk_id, kem_ciphertext)) => {
+                bytes.push(FLAG_PRESENT);
+                bytes.extend_from_slice(&pqspk_id.to_be_bytes());
+                bytes.extend_from_slice(kem_ciphertext);
+            },
+            None => bytes.push(FLAG_ABSENT),
+        }
+        match self.aead {
+            Some(aead) => {
+                bytes.push(FLAG_PRESENT);
+                bytes.push(aead.to_byte());
+            },
+            None => bytes.push(FLAG_ABSENT),
+        }
+        Ok(bytes)
+    }
+
+    /// Parse a message from its wire encoding
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` (&\[u8\]): Encoded message
+    ///
+    /// # Output
+    ///
+    /// * `message` (Result\<Message, ParseError\>): Decoded message
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ParseError> {
+        let mut reader: Reader = Reader::new(bytes);
+        let version: u8 = reader.read_u8()?;
+        if version != WIRE_VERSION {
+            return Err(ParseError::UnsupportedVersion(version))
+        }
+        let username: String = String::from_utf8(reader.read_bytes()?.to_vec())
+            .map_err(|_| ParseError::InvalidUsername)?;
+        let device_id: DeviceId = u32::from_be_bytes(reader.read_array::<4>()?);
+        let header: Header = Header::from_bytes(reader.read_bytes()?)?;
+        let ciphertext: Ciphertext = Ciphertext::from_bytes(reader.read_bytes()?)?;
+        let ek_sender: Option<PublicKey> = reader.read_optional_key()?;
+        let spk_id: Option<u32> = match reader.read_u8()? {
+            FLAG_ABSENT => None,
+            FLAG_PRESENT => Some(u32::from_be_bytes(reader.read_array::<4>()?)),
+            flag => return Err(ParseError::InvalidFlag(flag)),
+        };
+        let opk_used: Option<PublicKey> = reader.read_optional_key()?;
+        let kem_ciphertext: Option<(u32, KemCiphertext)> = match reader.read_u8()? {
+            FLAG_ABSENT => None,
+            FLAG_PRESENT => Some((u32::from_be_bytes(reader.read_array::<4>()?), reader.read_array::<CIPHERTEXT_LENGTH>()?)),
+            flag => return Err(ParseError::InvalidFlag(flag)),
+        };
+        let aead: Option<AeadAlgorithm> = match reader.read_u8()? {
+            FLAG_ABSENT => None,
+            FLAG_PRESENT => {
+                let aead: u8 = reader.read_u8()?;
+                Some(AeadAlgorithm::from_byte(aead).ok_or(ParseError::UnknownAead(aead))?)
+            },
+            flag => return Err(ParseError::InvalidFlag(flag)),
+        };
+        reader.finish()?;
+
+        Ok(Message { username, device_id, header, ciphertext, ek_sender, spk_id, opk_used, kem_ciphertext, aead })
+    }
+}
+
+/// Message queued on the relay, with the name of its sender in clear or sealed *(see `sealed_sender`)*, or message of a group *(see `group`)*
+#[derive(Clone, Debug, PartialEq)]
+pub enum Envelope {
+    Plain(Box<Message>),
+    Sealed(SealedMessage),
+    Group(GroupMessage),
+}
+
+impl Envelope {
+    /// Returns the wire encoding of the message it holds *(sealed and group messages are told apart by their version)*
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ParseError> {
+        match self {
+            Envelope::Plain(message) => message.to_bytes(),
+            Envelope::Sealed(sealed_message) => sealed_message.to_bytes(),
+            Envelope::Group(group_message) => group_message.to_bytes(),
+        }
+    }
+
+    /// Parse an envelope from the wire encoding of the message it holds
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ParseError> {
+        match bytes.first() {
+            Some(&SEALED_WIRE_VERSION) => Ok(Envelope::Sealed(SealedMessage::from_bytes(bytes)?)),
+            Some(&GROUP_WIRE_VERSION) => Ok(Envelope::Group(GroupMessage::from_bytes(bytes)?)),
+            _ => Ok(Envelope::Plain(Box::new(Message::from_bytes(bytes)?))),
+        }
+    }
+}
+
+/// Plaintext of a pairwise message: a text of the user or a group update
+#[derive(Clone, Debug, PartialEq)]
+pub enum Content {
+    Text(Vec<u8>),
+    SenderKey(SenderKeyDistribution),
+    GroupLeave(GroupId),
+}
+
+impl Content {
+    /// Returns the encoding of the content: `type (1) || text | sender key distribution | group_id (16)`
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ParseError> {
+        match self {
+            Content::Text(text) => Ok([&[CONTENT_TEXT], text.as_slice()].concat()),
+            Content::SenderKey(distribution) => Ok([vec![CONTENT_SENDER_KEY], distribution.to_bytes()?].concat()),
+            Content::GroupLeave(group_id) => Ok([&[CONTENT_GROUP_LEAVE], group_id.as_slice()].concat()),
+        }
+    }
+
+    /// Parse a content from its encoding
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ParseError> {
+        let mut reader: Reader = Reader::new(bytes);
+        match reader.read_u8()? {
+            CONTENT_TEXT => Ok(Content::Text(reader.read_remaining().to_vec())),
+            CONTENT_SENDER_KEY => Ok(Content::SenderKey(SenderKeyDistribution::from_bytes(reader.read_remaining())?)),
+            CONTENT_GROUP_LEAVE => {
+                let group_id: GroupId = reader.read_array::<16>()?;
+                reader.finish()?;
+                Ok(Content::GroupLeave(group_id))
+            },
+            content_type => Err(ParseError::UnknownContentType(content_type)),
+        }
+    }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Ciphertext {
     ciphertext: Vec<u8>,
     nonce: Vec<u8>,
@@ -43,7 +242,7 @@ pub struct Ciphertext {
 
 impl Ciphertext {
     pub fn new(ciphertext: Vec<u8>, nonce: Vec<u8>) -> Self {
-        Ciphertext { ciphertext: ciphertext, nonce: nonce }
+        Ciphertext { ciphertext, nonce }
     }
 
     pub fn get_ciphertext(&self) -> Vec<u8> {
@@ -53,29 +252,277 @@ impl Ciphertext {
     pub fn get_nonce(&self) -> Vec<u8> {
         self.nonce.clone()
     }
+
+    /// Returns the wire encoding of the ciphertext: `ciphertext (4 + len) || nonce (1 + len)`
+    /// 
+    /// The nonce is empty when the AEAD derives it from the message key *(AES-256-CBC + HMAC-SHA256)*, so it only costs its length byte.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ParseError> {
+        let mut bytes: Vec<u8> = Vec::with_capacity(5 + self.ciphertext.len() + self.nonce.len());
+        write_bytes(&mut bytes, &self.ciphertext)?;
+        bytes.push(self.nonce.len().try_into().map_err(|_| ParseError::FieldTooLong)?);
+        bytes.extend_from_slice(&self.nonce);
+        Ok(bytes)
+    }
+
+    /// Parse a ciphertext from its wire encoding
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ParseError> {
+        let mut reader: Reader = Reader::new(bytes);
+        let ciphertext: Vec<u8> = reader.read_bytes()?.to_vec();
+        let nonce_length: u8 = reader.read_u8()?;
+        let nonce: Vec<u8> = reader.take(nonce_length as usize)?.to_vec();
+        reader.finish()?;
+
+        Ok(Ciphertext { ciphertext, nonce })
+    }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Header {
     dh_pub: PublicKey,
-    pn: u8,
-    n: u8,
+    pn: u32,
+    n: u32,
 }
 
 impl Header {
-    pub fn new(dh_pub: PublicKey, pn: u8, n: u8) -> Self {
-        Header { dh_pub: dh_pub, pn: pn, n: n }
+    pub fn new(dh_pub: PublicKey, pn: u32, n: u32) -> Self {
+        Header { dh_pub, pn, n }
     }
 
     pub fn get_dh_pub(&self) -> PublicKey {
         self.dh_pub
     }
 
-    pub fn get_pn(&self) -> u8 {
+    pub fn get_pn(&self) -> u32 {
         self.pn
     }
 
-    pub fn get_n(&self) -> u8 {
+    pub fn get_n(&self) -> u32 {
         self.n
     }
-}
\ No newline at end of file
+
+    /// Returns the wire encoding of the header: `dh_pub (32) || pn (4) || n (4)`
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::with_capacity(40);
+        bytes.extend_from_slice(self.dh_pub.as_bytes());
+        bytes.extend_from_slice(&self.pn.to_be_bytes());
+        bytes.extend_from_slice(&self.n.to_be_bytes());
+        bytes
+    }
+
+    /// Parse a header from its wire encoding
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ParseError> {
+        let mut reader: Reader = Reader::new(bytes);
+        let dh_pub: PublicKey = PublicKey::from(reader.read_array::<32>()?);
+        let pn: u32 = u32::from_be_bytes(reader.read_array::<4>()?);
+        let n: u32 = u32::from_be_bytes(reader.read_array::<4>()?);
+        reader.finish()?;
+
+        Ok(Header { dh_pub, pn, n })
+    }
+}
+
+/// Append `data` to `bytes`, prefixed by its length as a big-endian `u32` *(`ParseError::FieldTooLong` from 4 GiB)*
+pub(crate) fn write_bytes(bytes: &mut Vec<u8>, data: &[u8]) -> Result<(), ParseError> {
+    let length: u32 = data.len().try_into().map_err(|_| ParseError::FieldTooLong)?;
+    bytes.extend_from_slice(&length.to_be_bytes());
+    bytes.extend_from_slice(data);
+    Ok(())
+}
+
+/// Append an optional public key to `bytes`, prefixed by a presence flag
+pub(crate) fn write_optional_key(bytes: &mut Vec<u8>, key: Option<PublicKey>) {
+    match key {
+        Some(key) => {
+            bytes.push(FLAG_PRESENT);
+            bytes.extend_from_slice(key.as_bytes());
+        },
+        None => bytes.push(FLAG_ABSENT),
+    }
+}
+
+/// Cursor over an encoded buffer
+pub(crate) struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> Self {
+        Reader { bytes, position: 0 }
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], ParseError> {
+        let end: usize = self.position.checked_add(length).ok_or(ParseError::UnexpectedEnd)?;
+        let data: &'a [u8] = self.bytes.get(self.position..end).ok_or(ParseError::UnexpectedEnd)?;
+        self.position = end;
+        Ok(data)
+    }
+
+    pub(crate) fn read_u8(&mut self) -> Result<u8, ParseError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn read_array<const N: usize>(&mut self) -> Result<[u8; N], ParseError> {
+        Ok(self.take(N)?.try_into().expect("Incorrect length"))
+    }
+
+    pub(crate) fn read_bytes(&mut self) -> Result<&'a [u8], ParseError> {
+        let length: u32 = u32::from_be_bytes(self.read_array::<4>()?);
+        self.take(length as usize)
+    }
+
+    /// Returns all the bytes left
+    pub(crate) fn read_remaining(&mut self) -> &'a [u8] {
+        let data: &'a [u8] = &self.bytes[self.position..];
+        self.position = self.bytes.len();
+        data
+    }
+
+    pub(crate) fn read_optional_key(&mut self) -> Result<Option<PublicKey>, ParseError> {
+        match self.read_u8()? {
+            FLAG_ABSENT => Ok(None),
+            FLAG_PRESENT => Ok(Some(PublicKey::from(self.read_array::<32>()?))),
+            flag => Err(ParseError::InvalidFlag(flag)),
+        }
+    }
+
+    /// Make sure the whole buffer has been consumed
+    pub(crate) fn finish(&self) -> Result<(), ParseError> {
+        if self.position != self.bytes.len() {
+            return Err(ParseError::TrailingBytes)
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::UnsupportedVersion(version) => write!(f, "Unsupported wire format version: {}", version),
+            ParseError::UnexpectedEnd => write!(f, "Unexpected end of the encoded message"),
+            ParseError::TrailingBytes => write!(f, "Unexpected bytes after the encoded message"),
+            ParseError::InvalidFlag(flag) => write!(f, "Invalid presence flag: {}", flag),
+            ParseError::InvalidUsername => write!(f, "Username is not valid UTF-8"),
+            ParseError::UnknownOperation(operation) => write!(f, "Unknown relay operation: {}", operation),
+            ParseError::UnknownContentType(content_type) => write!(f, "Unknown content type: {}", content_type),
+            ParseError::UnknownAead(aead) => write!(f, "Unknown AEAD: {}", aead),
+            ParseError::FieldTooLong => write!(f, "A field is too long for its length prefix"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::communication::group::{new_group_id, Group};
+    use crate::communication::sealed_sender::{seal, SenderCertificate};
+    use x25519_dalek::StaticSecret;
+    use x3dh::IdentityKey;
+
+    fn public_key(seed: u8) -> PublicKey {
+        PublicKey::from(&StaticSecret::from([seed; 32]))
+    }
+
+    fn message(ek_sender: Option<PublicKey>, spk_id: Option<u32>, opk_used: Option<PublicKey>, kem_ciphertext: Option<(u32, KemCiphertext)>) -> Message {
+        let header: Header = Header::new(public_key(1), 300, 70_000);
+        let ciphertext: Ciphertext = Ciphertext::new(vec![0xAA; 26], vec![0xBB; 12]);
+        Message::new(("Alice".to_string(), 2), (header, ciphertext), ek_sender, spk_id, opk_used, kem_ciphertext, None)
+    }
+
+    #[test]
+    fn test_message_round_trip() {
+        let first_message: Message = message(Some(public_key(2)), Some(7), Some(public_key(3)), Some((3, [0xEE; CIPHERTEXT_LENGTH])));
+        let message_without_opk: Message = message(Some(public_key(2)), Some(7), None, Some((3, [0xEE; CIPHERTEXT_LENGTH])));
+        let classical_first_message: Message = message(Some(public_key(2)), Some(7), Some(public_key(3)), None);
+        let next_message: Message = message(None, None, None, None);
+
+        let chacha_first_message: Message = Message { aead: Some(AeadAlgorithm::ChaCha20Poly1305), ..message(Some(public_key(2)), Some(7), Some(public_key(3)), Some((3, [0xEE; CIPHERTEXT_LENGTH]))) };
+
+        for expected_value in [first_message, message_without_opk, classical_first_message, next_message, chacha_first_message] {
+            assert_eq!(Message::from_bytes(&expected_value.to_bytes().unwrap()), Ok(expected_value));
+        }
+    }
+
+    #[test]
+    fn test_message_unknown_aead() {
+        let mut bytes: Vec<u8> = message(None, None, None, None).to_bytes().unwrap();
+        let last: usize = bytes.len() - 1;
+        bytes[last] = FLAG_PRESENT;
+        bytes.push(0x03);
+
+        assert_eq!(Message::from_bytes(&bytes), Err(ParseError::UnknownAead(0x03)));
+    }
+
+    #[test]
+    fn test_ciphertext_without_nonce() {
+        let ciphertext: Ciphertext = Ciphertext::new(vec![0xAA; 48], Vec::new());
+        let bytes: Vec<u8> = ciphertext.to_bytes().unwrap();
+
+        assert_eq!(bytes.len(), 4 + 48 + 1);
+        assert_eq!(Ciphertext::from_bytes(&bytes), Ok(ciphertext));
+        assert_eq!(Ciphertext::new(vec![0xAA; 48], vec![0xBB; 12]).to_bytes().unwrap().len(), 4 + 48 + 1 + 12);
+    }
+
+    #[test]
+    fn test_field_too_long() {
+        // The nonce length is a single byte
+        let ciphertext: Ciphertext = Ciphertext::new(vec![0xAA; 48], vec![0xBB; 256]);
+        assert_eq!(ciphertext.to_bytes(), Err(ParseError::FieldTooLong));
+        assert_eq!(Message::new(("Alice".to_string(), 2), (Header::new(public_key(1), 300, 70_000), ciphertext), None, None, None, None, None).to_bytes(), Err(ParseError::FieldTooLong));
+    }
+
+    #[test]
+    fn test_message_unsupported_version() {
+        let mut bytes: Vec<u8> = message(None, None, None, None).to_bytes().unwrap();
+        bytes[0] = WIRE_VERSION + 1;
+
+        assert_eq!(Message::from_bytes(&bytes), Err(ParseError::UnsupportedVersion(WIRE_VERSION + 1)));
+    }
+
+    #[test]
+    fn test_message_truncated_or_extended() {
+        let bytes: Vec<u8> = message(Some(public_key(2)), Some(7), Some(public_key(3)), Some((3, [0xEE; CIPHERTEXT_LENGTH]))).to_bytes().unwrap();
+        let mut extended_bytes: Vec<u8> = bytes.clone();
+        extended_bytes.push(0);
+
+        for length in 0..bytes.len() {
+            assert_eq!(Message::from_bytes(&bytes[..length]), Err(ParseError::UnexpectedEnd));
+        }
+        assert_eq!(Message::from_bytes(&extended_bytes), Err(ParseError::TrailingBytes));
+    }
+
+    #[test]
+    fn test_message_invalid_flag() {
+        let mut bytes: Vec<u8> = message(None, None, None, None).to_bytes().unwrap();
+        let last: usize = bytes.len() - 1;
+        bytes[last] = 0x02;
+
+        assert_eq!(Message::from_bytes(&bytes), Err(ParseError::InvalidFlag(0x02)));
+    }
+
+    #[test]
+    fn test_envelope_round_trip() {
+        let ik_alice: IdentityKey = IdentityKey::new();
+        let certificate: SenderCertificate = SenderCertificate::issue(&IdentityKey::new(), "Alice".to_string(), 2, ik_alice.get_public_key(), 0).unwrap();
+        let sealed_message: SealedMessage = seal(&ik_alice, &public_key(4), &certificate, &message(None, None, None, None)).unwrap();
+        let mut group: Group = Group::new(new_group_id(), vec!["Alice".to_string(), "Bob".to_string()], vec!["Alice".to_string()]);
+        group.rotate_sender_key();
+        let group_message: GroupMessage = group.encrypt(&"Alice".to_string(), 2, b"group").unwrap();
+
+        for expected_value in [Envelope::Plain(Box::new(message(None, None, None, None))), Envelope::Sealed(sealed_message), Envelope::Group(group_message)] {
+            assert_eq!(Envelope::from_bytes(&expected_value.to_bytes().unwrap()), Ok(expected_value));
+        }
+    }
+
+    #[test]
+    fn test_content_round_trip() {
+        let group_id: GroupId = new_group_id();
+        let distribution: SenderKeyDistribution = Group::new(group_id, vec!["Alice".to_string()], vec!["Alice".to_string()]).rotate_sender_key();
+
+        for expected_value in [Content::Text(b"text".to_vec()), Content::Text(Vec::new()), Content::SenderKey(distribution), Content::GroupLeave(group_id)] {
+            assert_eq!(Content::from_bytes(&expected_value.to_bytes().unwrap()), Ok(expected_value));
+        }
+        assert_eq!(Content::from_bytes(&[0x03]), Err(ParseError::UnknownContentType(0x03)));
+        assert_eq!(Content::from_bytes(&[CONTENT_GROUP_LEAVE, 0x00]), Err(ParseError::UnexpectedEnd));
+    }
+}
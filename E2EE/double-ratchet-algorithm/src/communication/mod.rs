@@ -1,4 +1,11 @@
 pub mod client;
 pub mod server;
 pub mod key_collection;
-pub mod message;
\ No newline at end of file
+pub mod mailbox;
+pub mod group;
+pub mod identity_store;
+pub mod message;
+pub mod relay;
+pub mod safety_number;
+pub mod sealed_sender;
+pub mod transport;
\ No newline at end of file
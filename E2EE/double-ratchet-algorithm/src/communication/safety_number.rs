@@ -0,0 +1,156 @@
+//! Safety numbers *(based on Signal: https://signal.org/blog/safety-number-updates/)*
+//!
+//! Two users compare a number derived from both identity keys and names to make sure the relay gave them the genuine keys.
+//!
+//! - Fingerprint of a user: `SHA-512` iterated `FINGERPRINT_ITERATIONS` times over `version || ik || username`, then `ik` at each iteration
+//! - Displayable fingerprint: 30 digits *(6 chunks of 5 bytes, each one `mod 100000`)*
+//! - Safety number: the two displayable fingerprints, the lowest one first *(the same for both users)*
+//! - Scannable payload *(QR code)*: `version (1) || local fingerprint (32) || remote fingerprint (32)`
+
+use sha2::{Digest, Sha512};
+use x25519_dalek::PublicKey;
+
+use super::message::{ParseError, Reader};
+
+pub const FINGERPRINT_ITERATIONS: u32 = 5200;
+pub const SCANNABLE_VERSION: u8 = 0x01;
+const FINGERPRINT_VERSION: [u8; 2] = [0x00, 0x00];
+const FINGERPRINT_LENGTH: usize = 32;
+
+/// Returns the fingerprint of a user
+///
+/// # Arguments
+///
+/// * `username` (&String): Name of the user
+/// * `ik` (&PublicKey): Public identity key of the user
+///
+/// # Output
+///
+/// * `fingerprint` (\[u8; 32\]): Fingerprint of the user
+pub fn fingerprint(username: &String, ik: &PublicKey) -> [u8; FINGERPRINT_LENGTH] {
+    let mut hash: Vec<u8> = [FINGERPRINT_VERSION.as_slice(), ik.as_bytes(), username.as_bytes()].concat();
+    for _ in 0..FINGERPRINT_ITERATIONS {
+        let mut hasher = Sha512::new();
+        hasher.update(&hash);
+        hasher.update(ik.as_bytes());
+        hash = hasher.finalize().to_vec();
+    }
+    hash[..FINGERPRINT_LENGTH].try_into().expect("Incorrect length")
+}
+
+/// Returns the 30 digits of a fingerprint
+pub fn displayable_fingerprint(fingerprint: &[u8; FINGERPRINT_LENGTH]) -> String {
+    fingerprint[..30].chunks(5)
+        .map(|chunk| {
+            let value: u64 = chunk.iter().fold(0, |value, byte| (value << 8) | *byte as u64);
+            format!("{:05}", value % 100_000)
+        })
+        .collect()
+}
+
+/// Returns the safety number of two users *(60 digits, the same for both of them)*
+///
+/// # Arguments
+///
+/// * `local_name` (&String): Name of the user
+/// * `local_ik` (&PublicKey): Public identity key of the user
+/// * `remote_name` (&String): Name of the contact
+/// * `remote_ik` (&PublicKey): Public identity key of the contact
+///
+/// # Output
+///
+/// * `safety_number` (String): Safety number
+pub fn safety_number(local_name: &String, local_ik: &PublicKey, remote_name: &String, remote_ik: &PublicKey) -> String {
+    let local: String = displayable_fingerprint(&fingerprint(local_name, local_ik));
+    let remote: String = displayable_fingerprint(&fingerprint(remote_name, remote_ik));
+    if local <= remote {
+        local + &remote
+    } else {
+        remote + &local
+    }
+}
+
+/// Returns the payload of the QR code shown to the contact
+pub fn scannable_payload(local_name: &String, local_ik: &PublicKey, remote_name: &String, remote_ik: &PublicKey) -> Vec<u8> {
+    let mut payload: Vec<u8> = vec![SCANNABLE_VERSION];
+    payload.extend_from_slice(&fingerprint(local_name, local_ik));
+    payload.extend_from_slice(&fingerprint(remote_name, remote_ik));
+    payload
+}
+
+/// Compare the payload scanned from the QR code of the contact with the keys known by the user
+///
+/// # Arguments
+///
+/// * `payload` (&\[u8\]): Payload scanned *(made by the contact, so its local fingerprint is the one of the contact)*
+/// * `local_name` (&String): Name of the user
+/// * `local_ik` (&PublicKey): Public identity key of the user
+/// * `remote_name` (&String): Name of the contact
+/// * `remote_ik` (&PublicKey): Public identity key of the contact known by the user
+///
+/// # Output
+///
+/// * `matching` (Result\<bool, ParseError\>): True if both users have the same keys
+pub fn verify_scannable_payload(payload: &[u8], local_name: &String, local_ik: &PublicKey, remote_name: &String, remote_ik: &PublicKey) -> Result<bool, ParseError> {
+    let mut reader: Reader = Reader::new(payload);
+    let version: u8 = reader.read_u8()?;
+    if version != SCANNABLE_VERSION {
+        return Err(ParseError::UnsupportedVersion(version))
+    }
+    let scanned_remote: [u8; FINGERPRINT_LENGTH] = reader.read_array::<FINGERPRINT_LENGTH>()?;
+    let scanned_local: [u8; FINGERPRINT_LENGTH] = reader.read_array::<FINGERPRINT_LENGTH>()?;
+    reader.finish()?;
+
+    Ok(scanned_remote == fingerprint(remote_name, remote_ik) && scanned_local == fingerprint(local_name, local_ik))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x25519_dalek::StaticSecret;
+
+    fn public_key(seed: u8) -> PublicKey {
+        PublicKey::from(&StaticSecret::from([seed; 32]))
+    }
+
+    #[test]
+    fn test_safety_number() {
+        let alice_name: String = "Alice".to_string();
+        let bob_name: String = "Bob".to_string();
+        let number: String = safety_number(&alice_name, &public_key(1), &bob_name, &public_key(2));
+
+        assert_eq!(number.len(), 60);
+        assert!(number.chars().all(|digit| digit.is_ascii_digit()));
+        assert_eq!(safety_number(&bob_name, &public_key(2), &alice_name, &public_key(1)), number);
+        assert_ne!(safety_number(&alice_name, &public_key(1), &bob_name, &public_key(3)), number);
+        assert_ne!(safety_number(&alice_name, &public_key(1), &"Mallory".to_string(), &public_key(2)), number);
+    }
+
+    #[test]
+    fn test_displayable_fingerprint() {
+        let mut fingerprint: [u8; 32] = [0u8; 32];
+        fingerprint[4] = 0x01;
+        fingerprint[5..10].copy_from_slice(&[0xFF; 5]);
+        // 0xFFFFFFFFFF = 1099511627775
+        assert_eq!(displayable_fingerprint(&fingerprint), "000012777500000000000000000000");
+    }
+
+    #[test]
+    fn test_scannable_payload() {
+        let alice_name: String = "Alice".to_string();
+        let bob_name: String = "Bob".to_string();
+        let payload: Vec<u8> = scannable_payload(&bob_name, &public_key(2), &alice_name, &public_key(1));
+        assert_eq!(payload.len(), 65);
+
+        assert_eq!(verify_scannable_payload(&payload, &alice_name, &public_key(1), &bob_name, &public_key(2)), Ok(true));
+        // The relay gave Alice another key for Bob
+        assert_eq!(verify_scannable_payload(&payload, &alice_name, &public_key(1), &bob_name, &public_key(3)), Ok(false));
+        // Alice scans her own code
+        assert_eq!(verify_scannable_payload(&scannable_payload(&alice_name, &public_key(1), &bob_name, &public_key(2)), &alice_name, &public_key(1), &bob_name, &public_key(2)), Ok(false));
+
+        assert_eq!(verify_scannable_payload(&payload[..64], &alice_name, &public_key(1), &bob_name, &public_key(2)), Err(ParseError::UnexpectedEnd));
+        let mut payload: Vec<u8> = payload;
+        payload[0] = 0x02;
+        assert_eq!(verify_scannable_payload(&payload, &alice_name, &public_key(1), &bob_name, &public_key(2)), Err(ParseError::UnsupportedVersion(0x02)));
+    }
+}
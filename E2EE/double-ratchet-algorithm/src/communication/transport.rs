@@ -0,0 +1,902 @@
+//! Relay daemon and client transport
+//!
+//! The relay keeps a `Server` (prekey bundles and queued messages of every user) and serves it over a TCP or Unix socket,
+//! `RemoteServer` gives the clients the same operations over a connection to the relay.
+//!
+//! Every request and response is sent as a frame `length (4) || body`, the length being a big-endian `u32`.
+//! - Request body: `operation (1) || arguments`
+//! - Response body: `status (1) || result` *(the result is only present with `STATUS_OK`)*
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::thread;
+use std::time::Duration;
+use x25519_dalek::PublicKey;
+use x3dh::Signature;
+use x3dh::mlkem::{EncapsulationKey, ENCAPSULATION_KEY_LENGTH};
+
+use super::key_collection::ServerKeyCollection;
+use super::message::{write_bytes, Envelope, ParseError, Reader};
+use super::sealed_sender::SenderCertificate;
+use super::relay::{Relay, RelayError};
+use super::server::{Challenge, DeviceId, Server, ServerError, SessionToken};
+
+const MAX_FRAME_LENGTH: usize = 16 * 1024 * 1024;
+const MAX_CONNECTIONS: usize = 256; // Connections served at the same time, the next ones are closed right away
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(60); // A client that sends or reads nothing for this long is disconnected
+
+const OP_ADD_USER: u8 = 0x01;
+const OP_GET_USER_KEYS: u8 = 0x02;
+const OP_FETCH_PREKEY_BUNDLE: u8 = 0x03;
+const OP_ADD_MESSAGE_TO: u8 = 0x04;
+const OP_GET_USER_MESSAGES: u8 = 0x05;
+const OP_GET_USERS: u8 = 0x06;
+const OP_UPDATE_USER_SPK: u8 = 0x07;
+const OP_ADD_USER_OPKS: u8 = 0x08;
+const OP_GET_OPK_COUNT: u8 = 0x09;
+const OP_ACKNOWLEDGE_MESSAGES: u8 = 0x0A;
+const OP_GET_CHALLENGE: u8 = 0x0B;
+const OP_LOGIN: u8 = 0x0C;
+const OP_REPLACE_USER_KEYS: u8 = 0x0D;
+const OP_GET_CERTIFICATE_KEY: u8 = 0x0E;
+const OP_GET_SENDER_CERTIFICATE: u8 = 0x0F;
+const OP_GET_DEVICES: u8 = 0x10;
+const OP_ADD_DEVICE: u8 = 0x11;
+const OP_REMOVE_DEVICE: u8 = 0x12;
+const OP_UPDATE_USER_PQSPK: u8 = 0x13;
+
+const STATUS_OK: u8 = 0x00;
+const STATUS_USER_DOES_NOT_EXIST: u8 = 0x01;
+const STATUS_MALFORMED_REQUEST: u8 = 0x02;
+const STATUS_STORAGE_FAILURE: u8 = 0x03;
+const STATUS_USER_ALREADY_EXISTS: u8 = 0x04;
+const STATUS_NOT_AUTHENTICATED: u8 = 0x05;
+const STATUS_DEVICE_DOES_NOT_EXIST: u8 = 0x06;
+const STATUS_PRIMARY_DEVICE_REMOVAL: u8 = 0x07;
+const STATUS_DEVICE_IDS_EXHAUSTED: u8 = 0x08;
+
+#[derive(Debug)]
+pub enum TransportError {
+    Io(io::Error),
+    Parse(ParseError),
+    Server(ServerError),
+    MalformedRequest,
+    InvalidStatus(u8),
+    ConnectionClosed,
+}
+
+/// Operations of the relay with their arguments
+#[derive(Debug, PartialEq)]
+enum Request {
+    AddUser(String, ServerKeyCollection),
+    GetUserKeys(String, DeviceId),
+    FetchPrekeyBundle(String, DeviceId),
+    AddMessageTo(String, DeviceId, Envelope),
+    GetUserMessages(String, SessionToken),
+    GetUsers(String),
+    UpdateUserSpk(String, SessionToken, u32, PublicKey, Signature),
+    AddUserOpks(String, SessionToken, Vec<(u32, PublicKey)>),
+    GetOpkCount(String, DeviceId),
+    AcknowledgeMessages(String, SessionToken, Vec<u64>),
+    GetChallenge(String, DeviceId),
+    Login(String, DeviceId, Signature),
+    ReplaceUserKeys(String, SessionToken, ServerKeyCollection),
+    GetCertificateKey,
+    GetSenderCertificate(String, SessionToken),
+    GetDevices(String),
+    AddDevice(String, SessionToken, ServerKeyCollection),
+    RemoveDevice(String, SessionToken, DeviceId),
+    UpdateUserPqspk(String, SessionToken, u32, EncapsulationKey, Signature),
+}
+
+impl Request {
+    /// Returns the body of the request frame: `operation (1) || username (4 + len) || arguments`
+    /// 
+    /// The session token *(32)* is the first argument of the requests that need a session, the device id *(4)* the first argument of the requests addressed to a device.
+    fn to_bytes(&self) -> Result<Vec<u8>, ParseError> {
+        let mut bytes: Vec<u8> = Vec::new();
+        match self {
+            Request::AddUser(username, keys) => {
+                bytes.push(OP_ADD_USER);
+                write_bytes(&mut bytes, username.as_bytes())?;
+                bytes.extend_from_slice(&keys.to_bytes());
+            },
+            Request::GetUserKeys(username, device_id) => {
+                bytes.push(OP_GET_USER_KEYS);
+                write_bytes(&mut bytes, username.as_bytes())?;
+                bytes.extend_from_slice(&device_id.to_be_bytes());
+            },
+            Request::FetchPrekeyBundle(username, device_id) => {
+                bytes.push(OP_FETCH_PREKEY_BUNDLE);
+                write_bytes(&mut bytes, username.as_bytes())?;
+                bytes.extend_from_slice(&device_id.to_be_bytes());
+            },
+            Request::AddMessageTo(username, device_id, message) => {
+                bytes.push(OP_ADD_MESSAGE_TO);
+                write_bytes(&mut bytes, username.as_bytes())?;
+                bytes.extend_from_slice(&device_id.to_be_bytes());
+                bytes.extend_from_slice(&message.to_bytes()?);
+            },
+            Request::GetUserMessages(username, session) => {
+                bytes.push(OP_GET_USER_MESSAGES);
+                write_bytes(&mut bytes, username.as_bytes())?;
+                bytes.extend_from_slice(session);
+            },
+            Request::GetUsers(requester_username) => {
+                bytes.push(OP_GET_USERS);
+                write_bytes(&mut bytes, requester_username.as_bytes())?;
+            },
+            Request::UpdateUserSpk(username, session, spk_id, spk, signature) => {
+                bytes.push(OP_UPDATE_USER_SPK);
+                write_bytes(&mut bytes, username.as_bytes())?;
+                bytes.extend_from_slice(session);
+                bytes.extend_from_slice(&spk_id.to_be_bytes());
+                bytes.extend_from_slice(spk.as_bytes());
+                bytes.extend_from_slice(signature);
+            },
+            Request::UpdateUserPqspk(username, session, pqspk_id, pqspk, pq_signature) => {
+                bytes.push(OP_UPDATE_USER_PQSPK);
+                write_bytes(&mut bytes, username.as_bytes())?;
+                bytes.extend_from_slice(session);
+                bytes.extend_from_slice(&pqspk_id.to_be_bytes());
+                bytes.extend_from_slice(pqspk);
+                bytes.extend_from_slice(pq_signature);
+            },
+            Request::AddUserOpks(username, session, opks) => {
+                bytes.push(OP_ADD_USER_OPKS);
+                write_bytes(&mut bytes, username.as_bytes())?;
+                bytes.extend_from_slice(session);
+                write_opks(&mut bytes, opks);
+            },
+            Request::GetOpkCount(username, device_id) => {
+                bytes.push(OP_GET_OPK_COUNT);
+                write_bytes(&mut bytes, username.as_bytes())?;
+                bytes.extend_from_slice(&device_id.to_be_bytes());
+            },
+            Request::AcknowledgeMessages(username, session, ids) => {
+                bytes.push(OP_ACKNOWLEDGE_MESSAGES);
+                write_bytes(&mut bytes, username.as_bytes())?;
+                bytes.extend_from_slice(session);
+                bytes.extend_from_slice(&(ids.len() as u32).to_be_bytes());
+                for id in ids {
+                    bytes.extend_from_slice(&id.to_be_bytes());
+                }
+            },
+            Request::GetChallenge(username, device_id) => {
+                bytes.push(OP_GET_CHALLENGE);
+                write_bytes(&mut bytes, username.as_bytes())?;
+                bytes.extend_from_slice(&device_id.to_be_bytes());
+            },
+            Request::Login(username, device_id, signature) => {
+                bytes.push(OP_LOGIN);
+                write_bytes(&mut bytes, username.as_bytes())?;
+                bytes.extend_from_slice(&device_id.to_be_bytes());
+                bytes.extend_from_slice(signature);
+            },
+            Request::ReplaceUserKeys(username, session, keys) => {
+                bytes.push(OP_REPLACE_USER_KEYS);
+                write_bytes(&mut bytes, username.as_bytes())?;
+                bytes.extend_from_slice(session);
+                bytes.extend_from_slice(&keys.to_bytes());
+            },
+            Request::GetCertificateKey => {
+                bytes.push(OP_GET_CERTIFICATE_KEY);
+                write_bytes(&mut bytes, &[])?; // No username
+            },
+            Request::GetSenderCertificate(username, session) => {
+                bytes.push(OP_GET_SENDER_CERTIFICATE);
+                write_bytes(&mut bytes, username.as_bytes())?;
+                bytes.extend_from_slice(session);
+            },
+            Request::GetDevices(username) => {
+                bytes.push(OP_GET_DEVICES);
+                write_bytes(&mut bytes, username.as_bytes())?;
+            },
+            Request::AddDevice(username, session, keys) => {
+                bytes.push(OP_ADD_DEVICE);
+                write_bytes(&mut bytes, username.as_bytes())?;
+                bytes.extend_from_slice(session);
+                bytes.extend_from_slice(&keys.to_bytes());
+            },
+            Request::RemoveDevice(username, session, device_id) => {
+                bytes.push(OP_REMOVE_DEVICE);
+                write_bytes(&mut bytes, username.as_bytes())?;
+                bytes.extend_from_slice(session);
+                bytes.extend_from_slice(&device_id.to_be_bytes());
+            },
+        }
+        Ok(bytes)
+    }
+
+    /// Parse a request from the body of its frame
+    fn from_bytes(bytes: &[u8]) -> Result<Self, ParseError> {
+        let mut reader: Reader = Reader::new(bytes);
+        let operation: u8 = reader.read_u8()?;
+        let username: String = read_string(&mut reader)?;
+        let request: Request = match operation {
+            OP_ADD_USER => Request::AddUser(username, ServerKeyCollection::from_bytes(reader.read_remaining())?),
+            OP_GET_USER_KEYS => Request::GetUserKeys(username, read_device_id(&mut reader)?),
+            OP_FETCH_PREKEY_BUNDLE => Request::FetchPrekeyBundle(username, read_device_id(&mut reader)?),
+            OP_ADD_MESSAGE_TO => {
+                let device_id: DeviceId = read_device_id(&mut reader)?;
+                Request::AddMessageTo(username, device_id, Envelope::from_bytes(reader.read_remaining())?)
+            },
+            OP_GET_USER_MESSAGES => Request::GetUserMessages(username, reader.read_array::<32>()?),
+            OP_GET_USERS => Request::GetUsers(username),
+            OP_UPDATE_USER_SPK => {
+                let session: SessionToken = reader.read_array::<32>()?;
+                let spk_id: u32 = u32::from_be_bytes(reader.read_array::<4>()?);
+                let spk: PublicKey = PublicKey::from(reader.read_array::<32>()?);
+                let signature: Signature = reader.read_array::<64>()?;
+                Request::UpdateUserSpk(username, session, spk_id, spk, signature)
+            },
+            OP_UPDATE_USER_PQSPK => {
+                let session: SessionToken = reader.read_array::<32>()?;
+                let pqspk_id: u32 = u32::from_be_bytes(reader.read_array::<4>()?);
+                let pqspk: EncapsulationKey = reader.read_array::<ENCAPSULATION_KEY_LENGTH>()?;
+                let pq_signature: Signature = reader.read_array::<64>()?;
+                Request::UpdateUserPqspk(username, session, pqspk_id, pqspk, pq_signature)
+            },
+            OP_ADD_USER_OPKS => {
+                let session: SessionToken = reader.read_array::<32>()?;
+                Request::AddUserOpks(username, session, read_opks(&mut reader)?)
+            },
+            OP_GET_OPK_COUNT => Request::GetOpkCount(username, read_device_id(&mut reader)?),
+            OP_ACKNOWLEDGE_MESSAGES => {
+                let session: SessionToken = reader.read_array::<32>()?;
+                let count: u32 = u32::from_be_bytes(reader.read_array::<4>()?);
+                let mut ids: Vec<u64> = Vec::new();
+                for _ in 0..count {
+                    ids.push(u64::from_be_bytes(reader.read_array::<8>()?));
+                }
+                Request::AcknowledgeMessages(username, session, ids)
+            },
+            OP_GET_CHALLENGE => Request::GetChallenge(username, read_device_id(&mut reader)?),
+            OP_LOGIN => {
+                let device_id: DeviceId = read_device_id(&mut reader)?;
+                Request::Login(username, device_id, reader.read_array::<64>()?)
+            },
+            OP_REPLACE_USER_KEYS => {
+                let session: SessionToken = reader.read_array::<32>()?;
+                Request::ReplaceUserKeys(username, session, ServerKeyCollection::from_bytes(reader.read_remaining())?)
+            },
+            OP_GET_CERTIFICATE_KEY if username.is_empty() => Request::GetCertificateKey,
+            OP_GET_SENDER_CERTIFICATE => Request::GetSenderCertificate(username, reader.read_array::<32>()?),
+            OP_GET_DEVICES => Request::GetDevices(username),
+            OP_ADD_DEVICE => {
+                let session: SessionToken = reader.read_array::<32>()?;
+                Request::AddDevice(username, session, ServerKeyCollection::from_bytes(reader.read_remaining())?)
+            },
+            OP_REMOVE_DEVICE => {
+                let session: SessionToken = reader.read_array::<32>()?;
+                Request::RemoveDevice(username, session, read_device_id(&mut reader)?)
+            },
+            operation => return Err(ParseError::UnknownOperation(operation)),
+        };
+        reader.finish()?;
+
+        Ok(request)
+    }
+}
+
+/// Serve a `Server` on a TCP socket, each connection is handled in its own thread
+///
+/// At most `MAX_CONNECTIONS` clients are served at the same time, and a client idle or stalled for `CONNECTION_TIMEOUT` is disconnected.
+///
+/// # Arguments
+///
+/// * `listener` (TcpListener): Bound socket of the relay
+/// * `server` (Arc\<RwLock\<Server\>\>): Users and messages shared by all the connections
+///
+/// # Output
+///
+/// * `result` (io::Result\<()\>): Only returns if the listener fails
+pub fn serve_tcp(listener: TcpListener, server: Arc<RwLock<Server>>) -> io::Result<()> {
+    let connections: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let stream: TcpStream = stream?;
+        if stream.set_read_timeout(Some(CONNECTION_TIMEOUT)).and_then(|_| stream.set_write_timeout(Some(CONNECTION_TIMEOUT))).is_ok() {
+            spawn_connection(stream, &server, &connections);
+        }
+    }
+    Ok(())
+}
+
+/// Serve a `Server` on a Unix socket, each connection is handled in its own thread *(see `serve_tcp`)*
+#[cfg(unix)]
+pub fn serve_unix(listener: UnixListener, server: Arc<RwLock<Server>>) -> io::Result<()> {
+    let connections: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let stream: UnixStream = stream?;
+        if stream.set_read_timeout(Some(CONNECTION_TIMEOUT)).and_then(|_| stream.set_write_timeout(Some(CONNECTION_TIMEOUT))).is_ok() {
+            spawn_connection(stream, &server, &connections);
+        }
+    }
+    Ok(())
+}
+
+/// Handle a connection in its own thread, or close it if `MAX_CONNECTIONS` are already served
+fn spawn_connection<S: Read + Write + Send + 'static>(stream: S, server: &Arc<RwLock<Server>>, connections: &Arc<AtomicUsize>) {
+    let Some(slot) = ConnectionSlot::acquire(connections) else {
+        return
+    };
+    let server: Arc<RwLock<Server>> = Arc::clone(server);
+    // A broken connection only affects its own client
+    thread::spawn(move || {
+        let _slot: ConnectionSlot = slot;
+        handle_connection(stream, &server)
+    });
+}
+
+/// Place of a connection in the `MAX_CONNECTIONS` served, freed when it's dropped
+struct ConnectionSlot {
+    connections: Arc<AtomicUsize>,
+}
+
+impl ConnectionSlot {
+    fn acquire(connections: &Arc<AtomicUsize>) -> Option<Self> {
+        connections.fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| (count < MAX_CONNECTIONS).then_some(count + 1)).ok()?;
+        Some(ConnectionSlot { connections: Arc::clone(connections) })
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.connections.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Answer the requests of a client until it closes the connection, sends a malformed request or times out
+fn handle_connection<S: Read + Write>(mut stream: S, server: &RwLock<Server>) -> io::Result<()> {
+    while let Some(body) = read_frame(&mut stream)? {
+        let request: Request = match Request::from_bytes(&body) {
+            Ok(request) => request,
+            Err(_) => return write_frame(&mut stream, &[STATUS_MALFORMED_REQUEST]),
+        };
+        let response: Vec<u8> = match handle_request(server, request) {
+            Ok(result) => [&[STATUS_OK], result.as_slice()].concat(),
+            Err(ServerError::UserDoesNotExist) => vec![STATUS_USER_DOES_NOT_EXIST],
+            Err(ServerError::UserAlreadyExists) => vec![STATUS_USER_ALREADY_EXISTS],
+            Err(ServerError::DeviceDoesNotExist) => vec![STATUS_DEVICE_DOES_NOT_EXIST],
+            Err(ServerError::PrimaryDeviceRemoval) => vec![STATUS_PRIMARY_DEVICE_REMOVAL],
+            Err(ServerError::DeviceIdsExhausted) => vec![STATUS_DEVICE_IDS_EXHAUSTED],
+            Err(ServerError::NotAuthenticated) => vec![STATUS_NOT_AUTHENTICATED],
+            Err(ServerError::Storage(_)) => vec![STATUS_STORAGE_FAILURE],
+        };
+        write_frame(&mut stream, &response)?;
+    }
+    Ok(())
+}
+
+/// Apply a request to the server and returns the encoded result
+///
+/// The lock is only held during the operation: the read-only ones share it, the others take it alone.
+fn handle_request(server: &RwLock<Server>, request: Request) -> Result<Vec<u8>, ServerError> {
+    let mut result: Vec<u8> = Vec::new();
+    match request {
+        Request::AddUser(username, keys) => write(server).add_user(username, keys)?,
+        Request::GetUserKeys(username, device_id) => result = read(server).get_user_keys(&username, device_id)?.to_bytes(),
+        Request::FetchPrekeyBundle(username, device_id) => result = write(server).fetch_prekey_bundle(&username, device_id)?.to_bytes(),
+        Request::AddMessageTo(username, device_id, message) => write(server).add_message_to(&username, device_id, message)?,
+        Request::GetUserMessages(username, session) => {
+            let messages: Vec<(u64, Envelope)> = read(server).get_user_messages(&username, &session)?;
+            result.extend_from_slice(&(messages.len() as u32).to_be_bytes());
+            for (id, message) in messages {
+                result.extend_from_slice(&id.to_be_bytes());
+                write_bytes(&mut result, &message.to_bytes().map_err(encoding_failure)?).map_err(encoding_failure)?;
+            }
+        },
+        Request::GetUsers(requester_username) => {
+            let users: Vec<String> = read(server).get_users(requester_username);
+            result.extend_from_slice(&(users.len() as u32).to_be_bytes());
+            for username in users {
+                write_bytes(&mut result, username.as_bytes()).map_err(encoding_failure)?;
+            }
+        },
+        Request::UpdateUserSpk(username, session, spk_id, spk, signature) => write(server).update_user_spk(&username, &session, spk_id, spk, signature)?,
+        Request::UpdateUserPqspk(username, session, pqspk_id, pqspk, pq_signature) => write(server).update_user_pqspk(&username, &session, pqspk_id, pqspk, pq_signature)?,
+        Request::AddUserOpks(username, session, opks) => write(server).add_user_opks(&username, &session, opks)?,
+        Request::GetOpkCount(username, device_id) => result.extend_from_slice(&(read(server).get_opk_count(&username, device_id)? as u32).to_be_bytes()),
+        Request::AcknowledgeMessages(username, session, ids) => write(server).acknowledge_messages(&username, &session, &ids)?,
+        Request::GetChallenge(username, device_id) => result.extend_from_slice(&write(server).create_challenge(&username, device_id)?),
+        Request::Login(username, device_id, signature) => result.extend_from_slice(&write(server).login(&username, device_id, signature)?),
+        Request::ReplaceUserKeys(username, session, keys) => write(server).replace_user_keys(&username, &session, keys)?,
+        Request::GetCertificateKey => result.extend_from_slice(read(server).get_certificate_key().as_bytes()),
+        Request::GetSenderCertificate(username, session) => result = read(server).issue_sender_certificate(&username, &session)?.to_bytes().map_err(encoding_failure)?,
+        Request::GetDevices(username) => {
+            let devices: Vec<DeviceId> = read(server).get_devices(&username)?;
+            result.extend_from_slice(&(devices.len() as u32).to_be_bytes());
+            for device_id in devices {
+                result.extend_from_slice(&device_id.to_be_bytes());
+            }
+        },
+        Request::AddDevice(username, session, keys) => result.extend_from_slice(&write(server).add_device(&username, &session, keys)?.to_be_bytes()),
+        Request::RemoveDevice(username, session, device_id) => write(server).remove_device(&username, &session, device_id)?,
+    }
+    Ok(result)
+}
+
+/// Shared lock of the server, for the operations that only read it
+fn read(server: &RwLock<Server>) -> RwLockReadGuard<'_, Server> {
+    server.read().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Exclusive lock of the server, for the operations that modify it
+fn write(server: &RwLock<Server>) -> RwLockWriteGuard<'_, Server> {
+    server.write().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Connection of a client to the relay
+enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buffer),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.read(buffer),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buffer),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write(buffer),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Client side of the relay, with the same operations as `Server`
+///
+/// The relay closes the connections idle for `CONNECTION_TIMEOUT` *(`TransportError::ConnectionClosed` or `TransportError::Io`)*, a long-lived client connects again.
+pub struct RemoteServer {
+    stream: Stream,
+}
+
+impl RemoteServer {
+    /// Connect to a relay listening on a TCP socket
+    pub fn connect_tcp<A: ToSocketAddrs>(address: A) -> Result<Self, TransportError> {
+        Ok(RemoteServer { stream: Stream::Tcp(TcpStream::connect(address)?) })
+    }
+
+    /// Connect to a relay listening on a Unix socket
+    #[cfg(unix)]
+    pub fn connect_unix<P: AsRef<Path>>(path: P) -> Result<Self, TransportError> {
+        Ok(RemoteServer { stream: Stream::Unix(UnixStream::connect(path)?) })
+    }
+
+    pub fn add_user(&mut self, username: String, keys: ServerKeyCollection) -> Result<(), TransportError> {
+        Reader::new(&self.call(Request::AddUser(username, keys))?).finish()?;
+        Ok(())
+    }
+
+    /// Link a new device to the user *(requires a session of one of its devices)*
+    pub fn add_device(&mut self, username: &str, session: &SessionToken, keys: ServerKeyCollection) -> Result<DeviceId, TransportError> {
+        let result: Vec<u8> = self.call(Request::AddDevice(username.to_string(), *session, keys))?;
+        let mut reader: Reader = Reader::new(&result);
+        let device_id: DeviceId = read_device_id(&mut reader)?;
+        reader.finish()?;
+        Ok(device_id)
+    }
+
+    /// Remove a linked device of the user *(requires a session of one of its devices)*
+    pub fn remove_device(&mut self, username: &str, session: &SessionToken, device_id: DeviceId) -> Result<(), TransportError> {
+        Reader::new(&self.call(Request::RemoveDevice(username.to_string(), *session, device_id))?).finish()?;
+        Ok(())
+    }
+
+    /// Returns the devices of a user
+    pub fn get_devices(&mut self, username: &str) -> Result<Vec<DeviceId>, TransportError> {
+        let result: Vec<u8> = self.call(Request::GetDevices(username.to_string()))?;
+        let mut reader: Reader = Reader::new(&result);
+        let count: u32 = u32::from_be_bytes(reader.read_array::<4>()?);
+        let mut devices: Vec<DeviceId> = Vec::new();
+        for _ in 0..count {
+            devices.push(read_device_id(&mut reader)?);
+        }
+        reader.finish()?;
+        Ok(devices)
+    }
+
+    /// Replace all the keys of the device that opened the session
+    pub fn replace_user_keys(&mut self, username: &str, session: &SessionToken, keys: ServerKeyCollection) -> Result<(), TransportError> {
+        Reader::new(&self.call(Request::ReplaceUserKeys(username.to_string(), *session, keys))?).finish()?;
+        Ok(())
+    }
+
+    /// Returns a new challenge to sign with the identity key of the device *(see `Server::login`)*
+    pub fn create_challenge(&mut self, username: &str, device_id: DeviceId) -> Result<Challenge, TransportError> {
+        let result: Vec<u8> = self.call(Request::GetChallenge(username.to_string(), device_id))?;
+        let mut reader: Reader = Reader::new(&result);
+        let challenge: Challenge = reader.read_array::<32>()?;
+        reader.finish()?;
+        Ok(challenge)
+    }
+
+    /// Open an authenticated session with the signature of the last challenge
+    pub fn login(&mut self, username: &str, device_id: DeviceId, signature: Signature) -> Result<SessionToken, TransportError> {
+        let result: Vec<u8> = self.call(Request::Login(username.to_string(), device_id, signature))?;
+        let mut reader: Reader = Reader::new(&result);
+        let session: SessionToken = reader.read_array::<32>()?;
+        reader.finish()?;
+        Ok(session)
+    }
+
+    /// Returns the public key verifying the sender certificates
+    pub fn get_certificate_key(&mut self) -> Result<PublicKey, TransportError> {
+        let result: Vec<u8> = self.call(Request::GetCertificateKey)?;
+        let mut reader: Reader = Reader::new(&result);
+        let certificate_key: PublicKey = PublicKey::from(reader.read_array::<32>()?);
+        reader.finish()?;
+        Ok(certificate_key)
+    }
+
+    /// Returns a certificate of the device that opened the session to send sealed messages
+    pub fn issue_sender_certificate(&mut self, username: &str, session: &SessionToken) -> Result<SenderCertificate, TransportError> {
+        let result: Vec<u8> = self.call(Request::GetSenderCertificate(username.to_string(), *session))?;
+        Ok(SenderCertificate::from_bytes(&result)?)
+    }
+
+    pub fn add_message_to(&mut self, username: &str, device_id: DeviceId, message: Envelope) -> Result<(), TransportError> {
+        Reader::new(&self.call(Request::AddMessageTo(username.to_string(), device_id, message))?).finish()?;
+        Ok(())
+    }
+
+    /// Publish the new signed prekey of the device that opened the session *(rotation)*
+    pub fn update_user_spk(&mut self, username: &str, session: &SessionToken, spk_id: u32, spk: PublicKey, signature: Signature) -> Result<(), TransportError> {
+        Reader::new(&self.call(Request::UpdateUserSpk(username.to_string(), *session, spk_id, spk, signature))?).finish()?;
+        Ok(())
+    }
+
+    /// Publish the new ML-KEM prekey of the device that opened the session *(rotation)*
+    pub fn update_user_pqspk(&mut self, username: &str, session: &SessionToken, pqspk_id: u32, pqspk: EncapsulationKey, pq_signature: Signature) -> Result<(), TransportError> {
+        Reader::new(&self.call(Request::UpdateUserPqspk(username.to_string(), *session, pqspk_id, pqspk, pq_signature))?).finish()?;
+        Ok(())
+    }
+
+    /// Returns the prekey bundle used to start a session with a device *(see `Server::fetch_prekey_bundle`)*
+    pub fn fetch_prekey_bundle(&mut self, username: &str, device_id: DeviceId) -> Result<ServerKeyCollection, TransportError> {
+        let result: Vec<u8> = self.call(Request::FetchPrekeyBundle(username.to_string(), device_id))?;
+        Ok(ServerKeyCollection::from_bytes(&result)?)
+    }
+
+    /// Upload a new batch of one-time prekeys of the device that opened the session, tagged with their id
+    pub fn add_user_opks(&mut self, username: &str, session: &SessionToken, opks: Vec<(u32, PublicKey)>) -> Result<(), TransportError> {
+        Reader::new(&self.call(Request::AddUserOpks(username.to_string(), *session, opks))?).finish()?;
+        Ok(())
+    }
+
+    /// Returns the number of one-time prekeys of a device still available
+    pub fn get_opk_count(&mut self, username: &str, device_id: DeviceId) -> Result<usize, TransportError> {
+        let result: Vec<u8> = self.call(Request::GetOpkCount(username.to_string(), device_id))?;
+        let mut reader: Reader = Reader::new(&result);
+        let opk_count: u32 = u32::from_be_bytes(reader.read_array::<4>()?);
+        reader.finish()?;
+        Ok(opk_count as usize)
+    }
+
+    pub fn get_user_keys(&mut self, username: &str, device_id: DeviceId) -> Result<ServerKeyCollection, TransportError> {
+        let result: Vec<u8> = self.call(Request::GetUserKeys(username.to_string(), device_id))?;
+        Ok(ServerKeyCollection::from_bytes(&result)?)
+    }
+
+    /// Returns the messages waiting for the device that opened the session with their id *(see `Server::get_user_messages`)*
+    pub fn get_user_messages(&mut self, username: &str, session: &SessionToken) -> Result<Vec<(u64, Envelope)>, TransportError> {
+        let result: Vec<u8> = self.call(Request::GetUserMessages(username.to_string(), *session))?;
+        let mut reader: Reader = Reader::new(&result);
+        let count: u32 = u32::from_be_bytes(reader.read_array::<4>()?);
+        let mut messages: Vec<(u64, Envelope)> = Vec::new();
+        for _ in 0..count {
+            let id: u64 = u64::from_be_bytes(reader.read_array::<8>()?);
+            messages.push((id, Envelope::from_bytes(reader.read_bytes()?)?));
+        }
+        reader.finish()?;
+        Ok(messages)
+    }
+
+    /// Delete the messages the device has read
+    pub fn acknowledge_messages(&mut self, username: &str, session: &SessionToken, ids: &[u64]) -> Result<(), TransportError> {
+        Reader::new(&self.call(Request::AcknowledgeMessages(username.to_string(), *session, ids.to_vec()))?).finish()?;
+        Ok(())
+    }
+
+    pub fn get_users(&mut self, requester_username: String) -> Result<Vec<String>, TransportError> {
+        let result: Vec<u8> = self.call(Request::GetUsers(requester_username))?;
+        let mut reader: Reader = Reader::new(&result);
+        let count: u32 = u32::from_be_bytes(reader.read_array::<4>()?);
+        let mut users: Vec<String> = Vec::new();
+        for _ in 0..count {
+            users.push(read_string(&mut reader)?);
+        }
+        reader.finish()?;
+        Ok(users)
+    }
+
+    /// Send a request and wait for its result
+    fn call(&mut self, request: Request) -> Result<Vec<u8>, TransportError> {
+        write_frame(&mut self.stream, &request.to_bytes()?)?;
+        let body: Vec<u8> = read_frame(&mut self.stream)?.ok_or(TransportError::ConnectionClosed)?;
+        match body.first() {
+            Some(&STATUS_OK) => Ok(body[1..].to_vec()),
+            Some(&STATUS_USER_DOES_NOT_EXIST) => Err(TransportError::Server(ServerError::UserDoesNotExist)),
+            Some(&STATUS_MALFORMED_REQUEST) => Err(TransportError::MalformedRequest),
+            Some(&STATUS_USER_ALREADY_EXISTS) => Err(TransportError::Server(ServerError::UserAlreadyExists)),
+            Some(&STATUS_NOT_AUTHENTICATED) => Err(TransportError::Server(ServerError::NotAuthenticated)),
+            Some(&STATUS_DEVICE_DOES_NOT_EXIST) => Err(TransportError::Server(ServerError::DeviceDoesNotExist)),
+            Some(&STATUS_PRIMARY_DEVICE_REMOVAL) => Err(TransportError::Server(ServerError::PrimaryDeviceRemoval)),
+            Some(&STATUS_DEVICE_IDS_EXHAUSTED) => Err(TransportError::Server(ServerError::DeviceIdsExhausted)),
+            Some(&STATUS_STORAGE_FAILURE) => Err(TransportError::Server(ServerError::Storage(io::Error::other("Storage failure on the relay")))),
+            Some(&status) => Err(TransportError::InvalidStatus(status)),
+            None => Err(TransportError::Parse(ParseError::UnexpectedEnd)),
+        }
+    }
+}
+
+/// Relay daemon reached through a socket
+impl Relay for RemoteServer {
+    fn publish_keys(&mut self, username: &str, keys: ServerKeyCollection) -> Result<(), RelayError> {
+        Ok(self.add_user(username.to_string(), keys)?)
+    }
+
+    fn replace_keys(&mut self, username: &str, session: &SessionToken, keys: ServerKeyCollection) -> Result<(), RelayError> {
+        Ok(self.replace_user_keys(username, session, keys)?)
+    }
+
+    fn add_device(&mut self, username: &str, session: &SessionToken, keys: ServerKeyCollection) -> Result<DeviceId, RelayError> {
+        Ok(RemoteServer::add_device(self, username, session, keys)?)
+    }
+
+    fn remove_device(&mut self, username: &str, session: &SessionToken, device_id: DeviceId) -> Result<(), RelayError> {
+        Ok(RemoteServer::remove_device(self, username, session, device_id)?)
+    }
+
+    fn devices(&mut self, username: &str) -> Result<Vec<DeviceId>, RelayError> {
+        Ok(self.get_devices(username)?)
+    }
+
+    fn challenge(&mut self, username: &str, device_id: DeviceId) -> Result<Challenge, RelayError> {
+        Ok(self.create_challenge(username, device_id)?)
+    }
+
+    fn login(&mut self, username: &str, device_id: DeviceId, signature: Signature) -> Result<SessionToken, RelayError> {
+        Ok(RemoteServer::login(self, username, device_id, signature)?)
+    }
+
+    fn certificate_key(&mut self) -> Result<PublicKey, RelayError> {
+        Ok(self.get_certificate_key()?)
+    }
+
+    fn sender_certificate(&mut self, username: &str, session: &SessionToken) -> Result<SenderCertificate, RelayError> {
+        Ok(self.issue_sender_certificate(username, session)?)
+    }
+
+    fn publish_spk(&mut self, username: &str, session: &SessionToken, spk_id: u32, spk: PublicKey, signature: Signature) -> Result<(), RelayError> {
+        Ok(self.update_user_spk(username, session, spk_id, spk, signature)?)
+    }
+
+    fn publish_pqspk(&mut self, username: &str, session: &SessionToken, pqspk_id: u32, pqspk: EncapsulationKey, pq_signature: Signature) -> Result<(), RelayError> {
+        Ok(self.update_user_pqspk(username, session, pqspk_id, pqspk, pq_signature)?)
+    }
+
+    fn publish_opks(&mut self, username: &str, session: &SessionToken, opks: Vec<(u32, PublicKey)>) -> Result<(), RelayError> {
+        Ok(self.add_user_opks(username, session, opks)?)
+    }
+
+    fn opk_count(&mut self, username: &str, device_id: DeviceId) -> Result<usize, RelayError> {
+        Ok(self.get_opk_count(username, device_id)?)
+    }
+
+    fn fetch_bundle(&mut self, username: &str, device_id: DeviceId) -> Result<ServerKeyCollection, RelayError> {
+        Ok(self.fetch_prekey_bundle(username, device_id)?)
+    }
+
+    fn identity_key(&mut self, username: &str, device_id: DeviceId) -> Result<PublicKey, RelayError> {
+        Ok(self.get_user_keys(username, device_id)?.get_ik())
+    }
+
+    fn enqueue(&mut self, username: &str, device_id: DeviceId, message: Envelope) -> Result<(), RelayError> {
+        Ok(self.add_message_to(username, device_id, message)?)
+    }
+
+    fn fetch(&mut self, username: &str, session: &SessionToken) -> Result<Vec<(u64, Envelope)>, RelayError> {
+        Ok(self.get_user_messages(username, session)?)
+    }
+
+    fn acknowledge(&mut self, username: &str, session: &SessionToken, ids: &[u64]) -> Result<(), RelayError> {
+        Ok(self.acknowledge_messages(username, session, ids)?)
+    }
+}
+
+/// Read a frame, returns None if the connection is closed before its first byte
+fn read_frame<S: Read>(stream: &mut S) -> io::Result<Option<Vec<u8>>> {
+    let mut length: [u8; 4] = [0u8; 4];
+    match stream.read_exact(&mut length) {
+        Ok(()) => {},
+        Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(error) => return Err(error),
+    }
+    let length: usize = u32::from_be_bytes(length) as usize;
+    if length > MAX_FRAME_LENGTH {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Frame larger than the limit of the relay"))
+    }
+    let mut body: Vec<u8> = vec![0u8; length];
+    stream.read_exact(&mut body)?;
+    Ok(Some(body))
+}
+
+fn write_frame<S: Write>(stream: &mut S, body: &[u8]) -> io::Result<()> {
+    let mut frame: Vec<u8> = Vec::with_capacity(4 + body.len());
+    write_bytes(&mut frame, body).map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error.to_string()))?;
+    stream.write_all(&frame)?;
+    stream.flush()
+}
+
+/// Result that can't be encoded, reported to the client as a failure of the relay
+fn encoding_failure(error: ParseError) -> ServerError {
+    ServerError::Storage(io::Error::new(io::ErrorKind::InvalidInput, error.to_string()))
+}
+
+fn read_string(reader: &mut Reader) -> Result<String, ParseError> {
+    String::from_utf8(reader.read_bytes()?.to_vec()).map_err(|_| ParseError::InvalidUsername)
+}
+
+fn read_device_id(reader: &mut Reader) -> Result<DeviceId, ParseError> {
+    Ok(u32::from_be_bytes(reader.read_array::<4>()?))
+}
+
+/// `count (4) || (opk id (4) || opk (32))*`
+fn write_opks(bytes: &mut Vec<u8>, opks: &[(u32, PublicKey)]) {
+    bytes.extend_from_slice(&(opks.len() as u32).to_be_bytes());
+    for (id, opk) in opks {
+        bytes.extend_from_slice(&id.to_be_bytes());
+        bytes.extend_from_slice(opk.as_bytes());
+    }
+}
+
+fn read_opks(reader: &mut Reader) -> Result<Vec<(u32, PublicKey)>, ParseError> {
+    let count: u32 = u32::from_be_bytes(reader.read_array::<4>()?);
+    let mut opks: Vec<(u32, PublicKey)> = Vec::new();
+    for _ in 0..count {
+        let id: u32 = u32::from_be_bytes(reader.read_array::<4>()?);
+        opks.push((id, PublicKey::from(reader.read_array::<32>()?)));
+    }
+    Ok(opks)
+}
+
+impl From<io::Error> for TransportError {
+    fn from(error: io::Error) -> Self {
+        TransportError::Io(error)
+    }
+}
+
+impl From<ParseError> for TransportError {
+    fn from(error: ParseError) -> Self {
+        TransportError::Parse(error)
+    }
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TransportError::Io(error) => write!(f, "Connection to the relay failed: {}", error),
+            TransportError::Parse(error) => write!(f, "Invalid response from the relay: {}", error),
+            TransportError::Server(error) => write!(f, "{}", error),
+            TransportError::MalformedRequest => write!(f, "The relay rejected a malformed request"),
+            TransportError::InvalidStatus(status) => write!(f, "Invalid response status from the relay: {}", status),
+            TransportError::ConnectionClosed => write!(f, "The relay closed the connection"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::communication::client::Client;
+    use x25519_dalek::StaticSecret;
+
+    fn public_key(seed: u8) -> PublicKey {
+        PublicKey::from(&StaticSecret::from([seed; 32]))
+    }
+
+    #[test]
+    fn test_connection_limit() {
+        let connections: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
+        let mut slots: Vec<ConnectionSlot> = (0..MAX_CONNECTIONS).map(|_| ConnectionSlot::acquire(&connections).unwrap()).collect();
+        assert!(ConnectionSlot::acquire(&connections).is_none());
+
+        // Closing a connection frees its place
+        slots.pop();
+        assert_eq!(connections.load(Ordering::Acquire), MAX_CONNECTIONS - 1);
+        assert!(ConnectionSlot::acquire(&connections).is_some());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_server_not_blocked_by_idle_client() {
+        let path: std::path::PathBuf = std::env::temp_dir().join(format!("relay-idle-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener: UnixListener = UnixListener::bind(&path).unwrap();
+        let server: Arc<RwLock<Server>> = Arc::new(RwLock::new(Server::new()));
+        thread::spawn(move || serve_unix(listener, server));
+
+        // A client that sent half a frame doesn't hold the server
+        let mut idle_stream: UnixStream = UnixStream::connect(&path).unwrap();
+        idle_stream.write_all(&[0x00, 0x00]).unwrap();
+        let mut remote: RemoteServer = RemoteServer::connect_unix(&path).unwrap();
+        assert_eq!(remote.get_users("Bob".to_string()).unwrap(), Vec::<String>::new());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_request_round_trip() {
+        let bob: String = "Bob".to_string();
+        let requests: Vec<Request> = vec![
+            Request::AddUser(bob.clone(), Client::new(bob.clone()).get_server_keys()),
+            Request::GetUserKeys(bob.clone(), 2),
+            Request::FetchPrekeyBundle(bob.clone(), 2),
+            Request::GetUserMessages(bob.clone(), [0x01; 32]),
+            Request::GetUsers(bob.clone()),
+            Request::UpdateUserSpk(bob.clone(), [0x02; 32], 3, public_key(1), [0xAA; 64]),
+            Request::AddUserOpks(bob.clone(), [0x03; 32], vec![(50, public_key(2)), (51, public_key(3))]),
+            Request::GetOpkCount(bob.clone(), 2),
+            Request::AcknowledgeMessages(bob.clone(), [0x04; 32], vec![0, 7, u64::MAX]),
+            Request::GetChallenge(bob.clone(), 2),
+            Request::Login(bob.clone(), 2, [0xBB; 64]),
+            Request::ReplaceUserKeys(bob.clone(), [0x05; 32], Client::new(bob.clone()).get_server_keys()),
+            Request::GetCertificateKey,
+            Request::GetSenderCertificate(bob.clone(), [0x06; 32]),
+            Request::GetDevices(bob.clone()),
+            Request::AddDevice(bob.clone(), [0x07; 32], Client::new(bob.clone()).get_server_keys()),
+            Request::RemoveDevice(bob.clone(), [0x08; 32], 3),
+            Request::UpdateUserPqspk(bob, [0x09; 32], 4, [0xCC; ENCAPSULATION_KEY_LENGTH], [0xDD; 64]),
+        ];
+
+        for expected_value in requests {
+            assert_eq!(Request::from_bytes(&expected_value.to_bytes().unwrap()), Ok(expected_value));
+        }
+    }
+
+    #[test]
+    fn test_request_unknown_operation_or_trailing_bytes() {
+        let mut unknown_operation: Vec<u8> = Request::GetUsers("Bob".to_string()).to_bytes().unwrap();
+        unknown_operation[0] = 0xFF;
+        let mut extended_bytes: Vec<u8> = Request::GetUsers("Bob".to_string()).to_bytes().unwrap();
+        extended_bytes.push(0);
+
+        assert_eq!(Request::from_bytes(&unknown_operation), Err(ParseError::UnknownOperation(0xFF)));
+        assert_eq!(Request::from_bytes(&extended_bytes), Err(ParseError::TrailingBytes));
+    }
+
+    #[test]
+    fn test_frame_too_large() {
+        let mut bytes: &[u8] = &[0xFF, 0xFF, 0xFF, 0xFF];
+        let error: io::Error = read_frame(&mut bytes).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        let mut empty: &[u8] = &[];
+        assert!(read_frame(&mut empty).unwrap().is_none());
+    }
+}
@@ -1,12 +1,257 @@
+use std::fmt;
 use aes_gcm_siv::{
-    aead::{Aead, KeyInit, OsRng, Payload, generic_array::GenericArray},
+    aead::{Aead as _, KeyInit, OsRng, Payload, generic_array::GenericArray},
     Aes256GcmSiv, AeadCore,
 };
+use aes::Aes256;
+use cbc::cipher::{block_padding::Pkcs7, BlockDecryptMut, BlockEncryptMut, KeyIvInit};
+use chacha20poly1305::ChaCha20Poly1305;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
 
-#[derive(Debug)]
+const NONCE_LENGTH: usize = 12;
+const MAC_LENGTH: usize = 32;
+const CBC_HMAC_INFO: &[u8] = b"DoubleRatchet AES-256-CBC HMAC-SHA256";
+const AEAD_AES_256_GCM_SIV: u8 = 0x00;
+const AEAD_CHACHA20_POLY1305: u8 = 0x01;
+const AEAD_AES_256_CBC_HMAC_SHA256: u8 = 0x02;
+const NONCE_RANDOM: u8 = 0x00;
+const NONCE_DERIVED: u8 = 0x01;
+const MESSAGE_KEY_INFO: &[u8] = b"DoubleRatchet message key and nonce";
+type HmacSha256 = Hmac<Sha256>;
+type Aes256CbcEncryptor = cbc::Encryptor<Aes256>;
+type Aes256CbcDecryptor = cbc::Decryptor<Aes256>;
+
+#[derive(Debug, PartialEq)]
 pub enum CryptoError {
     EncryptionError,
     DecryptionError,
+    TooManySkippedMessages,
+    NotInitialized,
+    InvalidSession,
+}
+
+/// How the sender chooses the nonces *(the receiver reads both: a message without nonce uses the derived one)*
+/// 
+/// With a random nonce, the message key is the AEAD key *(as before the nonce mode existed, so older messages still decrypt)*.
+/// With a derived nonce, the AEAD key is derived from the message key together with the nonce, so that the message key
+/// is never both the key and the source of the nonce.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum NonceMode {
+    #[default]
+    Random, // Random nonce, sent with the message
+    Derived, // Nonce derived from the message key, not sent
+}
+
+impl NonceMode {
+    pub fn to_byte(&self) -> u8 {
+        match self {
+            NonceMode::Random => NONCE_RANDOM,
+            NonceMode::Derived => NONCE_DERIVED,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            NONCE_RANDOM => Some(NonceMode::Random),
+            NONCE_DERIVED => Some(NonceMode::Derived),
+            _ => None,
+        }
+    }
+}
+
+/// Authenticated encryption of the messages with their message key *(each message key is used once)*
+pub trait Aead {
+    /// Encrypt `plaintext` with the message key `mk`, returns the ciphertext and the nonce to send *(empty if it's derived from `mk`)*
+    fn encrypt(&self, mk: [u8; 32], plaintext: &[u8], ad: &[u8], nonce_mode: NonceMode) -> Result<(Vec<u8>, Vec<u8>), CryptoError>;
+
+    /// Decrypt `ciphertext` with the message key `mk` and the nonce sent with it *(derived from `mk` if empty)*
+    fn decrypt(&self, mk: [u8; 32], ciphertext: &[u8], nonce: &[u8], ad: &[u8]) -> Result<Vec<u8>, CryptoError>;
+}
+
+/// AES-256-GCM-SIV with a random or derived nonce
+pub struct Aes256GcmSivAead;
+
+/// ChaCha20-Poly1305 with a random or derived nonce
+pub struct ChaCha20Poly1305Aead;
+
+/// AES-256-CBC (PKCS#7) then HMAC-SHA256 *(based on Signal: https://signal.org/docs/specifications/doubleratchet/#recommended-cryptographic-algorithms)*
+/// 
+/// The encryption key, the authentication key and the IV are derived from the message key with HKDF, so no nonce is sent.
+pub struct Aes256CbcHmacAead;
+
+/// AEAD used by a session *(chosen by the device starting it)*
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum AeadAlgorithm {
+    #[default]
+    Aes256GcmSiv,
+    ChaCha20Poly1305,
+    Aes256CbcHmacSha256,
+}
+
+impl AeadAlgorithm {
+    pub fn to_byte(&self) -> u8 {
+        match self {
+            AeadAlgorithm::Aes256GcmSiv => AEAD_AES_256_GCM_SIV,
+            AeadAlgorithm::ChaCha20Poly1305 => AEAD_CHACHA20_POLY1305,
+            AeadAlgorithm::Aes256CbcHmacSha256 => AEAD_AES_256_CBC_HMAC_SHA256,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            AEAD_AES_256_GCM_SIV => Some(AeadAlgorithm::Aes256GcmSiv),
+            AEAD_CHACHA20_POLY1305 => Some(AeadAlgorithm::ChaCha20Poly1305),
+            AEAD_AES_256_CBC_HMAC_SHA256 => Some(AeadAlgorithm::Aes256CbcHmacSha256),
+            _ => None,
+        }
+    }
+}
+
+impl Aead for AeadAlgorithm {
+    fn encrypt(&self, mk: [u8; 32], plaintext: &[u8], ad: &[u8], nonce_mode: NonceMode) -> Result<(Vec<u8>, Vec<u8>), CryptoError> {
+        match self {
+            AeadAlgorithm::Aes256GcmSiv => Aes256GcmSivAead.encrypt(mk, plaintext, ad, nonce_mode),
+            AeadAlgorithm::ChaCha20Poly1305 => ChaCha20Poly1305Aead.encrypt(mk, plaintext, ad, nonce_mode),
+            AeadAlgorithm::Aes256CbcHmacSha256 => Aes256CbcHmacAead.encrypt(mk, plaintext, ad, nonce_mode),
+        }
+    }
+
+    fn decrypt(&self, mk: [u8; 32], ciphertext: &[u8], nonce: &[u8], ad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        match self {
+            AeadAlgorithm::Aes256GcmSiv => Aes256GcmSivAead.decrypt(mk, ciphertext, nonce, ad),
+            AeadAlgorithm::ChaCha20Poly1305 => ChaCha20Poly1305Aead.decrypt(mk, ciphertext, nonce, ad),
+            AeadAlgorithm::Aes256CbcHmacSha256 => Aes256CbcHmacAead.decrypt(mk, ciphertext, nonce, ad),
+        }
+    }
+}
+
+impl Aead for Aes256GcmSivAead {
+    fn encrypt(&self, mk: [u8; 32], plaintext: &[u8], ad: &[u8], nonce_mode: NonceMode) -> Result<(Vec<u8>, Vec<u8>), CryptoError> {
+        let (key, nonce, sent_nonce): ([u8; 32], [u8; NONCE_LENGTH], Vec<u8>) = new_key_nonce(mk, nonce_mode);
+        let cipher = Aes256GcmSiv::new(&GenericArray::clone_from_slice(&key));
+        let ciphertext = cipher
+            .encrypt(GenericArray::from_slice(&nonce), Payload { msg: plaintext, aad: ad })
+            .map_err(|_| CryptoError::EncryptionError)?;
+
+        Ok((ciphertext, sent_nonce))
+    }
+
+    fn decrypt(&self, mk: [u8; 32], ciphertext: &[u8], nonce: &[u8], ad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let (key, nonce): ([u8; 32], [u8; NONCE_LENGTH]) = received_key_nonce(mk, nonce)?;
+        decrypt(key, &ciphertext.to_vec(), nonce.as_ref(), ad)
+    }
+}
+
+impl Aead for ChaCha20Poly1305Aead {
+    fn encrypt(&self, mk: [u8; 32], plaintext: &[u8], ad: &[u8], nonce_mode: NonceMode) -> Result<(Vec<u8>, Vec<u8>), CryptoError> {
+        let (key, nonce, sent_nonce): ([u8; 32], [u8; NONCE_LENGTH], Vec<u8>) = new_key_nonce(mk, nonce_mode);
+        let cipher = ChaCha20Poly1305::new(&GenericArray::clone_from_slice(&key));
+        let ciphertext = cipher
+            .encrypt(GenericArray::from_slice(&nonce), Payload { msg: plaintext, aad: ad })
+            .map_err(|_| CryptoError::EncryptionError)?;
+
+        Ok((ciphertext, sent_nonce))
+    }
+
+    fn decrypt(&self, mk: [u8; 32], ciphertext: &[u8], nonce: &[u8], ad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let (key, nonce): ([u8; 32], [u8; NONCE_LENGTH]) = received_key_nonce(mk, nonce)?;
+        let cipher = ChaCha20Poly1305::new(&GenericArray::clone_from_slice(&key));
+        cipher
+            .decrypt(GenericArray::from_slice(&nonce), Payload { msg: ciphertext, aad: ad })
+            .map_err(|_| CryptoError::DecryptionError)
+    }
+}
+
+impl Aes256CbcHmacAead {
+    /// Returns the encryption key, the authentication key and the IV derived from the message key
+    fn derive_keys(mk: [u8; 32]) -> ([u8; 32], [u8; 32], [u8; 16]) {
+        let hk = Hkdf::<Sha256>::new(Some(&[0u8; 32]), &mk);
+        let mut okm = [0u8; 80];
+        hk.expand(CBC_HMAC_INFO, &mut okm)
+            .expect("Output length invalid AES-256-CBC-HMAC");
+
+        (okm[..32].try_into().expect("Incorrect length"),
+        okm[32..64].try_into().expect("Incorrect length"),
+        okm[64..].try_into().expect("Incorrect length"))
+    }
+
+    /// Returns the HMAC-SHA256 of `ad || ciphertext`
+    fn mac(auth_key: [u8; 32], ciphertext: &[u8], ad: &[u8]) -> HmacSha256 {
+        let mut mac = <HmacSha256 as Mac>::new_from_slice(&auth_key)
+            .expect("HMAC can take key of any size");
+        mac.update(ad);
+        mac.update(ciphertext);
+        mac
+    }
+}
+
+impl Aead for Aes256CbcHmacAead {
+    /// The IV is always derived from the message key, whatever `nonce_mode`
+    fn encrypt(&self, mk: [u8; 32], plaintext: &[u8], ad: &[u8], _nonce_mode: NonceMode) -> Result<(Vec<u8>, Vec<u8>), CryptoError> {
+        let (encryption_key, auth_key, iv) = Self::derive_keys(mk);
+        let mut ciphertext: Vec<u8> = Aes256CbcEncryptor::new(&encryption_key.into(), &iv.into()).encrypt_padded_vec_mut::<Pkcs7>(plaintext);
+        let tag = Self::mac(auth_key, &ciphertext, ad).finalize().into_bytes();
+        ciphertext.extend_from_slice(&tag);
+
+        Ok((ciphertext, Vec::new()))
+    }
+
+    fn decrypt(&self, mk: [u8; 32], ciphertext: &[u8], nonce: &[u8], ad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        if !nonce.is_empty() || ciphertext.len() < MAC_LENGTH {
+            return Err(CryptoError::DecryptionError)
+        }
+        let (encryption_key, auth_key, iv) = Self::derive_keys(mk);
+        let (ciphertext, tag) = ciphertext.split_at(ciphertext.len() - MAC_LENGTH);
+        // The MAC is checked (in constant time) before decrypting
+        Self::mac(auth_key, ciphertext, ad)
+            .verify_slice(tag)
+            .map_err(|_| CryptoError::DecryptionError)?;
+
+        Aes256CbcDecryptor::new(&encryption_key.into(), &iv.into())
+            .decrypt_padded_vec_mut::<Pkcs7>(ciphertext)
+            .map_err(|_| CryptoError::DecryptionError)
+    }
+}
+
+/// Returns the AEAD key and the nonce derived from a message key with one HKDF expansion *(each message key encrypts one message, so each derived nonce is used once)*
+pub fn derive_key_nonce(mk: [u8; 32]) -> ([u8; 32], [u8; NONCE_LENGTH]) {
+    let hkdf = Hkdf::<Sha256>::new(Some(&[0u8; 32]), &mk);
+    let mut okm = [0u8; 32 + NONCE_LENGTH];
+    hkdf.expand(MESSAGE_KEY_INFO, &mut okm)
+        .expect("Output length invalid message key and nonce");
+
+    (okm[..32].try_into().expect("Incorrect length"),
+    okm[32..].try_into().expect("Incorrect length"))
+}
+
+/// Returns a random nonce
+fn random_nonce() -> [u8; NONCE_LENGTH] {
+    Aes256GcmSiv::generate_nonce(&mut OsRng).as_slice().try_into().expect("Incorrect length")
+}
+
+/// Returns the key and the nonce encrypting a message, and the nonce to send with it *(nothing if it's derived)*
+fn new_key_nonce(mk: [u8; 32], nonce_mode: NonceMode) -> ([u8; 32], [u8; NONCE_LENGTH], Vec<u8>) {
+    match nonce_mode {
+        NonceMode::Random => {
+            let nonce: [u8; NONCE_LENGTH] = random_nonce();
+            (mk, nonce, nonce.to_vec())
+        },
+        NonceMode::Derived => {
+            let (key, nonce): ([u8; 32], [u8; NONCE_LENGTH]) = derive_key_nonce(mk);
+            (key, nonce, Vec::new())
+        },
+    }
+}
+
+/// Returns the key and the nonce of a message received: the message key and the nonce sent with it, or the key and the nonce
+/// derived from the message key if none was sent
+fn received_key_nonce(mk: [u8; 32], nonce: &[u8]) -> Result<([u8; 32], [u8; NONCE_LENGTH]), CryptoError> {
+    if nonce.is_empty() {
+        return Ok(derive_key_nonce(mk))
+    }
+    Ok((mk, nonce.try_into().map_err(|_| CryptoError::DecryptionError)?))
 }
 
 /// Encrypt the message using AES-GCM-SIV-256
@@ -41,22 +286,182 @@ pub fn encrypt(mk: [u8; 32], plaintext: &[u8], ad: &[u8]) -> Result<(Vec<u8>, Ve
 /// 
 /// * `mk` (\[u8; 32\]): Message key
 /// * `ciphertext` (&Vec\<u8\>): Ciphertext
-/// * `nonce` (&Vec\<u8\>): Nonce
+/// * `nonce` (&\[u8\]): Nonce
 /// * `ad` (&\[u8\]): Associated Data
 /// 
 /// # Output
 /// 
 /// * `plaintext` (Result\<Vec\<u8\>, CryptoError\>): Plaintext
-pub fn decrypt(mk: [u8; 32], ciphertext: &Vec<u8>, nonce: &Vec<u8>, ad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+pub fn decrypt(mk: [u8; 32], ciphertext: &Vec<u8>, nonce: &[u8], ad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    if nonce.len() != NONCE_LENGTH {
+        return Err(CryptoError::DecryptionError)
+    }
     let cipher = Aes256GcmSiv::new(&GenericArray::clone_from_slice(&mk));
     let payload = Payload {
-        msg: &ciphertext,
+        msg: ciphertext,
         aad: ad,
     };
 
     let plaintext = cipher
-        .decrypt(&GenericArray::clone_from_slice(&nonce), payload)
+        .decrypt(&GenericArray::clone_from_slice(nonce), payload)
         .map_err(|_| CryptoError::DecryptionError)?;
 
     Ok(plaintext)
-}
\ No newline at end of file
+}
+
+/// Encrypt data at rest using AES-GCM-SIV-256 *(the nonce is stored in front of the ciphertext)*
+/// 
+/// # Arguments
+/// 
+/// * `key` (\[u8; 32\]): Storage key
+/// * `plaintext` (&\[u8\]): Plaintext
+/// * `ad` (&\[u8\]): Associated Data
+/// 
+/// # Output
+/// 
+/// * `sealed` (Result\<Vec\<u8\>, CryptoError\>): Nonce followed by the ciphertext
+pub fn seal(key: [u8; 32], plaintext: &[u8], ad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    let (ciphertext, nonce) = encrypt(key, plaintext, ad)?;
+    Ok([nonce, ciphertext].concat())
+}
+
+/// Decrypt data sealed with `seal`
+/// 
+/// # Arguments
+/// 
+/// * `key` (\[u8; 32\]): Storage key
+/// * `sealed` (&\[u8\]): Nonce followed by the ciphertext
+/// * `ad` (&\[u8\]): Associated Data
+/// 
+/// # Output
+/// 
+/// * `plaintext` (Result\<Vec\<u8\>, CryptoError\>): Plaintext
+pub fn open(key: [u8; 32], sealed: &[u8], ad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    if sealed.len() < NONCE_LENGTH {
+        return Err(CryptoError::DecryptionError)
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LENGTH);
+    decrypt(key, &ciphertext.to_vec(), nonce, ad)
+}
+
+impl fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CryptoError::EncryptionError => write!(f, "Encryption failed (AEAD)"),
+            CryptoError::DecryptionError => write!(f, "Decryption failed (AEAD)"),
+            CryptoError::TooManySkippedMessages => write!(f, "Too many skipped messages in the receiving chain"),
+            CryptoError::NotInitialized => write!(f, "Double ratchet not initialized"),
+            CryptoError::InvalidSession => write!(f, "The stored session is malformed"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MK: [u8; 32] = [0x42; 32];
+    const AD: &[u8] = b"associated data";
+    const AEADS: [AeadAlgorithm; 3] = [AeadAlgorithm::Aes256GcmSiv, AeadAlgorithm::ChaCha20Poly1305, AeadAlgorithm::Aes256CbcHmacSha256];
+
+    #[test]
+    fn test_aead_round_trip() {
+        for (aead, nonce_mode) in AEADS.into_iter().flat_map(|aead| [(aead, NonceMode::Random), (aead, NonceMode::Derived)]) {
+            let (ciphertext, nonce): (Vec<u8>, Vec<u8>) = aead.encrypt(MK, b"plaintext", AD, nonce_mode).unwrap();
+            assert_eq!(aead.decrypt(MK, &ciphertext, &nonce, AD), Ok(b"plaintext".to_vec()));
+            assert_eq!(aead.decrypt([0x43; 32], &ciphertext, &nonce, AD), Err(CryptoError::DecryptionError));
+            assert_eq!(aead.decrypt(MK, &ciphertext, &nonce, b"other data"), Err(CryptoError::DecryptionError));
+
+            let mut forged_ciphertext: Vec<u8> = ciphertext.clone();
+            forged_ciphertext[0] ^= 0x01;
+            assert_eq!(aead.decrypt(MK, &forged_ciphertext, &nonce, AD), Err(CryptoError::DecryptionError));
+            assert_eq!(AeadAlgorithm::from_byte(aead.to_byte()), Some(aead));
+        }
+        assert_eq!(AeadAlgorithm::from_byte(0x03), None);
+        assert_eq!(NonceMode::from_byte(NonceMode::Derived.to_byte()), Some(NonceMode::Derived));
+        assert_eq!(NonceMode::from_byte(0x02), None);
+    }
+
+    #[test]
+    fn test_sent_nonce() {
+        for (aead, random_nonce_length) in [(AeadAlgorithm::Aes256GcmSiv, NONCE_LENGTH), (AeadAlgorithm::ChaCha20Poly1305, NONCE_LENGTH), (AeadAlgorithm::Aes256CbcHmacSha256, 0)] {
+            let (random_ciphertext, random_nonce): (Vec<u8>, Vec<u8>) = aead.encrypt(MK, b"same length", AD, NonceMode::Random).unwrap();
+            let (derived_ciphertext, derived_nonce): (Vec<u8>, Vec<u8>) = aead.encrypt(MK, b"same length", AD, NonceMode::Derived).unwrap();
+            assert_eq!(random_nonce.len(), random_nonce_length);
+            assert!(derived_nonce.is_empty());
+            // Deriving the nonce only saves the nonce
+            assert_eq!(random_ciphertext.len(), derived_ciphertext.len());
+
+            // The receiver reads both modes
+            assert_eq!(aead.decrypt(MK, &random_ciphertext, &random_nonce, AD), Ok(b"same length".to_vec()));
+            assert_eq!(aead.decrypt(MK, &derived_ciphertext, &derived_nonce, AD), Ok(b"same length".to_vec()));
+        }
+    }
+
+    #[test]
+    fn test_aead_of_the_session_required() {
+        for (aead, other_aead) in AEADS.into_iter().flat_map(|aead| AEADS.into_iter().filter(move |other_aead| *other_aead != aead).map(move |other_aead| (aead, other_aead))) {
+            for nonce_mode in [NonceMode::Random, NonceMode::Derived] {
+                let (ciphertext, nonce): (Vec<u8>, Vec<u8>) = aead.encrypt(MK, b"plaintext", AD, nonce_mode).unwrap();
+                assert_eq!(other_aead.decrypt(MK, &ciphertext, &nonce, AD), Err(CryptoError::DecryptionError));
+            }
+        }
+    }
+
+    #[test]
+    fn test_derived_nonce() {
+        for aead in [AeadAlgorithm::Aes256GcmSiv, AeadAlgorithm::ChaCha20Poly1305] {
+            let (ciphertext, nonce): (Vec<u8>, Vec<u8>) = aead.encrypt(MK, b"plaintext", AD, NonceMode::Derived).unwrap();
+            assert!(nonce.is_empty());
+            assert_eq!(aead.decrypt(MK, &ciphertext, &[], AD), Ok(b"plaintext".to_vec()));
+            // The derived key is only used without a nonce sent
+            assert_eq!(aead.decrypt(MK, &ciphertext, &derive_key_nonce(MK).1, AD), Err(CryptoError::DecryptionError));
+
+            let (random_ciphertext, random_nonce): (Vec<u8>, Vec<u8>) = aead.encrypt(MK, b"plaintext", AD, NonceMode::Random).unwrap();
+            assert_eq!(random_nonce.len(), NONCE_LENGTH);
+            assert_eq!(random_ciphertext.len(), ciphertext.len());
+            assert_eq!(aead.decrypt(MK, &random_ciphertext, &[], AD), Err(CryptoError::DecryptionError));
+            assert_eq!(aead.decrypt(MK, &ciphertext, &[0u8; 8], AD), Err(CryptoError::DecryptionError));
+        }
+        assert_ne!(derive_key_nonce(MK), derive_key_nonce([0x43; 32]));
+    }
+
+    #[test]
+    fn test_key_and_nonce_domain_separated() {
+        let (key, nonce): ([u8; 32], [u8; NONCE_LENGTH]) = derive_key_nonce(MK);
+        // The message key is neither the AEAD key nor the source of a nonce that is part of the key
+        assert_ne!(key, MK);
+        assert!(!key.windows(NONCE_LENGTH).any(|window| window == nonce));
+        // Other info than the CBC-HMAC keys: no key or IV is shared between the AEADs
+        let (encryption_key, auth_key, iv) = Aes256CbcHmacAead::derive_keys(MK);
+        assert!(key != encryption_key && key != auth_key && nonce != iv[..NONCE_LENGTH]);
+
+        // The message is encrypted with the derived key, not with the message key
+        let (ciphertext, _): (Vec<u8>, Vec<u8>) = Aes256GcmSivAead.encrypt(MK, b"plaintext", AD, NonceMode::Derived).unwrap();
+        assert_eq!(decrypt(key, &ciphertext, nonce.as_ref(), AD), Ok(b"plaintext".to_vec()));
+        assert_eq!(decrypt(MK, &ciphertext, nonce.as_ref(), AD), Err(CryptoError::DecryptionError));
+    }
+
+    #[test]
+    fn test_random_nonce_uses_message_key() {
+        // Messages encrypted before the nonce modes (message key and random nonce) still decrypt
+        let (legacy_ciphertext, legacy_nonce): (Vec<u8>, Vec<u8>) = encrypt(MK, b"plaintext", AD).unwrap();
+        assert_eq!(Aes256GcmSivAead.decrypt(MK, &legacy_ciphertext, &legacy_nonce, AD), Ok(b"plaintext".to_vec()));
+
+        let (ciphertext, nonce): (Vec<u8>, Vec<u8>) = Aes256GcmSivAead.encrypt(MK, b"plaintext", AD, NonceMode::Random).unwrap();
+        assert_eq!(decrypt(MK, &ciphertext, &nonce, AD), Ok(b"plaintext".to_vec()));
+    }
+
+    #[test]
+    fn test_cbc_hmac_without_nonce() {
+        let (ciphertext, nonce): (Vec<u8>, Vec<u8>) = Aes256CbcHmacAead.encrypt(MK, b"plaintext", AD, NonceMode::Random).unwrap();
+        assert!(nonce.is_empty());
+        // One block of AES-256-CBC and the HMAC-SHA256
+        assert_eq!(ciphertext.len(), 16 + MAC_LENGTH);
+        // The IV is derived from the message key
+        assert_eq!(Aes256CbcHmacAead.encrypt(MK, b"plaintext", AD, NonceMode::Random).unwrap().0, ciphertext);
+
+        assert_eq!(Aes256CbcHmacAead.decrypt(MK, &ciphertext, &[0u8; NONCE_LENGTH], AD), Err(CryptoError::DecryptionError));
+        assert_eq!(Aes256CbcHmacAead.decrypt(MK, &ciphertext[..MAC_LENGTH - 1], &[], AD), Err(CryptoError::DecryptionError));
+    }
+}
@@ -1,35 +1,92 @@
 use crate::double_ratchet::state::State;
-use crate::double_ratchet::aead::{encrypt as aead_encrypt, decrypt as aead_decrypt};
+use crate::double_ratchet::skipped_keys::SkippedKeys;
+use crate::double_ratchet::aead::{Aead, AeadAlgorithm, CryptoError, NonceMode};
 use sha2::Sha256;
 use hmac::{Hmac, Mac};
 use hkdf::Hkdf;
 use rand_core::OsRng;
-use x25519_dalek::{ReusableSecret, PublicKey};
+use x25519_dalek::{StaticSecret, PublicKey};
 
 
-const MAX_SKIP: u16 = 1000;
+const MAX_SKIP: u32 = 1000;
 const BYTE_MESSAGE_KEY: &[u8] = &[0x01];
 const BYTE_NEXT_CHAIN_KEY: &[u8] = &[0x02];
 const INFO: &[u8] = &[0x73];
 type HmacSha256 = Hmac<Sha256>;
+/// Header and (ciphertext, nonce) of an encrypted message
+pub type EncryptedMessage = ((PublicKey, u32, u32), (Vec<u8>, Vec<u8>));
 
 #[derive(Clone)]
 pub struct DoubleRatchet {
     state: State,
 }
 
+impl Default for DoubleRatchet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl DoubleRatchet {
     pub fn new() -> Self {
         DoubleRatchet { state: State::new() }
     }
 
+    /// Returns a Double Ratchet encrypting its messages with `aead` *(both parties have to use the same one)*
+    pub fn with_aead(aead: AeadAlgorithm) -> Self {
+        let mut state: State = State::new();
+        state.aead = aead;
+        DoubleRatchet { state }
+    }
+
+    pub fn get_aead(&self) -> AeadAlgorithm {
+        self.state.aead
+    }
+
+    /// Choose how the nonces of the next messages sent are chosen *(the other party reads both modes)*
+    pub fn set_nonce_mode(&mut self, nonce_mode: NonceMode) {
+        self.state.nonce_mode = nonce_mode;
+    }
+
+    pub fn get_nonce_mode(&self) -> NonceMode {
+        self.state.nonce_mode
+    }
+
+    /// Returns the serialization of the whole ratchet state *(contains secret keys, seal it before storing it)*
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.state.to_bytes()
+    }
+
+    /// Returns the store of skipped message keys, to inspect them
+    pub fn get_skipped_keys(&self) -> &SkippedKeys<PublicKey> {
+        &self.state.mkskipped
+    }
+
+    /// Returns the store of skipped message keys, to purge them
+    pub fn get_skipped_keys_mut(&mut self) -> &mut SkippedKeys<PublicKey> {
+        &mut self.state.mkskipped
+    }
+
+    /// Restore a Double Ratchet from the serialization of its state
+    /// 
+    /// # Arguments
+    /// 
+    /// * `bytes` (&\[u8\]): Serialized state
+    /// 
+    /// # Output
+    /// 
+    /// * `double_ratchet` (Result\<DoubleRatchet, CryptoError\>): Restored Double Ratchet
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CryptoError> {
+        Ok(DoubleRatchet { state: State::from_bytes(bytes)? })
+    }
+
     /// Initialize the sender Double Ratchet
     /// 
     /// # Arguments
     /// 
     /// * `sk` (\[u8; 32\]): Shared Key *(X3DH shared secret)*
     /// * `receiver_public_key` (PublicKey): Receiver public key
-    pub fn init_sender(&mut self, sk: [u8; 32], receiver_public_key: PublicKey) -> () {
+    pub fn init_sender(&mut self, sk: [u8; 32], receiver_public_key: PublicKey) {
         self.generate_dh(); // Set dh_s
         self.state.dh_r = Some(receiver_public_key);
         let (rk_result, ck_r_result) = self.kdf_rk(sk, self.dh(self.state.dh_s.as_ref().unwrap(), self.state.dh_r.unwrap()));
@@ -41,15 +98,15 @@ impl DoubleRatchet {
     /// # Arguments
     /// 
     /// * `sk` (\[u8; 32\]): Shared Key *(X3DH shared secret)*
-    /// * `receiver_pair` (ReusableSecret, PublicKey): Receiver pair
-    pub fn init_receiver(&mut self, sk: [u8; 32], receiver_pair: (ReusableSecret, PublicKey)) -> () {
+    /// * `receiver_pair` (StaticSecret, PublicKey): Receiver pair
+    pub fn init_receiver(&mut self, sk: [u8; 32], receiver_pair: (StaticSecret, PublicKey)) {
         self.state.dh_s = Some(receiver_pair);
         self.state.rk = Some(sk);
     }
     
     /// Create and set a new Diffie-Hellman *(Curve25519)* key pair to `dh_s`
-    fn generate_dh(&mut self) -> () {
-        let private_key: ReusableSecret = ReusableSecret::random_from_rng(OsRng);
+    fn generate_dh(&mut self) {
+        let private_key: StaticSecret = StaticSecret::random_from_rng(OsRng);
         let public_key: PublicKey = PublicKey::from(&private_key);
         self.state.dh_s = Some((private_key, public_key));
     }
@@ -64,7 +121,7 @@ impl DoubleRatchet {
     /// # Output
     /// 
     /// * `dh_out` (SharedSecret): Diffie-Hellman output
-    fn dh(&self, dh_pair: &(ReusableSecret, PublicKey), dh_pub: PublicKey) -> [u8; 32] {
+    fn dh(&self, dh_pair: &(StaticSecret, PublicKey), dh_pub: PublicKey) -> [u8; 32] {
         *dh_pair.0.diffie_hellman(&dh_pub).as_bytes()
     }
     
@@ -124,7 +181,7 @@ impl DoubleRatchet {
         (Some(new_chain_key), new_message_key)
     }
     
-    /// Returns an AEAD (AES-GCM-SIV-256) encryption of plaintext with message key `mk`.
+    /// Returns an AEAD encryption of plaintext with message key `mk`.
     /// 
     /// # Arguments
     /// 
@@ -133,57 +190,89 @@ impl DoubleRatchet {
     /// 
     /// # Output
     /// 
-    /// * `(header, res)` ((PublicKey, u8, u8), (Vec\<u8\>, Vec\<u8\>)): Header and ciphertext
-    pub fn encrypt(&mut self, plaintext: &[u8], ad: &[u8]) -> ((PublicKey, u8, u8), (Vec<u8>, Vec<u8>)) {
-        let mk: [u8; 32];
-        (self.state.ck_s, mk) = self.kdf_ck(self.state.ck_s.unwrap());
-        let header: (PublicKey, u8, u8) = self.header(self.state.dh_s.as_ref().unwrap(), self.state.pn, self.state.n_s);
+    /// * `(header, res)` (Result\<((PublicKey, u32, u32), (Vec\<u8\>, Vec\<u8\>)), CryptoError\>): Header and ciphertext
+    pub fn encrypt(&mut self, plaintext: &[u8], ad: &[u8]) -> Result<EncryptedMessage, CryptoError> {
+        let ck_s: [u8; 32] = self.state.ck_s.ok_or(CryptoError::NotInitialized)?;
+        let dh_s: &(StaticSecret, PublicKey) = self.state.dh_s.as_ref().ok_or(CryptoError::NotInitialized)?;
+        let (new_ck_s, mk): (Option<[u8; 32]>, [u8; 32]) = self.kdf_ck(ck_s);
+        let header: (PublicKey, u32, u32) = self.header(dh_s, self.state.pn, self.state.n_s);
+        let res: (Vec<u8>, Vec<u8>) = self.state.aead.encrypt(mk, plaintext, &self.concat(ad, header), self.state.nonce_mode)?;
+        // The state only moves forward once the message has been encrypted
+        self.state.ck_s = new_ck_s;
         self.state.n_s += 1;
-        let res = match aead_encrypt(mk, plaintext, &self.concat(ad, header)) {
-            Ok((ciphertext, nonce)) => (ciphertext, nonce),
-            Err(error) => panic!("Error (AES-GCM-SIV): {:?}", error),
-        };
-        (header, res)
+        Ok((header, res))
     }
     
-    /// Returns the AEAD (AES-GCM-SIV-256) decryption of ciphertext with message key mk.
+    /// Returns the AEAD decryption of ciphertext with message key mk.
+    /// 
+    /// The decryption is transactional: if it fails, the state is left exactly as it was before the call.
     /// 
     /// # Arguments
     /// 
-    /// * `header` ((PublicKey, u8, u8)): Header
+    /// * `header` ((PublicKey, u32, u32)): Header
     /// * `ciphertext` (&\[u8\]): Ciphertext
     /// * `nonce` (Vec\<u8\>): Nonce
     /// * `ad` (&\[u8\]): Associated Data
     /// 
     /// # Output
     /// 
-    /// * `plaintext` (Vec\<u8\>): Plaintext
-    pub fn decrypt(&mut self, header: (PublicKey, u8, u8), ciphertext: Vec<u8>, nonce: Vec<u8>, ad: &[u8]) -> Vec<u8> {
-        let plaintext = self.try_skipped_message_keys(header, &ciphertext, &nonce, ad);
-        if plaintext.is_some() {
-            return plaintext.unwrap()
+    /// * `plaintext` (Result\<Vec\<u8\>, CryptoError\>): Plaintext
+    pub fn decrypt(&mut self, header: (PublicKey, u32, u32), ciphertext: Vec<u8>, nonce: Vec<u8>, ad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let backup: State = self.state.clone();
+        let res: Result<Vec<u8>, CryptoError> = self.ratchet_decrypt(header, &ciphertext, &nonce, ad);
+        if res.is_err() {
+            // Discard every change made to the state while processing the message
+            self.state = backup;
+        }
+        res
+    }
+
+    /// Decrypt the message and update the state *(the state may be partially updated on error)*
+    /// 
+    /// # Arguments
+    /// 
+    /// * `header` ((PublicKey, u32, u32)): Header
+    /// * `ciphertext` (&\[u8\]): Ciphertext
+    /// * `nonce` (&\[u8\]): Nonce
+    /// * `ad` (&\[u8\]): Associated Data
+    /// 
+    /// # Output
+    /// 
+    /// * `plaintext` (Result\<Vec\<u8\>, CryptoError\>): Plaintext
+    fn ratchet_decrypt(&mut self, header: (PublicKey, u32, u32), ciphertext: &[u8], nonce: &[u8], ad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        if self.state.rk.is_none() || self.state.dh_s.is_none() {
+            return Err(CryptoError::NotInitialized)
         }
-        if self.state.dh_r.is_none() || header.0 != self.state.dh_r.unwrap() {
-            self.skip_message_keys(header.1);
-            self.state.pn = self.state.n_s;
-            (self.state.n_s, self.state.n_r) = (0, 0);
-            self.state.dh_r = Some(header.0);
-            let (rk_result, ck_r_result) = self.kdf_rk(self.state.rk.unwrap(), self.dh(self.state.dh_s.as_ref().unwrap(), self.state.dh_r.unwrap()));
-            (self.state.rk, self.state.ck_r) = (Some(rk_result), Some(ck_r_result));
-            self.generate_dh(); // New dh_s
-            let (rk_result, ck_s_result) = self.kdf_rk(self.state.rk.unwrap(), self.dh(self.state.dh_s.as_ref().unwrap(), self.state.dh_r.unwrap()));
-            (self.state.rk, self.state.ck_s) = (Some(rk_result), Some(ck_s_result));
+        if let Some(plaintext) = self.try_skipped_message_keys(header, ciphertext, nonce, ad)? {
+            return Ok(plaintext)
         }
-        self.skip_message_keys(header.2);
+        if self.state.dh_r != Some(header.0) {
+            self.skip_message_keys(header.1)?;
+            self.dh_ratchet(header.0);
+        }
+        self.skip_message_keys(header.2)?;
         let mk: [u8; 32];
-        (self.state.ck_r, mk) = self.kdf_ck(self.state.ck_r.unwrap());
+        (self.state.ck_r, mk) = self.kdf_ck(self.state.ck_r.ok_or(CryptoError::NotInitialized)?);
         self.state.n_r += 1;
         
-        let res = match aead_decrypt(mk, &ciphertext, &nonce, &self.concat(ad, header)) {
-            Ok(plaintext) => plaintext,
-            Err(error) => panic!("Error (AES-GCM-SIV): {:?}", error),
-        };
-        res
+        self.state.aead.decrypt(mk, ciphertext, nonce, &self.concat(ad, header))
+    }
+
+    /// Applies a DH ratchet step with the new ratchet public key of the other party
+    /// 
+    /// # Arguments
+    /// 
+    /// * `dh_pub` (PublicKey): Ratchet public key received in the header
+    fn dh_ratchet(&mut self, dh_pub: PublicKey) {
+        self.state.pn = self.state.n_s;
+        (self.state.n_s, self.state.n_r) = (0, 0);
+        self.state.dh_r = Some(dh_pub);
+        self.state.mkskipped.advance_step(); // Expire the skipped message keys that are too old
+        let (rk_result, ck_r_result) = self.kdf_rk(self.state.rk.unwrap(), self.dh(self.state.dh_s.as_ref().unwrap(), dh_pub));
+        (self.state.rk, self.state.ck_r) = (Some(rk_result), Some(ck_r_result));
+        self.generate_dh(); // New dh_s
+        let (rk_result, ck_s_result) = self.kdf_rk(self.state.rk.unwrap(), self.dh(self.state.dh_s.as_ref().unwrap(), dh_pub));
+        (self.state.rk, self.state.ck_s) = (Some(rk_result), Some(ck_s_result));
     }
     
     /// Check if the message corresponds to a skipped message key. 
@@ -191,55 +280,52 @@ impl DoubleRatchet {
     /// If it's a skipped message, this function decrypts the message, deletes the message key, and return the plaintext.
     /// 
     /// # Arguments
-    /// * `header` ((PublicKey, u8, u8)): Header
-    /// * `ciphertext` (&Vec\<u8\>): Ciphertext
-    /// * `nonce` (&Vec\<u8\>): Nonce
+    /// * `header` ((PublicKey, u32, u32)): Header
+    /// * `ciphertext` (&\[u8\]): Ciphertext
+    /// * `nonce` (&\[u8\]): Nonce
     /// * `ad` (&\[u8\]): Associated Data
     /// 
     /// # Output
     /// 
-    /// `plaintext` (Option\<Vec\<u8\>\>): Optional plaintext
-    fn try_skipped_message_keys(&mut self, header: (PublicKey, u8, u8), ciphertext: &Vec<u8>, nonce: &Vec<u8>,  ad: &[u8]) -> Option<Vec<u8>> {
-        if let Some(mk) = self.state.mkskipped.remove(&(header.0, header.2)) {
-            let res = match aead_decrypt(mk, ciphertext, &nonce, &self.concat(ad, header)) {
-                Ok(plaintext) => plaintext,
-                Err(error) => panic!("Error (AES-GCM-SIV): {:?}", error),
-            };
-            return Some(res)
+    /// `plaintext` (Result\<Option\<Vec\<u8\>\>, CryptoError\>): Optional plaintext
+    fn try_skipped_message_keys(&mut self, header: (PublicKey, u32, u32), ciphertext: &[u8], nonce: &[u8],  ad: &[u8]) -> Result<Option<Vec<u8>>, CryptoError> {
+        if let Some(mk) = self.state.mkskipped.remove(&header.0, header.2) {
+            return self.state.aead.decrypt(mk, ciphertext, nonce, &self.concat(ad, header)).map(Some)
         }
-        None
+        Ok(None)
     }
     
     /// Stores any skipped message keys from the current receiving chain.
     /// 
     /// # Arguments
-    /// * `until` (u8)
-    fn skip_message_keys(&mut self, until: u8) -> () {
-        if self.state.n_r as u16 + MAX_SKIP < until as u16 {
-            panic!("Error: state.Nr + MAX_SKIP < until");
+    /// * `until` (u32)
+    fn skip_message_keys(&mut self, until: u32) -> Result<(), CryptoError> {
+        if self.state.n_r.saturating_add(MAX_SKIP) < until {
+            return Err(CryptoError::TooManySkippedMessages)
         }
-        if self.state.ck_r != None {
+        if self.state.ck_r.is_some() {
             while self.state.n_r < until {
                 let mk: [u8; 32];
                 (self.state.ck_r, mk) = self.kdf_ck(self.state.ck_r.unwrap());
-                self.state.mkskipped.insert((self.state.dh_r.unwrap(), self.state.n_r), mk);
+                self.state.mkskipped.insert(self.state.dh_r.unwrap(), self.state.n_r, mk);
                 self.state.n_r += 1;
             }
         }
+        Ok(())
     }
     
     /// Returns the output of applying a KDF keyed by a 32-byte chain key `ck` to some constant.
     /// 
     /// # Arguments
     /// 
-    /// * `dh_pair` (&(ReusableSecret, PublicKey)): Diffie-Hellman key pair
-    /// * `pn` (u8): Number of messages in previous sending chain
-    /// * `n` (u8): Message numbers for sending and receiving
+    /// * `dh_pair` (&(StaticSecret, PublicKey)): Diffie-Hellman key pair
+    /// * `pn` (u32): Number of messages in previous sending chain
+    /// * `n` (u32): Message numbers for sending and receiving
     /// 
     /// # Output
     /// 
-    /// * `header` ((PublicKey, u8, u8)): Header
-    fn header(&self, dh_pair: &(ReusableSecret, PublicKey), pn: u8, n: u8) -> (PublicKey, u8, u8) {
+    /// * `header` ((PublicKey, u32, u32)): Header
+    fn header(&self, dh_pair: &(StaticSecret, PublicKey), pn: u32, n: u32) -> (PublicKey, u32, u32) {
         (dh_pair.1, pn, n)
     }
      
@@ -248,16 +334,157 @@ impl DoubleRatchet {
     /// # Arguments
     /// 
     /// * `ad` (&\[u8\]): Associated Data
-    /// * `header` ((PublicKey, u8, u8)): Header
+    /// * `header` ((PublicKey, u32, u32)): Header
     /// 
     /// # Output
     /// 
     /// * `res` (Vec\<u8\>): Concatenation
-    fn concat(&self, ad: &[u8], header: (PublicKey, u8, u8)) -> Vec<u8> {
+    fn concat(&self, ad: &[u8], header: (PublicKey, u32, u32)) -> Vec<u8> {
         let public_key: &[u8; 32] = header.0.as_bytes();
-        let nb_messages_previous_chain: u8 = header.1;
-        let message_number: u8 = header.2;
+        let nb_messages_previous_chain: u32 = header.1;
+        let message_number: u32 = header.2;
 
         [ad, public_key, &nb_messages_previous_chain.to_be_bytes(), &message_number.to_be_bytes()].concat()
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::double_ratchet::skipped_keys::MAX_SKIPPED_AGE;
+
+    const SK: [u8; 32] = [0x42; 32];
+    const AD: &[u8] = b"associated data";
+
+    fn init_pair() -> (DoubleRatchet, DoubleRatchet) {
+        let bob_private_key: StaticSecret = StaticSecret::random_from_rng(OsRng);
+        let bob_public_key: PublicKey = PublicKey::from(&bob_private_key);
+        let mut alice: DoubleRatchet = DoubleRatchet::new();
+        let mut bob: DoubleRatchet = DoubleRatchet::new();
+        alice.init_sender(SK, bob_public_key);
+        bob.init_receiver(SK, (bob_private_key, bob_public_key));
+        (alice, bob)
+    }
+
+    #[test]
+    fn test_decrypt_forged_message_keeps_state() {
+        let (mut alice, mut bob) = init_pair();
+        let (header, (ciphertext, nonce)) = alice.encrypt(b"Message A1", AD).unwrap();
+        let mut forged_ciphertext: Vec<u8> = ciphertext.clone();
+        forged_ciphertext[0] ^= 0x01;
+
+        assert_eq!(bob.decrypt(header, forged_ciphertext, nonce.clone(), AD), Err(CryptoError::DecryptionError));
+        assert_eq!(bob.decrypt(header, ciphertext, nonce, AD), Ok(b"Message A1".to_vec()));
+    }
+
+    #[test]
+    fn test_decrypt_forged_skipped_message_keeps_key() {
+        let (mut alice, mut bob) = init_pair();
+        let (header_1, (ciphertext_1, nonce_1)) = alice.encrypt(b"Message A1", AD).unwrap();
+        let (header_2, (ciphertext_2, nonce_2)) = alice.encrypt(b"Message A2", AD).unwrap();
+
+        assert_eq!(bob.decrypt(header_2, ciphertext_2, nonce_2, AD), Ok(b"Message A2".to_vec()));
+        assert_eq!(bob.decrypt(header_1, ciphertext_1.clone(), nonce_1.clone(), b"wrong associated data"), Err(CryptoError::DecryptionError));
+        assert_eq!(bob.decrypt(header_1, ciphertext_1, nonce_1, AD), Ok(b"Message A1".to_vec()));
+    }
+
+    #[test]
+    fn test_derived_nonces() {
+        let (mut alice, mut bob) = init_pair();
+        alice.set_nonce_mode(NonceMode::Derived);
+        let (header_1, (ciphertext_1, nonce_1)) = alice.encrypt(b"Message A1", AD).unwrap();
+        let (header_2, (ciphertext_2, nonce_2)) = alice.encrypt(b"Message A2", AD).unwrap();
+        assert!(nonce_1.is_empty() && nonce_2.is_empty());
+
+        // Out of order, then a reply with random nonces: both modes are read by both parties
+        assert_eq!(bob.decrypt(header_2, ciphertext_2, nonce_2, AD), Ok(b"Message A2".to_vec()));
+        assert_eq!(bob.decrypt(header_1, ciphertext_1, nonce_1, AD), Ok(b"Message A1".to_vec()));
+        let (header, (ciphertext, nonce)) = bob.encrypt(b"Message B1", AD).unwrap();
+        assert_eq!(nonce.len(), 12);
+        assert_eq!(alice.decrypt(header, ciphertext, nonce, AD), Ok(b"Message B1".to_vec()));
+
+        // The mode is kept with the session
+        let mut restored_alice: DoubleRatchet = DoubleRatchet::from_bytes(&alice.to_bytes()).unwrap();
+        assert_eq!(restored_alice.get_nonce_mode(), NonceMode::Derived);
+        let (header, (ciphertext, nonce)) = restored_alice.encrypt(b"Message A3", AD).unwrap();
+        assert!(nonce.is_empty());
+        assert_eq!(bob.decrypt(header, ciphertext, nonce, AD), Ok(b"Message A3".to_vec()));
+    }
+
+    #[test]
+    fn test_not_initialized() {
+        let (mut alice, _) = init_pair();
+        let (header, (ciphertext, nonce)) = alice.encrypt(b"Message A1", AD).unwrap();
+        let mut double_ratchet: DoubleRatchet = DoubleRatchet::new();
+
+        assert!(matches!(double_ratchet.encrypt(b"Message", AD), Err(CryptoError::NotInitialized)));
+        assert_eq!(double_ratchet.decrypt(header, ciphertext, nonce, AD), Err(CryptoError::NotInitialized));
+    }
+
+    #[test]
+    fn test_more_than_256_messages_on_one_chain() {
+        let (mut alice, mut bob) = init_pair();
+        let mut messages: Vec<EncryptedMessage> = Vec::new();
+        for i in 0..300 {
+            messages.push(alice.encrypt(format!("Message A{}", i).as_bytes(), AD).unwrap());
+        }
+        assert_eq!(messages[299].0.2, 299);
+
+        // Deliver the last message first, then the rest of the chain from the skipped message keys
+        let (header, (ciphertext, nonce)) = messages.pop().unwrap();
+        assert_eq!(bob.decrypt(header, ciphertext, nonce, AD), Ok(b"Message A299".to_vec()));
+        for (i, (header, (ciphertext, nonce))) in messages.into_iter().enumerate() {
+            assert_eq!(bob.decrypt(header, ciphertext, nonce, AD), Ok(format!("Message A{}", i).into_bytes()));
+        }
+
+        // The reply still goes through a DH ratchet step on a long chain
+        let (header, (ciphertext, nonce)) = bob.encrypt(b"Message B1", AD).unwrap();
+        assert_eq!(alice.decrypt(header, ciphertext, nonce, AD), Ok(b"Message B1".to_vec()));
+    }
+
+    #[test]
+    fn test_too_many_skipped_messages() {
+        let (mut alice, mut bob) = init_pair();
+        let mut messages: Vec<EncryptedMessage> = Vec::new();
+        for i in 0..(MAX_SKIP + 2) {
+            messages.push(alice.encrypt(format!("Message A{}", i).as_bytes(), AD).unwrap());
+        }
+
+        let (header, (ciphertext, nonce)) = messages.pop().unwrap();
+        assert_eq!(bob.decrypt(header, ciphertext, nonce, AD), Err(CryptoError::TooManySkippedMessages));
+        let (header, (ciphertext, nonce)) = messages.remove(0);
+        assert_eq!(bob.decrypt(header, ciphertext, nonce, AD), Ok(b"Message A0".to_vec()));
+    }
+    #[test]
+    fn test_skipped_keys_expire_after_ratchet_steps() {
+        let (mut alice, mut bob) = init_pair();
+        let (delayed_header, (delayed_ciphertext, delayed_nonce)) = alice.encrypt(b"Message A0", AD).unwrap();
+        let (header, (ciphertext, nonce)) = alice.encrypt(b"Message A1", AD).unwrap();
+        bob.decrypt(header, ciphertext, nonce, AD).unwrap();
+        assert!(bob.get_skipped_keys().contains(&delayed_header.0, 0));
+
+        for i in 0..(MAX_SKIPPED_AGE + 1) {
+            assert!(bob.get_skipped_keys().contains(&delayed_header.0, 0), "Expired after {} ratchet steps", i);
+            let (header, (ciphertext, nonce)) = bob.encrypt(b"Ping", AD).unwrap();
+            alice.decrypt(header, ciphertext, nonce, AD).unwrap();
+            let (header, (ciphertext, nonce)) = alice.encrypt(b"Pong", AD).unwrap();
+            bob.decrypt(header, ciphertext, nonce, AD).unwrap();
+        }
+
+        assert!(bob.get_skipped_keys().is_empty());
+        assert_eq!(bob.decrypt(delayed_header, delayed_ciphertext, delayed_nonce, AD), Err(CryptoError::DecryptionError));
+    }
+
+    #[test]
+    fn test_purge_skipped_keys() {
+        let (mut alice, mut bob) = init_pair();
+        let (delayed_header, (delayed_ciphertext, delayed_nonce)) = alice.encrypt(b"Message A0", AD).unwrap();
+        let (header, (ciphertext, nonce)) = alice.encrypt(b"Message A1", AD).unwrap();
+        bob.decrypt(header, ciphertext, nonce, AD).unwrap();
+        assert_eq!(bob.get_skipped_keys().len(), 1);
+
+        bob.get_skipped_keys_mut().purge_chain(&delayed_header.0);
+        assert!(bob.get_skipped_keys().is_empty());
+        assert_eq!(bob.decrypt(delayed_header, delayed_ciphertext, delayed_nonce, AD), Err(CryptoError::DecryptionError));
+    }
 }
\ No newline at end of file
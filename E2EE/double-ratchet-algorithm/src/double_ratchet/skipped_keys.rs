@@ -0,0 +1,242 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+pub const MAX_SKIPPED_KEYS: usize = 2000; // Maximum number of skipped message keys stored for one session
+pub const MAX_SKIPPED_AGE: u32 = 10; // Number of DH ratchet steps a skipped message key is kept before being deleted
+
+#[derive(Clone, Debug, PartialEq)]
+struct SkippedKey {
+    mk: [u8; 32],
+    step: u32, // DH ratchet step at which the key was stored
+    sequence: u64, // Insertion order, used to evict the oldest key first
+}
+
+/// Store of the skipped-over message keys, indexed by chain (`K`) and message number
+///
+/// The store is bounded: once `max_keys` keys are stored, the oldest one is evicted to make room for a new one,
+/// and every key older than `max_age` DH ratchet steps is deleted when the ratchet moves forward.
+#[derive(Clone, Debug)]
+pub struct SkippedKeys<K> {
+    keys: HashMap<(K, u32), SkippedKey>,
+    order: BTreeMap<u64, (K, u32)>,
+    next_sequence: u64,
+    step: u32,
+    max_keys: usize,
+    max_age: u32,
+}
+
+impl<K: Copy + Eq + Hash> SkippedKeys<K> {
+    pub fn new() -> Self {
+        SkippedKeys::with_limits(MAX_SKIPPED_KEYS, MAX_SKIPPED_AGE)
+    }
+
+    /// Create an empty store with custom limits
+    ///
+    /// # Arguments
+    ///
+    /// * `max_keys` (usize): Maximum number of keys stored
+    /// * `max_age` (u32): Number of DH ratchet steps a key is kept
+    pub fn with_limits(max_keys: usize, max_age: u32) -> Self {
+        SkippedKeys {
+            keys: HashMap::new(),
+            order: BTreeMap::new(),
+            next_sequence: 0,
+            step: 0,
+            max_keys,
+            max_age,
+        }
+    }
+
+    /// Create an empty store at a given DH ratchet step *(used to restore a store)*
+    pub fn from_step(max_keys: usize, max_age: u32, step: u32) -> Self {
+        SkippedKeys { step, ..SkippedKeys::with_limits(max_keys, max_age) }
+    }
+
+    pub fn get_max_keys(&self) -> usize {
+        self.max_keys
+    }
+
+    pub fn get_max_age(&self) -> u32 {
+        self.max_age
+    }
+
+    /// Returns the current DH ratchet step of the store
+    pub fn get_step(&self) -> u32 {
+        self.step
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn contains(&self, chain: &K, n: u32) -> bool {
+        self.keys.contains_key(&(*chain, n))
+    }
+
+    /// Returns every stored key, from the oldest to the newest
+    ///
+    /// # Output
+    ///
+    /// * `entries` (Vec\<(K, u32, \[u8; 32\], u32)\>): (Chain, Message number, Message key, Age in DH ratchet steps)
+    pub fn entries(&self) -> Vec<(K, u32, [u8; 32], u32)> {
+        self.order.values()
+            .map(|(chain, n)| {
+                let skipped_key: &SkippedKey = &self.keys[&(*chain, *n)];
+                (*chain, *n, skipped_key.mk, self.step - skipped_key.step)
+            })
+            .collect()
+    }
+
+    /// Store a skipped message key, evicting the oldest keys if the store is full
+    ///
+    /// # Arguments
+    ///
+    /// * `chain` (K): Chain of the message
+    /// * `n` (u32): Message number
+    /// * `mk` (\[u8; 32\]): Message key
+    pub fn insert(&mut self, chain: K, n: u32, mk: [u8; 32]) {
+        self.insert_with_age(chain, n, mk, 0);
+    }
+
+    /// Store a skipped message key that was stored `age` DH ratchet steps ago *(used to restore a store)*
+    pub fn insert_with_age(&mut self, chain: K, n: u32, mk: [u8; 32], age: u32) {
+        if self.max_keys == 0 || age > self.max_age {
+            return
+        }
+        self.remove(&chain, n);
+        while self.keys.len() >= self.max_keys {
+            self.evict_oldest();
+        }
+
+        let sequence: u64 = self.next_sequence;
+        self.next_sequence += 1;
+        self.order.insert(sequence, (chain, n));
+        self.keys.insert((chain, n), SkippedKey { mk, step: self.step.saturating_sub(age), sequence });
+    }
+
+    /// Remove a skipped message key from the store and return it
+    ///
+    /// # Arguments
+    ///
+    /// * `chain` (&K): Chain of the message
+    /// * `n` (u32): Message number
+    ///
+    /// # Output
+    ///
+    /// * `mk` (Option\<\[u8; 32\]\>): Message key, if it was stored
+    pub fn remove(&mut self, chain: &K, n: u32) -> Option<[u8; 32]> {
+        let skipped_key: SkippedKey = self.keys.remove(&(*chain, n))?;
+        self.order.remove(&skipped_key.sequence);
+        Some(skipped_key.mk)
+    }
+
+    /// Move to the next DH ratchet step and delete the keys that became too old
+    pub fn advance_step(&mut self) {
+        self.step = self.step.saturating_add(1);
+        self.purge_older_than(self.max_age);
+    }
+
+    /// Delete every key stored more than `age` DH ratchet steps ago
+    pub fn purge_older_than(&mut self, age: u32) {
+        let step: u32 = self.step;
+        let expired: Vec<(K, u32)> = self.keys.iter()
+            .filter(|(_, skipped_key)| step - skipped_key.step > age)
+            .map(|(key, _)| *key)
+            .collect();
+        for (chain, n) in expired {
+            self.remove(&chain, n);
+        }
+    }
+
+    /// Delete every key of one chain
+    pub fn purge_chain(&mut self, chain: &K) {
+        let n_to_remove: Vec<u32> = self.keys.keys()
+            .filter(|(current_chain, _)| current_chain == chain)
+            .map(|(_, n)| *n)
+            .collect();
+        for n in n_to_remove {
+            self.remove(chain, n);
+        }
+    }
+
+    /// Delete every stored key
+    pub fn purge(&mut self) {
+        self.keys.clear();
+        self.order.clear();
+    }
+
+    fn evict_oldest(&mut self) {
+        if let Some((_, (chain, n))) = self.order.pop_first() {
+            self.keys.remove(&(chain, n));
+        }
+    }
+}
+
+impl<K: Copy + Eq + Hash> Default for SkippedKeys<K> {
+    fn default() -> Self {
+        SkippedKeys::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_oldest_key_is_evicted() {
+        let mut skipped_keys: SkippedKeys<u8> = SkippedKeys::with_limits(3, MAX_SKIPPED_AGE);
+        for n in 0..4 {
+            skipped_keys.insert(1, n, [n as u8; 32]);
+        }
+
+        assert_eq!(skipped_keys.len(), 3);
+        assert!(!skipped_keys.contains(&1, 0));
+        assert_eq!(skipped_keys.remove(&1, 3), Some([3; 32]));
+
+        // Removed keys do not count anymore when evicting
+        skipped_keys.insert(2, 0, [4; 32]);
+        skipped_keys.insert(2, 1, [5; 32]);
+        let expected_value: Vec<(u8, u32)> = vec![(1, 2), (2, 0), (2, 1)];
+        assert_eq!(skipped_keys.entries().iter().map(|(chain, n, _, _)| (*chain, *n)).collect::<Vec<(u8, u32)>>(), expected_value);
+    }
+
+    #[test]
+    fn test_keys_expire_by_ratchet_step() {
+        let mut skipped_keys: SkippedKeys<u8> = SkippedKeys::with_limits(MAX_SKIPPED_KEYS, 2);
+        skipped_keys.insert(1, 0, [0; 32]);
+        skipped_keys.advance_step();
+        skipped_keys.insert(2, 0, [1; 32]);
+        skipped_keys.advance_step();
+
+        assert_eq!(skipped_keys.len(), 2);
+        assert_eq!(skipped_keys.entries()[0].3, 2);
+
+        skipped_keys.advance_step();
+        assert!(!skipped_keys.contains(&1, 0));
+        assert!(skipped_keys.contains(&2, 0));
+    }
+
+    #[test]
+    fn test_purge() {
+        let mut skipped_keys: SkippedKeys<u8> = SkippedKeys::new();
+        skipped_keys.insert(1, 0, [0; 32]);
+        skipped_keys.insert(1, 1, [1; 32]);
+        skipped_keys.insert(2, 0, [2; 32]);
+        skipped_keys.advance_step();
+
+        skipped_keys.purge_chain(&1);
+        assert_eq!(skipped_keys.len(), 1);
+
+        skipped_keys.insert(1, 2, [3; 32]);
+        skipped_keys.purge_older_than(0);
+        assert!(skipped_keys.contains(&1, 2));
+        assert!(!skipped_keys.contains(&2, 0));
+
+        skipped_keys.purge();
+        assert!(skipped_keys.is_empty());
+    }
+}
\ No newline at end of file
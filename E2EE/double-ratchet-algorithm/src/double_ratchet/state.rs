@@ -1,23 +1,36 @@
-use std::collections::HashMap;
-use x25519_dalek::{ReusableSecret, PublicKey as PublicKey25519};
+use x25519_dalek::{StaticSecret, PublicKey as PublicKey25519};
+use crate::double_ratchet::aead::{AeadAlgorithm, CryptoError, NonceMode};
+use crate::double_ratchet::skipped_keys::SkippedKeys;
+
+const STATE_VERSION: u8 = 4;
 
 // split dh_s to two variable, because EphemeralSecret does not implement the Copy trait
 #[derive(Clone)]
 pub struct State {
-    pub dh_s: Option<(ReusableSecret, PublicKey25519)>, // DH Ratchet key pair (the "sending" or "self" ratchet key)
+    pub aead: AeadAlgorithm, // AEAD of the messages
+    pub nonce_mode: NonceMode, // Nonces of the messages sent
+    pub dh_s: Option<(StaticSecret, PublicKey25519)>, // DH Ratchet key pair (the "sending" or "self" ratchet key)
     pub dh_r: Option<PublicKey25519>, // DH Ratchet public key (the "received" or "remote" key)
     pub rk: Option<[u8; 32]>, // 32-byte Root Key
     pub ck_s: Option<[u8; 32]>, // 32-byte Chain Keys for sending
     pub ck_r: Option<[u8; 32]>, // 32-byte Chain Keys for receiving
-    pub n_s: u8, // Message numbers for sending
-    pub n_r: u8, // Message numbers for receiving
-    pub pn: u8, // Number of messages in previous sending chain
-    pub mkskipped: HashMap<(PublicKey25519, u8), [u8; 32]>, // Dictionary of skipped-over message keys, indexed by ratchet public key and message number.
+    pub n_s: u32, // Message numbers for sending
+    pub n_r: u32, // Message numbers for receiving
+    pub pn: u32, // Number of messages in previous sending chain
+    pub mkskipped: SkippedKeys<PublicKey25519>, // Bounded store of skipped-over message keys, indexed by ratchet public key and message number.
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl State {
     pub fn new() -> Self {
         State { 
+            aead: AeadAlgorithm::default(),
+            nonce_mode: NonceMode::default(),
             dh_s: None,
             dh_r: None, 
             rk: None, 
@@ -26,6 +39,115 @@ impl State {
             n_s: 0, 
             n_r: 0, 
             pn: 0, 
-            mkskipped: HashMap::new() }
+            mkskipped: SkippedKeys::new() }
+    }
+    /// Returns the serialization of the state *(contains every secret key of the session, seal it before storing it)*
+    /// 
+    /// `version (1) || aead (1) || nonce_mode (1) || dh_s || dh_r || rk || ck_s || ck_r || n_s (4) || n_r (4) || pn (4) || max_keys (4) || max_age (4) || step (4) || count (4) || mkskipped (count * 72)`
+    /// 
+    /// Skipped message keys are written from the oldest to the newest, each one followed by its age.
+    /// 
+    /// Each optional key is preceded by a presence flag.
+    /// 
+    /// # Output
+    /// 
+    /// * `bytes` (Vec\<u8\>): Serialized state
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = vec![STATE_VERSION, self.aead.to_byte(), self.nonce_mode.to_byte()];
+        write_key(&mut bytes, self.dh_s.as_ref().map(|(private_key, _)| private_key.to_bytes()));
+        write_key(&mut bytes, self.dh_r.map(|public_key| public_key.to_bytes()));
+        write_key(&mut bytes, self.rk);
+        write_key(&mut bytes, self.ck_s);
+        write_key(&mut bytes, self.ck_r);
+        bytes.extend_from_slice(&self.n_s.to_be_bytes());
+        bytes.extend_from_slice(&self.n_r.to_be_bytes());
+        bytes.extend_from_slice(&self.pn.to_be_bytes());
+        bytes.extend_from_slice(&(self.mkskipped.get_max_keys() as u32).to_be_bytes());
+        bytes.extend_from_slice(&self.mkskipped.get_max_age().to_be_bytes());
+        bytes.extend_from_slice(&self.mkskipped.get_step().to_be_bytes());
+        bytes.extend_from_slice(&(self.mkskipped.len() as u32).to_be_bytes());
+        for (public_key, n, mk, age) in self.mkskipped.entries() {
+            bytes.extend_from_slice(public_key.as_bytes());
+            bytes.extend_from_slice(&n.to_be_bytes());
+            bytes.extend_from_slice(&mk);
+            bytes.extend_from_slice(&age.to_be_bytes());
+        }
+        bytes
+    }
+
+    /// Restore a state from its serialization
+    /// 
+    /// # Arguments
+    /// 
+    /// * `bytes` (&\[u8\]): Serialized state
+    /// 
+    /// # Output
+    /// 
+    /// * `state` (Result\<State, CryptoError\>): Restored state
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Self, CryptoError> {
+        if read_array::<1>(&mut bytes)?[0] != STATE_VERSION {
+            return Err(CryptoError::InvalidSession)
+        }
+        let aead: AeadAlgorithm = AeadAlgorithm::from_byte(read_array::<1>(&mut bytes)?[0]).ok_or(CryptoError::InvalidSession)?;
+        let nonce_mode: NonceMode = NonceMode::from_byte(read_array::<1>(&mut bytes)?[0]).ok_or(CryptoError::InvalidSession)?;
+        let dh_s: Option<(StaticSecret, PublicKey25519)> = read_key(&mut bytes)?.map(|private_key| {
+            let private_key: StaticSecret = StaticSecret::from(private_key);
+            let public_key: PublicKey25519 = PublicKey25519::from(&private_key);
+            (private_key, public_key)
+        });
+        let dh_r: Option<PublicKey25519> = read_key(&mut bytes)?.map(PublicKey25519::from);
+        let rk: Option<[u8; 32]> = read_key(&mut bytes)?;
+        let ck_s: Option<[u8; 32]> = read_key(&mut bytes)?;
+        let ck_r: Option<[u8; 32]> = read_key(&mut bytes)?;
+        let n_s: u32 = u32::from_be_bytes(read_array(&mut bytes)?);
+        let n_r: u32 = u32::from_be_bytes(read_array(&mut bytes)?);
+        let pn: u32 = u32::from_be_bytes(read_array(&mut bytes)?);
+        let max_keys: u32 = u32::from_be_bytes(read_array(&mut bytes)?);
+        let max_age: u32 = u32::from_be_bytes(read_array(&mut bytes)?);
+        let step: u32 = u32::from_be_bytes(read_array(&mut bytes)?);
+        let count: u32 = u32::from_be_bytes(read_array(&mut bytes)?);
+        let mut mkskipped: SkippedKeys<PublicKey25519> = SkippedKeys::from_step(max_keys as usize, max_age, step);
+        for _ in 0..count {
+            let public_key: PublicKey25519 = PublicKey25519::from(read_array::<32>(&mut bytes)?);
+            let n: u32 = u32::from_be_bytes(read_array(&mut bytes)?);
+            let mk: [u8; 32] = read_array(&mut bytes)?;
+            let age: u32 = u32::from_be_bytes(read_array(&mut bytes)?);
+            mkskipped.insert_with_age(public_key, n, mk, age);
+        }
+        if !bytes.is_empty() {
+            return Err(CryptoError::InvalidSession)
+        }
+
+        Ok(State { aead, nonce_mode, dh_s, dh_r, rk, ck_s, ck_r, n_s, n_r, pn, mkskipped })
+    }
+}
+
+/// Append an optional 32-byte key to `bytes`, preceded by a presence flag
+fn write_key(bytes: &mut Vec<u8>, key: Option<[u8; 32]>) {
+    match key {
+        Some(key) => {
+            bytes.push(0x01);
+            bytes.extend_from_slice(&key);
+        },
+        None => bytes.push(0x00),
+    }
+}
+
+/// Read `N` bytes and move `bytes` forward
+fn read_array<const N: usize>(bytes: &mut &[u8]) -> Result<[u8; N], CryptoError> {
+    if bytes.len() < N {
+        return Err(CryptoError::InvalidSession)
+    }
+    let (data, rest) = bytes.split_at(N);
+    *bytes = rest;
+    Ok(data.try_into().expect("Incorrect length"))
+}
+
+/// Read an optional 32-byte key written by `write_key`
+fn read_key(bytes: &mut &[u8]) -> Result<Option<[u8; 32]>, CryptoError> {
+    match read_array::<1>(bytes)?[0] {
+        0x00 => Ok(None),
+        0x01 => Ok(Some(read_array(bytes)?)),
+        _ => Err(CryptoError::InvalidSession),
     }
 }
\ No newline at end of file
@@ -0,0 +1,2 @@
+pub mod communication;
+pub mod double_ratchet;
\ No newline at end of file
@@ -1,12 +1,9 @@
-mod communication;
-mod double_ratchet;
-mod x3dh;
-
-use communication::client::Client;
-use communication::server::Server;
+use double_ratchet_algorithm::communication::client::Client;
+use double_ratchet_algorithm::communication::server::{Server, SessionToken, PRIMARY_DEVICE_ID};
 use x25519_dalek::PublicKey;
+use x3dh::mlkem::KemCiphertext;
 
-use crate::communication::{key_collection::ServerKeyCollection, message::{Header, Ciphertext, Message}};
+use double_ratchet_algorithm::communication::{key_collection::{ServerKeyCollection, unix_time, SPK_ROTATION_PERIOD}, message::{Envelope, Header, Ciphertext, Message}};
 
 
 
@@ -16,60 +13,65 @@ fn main() {
     // User creation
     let mut alice: Client = Client::new("Alice".to_string());
     
-    server.add_user(alice.get_client_name(), alice.get_server_keys());
+    if let Err(error) = alice.register(&mut server) {
+        panic!("{}", error);
+    }
     
     let mut bob: Client = Client::new("Bob".to_string());
     
-    server.add_user(bob.get_client_name(), bob.get_server_keys());
+    if let Err(error) = bob.register(&mut server) {
+        panic!("{}", error);
+    }
+
+    // Sealed sender: the server only learns the receiver of the messages, the sender is certified by the server inside the encrypted envelope
+    alice.enable_sealed_sender(server.get_certificate_key());
+    bob.enable_sealed_sender(server.get_certificate_key());
     
     // Alice want to send a message to Bob
-    // Alice use X3DH to start the communication and use Double Ratchet to create the initial message
-    let (ek_pub, opk_used, header, ciphertext): (Option<PublicKey>, Option<PublicKey>, Header, Ciphertext);
-
-    if let Some(bob_username) = server.get_users(alice.get_client_name()).get(0) { // Gather all the users on the server and select the first one (in our case Bob)
-        let bob_keys: &ServerKeyCollection = match server.get_user_keys(bob_username) {
-            Ok(keys) => keys,
-            Err(error) => panic!("{}", error)
-        };
-        
-        ((ek_pub, opk_used), (header, ciphertext)) = match alice.send_message(bob_username, "Message A1".as_bytes(), bob_keys) {
-            Ok((None, (header_result, ciphertext_result))) => ((None, None), (header_result, ciphertext_result)),
-            Ok((Some((ek_pub_result, opk_used_result)), (header_result, ciphertext_result))) => ((Some(ek_pub_result), opk_used_result), (header_result, ciphertext_result)),
-            Err(error) => panic!("{}", error),
+    // Alice use X3DH to start the communication and use Double Ratchet to create the initial message, the relay gives her a prekey bundle of Bob with a one-time prekey that is removed from it
+    if let Some(bob_username) = server.get_users(alice.get_client_name()).first().cloned() { // Gather all the users on the server and select the first one (in our case Bob)
+        // Alice send the information to the server (possibility that Bob is offline)
+        if let Err(error) = alice.send_to(&mut server, &bob_username, "Message A1".as_bytes()) {
+            panic!("{}", error);
         }
     } else {
         panic!("No user in the server");
     }
 
-    // Alice send the information to the server (possibility that Bob is offline)
-    if let Err(error) = server.add_message_to(&"Bob".to_string(), Message::new(alice.get_client_name(), header, ciphertext, ek_pub, opk_used)) {
-        panic!("{}", error);
-    }
-    
-    // Bob want to read the message sent by Alice
-    // Ask the server for new messages
-    let new_messages: Vec<Message> =  match server.get_user_messages(&bob.get_client_name()) {
-        Ok(messages) => messages,
+    // Only Bob can change his keys: he logs in by signing a challenge of the server with his identity key
+    let bob_session: SessionToken = match bob.login(&mut server) {
+        Ok(session) => session,
         Err(error) => panic!("{}", error),
     };
 
-    //observe_double_ratchet(&new_messages);
-
-    // Ask Alice X3DH public keys
-    let alice_keys: &ServerKeyCollection = match server.get_user_keys(&"Alice".to_string()) {
-        Ok(keys) => keys,
-        Err(error) => panic!("{}", error),
-    };
+    // A week later, the scheduled rotation replaces Bob's signed prekey while Alice's first message is still on the server (it's still accepted during the grace period)
+    if let Some((new_spk_id, new_spk, new_signature)) = bob.rotate_spk_if_due(unix_time() + SPK_ROTATION_PERIOD) {
+        if let Err(error) = server.update_user_spk(&bob.get_client_name(), &bob_session, new_spk_id, new_spk, new_signature) {
+            panic!("{}", error);
+        }
+    }
+    // The ML-KEM prekey follows the same schedule
+    if let Some((new_pqspk_id, new_pqspk, new_pq_signature)) = bob.rotate_pqspk_if_due(unix_time() + SPK_ROTATION_PERIOD) {
+        if let Err(error) = server.update_user_pqspk(&bob.get_client_name(), &bob_session, new_pqspk_id, new_pqspk, new_pq_signature) {
+            panic!("{}", error);
+        }
+    }
+    
+    // Bob want to read the message sent by Alice
+    // Ask the server for new messages, the identity key of Alice is fetched to start the session
+    read_messages(&mut server, &mut bob);
 
-    // Read the message(s) sent by Alice
-    match bob.read_messages(&"Alice".to_string(), Some(alice_keys.get_ik()), new_messages.clone()) {
-        Ok(res) => {
-            for plaintext in res {
-                println!("Bob messages:");
-                println!("Sent by Alice: {}", String::from_utf8_lossy(&plaintext));
+    // Bob checks how many one-time prekeys are left on the server and uploads a new batch if needed
+    if let Ok(true) = server.has_low_opk_stock(&bob.get_client_name(), bob.get_device_id()) {
+        let opk_count: usize = match server.get_opk_count(&bob.get_client_name(), bob.get_device_id()) {
+            Ok(opk_count) => opk_count,
+            Err(error) => panic!("{}", error),
+        };
+        if let Some(new_opks) = bob.replenish_opks(opk_count) {
+            if let Err(error) = server.add_user_opks(&bob.get_client_name(), &bob_session, new_opks) {
+                panic!("{}", error);
             }
-        },
-        Err(error) => panic!("{}", error),
+        }
     }
 
     // (After initialization) Simulation of a conversation (Base on the signal example: https://signal.org/docs/specifications/doubleratchet/#double-ratchet AND https://signal.org/docs/specifications/doubleratchet/#out-of-order-messages)
@@ -78,94 +80,104 @@ fn main() {
     send_message(&mut server, &mut bob, "Alice".to_string(), "Message B1");
     simulate_out_of_order_message(&mut server, &mut bob, "Alice".to_string(), "Message B2", &mut out_of_order_messages);
 
-    read_messages(&mut server, &mut alice, &bob.get_client_name());
+    read_messages(&mut server, &mut alice);
 
     send_message(&mut server, &mut alice, "Bob".to_string(),"Message A2");
     send_message(&mut server, &mut alice, "Bob".to_string(),"Message A3");
     send_message(&mut server, &mut alice, "Bob".to_string(),"Message A4");
 
-    read_messages(&mut server, &mut bob, &alice.get_client_name());
+    read_messages(&mut server, &mut bob);
 
     simulate_out_of_order_message(&mut server, &mut bob, "Alice".to_string(), "Message B3", &mut out_of_order_messages);
     send_message(&mut server, &mut bob, "Alice".to_string(), "Message B4");
 
-    read_messages(&mut server, &mut alice, &bob.get_client_name());
+    read_messages(&mut server, &mut alice);
+
+    // Alice restarts her client: the session with Bob (including the keys of the messages still missing) is sealed and restored
+    // Her new client has new keys, she replaces the old ones on the server with a session opened before the restart
+    let alice_session: SessionToken = match alice.login(&mut server) {
+        Ok(session) => session,
+        Err(error) => panic!("{}", error),
+    };
+    let storage_key: [u8; 32] = [0x01; 32];
+    let sealed_session: Vec<u8> = match alice.export_session(&bob.get_client_name(), bob.get_device_id(), storage_key) {
+        Ok(sealed_session) => sealed_session,
+        Err(error) => panic!("{}", error),
+    };
+    alice = Client::new("Alice".to_string());
+    if let Err(error) = alice.import_session(&bob.get_client_name(), bob.get_device_id(), &sealed_session, storage_key) {
+        panic!("{}", error);
+    }
+    if let Err(error) = server.replace_user_keys(&alice.get_client_name(), &alice_session, alice.get_server_keys()) {
+        panic!("{}", error);
+    }
+    alice.enable_sealed_sender(server.get_certificate_key());
 
     send_message(&mut server, &mut alice, "Bob".to_string(), "Message A5");
     for ooom in out_of_order_messages {
         send_out_of_order_message(&mut server, &ooom.0, ooom.1);
     }
     
-    read_messages(&mut server, &mut bob, &alice.get_client_name());
+    read_messages(&mut server, &mut bob);
 
-    read_messages(&mut server, &mut alice, &bob.get_client_name());
+    read_messages(&mut server, &mut alice);
 
 }
 
-fn simulate_out_of_order_message(current_server: &mut Server, current_sender: &mut Client, receiver_name: String, message: &str, out_of_order_bundle: &mut Vec<(String, Message)>) -> () {
-    let (ek_pub, opk_used, header, ciphertext) = create_message(current_server, current_sender, message);
-    out_of_order_bundle.push((receiver_name, Message::new(current_sender.get_client_name(), header, ciphertext, ek_pub, opk_used)));
+// (ek, spk id, opk used, KEM ciphertext, header, ciphertext) of a message created for the simulation
+type CreatedMessage = (Option<PublicKey>, Option<u32>, Option<PublicKey>, Option<(u32, KemCiphertext)>, Header, Ciphertext);
+
+fn simulate_out_of_order_message(current_server: &mut Server, current_sender: &mut Client, receiver_name: String, message: &str, out_of_order_bundle: &mut Vec<(String, Message)>) {
+    let (ek_pub, spk_id, opk_used, kem_ciphertext, header, ciphertext) = create_message(current_server, current_sender, message);
+    out_of_order_bundle.push((receiver_name, Message::new((current_sender.get_client_name(), current_sender.get_device_id()), (header, ciphertext), ek_pub, spk_id, opk_used, kem_ciphertext, ek_pub.map(|_| current_sender.get_aead()))));
 }
 
-fn create_message(current_server: &mut Server, current_sender: &mut Client, message: &str) -> (Option<PublicKey>, Option<PublicKey>, Header, Ciphertext) {
+fn create_message(current_server: &mut Server, current_sender: &mut Client, message: &str) -> CreatedMessage {
     // Encrypt the message (Double ratchet and AES-GCM-SIV)
-    let (ek_pub, opk_used, header, ciphertext): (Option<PublicKey>, Option<PublicKey>, Header, Ciphertext);
-    if let Some(receiver) = current_server.get_users(current_sender.get_client_name()).get(0) { // Gather all the users on the server and select the first one (in our case Bob)
-        let bob_keys: &ServerKeyCollection = match current_server.get_user_keys(receiver) {
+    let (ek_pub, spk_id, opk_used, kem_ciphertext, header, ciphertext): CreatedMessage;
+    if let Some(receiver) = current_server.get_users(current_sender.get_client_name()).first() { // Gather all the users on the server and select the first one (in our case Bob)
+        // The session already exists, so the one-time prekeys don't need to be fetched
+        let bob_keys: &ServerKeyCollection = match current_server.get_user_keys(receiver, PRIMARY_DEVICE_ID) {
             Ok(keys) => keys,
             Err(error) => panic!("{}", error)
         };
         
-        ((ek_pub, opk_used), (header, ciphertext)) = match current_sender.send_message(receiver, message.as_bytes(), bob_keys) {
-            Ok((None, (header_result, ciphertext_result))) => ((None, None), (header_result, ciphertext_result)),
-            Ok((Some((ek_pub_result, opk_used_result)), (header_result, ciphertext_result))) => ((Some(ek_pub_result), opk_used_result), (header_result, ciphertext_result)),
+        ((ek_pub, spk_id, opk_used, kem_ciphertext), (header, ciphertext)) = match current_sender.send_message(receiver, PRIMARY_DEVICE_ID, message.as_bytes(), bob_keys) {
+            Ok((None, (header_result, ciphertext_result))) => ((None, None, None, None), (header_result, ciphertext_result)),
+            Ok((Some((ek_pub_result, spk_id_result, opk_used_result, kem_ciphertext_result)), (header_result, ciphertext_result))) => ((Some(ek_pub_result), Some(spk_id_result), opk_used_result, kem_ciphertext_result), (header_result, ciphertext_result)),
             Err(error) => panic!("{}", error),
         };
 
-        return (ek_pub, opk_used, header, ciphertext)
+        (ek_pub, spk_id, opk_used, kem_ciphertext, header, ciphertext)
     } else {
         panic!("No user in the server");
     }
 }
 
-fn send_out_of_order_message(current_server: &mut Server, receiver_name: &String, message: Message) -> () {
-    if let Err(error) = current_server.add_message_to(&receiver_name, message) {
+fn send_out_of_order_message(current_server: &mut Server, receiver_name: &str, message: Message) {
+    // Queued without sealed sender
+    if let Err(error) = current_server.add_message_to(receiver_name, PRIMARY_DEVICE_ID, Envelope::Plain(Box::new(message))) {
         panic!("{}", error);
     }
 }
 
-fn send_message(current_server: &mut Server, current_sender: &mut Client, receiver_name: String, message: &str) -> () {
-    // Encrypt the message (Double ratchet and AES-GCM-SIV)
-    let (ek_pub, opk_used, header, ciphertext) = create_message(current_server, current_sender, message);
-    if let Err(error) = current_server.add_message_to(&receiver_name, Message::new(current_sender.get_client_name(), header, ciphertext, ek_pub, opk_used)) {
+fn send_message(current_server: &mut Server, current_sender: &mut Client, receiver_name: String, message: &str) {
+    // Encrypt the message (Double ratchet and AES-GCM-SIV) and queue it on the server
+    if let Err(error) = current_sender.send_to(current_server, &receiver_name, message.as_bytes()) {
         panic!("{}", error);
     }
 }
 
-fn read_messages(current_server: &mut Server, current_receiver: &mut Client, sender_name: &String) -> () {
+fn read_messages(current_server: &mut Server, current_receiver: &mut Client) {
     println!("===============================================");
     println!("{} messages:", current_receiver.get_client_name());
-    // Ask the server for new messages
-    let new_messages: Vec<Message> =  match current_server.get_user_messages(&current_receiver.get_client_name()) {
-        Ok(messages) => messages,
-        Err(error) => panic!("{}", error),
-    };
-
-    //observe_double_ratchet(&new_messages);
-
-    // Read the message(s) sent by Alice
-    match current_receiver.read_messages(sender_name, None, new_messages.clone()) {
+    // Ask the server for new messages and read them
+    match current_receiver.poll(current_server) {
         Ok(res) => {
-            for plaintext in res {
+            for (sender_name, plaintext) in res {
                 println!("- Sent by {}: {}", sender_name, String::from_utf8_lossy(&plaintext));
             }
         },
         Err(error) => panic!("{}", error),
     }
-}
-
-fn observe_double_ratchet(messages: &Vec<Message>) -> () {
-    println!("*********************");
-    println!("{:?}", messages);
-    println!("*********************");
 }
\ No newline at end of file
@@ -0,0 +1,83 @@
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use double_ratchet_algorithm::double_ratchet::aead::NonceMode;
+use double_ratchet_algorithm::double_ratchet::double_ratchet::{DoubleRatchetHE, EncryptedMessage};
+use rand_core::OsRng;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+const SK: [u8; 32] = [0x42; 32];
+const SHARED_HK: [u8; 32] = [0x43; 32];
+const SHARED_NHK: [u8; 32] = [0x44; 32];
+const AD: &[u8] = b"associated data";
+
+/// Returns Alice and Bob, Bob having received a first message from Alice
+fn init_pair(nonce_mode: NonceMode) -> (DoubleRatchetHE, DoubleRatchetHE) {
+    let bob_private_key: StaticSecret = StaticSecret::random_from_rng(OsRng);
+    let bob_public_key: PublicKey = PublicKey::from(&bob_private_key);
+    let mut alice: DoubleRatchetHE = DoubleRatchetHE::new();
+    let mut bob: DoubleRatchetHE = DoubleRatchetHE::new();
+    alice.init_sender_he(SK, bob_public_key, SHARED_HK, SHARED_NHK);
+    bob.init_receiver_he(SK, (bob_private_key, bob_public_key), SHARED_HK, SHARED_NHK);
+    alice.set_nonce_mode(nonce_mode);
+    bob.set_nonce_mode(nonce_mode);
+
+    let ((header, header_nonce), (ciphertext, nonce)) = alice.encrypt_he(b"first", AD).unwrap();
+    bob.decrypt_he((header, header_nonce), ciphertext, nonce, AD).unwrap();
+    (alice, bob)
+}
+
+/// Returns Bob and a message sent by Alice after `skipped` messages that Bob hasn't received
+fn same_chain(nonce_mode: NonceMode, skipped: u32) -> (DoubleRatchetHE, EncryptedMessage) {
+    let (mut alice, bob) = init_pair(nonce_mode);
+    for _ in 0..skipped {
+        alice.encrypt_he(b"skipped", AD).unwrap();
+    }
+    (bob, alice.encrypt_he(b"delivered", AD).unwrap())
+}
+
+/// Returns Alice and the first message of Bob's reply chain *(Alice applies a DH ratchet step)*
+fn dh_ratchet_step(nonce_mode: NonceMode) -> (DoubleRatchetHE, EncryptedMessage) {
+    let (alice, mut bob) = init_pair(nonce_mode);
+    (alice, bob.encrypt_he(b"reply", AD).unwrap())
+}
+
+/// Returns Bob and a message whose header was forged *(decrypts under no header key)*
+fn forged_header(nonce_mode: NonceMode) -> (DoubleRatchetHE, EncryptedMessage) {
+    let (bob, ((mut header, header_nonce), message)) = same_chain(nonce_mode, 0);
+    header[0] ^= 0x01;
+    (bob, ((header, header_nonce), message))
+}
+
+fn bench_decrypt(c: &mut Criterion, name: &str, cases: Vec<(String, (DoubleRatchetHE, EncryptedMessage))>, expect_ok: bool) {
+    let mut group = c.benchmark_group(name);
+    for (id, (receiver, message)) in cases {
+        group.bench_with_input(BenchmarkId::from_parameter(id), &(receiver, message), |b, (receiver, message)| {
+            b.iter_batched_ref(
+                || (receiver.clone(), message.clone()),
+                |(receiver, ((header, header_nonce), (ciphertext, nonce)))| {
+                    let res = receiver.decrypt_he((header.clone(), header_nonce.clone()), ciphertext.clone(), nonce.clone(), AD);
+                    assert_eq!(res.is_ok(), expect_ok);
+                    black_box(res)
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+/// Without nonce, the header nonce is derived from the ciphertext: one trial decryption per header key, like with random nonces
+fn bench_decrypt_message(c: &mut Criterion) {
+    let nonce_modes: [NonceMode; 2] = [NonceMode::Random, NonceMode::Derived];
+    bench_decrypt(c, "decrypt message", nonce_modes.iter()
+        .flat_map(|nonce_mode| [0, 100, 1000].map(|skipped| (format!("{:?}/{}", nonce_mode, skipped), same_chain(*nonce_mode, skipped))))
+        .collect(), true);
+    bench_decrypt(c, "decrypt after DH ratchet step", nonce_modes.iter()
+        .map(|nonce_mode| (format!("{:?}", nonce_mode), dh_ratchet_step(*nonce_mode)))
+        .collect(), true);
+    bench_decrypt(c, "reject forged header", nonce_modes.iter()
+        .map(|nonce_mode| (format!("{:?}", nonce_mode), forged_header(*nonce_mode)))
+        .collect(), false);
+}
+
+criterion_group!(benches, bench_decrypt_message);
+criterion_main!(benches);
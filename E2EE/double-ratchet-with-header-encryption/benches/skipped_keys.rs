@@ -0,0 +1,56 @@
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use double_ratchet_algorithm::double_ratchet::double_ratchet::{DoubleRatchetHE, EncryptedMessage};
+use rand_core::OsRng;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+const SK: [u8; 32] = [0x42; 32];
+const SHARED_HK: [u8; 32] = [0x43; 32];
+const SHARED_NHK: [u8; 32] = [0x44; 32];
+const AD: &[u8] = b"associated data";
+const KEYS_PER_CHAIN: u32 = 500;
+
+/// Returns Bob holding `skipped_keys` skipped message keys (spread over chains of `KEYS_PER_CHAIN` keys), and the last message skipped
+fn setup(skipped_keys: u32) -> (DoubleRatchetHE, EncryptedMessage) {
+    let bob_private_key: StaticSecret = StaticSecret::random_from_rng(OsRng);
+    let bob_public_key: PublicKey = PublicKey::from(&bob_private_key);
+    let mut alice: DoubleRatchetHE = DoubleRatchetHE::new();
+    let mut bob: DoubleRatchetHE = DoubleRatchetHE::new();
+    alice.init_sender_he(SK, bob_public_key, SHARED_HK, SHARED_NHK);
+    bob.init_receiver_he(SK, (bob_private_key, bob_public_key), SHARED_HK, SHARED_NHK);
+
+    let mut last_skipped: Option<EncryptedMessage> = None;
+    for _ in 0..(skipped_keys / KEYS_PER_CHAIN) {
+        for _ in 0..KEYS_PER_CHAIN {
+            last_skipped = Some(alice.encrypt_he(b"skipped", AD).unwrap());
+        }
+        let (enc_header, (ciphertext, nonce)) = alice.encrypt_he(b"delivered", AD).unwrap();
+        bob.decrypt_he(enc_header, ciphertext, nonce, AD).unwrap();
+
+        // Reply so that Alice moves to a new chain (and a new header key)
+        let (enc_header, (ciphertext, nonce)) = bob.encrypt_he(b"reply", AD).unwrap();
+        alice.decrypt_he(enc_header, ciphertext, nonce, AD).unwrap();
+    }
+    assert_eq!(bob.get_skipped_keys().len(), skipped_keys as usize);
+
+    (bob, last_skipped.unwrap())
+}
+
+fn bench_skipped_message(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decrypt skipped message");
+    for skipped_keys in [500, 1000, 2000] {
+        let (bob, message) = setup(skipped_keys);
+        group.bench_with_input(BenchmarkId::from_parameter(skipped_keys), &(bob, message), |b, (bob, message)| {
+            b.iter_batched_ref(
+                || (bob.clone(), message.clone()),
+                |(bob, ((header, header_nonce), (ciphertext, nonce)))| {
+                    black_box(bob.decrypt_he((header.clone(), header_nonce.clone()), ciphertext.clone(), nonce.clone(), AD).unwrap())
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_skipped_message);
+criterion_main!(benches);
\ No newline at end of file
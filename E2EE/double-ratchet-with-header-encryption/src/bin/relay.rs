@@ -0,0 +1,61 @@
+//! Standalone relay storing the prekey bundles and the queued messages of the users
+//!
+//! Usage: `relay tcp <address> [data directory]` *(e.g. `relay tcp 127.0.0.1:7878 relay-data`)* or `relay unix <path> [data directory]`
+//!
+//! Without a data directory, the keys and the messages are lost when the relay stops.
+
+use double_ratchet_algorithm::communication::server::Server;
+use double_ratchet_algorithm::communication::transport::serve_tcp;
+#[cfg(unix)]
+use double_ratchet_algorithm::communication::transport::serve_unix;
+use std::env;
+use std::io::{self, Write};
+use std::net::TcpListener;
+use std::path::Path;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+use std::process;
+use std::sync::{Arc, RwLock};
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let server: Server = match args.get(3) {
+        Some(directory) => match Server::open(Path::new(directory)) {
+            Ok(server) => server,
+            Err(error) => {
+                eprintln!("Can't open the data directory: {}", error);
+                process::exit(1);
+            },
+        },
+        None => Server::new(),
+    };
+    let server: Arc<RwLock<Server>> = Arc::new(RwLock::new(server));
+
+    let result: io::Result<()> = match (args.get(1).map(String::as_str), args.get(2)) {
+        (Some("tcp"), Some(address)) => TcpListener::bind(address).and_then(|listener| {
+            // The address is printed so that the port can be chosen by the system (port 0)
+            announce(&format!("tcp {}", listener.local_addr()?))?;
+            serve_tcp(listener, server)
+        }),
+        #[cfg(unix)]
+        (Some("unix"), Some(path)) => UnixListener::bind(path).and_then(|listener| {
+            announce(&format!("unix {}", path))?;
+            serve_unix(listener, server)
+        }),
+        _ => {
+            eprintln!("Usage: {} tcp <address> [data directory] | unix <path> [data directory]", args.first().map(String::as_str).unwrap_or("relay"));
+            process::exit(2);
+        },
+    };
+
+    if let Err(error) = result {
+        eprintln!("Relay stopped: {}", error);
+        process::exit(1);
+    }
+}
+
+fn announce(address: &str) -> io::Result<()> {
+    let mut stdout = io::stdout();
+    writeln!(stdout, "Relay listening on {}", address)?;
+    stdout.flush()
+}
@@ -0,0 +1,653 @@
+//! Group messaging with sender keys *(based on Signal: https://signal.org/blog/private-groups/)*
+//!
+//! Each device of a member has its own sender key: a chain key for a symmetric ratchet and a key signing its group messages.
+//! The sender keys are distributed over the pairwise double ratchet sessions *(`Content::SenderKey`)*, a group message is then encrypted once for all the members.
+//!
+//! - Symmetric ratchet: `mk = HMAC(ck, 0x01)`, `ck = HMAC(ck, 0x02)`
+//! - Every membership change drops the sender keys, a new one is distributed before the next group message *(a member that left can't read the next messages)*
+//! - Only the admins *(the creator, then the members it makes admin)* change the members, the other members only distribute their sender keys
+//! - The previous sender key of a device is kept for `SENDER_KEY_GRACE_PERIOD` to read the group messages still in flight
+
+use std::collections::HashMap;
+use std::fmt;
+use hmac::{Hmac, Mac};
+use rand_core::{OsRng, RngCore};
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+use x3dh::{xeddsa_sign, xeddsa_verify, Signature};
+
+use crate::double_ratchet::aead::{self, CryptoError};
+use crate::double_ratchet::skipped_keys::{SkippedKeys, MAX_SKIPPED_KEYS};
+use super::message::{write_bytes, ParseError, Reader};
+use super::server::DeviceId;
+
+pub type GroupId = [u8; 16];
+
+pub const GROUP_WIRE_VERSION: u8 = 0x81; // Distinct from the versions of `Message` and `SealedMessage`, so that they can all be queued on the relay
+const BYTE_MESSAGE_KEY: &[u8] = &[0x01];
+const BYTE_NEXT_CHAIN_KEY: &[u8] = &[0x02];
+pub const SENDER_KEY_GRACE_PERIOD: u64 = 24 * 60 * 60; // Time (in seconds) a replaced sender key is kept to read the group messages in flight
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, PartialEq)]
+pub enum GroupError {
+    Crypto(CryptoError),
+    Parse(ParseError),
+    GroupNotFound,
+    SenderKeyNotFound,
+    InvalidSignature,
+    DuplicateMessage,
+    NotAdmin,
+    MemberNotFound,
+    IterationOverflow,
+}
+
+/// Sender key of a device, sent to the other members over the pairwise sessions
+#[derive(Clone, Debug, PartialEq)]
+pub struct SenderKeyDistribution {
+    group_id: GroupId,
+    members: Vec<String>, // Members of the group known by the sender
+    admins: Vec<String>, // Members allowed to change the members
+    key_id: u32,
+    iteration: u32, // Number of the next message of the chain
+    chain_key: [u8; 32],
+    signing_key: PublicKey,
+}
+
+/// Message encrypted once for all the members of a group
+#[derive(Clone, Debug, PartialEq)]
+pub struct GroupMessage {
+    group_id: GroupId,
+    username: String,
+    device_id: DeviceId, // Device of the sender
+    key_id: u32,
+    iteration: u32,
+    ciphertext: Vec<u8>, // Nonce followed by the ciphertext
+    signature: Signature,
+}
+
+/// Sender key of the device running the client
+struct SenderKey {
+    key_id: u32,
+    iteration: u32,
+    chain_key: [u8; 32],
+    signing_key: StaticSecret,
+}
+
+/// Sender key received from another device
+struct ReceivedSenderKey {
+    key_id: u32,
+    iteration: u32,
+    chain_key: [u8; 32],
+    signing_key: PublicKey,
+    skipped_keys: SkippedKeys<u32>, // Message keys of the messages not received yet (Chain: key id)
+}
+
+/// Group as seen by one device
+pub struct Group {
+    group_id: GroupId,
+    members: Vec<String>, // Sorted names of the members, the user of the client included
+    admins: Vec<String>, // Sorted names of the members allowed to change the members
+    sender_key: Option<SenderKey>, // None until a new sender key has been distributed
+    next_key_id: u32,
+    sender_keys: HashMap<(String, DeviceId), ReceivedSenderKey>,
+    previous_sender_keys: HashMap<(String, DeviceId), (ReceivedSenderKey, u64)>, // (Replaced sender key, time it was replaced at)
+}
+
+impl SenderKeyDistribution {
+    pub fn get_group_id(&self) -> GroupId {
+        self.group_id
+    }
+
+    pub fn get_members(&self) -> Vec<String> {
+        self.members.clone()
+    }
+
+    pub fn get_admins(&self) -> Vec<String> {
+        self.admins.clone()
+    }
+
+    pub fn get_key_id(&self) -> u32 {
+        self.key_id
+    }
+
+    /// Returns the encoding of the distribution: `group_id (16) || members count (4) || (member (4 + len))* || admins count (4) || (admin (4 + len))* || key_id (4) || iteration (4) || chain_key (32) || signing_key (32)`
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ParseError> {
+        let mut bytes: Vec<u8> = self.group_id.to_vec();
+        write_names(&mut bytes, &self.members)?;
+        write_names(&mut bytes, &self.admins)?;
+        bytes.extend_from_slice(&self.key_id.to_be_bytes());
+        bytes.extend_from_slice(&self.iteration.to_be_bytes());
+        bytes.extend_from_slice(&self.chain_key);
+        bytes.extend_from_slice(self.signing_key.as_bytes());
+        Ok(bytes)
+    }
+
+    /// Parse a distribution from its encoding
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ParseError> {
+        let mut reader: Reader = Reader::new(bytes);
+        let group_id: GroupId = reader.read_array::<16>()?;
+        let members: Vec<String> = read_names(&mut reader)?;
+        let admins: Vec<String> = read_names(&mut reader)?;
+        let key_id: u32 = u32::from_be_bytes(reader.read_array::<4>()?);
+        let iteration: u32 = u32::from_be_bytes(reader.read_array::<4>()?);
+        let chain_key: [u8; 32] = reader.read_array::<32>()?;
+        let signing_key: PublicKey = PublicKey::from(reader.read_array::<32>()?);
+        reader.finish()?;
+
+        Ok(SenderKeyDistribution { group_id, members, admins, key_id, iteration, chain_key, signing_key })
+    }
+}
+
+impl GroupMessage {
+    pub fn get_group_id(&self) -> GroupId {
+        self.group_id
+    }
+
+    pub fn get_username(&self) -> String {
+        self.username.clone()
+    }
+
+    pub fn get_device_id(&self) -> DeviceId {
+        self.device_id
+    }
+
+    pub fn get_key_id(&self) -> u32 {
+        self.key_id
+    }
+
+    pub fn get_iteration(&self) -> u32 {
+        self.iteration
+    }
+
+    /// Returns the wire encoding of the message: `version (1) || group_id (16) || username (4 + len) || device_id (4) || key_id (4) || iteration (4) || ciphertext (4 + len) || signature (64)`
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ParseError> {
+        let mut bytes: Vec<u8> = group_message_header(&self.group_id, &self.username, self.device_id, self.key_id, self.iteration)?;
+        write_bytes(&mut bytes, &self.ciphertext)?;
+        bytes.extend_from_slice(&self.signature);
+        Ok(bytes)
+    }
+
+    /// Parse a group message from its wire encoding
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ParseError> {
+        let mut reader: Reader = Reader::new(bytes);
+        let version: u8 = reader.read_u8()?;
+        if version != GROUP_WIRE_VERSION {
+            return Err(ParseError::UnsupportedVersion(version))
+        }
+        let group_id: GroupId = reader.read_array::<16>()?;
+        let username: String = String::from_utf8(reader.read_bytes()?.to_vec())
+            .map_err(|_| ParseError::InvalidUsername)?;
+        let device_id: DeviceId = u32::from_be_bytes(reader.read_array::<4>()?);
+        let key_id: u32 = u32::from_be_bytes(reader.read_array::<4>()?);
+        let iteration: u32 = u32::from_be_bytes(reader.read_array::<4>()?);
+        let ciphertext: Vec<u8> = reader.read_bytes()?.to_vec();
+        let signature: Signature = reader.read_array::<64>()?;
+        reader.finish()?;
+
+        Ok(GroupMessage { group_id, username, device_id, key_id, iteration, ciphertext, signature })
+    }
+
+    /// Returns the encoding of the message without its signature *(signed by the sender)*
+    fn signed_bytes(&self) -> Result<Vec<u8>, ParseError> {
+        let mut bytes: Vec<u8> = group_message_header(&self.group_id, &self.username, self.device_id, self.key_id, self.iteration)?;
+        write_bytes(&mut bytes, &self.ciphertext)?;
+        Ok(bytes)
+    }
+}
+
+impl ReceivedSenderKey {
+    /// Check the signature of a message and decrypt it, the chain only moves forward if the message is decrypted
+    fn decrypt(&mut self, message: &GroupMessage) -> Result<Vec<u8>, GroupError> {
+        if message.key_id != self.key_id {
+            return Err(GroupError::SenderKeyNotFound)
+        }
+        if !xeddsa_verify(&self.signing_key, &message.signed_bytes()?, &message.signature) {
+            return Err(GroupError::InvalidSignature)
+        }
+        let ad: Vec<u8> = group_message_header(&message.group_id, &message.username, message.device_id, message.key_id, message.iteration)?;
+
+        // Message older than the chain: its key has been stored when a later message was received
+        if message.iteration < self.iteration {
+            let mk: [u8; 32] = self.skipped_keys.remove(&self.key_id, message.iteration).ok_or(GroupError::DuplicateMessage)?;
+            return Ok(aead::open(mk, &message.ciphertext, &ad)?)
+        }
+
+        if (message.iteration - self.iteration) as usize > MAX_SKIPPED_KEYS {
+            return Err(GroupError::Crypto(CryptoError::TooManySkippedMessages))
+        }
+        let mut chain_key: [u8; 32] = self.chain_key;
+        let mut skipped_keys: Vec<(u32, [u8; 32])> = Vec::new();
+        for iteration in self.iteration..message.iteration {
+            let mk: [u8; 32];
+            (chain_key, mk) = kdf_sender_chain(chain_key);
+            skipped_keys.push((iteration, mk));
+        }
+        let mk: [u8; 32];
+        (chain_key, mk) = kdf_sender_chain(chain_key);
+        let plaintext: Vec<u8> = aead::open(mk, &message.ciphertext, &ad)?;
+        let next_iteration: u32 = message.iteration.checked_add(1).ok_or(GroupError::IterationOverflow)?;
+
+        for (iteration, mk) in skipped_keys {
+            self.skipped_keys.insert(self.key_id, iteration, mk);
+        }
+        self.chain_key = chain_key;
+        self.iteration = next_iteration;
+        Ok(plaintext)
+    }
+}
+
+impl Group {
+    /// Create a group, the sender key of the client is distributed before its first message
+    ///
+    /// # Arguments
+    ///
+    /// * `group_id` (GroupId): Id of the group
+    /// * `members` (Vec\<String\>): Names of the members *(the user of the client included)*
+    /// * `admins` (Vec\<String\>): Names of the members allowed to change the members *(the creator of the group)*
+    pub fn new(group_id: GroupId, members: Vec<String>, admins: Vec<String>) -> Self {
+        let members: Vec<String> = sorted_members(members);
+        let admins: Vec<String> = sorted_members(admins).into_iter().filter(|admin| members.contains(admin)).collect();
+        Group {
+            group_id,
+            members,
+            admins,
+            sender_key: None,
+            next_key_id: 0,
+            sender_keys: HashMap::new(),
+            previous_sender_keys: HashMap::new(),
+        }
+    }
+
+    pub fn get_group_id(&self) -> GroupId {
+        self.group_id
+    }
+
+    pub fn get_members(&self) -> Vec<String> {
+        self.members.clone()
+    }
+
+    pub fn is_member(&self, username: &String) -> bool {
+        self.members.contains(username)
+    }
+
+    pub fn get_admins(&self) -> Vec<String> {
+        self.admins.clone()
+    }
+
+    pub fn is_admin(&self, username: &String) -> bool {
+        self.admins.contains(username)
+    }
+
+    /// Returns true if a new sender key must be distributed before the next message *(none yet, or its chain is exhausted)*
+    pub fn needs_sender_key(&self) -> bool {
+        self.sender_key.as_ref().is_none_or(|sender_key| sender_key.iteration == u32::MAX)
+    }
+
+    /// Replace the members of the group, any change drops the sender key of the client *(rekeying)* and the sender keys of the members removed
+    ///
+    /// # Output
+    ///
+    /// * `changed` (bool): True if the members have changed
+    pub fn set_members(&mut self, members: Vec<String>) -> bool {
+        let members: Vec<String> = sorted_members(members);
+        if members == self.members {
+            return false
+        }
+        self.sender_keys.retain(|(username, _), _| members.contains(username));
+        self.previous_sender_keys.retain(|(username, _), _| members.contains(username));
+        self.admins.retain(|admin| members.contains(admin));
+        self.members = members;
+        self.sender_key = None;
+        true
+    }
+
+    /// Replace the admins of the group, the names that aren't members are ignored *(the sender keys are kept)*
+    ///
+    /// # Output
+    ///
+    /// * `changed` (bool): True if the admins have changed
+    pub fn set_admins(&mut self, admins: Vec<String>) -> bool {
+        let admins: Vec<String> = sorted_members(admins).into_iter().filter(|admin| self.members.contains(admin)).collect();
+        if admins == self.admins {
+            return false
+        }
+        self.admins = admins;
+        true
+    }
+
+    /// Remove a member of the group *(see `set_members`)*
+    pub fn remove_member(&mut self, username: &String) -> bool {
+        let members: Vec<String> = self.members.iter().filter(|member| *member != username).cloned().collect();
+        self.set_members(members)
+    }
+
+    /// Generate a new sender key for the client
+    ///
+    /// # Output
+    ///
+    /// * `distribution` (SenderKeyDistribution): Sender key to send to every device of the members
+    pub fn rotate_sender_key(&mut self) -> SenderKeyDistribution {
+        let mut chain_key: [u8; 32] = [0u8; 32];
+        OsRng.fill_bytes(&mut chain_key);
+        let sender_key: SenderKey = SenderKey { key_id: self.next_key_id, iteration: 0, chain_key, signing_key: StaticSecret::random_from_rng(OsRng) };
+        self.next_key_id = self.next_key_id.wrapping_add(1);
+
+        let distribution: SenderKeyDistribution = SenderKeyDistribution {
+            group_id: self.group_id,
+            members: self.members.clone(),
+            admins: self.admins.clone(),
+            key_id: sender_key.key_id,
+            iteration: sender_key.iteration,
+            chain_key: sender_key.chain_key,
+            signing_key: PublicKey::from(&sender_key.signing_key),
+        };
+        self.sender_key = Some(sender_key);
+        distribution
+    }
+
+    /// Store the sender key of another device, its previous one is kept for `SENDER_KEY_GRACE_PERIOD`
+    ///
+    /// # Arguments
+    ///
+    /// * `username` (&str): Name of the sender
+    /// * `device_id` (DeviceId): Device of the sender
+    /// * `distribution` (&SenderKeyDistribution): Sender key received over the pairwise session
+    /// * `now` (u64): Current Unix time in seconds
+    pub fn process_distribution(&mut self, username: &str, device_id: DeviceId, distribution: &SenderKeyDistribution, now: u64) {
+        self.previous_sender_keys.retain(|_, (_, replaced_at)| now.saturating_sub(*replaced_at) < SENDER_KEY_GRACE_PERIOD);
+        let sender_key: ReceivedSenderKey = ReceivedSenderKey {
+            key_id: distribution.key_id,
+            iteration: distribution.iteration,
+            chain_key: distribution.chain_key,
+            signing_key: distribution.signing_key,
+            skipped_keys: SkippedKeys::new(),
+        };
+        if let Some(previous_sender_key) = self.sender_keys.insert((username.to_string(), device_id), sender_key) {
+            if previous_sender_key.key_id != distribution.key_id {
+                self.previous_sender_keys.insert((username.to_string(), device_id), (previous_sender_key, now));
+            }
+        }
+    }
+
+    /// Encrypt a message with the sender key of the client *(see `needs_sender_key`)*
+    ///
+    /// # Arguments
+    ///
+    /// * `username` (&String): Name of the user of the client
+    /// * `device_id` (DeviceId): Device of the client
+    /// * `plaintext` (&\[u8\]): Plaintext
+    ///
+    /// # Output
+    ///
+    /// * `message` (Result\<GroupMessage, GroupError\>): Message to queue for every device of the members
+    pub fn encrypt(&mut self, username: &String, device_id: DeviceId, plaintext: &[u8]) -> Result<GroupMessage, GroupError> {
+        let sender_key: &mut SenderKey = self.sender_key.as_mut().ok_or(GroupError::SenderKeyNotFound)?;
+        let next_iteration: u32 = sender_key.iteration.checked_add(1).ok_or(GroupError::IterationOverflow)?;
+        let (chain_key, mk): ([u8; 32], [u8; 32]) = kdf_sender_chain(sender_key.chain_key);
+        let ad: Vec<u8> = group_message_header(&self.group_id, username, device_id, sender_key.key_id, sender_key.iteration)?;
+        let ciphertext: Vec<u8> = aead::seal(mk, plaintext, &ad)?;
+
+        let mut message: GroupMessage = GroupMessage {
+            group_id: self.group_id,
+            username: username.clone(),
+            device_id,
+            key_id: sender_key.key_id,
+            iteration: sender_key.iteration,
+            ciphertext,
+            signature: [0u8; 64],
+        };
+        message.signature = xeddsa_sign(&sender_key.signing_key, &message.signed_bytes()?);
+        sender_key.chain_key = chain_key;
+        sender_key.iteration = next_iteration;
+        Ok(message)
+    }
+
+    /// Decrypt a message of another device with its sender key, or with its previous one during `SENDER_KEY_GRACE_PERIOD`
+    ///
+    /// # Arguments
+    ///
+    /// * `message` (&GroupMessage): Message received
+    /// * `now` (u64): Current Unix time in seconds
+    ///
+    /// # Output
+    ///
+    /// * `plaintext` (Result\<Vec\<u8\>, GroupError\>): Plaintext
+    pub fn decrypt(&mut self, message: &GroupMessage, now: u64) -> Result<Vec<u8>, GroupError> {
+        if message.group_id != self.group_id || !self.is_member(&message.username) {
+            return Err(GroupError::SenderKeyNotFound)
+        }
+        let device: (String, DeviceId) = (message.username.clone(), message.device_id);
+        match self.previous_sender_keys.get_mut(&device) {
+            Some((previous_sender_key, replaced_at)) if previous_sender_key.key_id == message.key_id && now.saturating_sub(*replaced_at) < SENDER_KEY_GRACE_PERIOD => {
+                previous_sender_key.decrypt(message)
+            },
+            _ => self.sender_keys.get_mut(&device).ok_or(GroupError::SenderKeyNotFound)?.decrypt(message),
+        }
+    }
+}
+
+/// Returns a new random group id
+pub fn new_group_id() -> GroupId {
+    let mut group_id: GroupId = [0u8; 16];
+    OsRng.fill_bytes(&mut group_id);
+    group_id
+}
+
+/// Write a list of names: `count (4) || (name (4 + len))*`
+fn write_names(bytes: &mut Vec<u8>, names: &[String]) -> Result<(), ParseError> {
+    let count: u32 = names.len().try_into().map_err(|_| ParseError::FieldTooLong)?;
+    bytes.extend_from_slice(&count.to_be_bytes());
+    for name in names {
+        write_bytes(bytes, name.as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Read a list of names written by `write_names`
+fn read_names(reader: &mut Reader) -> Result<Vec<String>, ParseError> {
+    let count: u32 = u32::from_be_bytes(reader.read_array::<4>()?);
+    let mut names: Vec<String> = Vec::new();
+    for _ in 0..count {
+        names.push(String::from_utf8(reader.read_bytes()?.to_vec()).map_err(|_| ParseError::InvalidUsername)?);
+    }
+    Ok(names)
+}
+
+/// Returns the members sorted and without duplicates, so that two lists of members can be compared
+fn sorted_members(mut members: Vec<String>) -> Vec<String> {
+    members.sort();
+    members.dedup();
+    members
+}
+
+/// `version (1) || group_id (16) || username (4 + len) || device_id (4) || key_id (4) || iteration (4)`, also used as associated data
+fn group_message_header(group_id: &GroupId, username: &String, device_id: DeviceId, key_id: u32, iteration: u32) -> Result<Vec<u8>, ParseError> {
+    let mut bytes: Vec<u8> = vec![GROUP_WIRE_VERSION];
+    bytes.extend_from_slice(group_id);
+    write_bytes(&mut bytes, username.as_bytes())?;
+    bytes.extend_from_slice(&device_id.to_be_bytes());
+    bytes.extend_from_slice(&key_id.to_be_bytes());
+    bytes.extend_from_slice(&iteration.to_be_bytes());
+    Ok(bytes)
+}
+
+/// Returns (next chain key, message key) of the symmetric ratchet of a sender key
+fn kdf_sender_chain(ck: [u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let mut mac_ck = HmacSha256::new_from_slice(&ck)
+        .expect("HMAC can take key of any size");
+    mac_ck.update(BYTE_NEXT_CHAIN_KEY);
+    let next_chain_key: [u8; 32] = mac_ck.finalize().into_bytes().as_slice().try_into().expect("slice to array conversion failed");
+
+    let mut mac_mk = HmacSha256::new_from_slice(&ck)
+        .expect("HMAC can take key of any size");
+    mac_mk.update(BYTE_MESSAGE_KEY);
+    let message_key: [u8; 32] = mac_mk.finalize().into_bytes().as_slice().try_into().expect("slice to array conversion failed");
+
+    (next_chain_key, message_key)
+}
+
+impl From<CryptoError> for GroupError {
+    fn from(error: CryptoError) -> Self {
+        GroupError::Crypto(error)
+    }
+}
+
+impl From<ParseError> for GroupError {
+    fn from(error: ParseError) -> Self {
+        GroupError::Parse(error)
+    }
+}
+
+impl fmt::Display for GroupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GroupError::Crypto(error) => write!(f, "Group message can't be decrypted: {}", error),
+            GroupError::Parse(error) => write!(f, "Malformed group message: {}", error),
+            GroupError::GroupNotFound => write!(f, "Unknown group"),
+            GroupError::SenderKeyNotFound => write!(f, "No sender key for this device of the group"),
+            GroupError::InvalidSignature => write!(f, "Invalid signature of the group message"),
+            GroupError::DuplicateMessage => write!(f, "Group message already received"),
+            GroupError::NotAdmin => write!(f, "Only an admin of the group can change its members"),
+            GroupError::MemberNotFound => write!(f, "Not a member of the group"),
+            GroupError::IterationOverflow => write!(f, "No message number left in the sender key"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn members() -> Vec<String> {
+        vec!["Charlie".to_string(), "Alice".to_string(), "Bob".to_string()]
+    }
+
+    #[test]
+    fn test_encrypt_decrypt_out_of_order() {
+        let group_id: GroupId = new_group_id();
+        let alice_name: String = "Alice".to_string();
+        let mut alice_group: Group = Group::new(group_id, members(), vec!["Alice".to_string()]);
+        let mut bob_group: Group = Group::new(group_id, members(), vec!["Alice".to_string()]);
+        assert!(alice_group.needs_sender_key());
+        let distribution: SenderKeyDistribution = alice_group.rotate_sender_key();
+        assert_eq!(SenderKeyDistribution::from_bytes(&distribution.to_bytes().unwrap()).unwrap(), distribution);
+        bob_group.process_distribution(&alice_name, 1, &distribution, NOW);
+
+        let first_message: GroupMessage = alice_group.encrypt(&alice_name, 1, b"first").unwrap();
+        let second_message: GroupMessage = alice_group.encrypt(&alice_name, 1, b"second").unwrap();
+        assert_eq!(GroupMessage::from_bytes(&second_message.to_bytes().unwrap()).unwrap(), second_message);
+        assert_eq!(bob_group.decrypt(&second_message, NOW).unwrap(), b"second".to_vec());
+        assert_eq!(bob_group.decrypt(&first_message, NOW).unwrap(), b"first".to_vec());
+        assert_eq!(bob_group.decrypt(&first_message, NOW), Err(GroupError::DuplicateMessage));
+        assert_eq!(bob_group.decrypt(&second_message, NOW), Err(GroupError::DuplicateMessage));
+    }
+
+    #[test]
+    fn test_signature_checked() {
+        let group_id: GroupId = new_group_id();
+        let alice_name: String = "Alice".to_string();
+        let mut alice_group: Group = Group::new(group_id, members(), vec!["Alice".to_string()]);
+        let mut bob_group: Group = Group::new(group_id, members(), vec!["Alice".to_string()]);
+        bob_group.process_distribution(&alice_name, 1, &alice_group.rotate_sender_key(), NOW);
+
+        // Charlie knows the chain key of Alice, but can't sign a message in her name
+        let mut message: GroupMessage = alice_group.encrypt(&alice_name, 1, b"from Alice").unwrap();
+        let mut forged_group: Group = Group::new(group_id, members(), vec!["Alice".to_string()]);
+        forged_group.rotate_sender_key();
+        message.signature = forged_group.encrypt(&alice_name, 1, b"from Charlie").unwrap().signature;
+        assert_eq!(bob_group.decrypt(&message, NOW), Err(GroupError::InvalidSignature));
+
+        // Unknown device
+        let message: GroupMessage = alice_group.encrypt(&alice_name, 2, b"from Alice").unwrap();
+        assert_eq!(bob_group.decrypt(&message, NOW), Err(GroupError::SenderKeyNotFound));
+    }
+
+    #[test]
+    fn test_rekey_on_member_removal() {
+        let group_id: GroupId = new_group_id();
+        let alice_name: String = "Alice".to_string();
+        let charlie_name: String = "Charlie".to_string();
+        let mut alice_group: Group = Group::new(group_id, members(), vec!["Alice".to_string()]);
+        let mut charlie_group: Group = Group::new(group_id, members(), vec!["Alice".to_string()]);
+        charlie_group.process_distribution(&alice_name, 1, &alice_group.rotate_sender_key(), NOW);
+        assert!(!alice_group.set_members(members()));
+        assert!(!alice_group.needs_sender_key());
+
+        // Charlie leaves: Alice drops her sender key and Charlie's
+        alice_group.process_distribution(&charlie_name, 1, &charlie_group.rotate_sender_key(), NOW);
+        assert!(alice_group.remove_member(&charlie_name));
+        assert_eq!(alice_group.get_members(), vec![alice_name.clone(), "Bob".to_string()]);
+        assert!(alice_group.needs_sender_key());
+        let message: GroupMessage = charlie_group.encrypt(&charlie_name, 1, b"from Charlie").unwrap();
+        assert_eq!(alice_group.decrypt(&message, NOW), Err(GroupError::SenderKeyNotFound));
+
+        // The old sender key of Alice can't decrypt her next messages
+        let distribution: SenderKeyDistribution = alice_group.rotate_sender_key();
+        assert_eq!(distribution.get_key_id(), 1);
+        assert!(!distribution.get_members().contains(&charlie_name));
+        let message: GroupMessage = alice_group.encrypt(&alice_name, 1, b"without Charlie").unwrap();
+        assert_eq!(charlie_group.decrypt(&message, NOW), Err(GroupError::SenderKeyNotFound));
+    }
+
+    #[test]
+    fn test_admins() {
+        let group_id: GroupId = new_group_id();
+        let mut group: Group = Group::new(group_id, members(), vec!["Alice".to_string(), "Eve".to_string()]);
+        assert_eq!(group.get_admins(), vec!["Alice".to_string()]);
+        assert!(!group.set_admins(vec!["Alice".to_string(), "Eve".to_string()]));
+        assert!(group.set_admins(vec!["Bob".to_string(), "Alice".to_string()]));
+        assert!(group.is_admin(&"Bob".to_string()));
+
+        // The admins are sent with the sender key, an admin removed from the group isn't an admin anymore
+        let distribution: SenderKeyDistribution = group.rotate_sender_key();
+        assert_eq!(SenderKeyDistribution::from_bytes(&distribution.to_bytes().unwrap()).unwrap().get_admins(), vec!["Alice".to_string(), "Bob".to_string()]);
+        assert!(group.remove_member(&"Bob".to_string()));
+        assert_eq!(group.get_admins(), vec!["Alice".to_string()]);
+    }
+
+    #[test]
+    fn test_previous_sender_key_grace_period() {
+        let group_id: GroupId = new_group_id();
+        let alice_name: String = "Alice".to_string();
+        let mut alice_group: Group = Group::new(group_id, members(), vec![alice_name.clone()]);
+        let mut bob_group: Group = Group::new(group_id, members(), vec![alice_name.clone()]);
+        bob_group.process_distribution(&alice_name, 1, &alice_group.rotate_sender_key(), NOW);
+        let first_message: GroupMessage = alice_group.encrypt(&alice_name, 1, b"first").unwrap();
+        let second_message: GroupMessage = alice_group.encrypt(&alice_name, 1, b"second").unwrap();
+
+        // Alice rekeys while her messages are in flight: Bob still reads them with her previous sender key
+        bob_group.process_distribution(&alice_name, 1, &alice_group.rotate_sender_key(), NOW);
+        let third_message: GroupMessage = alice_group.encrypt(&alice_name, 1, b"third").unwrap();
+        assert_eq!(bob_group.decrypt(&third_message, NOW).unwrap(), b"third".to_vec());
+        assert_eq!(bob_group.decrypt(&first_message, NOW).unwrap(), b"first".to_vec());
+        assert_eq!(bob_group.decrypt(&second_message, NOW + SENDER_KEY_GRACE_PERIOD), Err(GroupError::SenderKeyNotFound));
+        assert_eq!(bob_group.decrypt(&second_message, NOW + SENDER_KEY_GRACE_PERIOD - 1).unwrap(), b"second".to_vec());
+
+        // Only the last sender key replaced is kept
+        bob_group.process_distribution(&alice_name, 1, &alice_group.rotate_sender_key(), NOW);
+        let fourth_message: GroupMessage = alice_group.encrypt(&alice_name, 1, b"fourth").unwrap();
+        assert_eq!(bob_group.decrypt(&fourth_message, NOW).unwrap(), b"fourth".to_vec());
+        assert_eq!(bob_group.decrypt(&first_message, NOW), Err(GroupError::SenderKeyNotFound));
+    }
+
+    #[test]
+    fn test_iteration_overflow() {
+        let group_id: GroupId = new_group_id();
+        let alice_name: String = "Alice".to_string();
+        let mut alice_group: Group = Group::new(group_id, members(), vec![alice_name.clone()]);
+        let mut bob_group: Group = Group::new(group_id, members(), vec![alice_name.clone()]);
+        bob_group.process_distribution(&alice_name, 1, &alice_group.rotate_sender_key(), NOW);
+        alice_group.sender_key.as_mut().unwrap().iteration = u32::MAX - 1;
+        bob_group.sender_keys.get_mut(&(alice_name.clone(), 1)).unwrap().iteration = u32::MAX - 1;
+
+        // The last message of the chain is read, then a new sender key is needed
+        let message: GroupMessage = alice_group.encrypt(&alice_name, 1, b"last").unwrap();
+        assert_eq!(bob_group.decrypt(&message, NOW).unwrap(), b"last".to_vec());
+        assert!(alice_group.needs_sender_key());
+        assert_eq!(alice_group.encrypt(&alice_name, 1, b"too many"), Err(GroupError::IterationOverflow));
+    }
+}
@@ -0,0 +1,258 @@
+//! Identity keys of the contacts of a client *(trust on first use)*
+//!
+//! The first identity key seen for a device of a contact is trusted. A different key for this device is then refused and kept aside,
+//! so that the user can inspect it and accept it *(`accept_changed_key`)*. The keys checked with a safety number are marked as verified *(see `safety_number`)*.
+
+use std::collections::HashMap;
+use std::fmt;
+use x25519_dalek::PublicKey;
+
+use super::message::{write_bytes, write_optional_key, ParseError, Reader};
+use super::server::DeviceId;
+
+const IDENTITY_STORE_VERSION: u8 = 0x01;
+const TRUST_FIRST_USE: u8 = 0x00;
+const TRUST_VERIFIED: u8 = 0x01;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Trust {
+    FirstUse, // Trusted because it was the first key seen
+    Verified, // Checked by the user
+}
+
+#[derive(Debug, PartialEq)]
+pub enum IdentityError {
+    Changed,
+    VerifiedChanged,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct IdentityStore {
+    identities: HashMap<(String, DeviceId), (PublicKey, Trust, Option<PublicKey>)>, // (Trusted identity key, trust, last different identity key seen)
+}
+
+impl Default for IdentityStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IdentityStore {
+    pub fn new() -> Self {
+        IdentityStore { identities: HashMap::new() }
+    }
+
+    /// Returns the identity key trusted for a device
+    pub fn get(&self, username: &str, device_id: DeviceId) -> Option<PublicKey> {
+        self.identities.get(&(username.to_string(), device_id)).map(|(ik, _, _)| *ik)
+    }
+
+    pub fn get_trust(&self, username: &str, device_id: DeviceId) -> Option<Trust> {
+        self.identities.get(&(username.to_string(), device_id)).map(|(_, trust, _)| *trust)
+    }
+
+    /// Returns the different identity key refused for a device, if any
+    pub fn get_changed_key(&self, username: &str, device_id: DeviceId) -> Option<PublicKey> {
+        self.identities.get(&(username.to_string(), device_id)).and_then(|(_, _, changed_ik)| *changed_ik)
+    }
+
+    /// Returns every identity known, sorted by user and device
+    ///
+    /// # Output
+    ///
+    /// * `identities` (Vec\<(String, DeviceId, PublicKey, Trust, Option\<PublicKey\>)\>): (Username, device, trusted identity key, trust, different identity key refused)
+    pub fn get_identities(&self) -> Vec<(String, DeviceId, PublicKey, Trust, Option<PublicKey>)> {
+        let mut identities: Vec<(String, DeviceId, PublicKey, Trust, Option<PublicKey>)> = self.identities.iter()
+            .map(|((username, device_id), (ik, trust, changed_ik))| (username.clone(), *device_id, *ik, *trust, *changed_ik))
+            .collect();
+        identities.sort_by(|a, b| (&a.0, a.1).cmp(&(&b.0, b.1)));
+        identities
+    }
+
+    /// Check the identity key presented for a device against the trusted one, a different key is kept aside *(see `get_changed_key`)*
+    ///
+    /// # Arguments
+    ///
+    /// * `username` (&str): Name of the contact
+    /// * `device_id` (DeviceId): Device of the contact
+    /// * `ik` (&PublicKey): Identity key presented
+    ///
+    /// # Output
+    ///
+    /// * `result` (Result\<(), IdentityError\>): Error if another key is trusted for this device *(the key isn't saved, see `save`)*
+    pub fn check(&mut self, username: &str, device_id: DeviceId, ik: &PublicKey) -> Result<(), IdentityError> {
+        match self.identities.get_mut(&(username.to_string(), device_id)) {
+            Some((trusted_ik, trust, changed_ik)) if trusted_ik != ik => {
+                *changed_ik = Some(*ik);
+                match trust {
+                    Trust::FirstUse => Err(IdentityError::Changed),
+                    Trust::Verified => Err(IdentityError::VerifiedChanged),
+                }
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// Trust the identity key of a device seen for the first time *(nothing changes if a key is already trusted)*
+    pub fn save(&mut self, username: &str, device_id: DeviceId, ik: PublicKey) {
+        self.identities.entry((username.to_string(), device_id)).or_insert((ik, Trust::FirstUse, None));
+    }
+
+    /// Mark the identity key trusted for a device as verified, returns false if no key is known
+    pub fn verify(&mut self, username: &str, device_id: DeviceId) -> bool {
+        match self.identities.get_mut(&(username.to_string(), device_id)) {
+            Some((_, trust, _)) => {
+                *trust = Trust::Verified;
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Trust the different identity key refused for a device instead of the previous one *(it has to be verified again)*, returns false if there is none
+    pub fn accept_changed_key(&mut self, username: &str, device_id: DeviceId) -> bool {
+        match self.identities.get_mut(&(username.to_string(), device_id)) {
+            Some((ik, trust, changed_ik)) if changed_ik.is_some() => {
+                *ik = changed_ik.take().unwrap();
+                *trust = Trust::FirstUse;
+                true
+            },
+            _ => false,
+        }
+    }
+
+    pub fn remove(&mut self, username: &str, device_id: DeviceId) {
+        self.identities.remove(&(username.to_string(), device_id));
+    }
+
+    /// Forget the devices of a user that aren't in `devices` *(removed devices)*
+    pub fn retain_devices(&mut self, username: &String, devices: &[DeviceId]) {
+        self.identities.retain(|(current_username, device_id), _| current_username != username || devices.contains(device_id));
+    }
+
+    /// Returns the encoding of the store
+    ///
+    /// `version (1) || count (4) || [username (4 + len) || device_id (4) || ik (32) || trust (1) || changed_ik (1 [+ 32])]*`
+    ///
+    /// # Output
+    ///
+    /// * `bytes` (Result\<Vec\<u8\>, ParseError\>): Encoded store, the identities are sorted by user and device
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ParseError> {
+        let identities: Vec<(String, DeviceId, PublicKey, Trust, Option<PublicKey>)> = self.get_identities();
+        let count: u32 = identities.len().try_into().map_err(|_| ParseError::FieldTooLong)?;
+        let mut bytes: Vec<u8> = vec![IDENTITY_STORE_VERSION];
+        bytes.extend_from_slice(&count.to_be_bytes());
+        for (username, device_id, ik, trust, changed_ik) in identities {
+            write_bytes(&mut bytes, username.as_bytes())?;
+            bytes.extend_from_slice(&device_id.to_be_bytes());
+            bytes.extend_from_slice(ik.as_bytes());
+            bytes.push(match trust {
+                Trust::FirstUse => TRUST_FIRST_USE,
+                Trust::Verified => TRUST_VERIFIED,
+            });
+            write_optional_key(&mut bytes, changed_ik);
+        }
+        Ok(bytes)
+    }
+
+    /// Parse a store from its encoding
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` (&\[u8\]): Encoded store
+    ///
+    /// # Output
+    ///
+    /// * `identity_store` (Result\<IdentityStore, ParseError\>): Decoded store
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ParseError> {
+        let mut reader: Reader = Reader::new(bytes);
+        let version: u8 = reader.read_u8()?;
+        if version != IDENTITY_STORE_VERSION {
+            return Err(ParseError::UnsupportedVersion(version))
+        }
+        let count: u32 = u32::from_be_bytes(reader.read_array::<4>()?);
+        let mut identities: HashMap<(String, DeviceId), (PublicKey, Trust, Option<PublicKey>)> = HashMap::new();
+        for _ in 0..count {
+            let username: String = String::from_utf8(reader.read_bytes()?.to_vec()).map_err(|_| ParseError::InvalidUsername)?;
+            let device_id: DeviceId = DeviceId::from_be_bytes(reader.read_array::<4>()?);
+            let ik: PublicKey = PublicKey::from(reader.read_array::<32>()?);
+            let trust: Trust = match reader.read_u8()? {
+                TRUST_FIRST_USE => Trust::FirstUse,
+                TRUST_VERIFIED => Trust::Verified,
+                flag => return Err(ParseError::InvalidFlag(flag)),
+            };
+            let changed_ik: Option<PublicKey> = reader.read_optional_key()?;
+            identities.insert((username, device_id), (ik, trust, changed_ik));
+        }
+        reader.finish()?;
+        Ok(IdentityStore { identities })
+    }
+}
+
+impl fmt::Display for IdentityError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IdentityError::Changed => write!(f, "The identity key of a contact has changed"),
+            IdentityError::VerifiedChanged => write!(f, "The identity key of a verified contact has changed"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x25519_dalek::StaticSecret;
+
+    fn public_key(seed: u8) -> PublicKey {
+        PublicKey::from(&StaticSecret::from([seed; 32]))
+    }
+
+    #[test]
+    fn test_trust_on_first_use() {
+        let bob_name: String = "Bob".to_string();
+        let mut identity_store: IdentityStore = IdentityStore::new();
+        assert_eq!(identity_store.check(&bob_name, 1, &public_key(1)), Ok(()));
+        identity_store.save(&bob_name, 1, public_key(1));
+        identity_store.save(&bob_name, 2, public_key(2));
+        identity_store.save(&bob_name, 1, public_key(3));
+        assert_eq!(identity_store.get(&bob_name, 1), Some(public_key(1)));
+        assert_eq!(identity_store.check(&bob_name, 1, &public_key(1)), Ok(()));
+
+        // Another key for the same device
+        assert_eq!(identity_store.check(&bob_name, 1, &public_key(3)), Err(IdentityError::Changed));
+        assert_eq!(identity_store.get(&bob_name, 1), Some(public_key(1)));
+        assert_eq!(identity_store.get_changed_key(&bob_name, 1), Some(public_key(3)));
+        assert!(identity_store.verify(&bob_name, 2));
+        assert_eq!(identity_store.check(&bob_name, 2, &public_key(3)), Err(IdentityError::VerifiedChanged));
+
+        assert!(identity_store.accept_changed_key(&bob_name, 1));
+        assert!(!identity_store.accept_changed_key(&bob_name, 1));
+        assert_eq!(identity_store.get(&bob_name, 1), Some(public_key(3)));
+        assert_eq!(identity_store.get_trust(&bob_name, 1), Some(Trust::FirstUse));
+        assert_eq!(identity_store.get_changed_key(&bob_name, 1), None);
+        assert_eq!(identity_store.check(&bob_name, 1, &public_key(3)), Ok(()));
+
+        identity_store.retain_devices(&bob_name, &[2]);
+        assert_eq!(identity_store.get(&bob_name, 1), None);
+        assert!(!identity_store.verify(&bob_name, 1));
+    }
+
+    #[test]
+    fn test_identity_store_round_trip() {
+        let mut identity_store: IdentityStore = IdentityStore::new();
+        identity_store.save("Bob", 1, public_key(1));
+        identity_store.save("Bob", 3, public_key(2));
+        identity_store.save("Alice", 1, public_key(3));
+        identity_store.verify("Alice", 1);
+        let _ = identity_store.check("Bob", 3, &public_key(4));
+
+        let bytes: Vec<u8> = identity_store.to_bytes().unwrap();
+        assert_eq!(IdentityStore::from_bytes(&bytes), Ok(identity_store.clone()));
+        assert_eq!(identity_store.get_identities()[0], ("Alice".to_string(), 1, public_key(3), Trust::Verified, None));
+        assert_eq!(identity_store.get_identities()[2], ("Bob".to_string(), 3, public_key(2), Trust::FirstUse, Some(public_key(4))));
+
+        assert_eq!(IdentityStore::from_bytes(&bytes[..bytes.len() - 1]), Err(ParseError::UnexpectedEnd));
+        assert_eq!(IdentityStore::from_bytes(&[bytes.as_slice(), &[0x00]].concat()), Err(ParseError::TrailingBytes));
+        assert_eq!(IdentityStore::from_bytes(&[0x02]), Err(ParseError::UnsupportedVersion(0x02)));
+    }
+}
@@ -0,0 +1,103 @@
+//! Message relay used by `Client`
+//!
+//! The relay stores the prekey bundles of the devices of each user and queues the messages until the device receiving them acknowledges them.
+//! The messages can be sealed so that the relay doesn't learn their sender *(see `sealed_sender`)*.
+//! Reading a mailbox and replacing keys require a session, opened by signing a challenge of the relay with the identity key of a device *(see `Client::login`)*.
+//! `Server` keeps everything in memory and `RemoteServer` forwards the operations to a relay daemon, other storages only have to implement `Relay`.
+
+use std::fmt;
+use x25519_dalek::PublicKey;
+use x3dh::Signature;
+use x3dh::mlkem::EncapsulationKey;
+
+use super::key_collection::ServerKeyCollection;
+use super::message::Envelope;
+use super::sealed_sender::SenderCertificate;
+use super::server::{Challenge, DeviceId, ServerError, SessionToken};
+use super::transport::TransportError;
+
+#[derive(Debug)]
+pub enum RelayError {
+    Server(ServerError),
+    Transport(TransportError),
+}
+
+pub trait Relay {
+    /// Publish the keys of the primary device of a new user *(registration)*
+    fn publish_keys(&mut self, username: &str, keys: ServerKeyCollection) -> Result<(), RelayError>;
+
+    /// Link a new device to the user, returns its id
+    fn add_device(&mut self, username: &str, session: &SessionToken, keys: ServerKeyCollection) -> Result<DeviceId, RelayError>;
+
+    /// Remove a linked device of the user
+    fn remove_device(&mut self, username: &str, session: &SessionToken, device_id: DeviceId) -> Result<(), RelayError>;
+
+    /// Returns the devices of a user
+    fn devices(&mut self, username: &str) -> Result<Vec<DeviceId>, RelayError>;
+
+    /// Replace all the keys of the device that opened the session
+    fn replace_keys(&mut self, username: &str, session: &SessionToken, keys: ServerKeyCollection) -> Result<(), RelayError>;
+
+    /// Returns a challenge to sign with the identity key of the device
+    fn challenge(&mut self, username: &str, device_id: DeviceId) -> Result<Challenge, RelayError>;
+
+    /// Open a session with the signature of the last challenge
+    fn login(&mut self, username: &str, device_id: DeviceId, signature: Signature) -> Result<SessionToken, RelayError>;
+
+    /// Returns the public key verifying the sender certificates
+    fn certificate_key(&mut self) -> Result<PublicKey, RelayError>;
+
+    /// Returns a certificate of the device that opened the session to send sealed messages
+    fn sender_certificate(&mut self, username: &str, session: &SessionToken) -> Result<SenderCertificate, RelayError>;
+
+    /// Publish the new signed prekey of the device that opened the session *(rotation)*
+    fn publish_spk(&mut self, username: &str, session: &SessionToken, spk_id: u32, spk: PublicKey, signature: Signature) -> Result<(), RelayError>;
+
+    /// Publish the new ML-KEM prekey of the device that opened the session *(rotation)*
+    fn publish_pqspk(&mut self, username: &str, session: &SessionToken, pqspk_id: u32, pqspk: EncapsulationKey, pq_signature: Signature) -> Result<(), RelayError>;
+
+    /// Publish a new batch of one-time prekeys of the device that opened the session, tagged with their id
+    fn publish_opks(&mut self, username: &str, session: &SessionToken, opks: Vec<(u32, PublicKey)>) -> Result<(), RelayError>;
+
+    /// Returns the number of one-time prekeys of a device still available
+    fn opk_count(&mut self, username: &str, device_id: DeviceId) -> Result<usize, RelayError>;
+
+    /// Returns the prekey bundle used to start a session with a device, the one-time prekey it contains is handed out only once
+    fn fetch_bundle(&mut self, username: &str, device_id: DeviceId) -> Result<ServerKeyCollection, RelayError>;
+
+    /// Returns the public identity key of a device
+    fn identity_key(&mut self, username: &str, device_id: DeviceId) -> Result<PublicKey, RelayError>;
+
+    /// Queue a message for a device
+    fn enqueue(&mut self, username: &str, device_id: DeviceId, message: Envelope) -> Result<(), RelayError>;
+
+    /// Returns the messages queued for the device that opened the session with their id, in their order of arrival
+    fn fetch(&mut self, username: &str, session: &SessionToken) -> Result<Vec<(u64, Envelope)>, RelayError>;
+
+    /// Delete the messages of the device that opened the session once it has read them
+    fn acknowledge(&mut self, username: &str, session: &SessionToken, ids: &[u64]) -> Result<(), RelayError>;
+}
+
+impl From<ServerError> for RelayError {
+    fn from(error: ServerError) -> Self {
+        RelayError::Server(error)
+    }
+}
+
+impl From<TransportError> for RelayError {
+    fn from(error: TransportError) -> Self {
+        match error {
+            TransportError::Server(error) => RelayError::Server(error),
+            error => RelayError::Transport(error),
+        }
+    }
+}
+
+impl fmt::Display for RelayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RelayError::Server(error) => write!(f, "{}", error),
+            RelayError::Transport(error) => write!(f, "{}", error),
+        }
+    }
+}
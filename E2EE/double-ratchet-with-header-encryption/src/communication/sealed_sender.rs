@@ -0,0 +1,339 @@
+//! Sealed sender *(based on Signal: https://signal.org/blog/sealed-sender/)*
+//!
+//! The relay only has to know the receiver of a message: the name and the identity key of the sender are encrypted to the identity key of the receiver.
+//! The sender proves its name and device with a short-lived `SenderCertificate` signed by the relay, so the receiver doesn't have to trust the content of the envelope.
+//!
+//! - Ephemeral layer: `DH(ephemeral key, receiver identity key)` encrypts the identity key of the sender
+//! - Static layer: `DH(sender identity key, receiver identity key)` encrypts `certificate || message` *(only the owner of the identity key could have sealed it)*
+
+use std::fmt;
+use hkdf::Hkdf;
+use rand_core::OsRng;
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+use x3dh::{create_identity_signature, xeddsa_verify, IdentityKey, Signature};
+
+use crate::double_ratchet::aead::{self, CryptoError};
+use super::message::{write_bytes, Message, ParseError, Reader};
+use super::server::DeviceId;
+
+pub const SEALED_WIRE_VERSION: u8 = 0x80; // Distinct from the versions of `Message`, so that both can be queued on the relay
+pub const SENDER_CERTIFICATE_LIFETIME: u64 = 7 * 24 * 60 * 60; // Time (in seconds) a sender certificate is valid
+const CERTIFICATE_CONTEXT: &[u8] = b"DoubleRatchetSenderCertificate";
+const INFO_EPHEMERAL: &[u8] = b"DoubleRatchetSealedSenderEphemeral";
+const INFO_STATIC: &[u8] = b"DoubleRatchetSealedSenderStatic";
+
+#[derive(Debug, PartialEq)]
+pub enum SealedSenderError {
+    Crypto(CryptoError),
+    Parse(ParseError),
+    CertificateKeyUnknown,
+    InvalidCertificate,
+    CertificateExpired,
+}
+
+/// Name, device and identity key of a user, signed by the relay
+#[derive(Clone, Debug, PartialEq)]
+pub struct SenderCertificate {
+    username: String,
+    device_id: DeviceId,
+    ik: PublicKey,
+    expiration: u64, // Unix time (in seconds)
+    signature: Signature,
+}
+
+/// Message whose sender is only known by the receiver
+#[derive(Clone, Debug, PartialEq)]
+pub struct SealedMessage {
+    ephemeral_key: PublicKey,
+    encrypted_static: Vec<u8>, // Identity key of the sender
+    encrypted_content: Vec<u8>, // Sender certificate and message
+}
+
+impl SenderCertificate {
+    /// Issue a certificate *(relay side)*
+    ///
+    /// # Arguments
+    ///
+    /// * `certificate_key` (&IdentityKey): Key of the relay signing the certificates
+    /// * `username` (String): Name of the sender
+    /// * `device_id` (DeviceId): Device of the sender
+    /// * `ik` (PublicKey): Public identity key of the device
+    /// * `expiration` (u64): Unix time (in seconds) after which the certificate is rejected
+    ///
+    /// # Output
+    ///
+    /// * `certificate` (Result\<SenderCertificate, ParseError\>)
+    pub fn issue(certificate_key: &IdentityKey, username: String, device_id: DeviceId, ik: PublicKey, expiration: u64) -> Result<Self, ParseError> {
+        let signature: Signature = create_identity_signature(certificate_key, &certificate_message(&username, device_id, &ik, expiration)?);
+        Ok(SenderCertificate { username, device_id, ik, expiration, signature })
+    }
+
+    pub fn get_username(&self) -> String {
+        self.username.clone()
+    }
+
+    pub fn get_device_id(&self) -> DeviceId {
+        self.device_id
+    }
+
+    pub fn get_ik(&self) -> PublicKey {
+        self.ik
+    }
+
+    pub fn get_expiration(&self) -> u64 {
+        self.expiration
+    }
+
+    /// Check the signature of the relay and the expiration of the certificate
+    pub fn validate(&self, certificate_key: &PublicKey, now: u64) -> Result<(), SealedSenderError> {
+        if !xeddsa_verify(certificate_key, &certificate_message(&self.username, self.device_id, &self.ik, self.expiration)?, &self.signature) {
+            return Err(SealedSenderError::InvalidCertificate)
+        }
+        if now >= self.expiration {
+            return Err(SealedSenderError::CertificateExpired)
+        }
+        Ok(())
+    }
+
+    /// Returns the wire encoding of the certificate: `username (4 + len) || device_id (4) || ik (32) || expiration (8) || signature (64)`
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ParseError> {
+        let mut bytes: Vec<u8> = Vec::new();
+        write_bytes(&mut bytes, self.username.as_bytes())?;
+        bytes.extend_from_slice(&self.device_id.to_be_bytes());
+        bytes.extend_from_slice(self.ik.as_bytes());
+        bytes.extend_from_slice(&self.expiration.to_be_bytes());
+        bytes.extend_from_slice(&self.signature);
+        Ok(bytes)
+    }
+
+    /// Parse a certificate from its wire encoding
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ParseError> {
+        let mut reader: Reader = Reader::new(bytes);
+        let username: String = String::from_utf8(reader.read_bytes()?.to_vec())
+            .map_err(|_| ParseError::InvalidUsername)?;
+        let device_id: DeviceId = u32::from_be_bytes(reader.read_array::<4>()?);
+        let ik: PublicKey = PublicKey::from(reader.read_array::<32>()?);
+        let expiration: u64 = u64::from_be_bytes(reader.read_array::<8>()?);
+        let signature: Signature = reader.read_array::<64>()?;
+        reader.finish()?;
+
+        Ok(SenderCertificate { username, device_id, ik, expiration, signature })
+    }
+}
+
+impl SealedMessage {
+    /// Returns the wire encoding of the sealed message: `version (1) || ephemeral_key (32) || encrypted_static (4 + len) || encrypted_content (4 + len)`
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ParseError> {
+        let mut bytes: Vec<u8> = vec![SEALED_WIRE_VERSION];
+        bytes.extend_from_slice(self.ephemeral_key.as_bytes());
+        write_bytes(&mut bytes, &self.encrypted_static)?;
+        write_bytes(&mut bytes, &self.encrypted_content)?;
+        Ok(bytes)
+    }
+
+    /// Parse a sealed message from its wire encoding
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ParseError> {
+        let mut reader: Reader = Reader::new(bytes);
+        let version: u8 = reader.read_u8()?;
+        if version != SEALED_WIRE_VERSION {
+            return Err(ParseError::UnsupportedVersion(version))
+        }
+        let ephemeral_key: PublicKey = PublicKey::from(reader.read_array::<32>()?);
+        let encrypted_static: Vec<u8> = reader.read_bytes()?.to_vec();
+        let encrypted_content: Vec<u8> = reader.read_bytes()?.to_vec();
+        reader.finish()?;
+
+        Ok(SealedMessage { ephemeral_key, encrypted_static, encrypted_content })
+    }
+}
+
+/// Seal a message so that only the receiver learns who sent it
+///
+/// # Arguments
+///
+/// * `ik_sender` (&IdentityKey): Identity key of the sender
+/// * `ik_receiver` (&PublicKey): Public identity key of the receiver
+/// * `certificate` (&SenderCertificate): Certificate of the sender
+/// * `message` (&Message): Message to seal
+///
+/// # Output
+///
+/// * `sealed_message` (Result\<SealedMessage, SealedSenderError\>)
+pub fn seal(ik_sender: &IdentityKey, ik_receiver: &PublicKey, certificate: &SenderCertificate, message: &Message) -> Result<SealedMessage, SealedSenderError> {
+    let ephemeral_private_key: StaticSecret = StaticSecret::random_from_rng(OsRng);
+    let ephemeral_key: PublicKey = PublicKey::from(&ephemeral_private_key);
+
+    let (chain_key, static_key): ([u8; 32], [u8; 32]) = kdf_ephemeral(ik_receiver, &ephemeral_key, ephemeral_private_key.diffie_hellman(ik_receiver).to_bytes());
+    let encrypted_static: Vec<u8> = aead::seal(static_key, ik_sender.get_public_key().as_bytes(), ephemeral_key.as_bytes())?;
+
+    let content_key: [u8; 32] = kdf_static(&chain_key, &encrypted_static, ik_sender.get_private_key().diffie_hellman(ik_receiver).to_bytes());
+    let mut content: Vec<u8> = Vec::new();
+    write_bytes(&mut content, &certificate.to_bytes()?)?;
+    content.extend_from_slice(&message.to_bytes()?);
+    let encrypted_content: Vec<u8> = aead::seal(content_key, &content, ephemeral_key.as_bytes())?;
+
+    Ok(SealedMessage { ephemeral_key, encrypted_static, encrypted_content })
+}
+
+/// Open a sealed message and check the certificate of its sender
+///
+/// # Arguments
+///
+/// * `ik_receiver` (&IdentityKey): Identity key of the receiver
+/// * `certificate_key` (&PublicKey): Public key of the relay signing the certificates
+/// * `sealed_message` (&SealedMessage): Sealed message
+/// * `now` (u64): Current Unix time (in seconds)
+///
+/// # Output
+///
+/// * `(username, device_id, ik_sender, message)` (Result\<(String, DeviceId, PublicKey, Message), SealedSenderError\>): Name, device and public identity key of the sender, message
+pub fn unseal(ik_receiver: &IdentityKey, certificate_key: &PublicKey, sealed_message: &SealedMessage, now: u64) -> Result<(String, DeviceId, PublicKey, Message), SealedSenderError> {
+    let ephemeral_key: &PublicKey = &sealed_message.ephemeral_key;
+    let (chain_key, static_key): ([u8; 32], [u8; 32]) = kdf_ephemeral(&ik_receiver.get_public_key(), ephemeral_key, ik_receiver.get_private_key().diffie_hellman(ephemeral_key).to_bytes());
+    let ik_sender: [u8; 32] = aead::open(static_key, &sealed_message.encrypted_static, ephemeral_key.as_bytes())?
+        .try_into()
+        .map_err(|_| SealedSenderError::Crypto(CryptoError::DecryptionError))?;
+    let ik_sender: PublicKey = PublicKey::from(ik_sender);
+
+    let content_key: [u8; 32] = kdf_static(&chain_key, &sealed_message.encrypted_static, ik_receiver.get_private_key().diffie_hellman(&ik_sender).to_bytes());
+    let content: Vec<u8> = aead::open(content_key, &sealed_message.encrypted_content, ephemeral_key.as_bytes())?;
+    let mut reader: Reader = Reader::new(&content);
+    let certificate: SenderCertificate = SenderCertificate::from_bytes(reader.read_bytes()?)?;
+    let message: Message = Message::from_bytes(reader.read_remaining())?;
+
+    // The certificate must belong to the key that sealed the message and name the sender of the message
+    certificate.validate(certificate_key, now)?;
+    if certificate.ik != ik_sender || certificate.username != message.get_username() || certificate.device_id != message.get_device_id() {
+        return Err(SealedSenderError::InvalidCertificate)
+    }
+
+    Ok((certificate.username, certificate.device_id, ik_sender, message))
+}
+
+/// Message signed by the relay: `CERTIFICATE_CONTEXT || username (4 + len) || device_id (4) || ik (32) || expiration (8)`
+fn certificate_message(username: &String, device_id: DeviceId, ik: &PublicKey, expiration: u64) -> Result<Vec<u8>, ParseError> {
+    let mut message: Vec<u8> = CERTIFICATE_CONTEXT.to_vec();
+    write_bytes(&mut message, username.as_bytes())?;
+    message.extend_from_slice(&device_id.to_be_bytes());
+    message.extend_from_slice(ik.as_bytes());
+    message.extend_from_slice(&expiration.to_be_bytes());
+    Ok(message)
+}
+
+/// Returns (chain key, key encrypting the identity key of the sender)
+fn kdf_ephemeral(ik_receiver: &PublicKey, ephemeral_key: &PublicKey, dh_out: [u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let salt: Vec<u8> = [ik_receiver.as_bytes().as_slice(), ephemeral_key.as_bytes()].concat();
+    let hk = Hkdf::<Sha256>::new(Some(&salt), &dh_out);
+    let mut okm = [0u8; 64];
+    hk.expand(INFO_EPHEMERAL, &mut okm)
+        .expect("Output length invalid KDF_EPHEMERAL");
+
+    let (chain_key, static_key) = okm.split_at(32);
+    (chain_key.try_into().expect("Incorrect length"), static_key.try_into().expect("Incorrect length"))
+}
+
+/// Returns the key encrypting the content of the sealed message
+fn kdf_static(chain_key: &[u8; 32], encrypted_static: &[u8], dh_out: [u8; 32]) -> [u8; 32] {
+    let salt: Vec<u8> = [chain_key.as_slice(), encrypted_static].concat();
+    let hk = Hkdf::<Sha256>::new(Some(&salt), &dh_out);
+    let mut okm = [0u8; 32];
+    hk.expand(INFO_STATIC, &mut okm)
+        .expect("Output length invalid KDF_STATIC");
+    okm
+}
+
+impl From<CryptoError> for SealedSenderError {
+    fn from(error: CryptoError) -> Self {
+        SealedSenderError::Crypto(error)
+    }
+}
+
+impl From<ParseError> for SealedSenderError {
+    fn from(error: ParseError) -> Self {
+        SealedSenderError::Parse(error)
+    }
+}
+
+impl fmt::Display for SealedSenderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SealedSenderError::Crypto(error) => write!(f, "Sealed message can't be opened: {}", error),
+            SealedSenderError::Parse(error) => write!(f, "Malformed sealed message: {}", error),
+            SealedSenderError::CertificateKeyUnknown => write!(f, "The key of the relay signing the sender certificates is unknown"),
+            SealedSenderError::InvalidCertificate => write!(f, "The sender certificate is invalid"),
+            SealedSenderError::CertificateExpired => write!(f, "The sender certificate has expired"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::communication::message::{Ciphertext, HeaderHE};
+    use crate::communication::server::PRIMARY_DEVICE_ID;
+
+    const NOW: u64 = 1_000_000;
+
+    fn message(username: &str, device_id: DeviceId) -> Message {
+        let header: HeaderHE = HeaderHE::new(vec![0xCC; 50], vec![0xDD; 12]);
+        let ciphertext: Ciphertext = Ciphertext::new(vec![0xAA; 26], vec![0xBB; 12]);
+        Message::new((username.to_string(), device_id), (header, ciphertext), None, None, None, None, None)
+    }
+
+    #[test]
+    fn test_seal_unseal() {
+        let relay_key: IdentityKey = IdentityKey::new();
+        let ik_alice: IdentityKey = IdentityKey::new();
+        let ik_bob: IdentityKey = IdentityKey::new();
+        let certificate: SenderCertificate = SenderCertificate::issue(&relay_key, "Alice".to_string(), PRIMARY_DEVICE_ID, ik_alice.get_public_key(), NOW + SENDER_CERTIFICATE_LIFETIME).unwrap();
+
+        let sealed_message: SealedMessage = seal(&ik_alice, &ik_bob.get_public_key(), &certificate, &message("Alice", PRIMARY_DEVICE_ID)).unwrap();
+        assert_eq!(SealedMessage::from_bytes(&sealed_message.to_bytes().unwrap()), Ok(sealed_message.clone()));
+        assert_eq!(unseal(&ik_bob, &relay_key.get_public_key(), &sealed_message, NOW), Ok(("Alice".to_string(), PRIMARY_DEVICE_ID, ik_alice.get_public_key(), message("Alice", PRIMARY_DEVICE_ID))));
+        // Only the receiver can open it
+        assert!(matches!(unseal(&ik_alice, &relay_key.get_public_key(), &sealed_message, NOW), Err(SealedSenderError::Crypto(_))));
+    }
+
+    #[test]
+    fn test_unseal_invalid_certificate() {
+        let relay_key: IdentityKey = IdentityKey::new();
+        let ik_alice: IdentityKey = IdentityKey::new();
+        let ik_bob: IdentityKey = IdentityKey::new();
+        let ik_eve: IdentityKey = IdentityKey::new();
+        let expiration: u64 = NOW + SENDER_CERTIFICATE_LIFETIME;
+
+        // Certificate signed by another key
+        let forged_certificate: SenderCertificate = SenderCertificate::issue(&ik_eve, "Alice".to_string(), PRIMARY_DEVICE_ID, ik_eve.get_public_key(), expiration).unwrap();
+        let sealed_message: SealedMessage = seal(&ik_eve, &ik_bob.get_public_key(), &forged_certificate, &message("Alice", PRIMARY_DEVICE_ID)).unwrap();
+        assert_eq!(unseal(&ik_bob, &relay_key.get_public_key(), &sealed_message, NOW), Err(SealedSenderError::InvalidCertificate));
+
+        // Certificate of Alice used by Eve
+        let certificate: SenderCertificate = SenderCertificate::issue(&relay_key, "Alice".to_string(), PRIMARY_DEVICE_ID, ik_alice.get_public_key(), expiration).unwrap();
+        let sealed_message: SealedMessage = seal(&ik_eve, &ik_bob.get_public_key(), &certificate, &message("Alice", PRIMARY_DEVICE_ID)).unwrap();
+        assert_eq!(unseal(&ik_bob, &relay_key.get_public_key(), &sealed_message, NOW), Err(SealedSenderError::InvalidCertificate));
+
+        // Certificate of Eve for a message in the name of Alice
+        let certificate: SenderCertificate = SenderCertificate::issue(&relay_key, "Eve".to_string(), PRIMARY_DEVICE_ID, ik_eve.get_public_key(), expiration).unwrap();
+        let sealed_message: SealedMessage = seal(&ik_eve, &ik_bob.get_public_key(), &certificate, &message("Alice", PRIMARY_DEVICE_ID)).unwrap();
+        assert_eq!(unseal(&ik_bob, &relay_key.get_public_key(), &sealed_message, NOW), Err(SealedSenderError::InvalidCertificate));
+
+        // Certificate of a device for a message in the name of another device
+        let certificate: SenderCertificate = SenderCertificate::issue(&relay_key, "Alice".to_string(), PRIMARY_DEVICE_ID, ik_alice.get_public_key(), expiration).unwrap();
+        let sealed_message: SealedMessage = seal(&ik_alice, &ik_bob.get_public_key(), &certificate, &message("Alice", PRIMARY_DEVICE_ID + 1)).unwrap();
+        assert_eq!(unseal(&ik_bob, &relay_key.get_public_key(), &sealed_message, NOW), Err(SealedSenderError::InvalidCertificate));
+    }
+
+    #[test]
+    fn test_unseal_expired_certificate() {
+        let relay_key: IdentityKey = IdentityKey::new();
+        let ik_alice: IdentityKey = IdentityKey::new();
+        let ik_bob: IdentityKey = IdentityKey::new();
+        let certificate: SenderCertificate = SenderCertificate::issue(&relay_key, "Alice".to_string(), PRIMARY_DEVICE_ID, ik_alice.get_public_key(), NOW).unwrap();
+        assert_eq!(SenderCertificate::from_bytes(&certificate.to_bytes().unwrap()), Ok(certificate.clone()));
+
+        let sealed_message: SealedMessage = seal(&ik_alice, &ik_bob.get_public_key(), &certificate, &message("Alice", PRIMARY_DEVICE_ID)).unwrap();
+        assert_eq!(unseal(&ik_bob, &relay_key.get_public_key(), &sealed_message, NOW - 1).map(|(username, _, _, _)| username), Ok("Alice".to_string()));
+        assert_eq!(unseal(&ik_bob, &relay_key.get_public_key(), &sealed_message, NOW), Err(SealedSenderError::CertificateExpired));
+    }
+}
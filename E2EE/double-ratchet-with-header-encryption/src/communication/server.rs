@@ -1,44 +1,301 @@
 use crate::communication;
-use std::collections::HashMap;
-use communication::key_collection::ServerKeyCollection;
+use std::collections::{BTreeMap, HashMap};
+use communication::key_collection::{unix_time, ServerKeyCollection, OPK_LOW_STOCK};
 use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use rand_core::{OsRng, RngCore};
+use x25519_dalek::PublicKey;
+use x3dh::{xeddsa_verify, IdentityKey, Signature};
+use x3dh::mlkem::EncapsulationKey;
 
-use super::message::Message;
+use super::mailbox::{decode_username, encode_username, Mailbox};
+use super::message::{write_bytes, Envelope, ParseError, Reader};
+use super::sealed_sender::{SenderCertificate, SENDER_CERTIFICATE_LIFETIME};
+use super::relay::{Relay, RelayError};
+
+const KEYS_DIRECTORY: &str = "keys";
+const KEYS_EXTENSION: &str = "keys";
+const MAILBOX_DIRECTORY: &str = "mailbox";
+const CERTIFICATE_KEY_FILE: &str = "certificate.key";
+const LOGIN_CONTEXT: &[u8] = b"DoubleRatchetRelayLogin";
+
+/// Random bytes the user signs with its identity key to log in
+pub type Challenge = [u8; 32];
+/// Proof of an authenticated session, sent with the requests that read a mailbox or replace keys
+pub type SessionToken = [u8; 32];
+/// Device of a user, each device has its own keys and mailbox *(the ids of the removed devices are never reused)*
+pub type DeviceId = u32;
+
+/// Device registered with the user *(`add_user`)*, the other devices are linked by a device already registered
+pub const PRIMARY_DEVICE_ID: DeviceId = 1;
+/// Duration of a login session in seconds, the device logs in again once it has expired
+pub const SESSION_LIFETIME: u64 = 24 * 60 * 60;
 
 #[derive(Debug)]
 pub enum ServerError {
     UserDoesNotExist,
+    UserAlreadyExists,
+    DeviceDoesNotExist,
+    PrimaryDeviceRemoval,
+    DeviceIdsExhausted,
+    NotAuthenticated,
+    Storage(io::Error),
 }
 
 pub struct Server {
-    users: HashMap<String, (ServerKeyCollection, Vec<Message>)>,
+    users: HashMap<String, (DeviceId, BTreeMap<DeviceId, ServerKeyCollection>)>, // (Next device id, keys of each device)
+    mailbox: Mailbox,
+    directory: Option<PathBuf>, // None: nothing is written to disk
+    challenges: HashMap<(String, DeviceId), Challenge>, // Last challenge handed out to each device, it can only be used once
+    sessions: HashMap<SessionToken, (String, DeviceId, u64)>, // (Username, device, expiration time), kept in memory only: the users log in again after a restart
+    certificate_key: IdentityKey, // Signs the sender certificates *(sealed sender)*
+}
+
+/// Message signed with the identity key of a device to log in: `LOGIN_CONTEXT || username || device_id (4) || challenge`
+/// 
+/// # Arguments
+/// 
+/// * `username` (&str): Name of the user logging in
+/// * `device_id` (DeviceId): Device of the user logging in
+/// * `challenge` (&Challenge): Challenge handed out by the server
+/// 
+/// # Output
+/// 
+/// * `message` (Vec\<u8\>)
+pub fn login_message(username: &str, device_id: DeviceId, challenge: &Challenge) -> Vec<u8> {
+    [LOGIN_CONTEXT, username.as_bytes(), &device_id.to_be_bytes(), challenge].concat()
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Server {
     pub fn new() -> Self {
         Server {
-            users: HashMap::new()
+            users: HashMap::new(),
+            mailbox: Mailbox::in_memory(),
+            directory: None,
+            challenges: HashMap::new(),
+            sessions: HashMap::new(),
+            certificate_key: IdentityKey::new(),
+        }
+    }
+
+    /// Open (or create) a server that keeps the keys and the messages of the users in a directory, so that they survive a restart
+    /// 
+    /// # Arguments
+    /// 
+    /// * `directory` (&Path): Data directory *(`keys/<hex(username)>.keys` with the keys of every device, the mailbox logs in `mailbox/` and the key signing the sender certificates)*
+    /// 
+    /// # Output
+    /// 
+    /// * `server` (Result\<Server, ServerError\>)
+    pub fn open(directory: &Path) -> Result<Self, ServerError> {
+        let keys_directory: PathBuf = directory.join(KEYS_DIRECTORY);
+        fs::create_dir_all(&keys_directory)?;
+        let mut users: HashMap<String, (DeviceId, BTreeMap<DeviceId, ServerKeyCollection>)> = HashMap::new();
+        for entry in fs::read_dir(&keys_directory)? {
+            let path: PathBuf = entry?.path();
+            if path.extension().and_then(|extension| extension.to_str()) != Some(KEYS_EXTENSION) {
+                continue;
+            }
+            if let Some(username) = path.file_stem().and_then(|stem| stem.to_str()).and_then(decode_username) {
+                let devices: (DeviceId, BTreeMap<DeviceId, ServerKeyCollection>) = devices_from_bytes(&fs::read(&path)?)
+                    .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error.to_string()))?;
+                users.insert(username, devices);
+            }
+        }
+
+        Ok(Server {
+            users,
+            mailbox: Mailbox::open(&directory.join(MAILBOX_DIRECTORY))?,
+            directory: Some(directory.to_path_buf()),
+            challenges: HashMap::new(),
+            sessions: HashMap::new(),
+            certificate_key: open_certificate_key(&directory.join(CERTIFICATE_KEY_FILE))?,
+        })
+    }
+
+    /// Register a new user with its primary device, the name can't be taken again *(see `replace_user_keys`)*
+    pub fn add_user(&mut self, username: String, keys: ServerKeyCollection) -> Result<(), ServerError> {
+        if self.users.contains_key(&username) {
+            return Err(ServerError::UserAlreadyExists)
         }
+        self.users.insert(username.clone(), (PRIMARY_DEVICE_ID + 1, BTreeMap::from([(PRIMARY_DEVICE_ID, keys)])));
+        self.save_keys(&username)
     }
 
-    pub fn add_user(&mut self, username: String, keys: ServerKeyCollection) -> () {
-        self.users.insert(username.to_string(), (keys, Vec::new()));
+    /// Link a new device to a user, from a session of one of its devices
+    /// 
+    /// # Arguments
+    /// 
+    /// * `username` (&str): Name of the user
+    /// * `session` (&SessionToken): Session opened by a device of the user
+    /// * `keys` (ServerKeyCollection): Keys of the new device
+    /// 
+    /// # Output
+    /// 
+    /// * `device_id` (Result\<DeviceId, ServerError\>): Id of the new device
+    pub fn add_device(&mut self, username: &str, session: &SessionToken, keys: ServerKeyCollection) -> Result<DeviceId, ServerError> {
+        self.check_session(username, session)?;
+        let (next_device_id, devices) = self.users.get_mut(username).ok_or(ServerError::UserDoesNotExist)?;
+        let device_id: DeviceId = *next_device_id;
+        *next_device_id = device_id.checked_add(1).ok_or(ServerError::DeviceIdsExhausted)?;
+        devices.insert(device_id, keys);
+        self.save_keys(username)?;
+        Ok(device_id)
     }
 
-    pub fn add_message_to(&mut self, username: &String, message: Message) -> Result<(), ServerError> {
-        let user_information = self.users.get_mut(username).ok_or(ServerError::UserDoesNotExist)?;
-        user_information.1.push(message);
+    /// Remove a linked device of a user with its keys, its mailbox and its sessions *(the primary device can't be removed)*
+    pub fn remove_device(&mut self, username: &str, session: &SessionToken, device_id: DeviceId) -> Result<(), ServerError> {
+        self.check_session(username, session)?;
+        if device_id == PRIMARY_DEVICE_ID {
+            return Err(ServerError::PrimaryDeviceRemoval)
+        }
+        let (_, devices) = self.users.get_mut(username).ok_or(ServerError::UserDoesNotExist)?;
+        devices.remove(&device_id).ok_or(ServerError::DeviceDoesNotExist)?;
+        self.save_keys(username)?;
+        self.mailbox.remove(username, device_id)?;
+        self.revoke_sessions(username, device_id);
+        Ok(())
+    }
+
+    /// Returns the devices of a user, the messages sent to the user are encrypted for each of them
+    pub fn get_devices(&self, username: &str) -> Result<Vec<DeviceId>, ServerError> {
+        let (_, devices) = self.users.get(username).ok_or(ServerError::UserDoesNotExist)?;
+        Ok(devices.keys().copied().collect())
+    }
+
+    /// Replace all the keys of the device that opened the session, the messages waiting for it are kept
+    /// 
+    /// The sessions of the device are revoked: it logs in again with its new identity key
+    pub fn replace_user_keys(&mut self, username: &str, session: &SessionToken, keys: ServerKeyCollection) -> Result<(), ServerError> {
+        let device_id: DeviceId = self.check_session(username, session)?;
+        *self.device_keys_mut(username, device_id)? = keys;
+        self.save_keys(username)?;
+        self.revoke_sessions(username, device_id);
+        Ok(())
+    }
+
+    /// Returns a new random challenge for a device, the previous one can't be used anymore
+    pub fn create_challenge(&mut self, username: &str, device_id: DeviceId) -> Result<Challenge, ServerError> {
+        self.get_user_keys(username, device_id)?;
+        let mut challenge: Challenge = [0u8; 32];
+        OsRng.fill_bytes(&mut challenge);
+        self.challenges.insert((username.to_string(), device_id), challenge);
+        Ok(challenge)
+    }
+
+    /// Open an authenticated session if the device proves that it owns its identity key
+    /// 
+    /// # Arguments
+    /// 
+    /// * `username` (&str): Name of the user logging in
+    /// * `device_id` (DeviceId): Device of the user logging in
+    /// * `signature` (Signature): XEdDSA signature of `login_message(username, device_id, challenge)` with the identity key of the device
+    /// 
+    /// # Output
+    /// 
+    /// * `session` (Result\<SessionToken, ServerError\>): Token of the session, valid for `SESSION_LIFETIME` seconds *(`NotAuthenticated` if there is no challenge pending or the signature is invalid)*
+    pub fn login(&mut self, username: &str, device_id: DeviceId, signature: Signature) -> Result<SessionToken, ServerError> {
+        let ik: PublicKey = self.get_user_keys(username, device_id)?.get_ik();
+        // The challenge is consumed even if the signature is invalid
+        let challenge: Challenge = self.challenges.remove(&(username.to_string(), device_id)).ok_or(ServerError::NotAuthenticated)?;
+        if !xeddsa_verify(&ik, &login_message(username, device_id, &challenge), &signature) {
+            return Err(ServerError::NotAuthenticated)
+        }
+        let now: u64 = unix_time();
+        self.sessions.retain(|_, (_, _, expiration)| *expiration > now);
+        let mut session: SessionToken = [0u8; 32];
+        OsRng.fill_bytes(&mut session);
+        self.sessions.insert(session, (username.to_string(), device_id, now + SESSION_LIFETIME));
+        Ok(session)
+    }
+
+    pub fn add_message_to(&mut self, username: &str, device_id: DeviceId, message: Envelope) -> Result<(), ServerError> {
+        self.get_user_keys(username, device_id)?;
+        self.mailbox.enqueue(username, device_id, message)?;
         Ok(())
     }
 
-    pub fn get_user_keys(&self, username: &String) -> Result<&ServerKeyCollection, ServerError> {
-        let user_information = self.users.get(username).ok_or(ServerError::UserDoesNotExist)?;
-        Ok(&user_information.0)
+    /// Publish the new signed prekey of the device that opened the session *(rotation)*
+    pub fn update_user_spk(&mut self, username: &str, session: &SessionToken, spk_id: u32, spk: PublicKey, signature: Signature) -> Result<(), ServerError> {
+        let device_id: DeviceId = self.check_session(username, session)?;
+        self.device_keys_mut(username, device_id)?.set_spk(spk_id, spk, signature);
+        self.save_keys(username)
+    }
+
+    /// Publish the new ML-KEM prekey of the device that opened the session *(rotation)*
+    pub fn update_user_pqspk(&mut self, username: &str, session: &SessionToken, pqspk_id: u32, pqspk: EncapsulationKey, pq_signature: Signature) -> Result<(), ServerError> {
+        let device_id: DeviceId = self.check_session(username, session)?;
+        self.device_keys_mut(username, device_id)?.set_pqspk(pqspk_id, pqspk, pq_signature);
+        self.save_keys(username)
     }
 
-    pub fn get_user_messages(&mut self, username: &String) -> Result<Vec<Message>, ServerError> {
-        let user_information = self.users.get_mut(username).ok_or(ServerError::UserDoesNotExist)?;
-        Ok(user_information.1.drain(..).collect::<Vec<Message>>())
+    /// Returns the prekey bundle used to start a session with a device, the one-time prekey it contains is handed out only once
+    /// 
+    /// # Arguments
+    /// 
+    /// * `username` (&str): Name of the receiver
+    /// * `device_id` (DeviceId): Device of the receiver
+    /// 
+    /// # Output
+    /// 
+    /// * `bundle` (Result\<ServerKeyCollection, ServerError\>): Keys of the device with at most one one-time prekey *(none once the stock is exhausted)*
+    pub fn fetch_prekey_bundle(&mut self, username: &str, device_id: DeviceId) -> Result<ServerKeyCollection, ServerError> {
+        let bundle: ServerKeyCollection = self.device_keys_mut(username, device_id)?.take_bundle();
+        // The one-time prekey handed out must not come back after a restart
+        self.save_keys(username)?;
+        Ok(bundle)
+    }
+
+    /// Upload a new batch of one-time prekeys of the device that opened the session, tagged with their id
+    pub fn add_user_opks(&mut self, username: &str, session: &SessionToken, opks: Vec<(u32, PublicKey)>) -> Result<(), ServerError> {
+        let device_id: DeviceId = self.check_session(username, session)?;
+        self.device_keys_mut(username, device_id)?.add_opks(opks);
+        self.save_keys(username)
+    }
+
+    /// Returns the number of one-time prekeys of a device still available
+    pub fn get_opk_count(&self, username: &str, device_id: DeviceId) -> Result<usize, ServerError> {
+        Ok(self.get_user_keys(username, device_id)?.get_opk_bundle().len())
+    }
+
+    /// Returns true if the device should upload new one-time prekeys
+    pub fn has_low_opk_stock(&self, username: &str, device_id: DeviceId) -> Result<bool, ServerError> {
+        Ok(self.get_opk_count(username, device_id)? < OPK_LOW_STOCK)
+    }
+
+    pub fn get_user_keys(&self, username: &str, device_id: DeviceId) -> Result<&ServerKeyCollection, ServerError> {
+        let (_, devices) = self.users.get(username).ok_or(ServerError::UserDoesNotExist)?;
+        devices.get(&device_id).ok_or(ServerError::DeviceDoesNotExist)
+    }
+
+    /// Returns the messages waiting for the device that opened the session, they are kept until they're acknowledged
+    /// 
+    /// # Arguments
+    /// 
+    /// * `username` (&str): Name of the receiver
+    /// * `session` (&SessionToken): Session opened by a device of the receiver
+    /// 
+    /// # Output
+    /// 
+    /// * `messages` (Result\<Vec\<(u64, Envelope)\>, ServerError\>): (Id used to acknowledge the message, message) in their order of arrival
+    pub fn get_user_messages(&self, username: &str, session: &SessionToken) -> Result<Vec<(u64, Envelope)>, ServerError> {
+        let device_id: DeviceId = self.check_session(username, session)?;
+        Ok(self.mailbox.pending(username, device_id))
+    }
+
+    /// Delete the messages the device has read
+    pub fn acknowledge_messages(&mut self, username: &str, session: &SessionToken, ids: &[u64]) -> Result<(), ServerError> {
+        let device_id: DeviceId = self.check_session(username, session)?;
+        self.mailbox.acknowledge(username, device_id, ids)?;
+        Ok(())
     }
 
     pub fn get_users(&self, requester_username: String) -> Vec<String> {
@@ -50,12 +307,325 @@ impl Server {
         }
         res
     }
+
+    /// Returns the public key verifying the sender certificates
+    pub fn get_certificate_key(&self) -> PublicKey {
+        self.certificate_key.get_public_key()
+    }
+
+    /// Returns a certificate binding the name of a user and the device that opened the session to its identity key, used to send sealed messages
+    pub fn issue_sender_certificate(&self, username: &str, session: &SessionToken) -> Result<SenderCertificate, ServerError> {
+        let device_id: DeviceId = self.check_session(username, session)?;
+        let ik: PublicKey = self.get_user_keys(username, device_id)?.get_ik();
+        SenderCertificate::issue(&self.certificate_key, username.to_string(), device_id, ik, unix_time() + SENDER_CERTIFICATE_LIFETIME)
+            .map_err(|error| ServerError::Storage(io::Error::new(io::ErrorKind::InvalidInput, error.to_string())))
+    }
+
+    /// Returns the device that opened the session, or an error unless the session was opened by the user and hasn't expired
+    fn check_session(&self, username: &str, session: &SessionToken) -> Result<DeviceId, ServerError> {
+        match self.sessions.get(session) {
+            Some((session_username, device_id, expiration)) if session_username == username && *expiration > unix_time() => Ok(*device_id),
+            _ => Err(ServerError::NotAuthenticated),
+        }
+    }
+
+    /// Drop the sessions and the pending challenge of a device
+    fn revoke_sessions(&mut self, username: &str, device_id: DeviceId) {
+        self.challenges.remove(&(username.to_string(), device_id));
+        self.sessions.retain(|_, (session_username, session_device_id, _)| session_username != username || *session_device_id != device_id);
+    }
+
+    fn device_keys_mut(&mut self, username: &str, device_id: DeviceId) -> Result<&mut ServerKeyCollection, ServerError> {
+        let (_, devices) = self.users.get_mut(username).ok_or(ServerError::UserDoesNotExist)?;
+        devices.get_mut(&device_id).ok_or(ServerError::DeviceDoesNotExist)
+    }
+
+    /// Write the keys of all the devices of a user to disk *(replacing the previous file in a single step)*
+    fn save_keys(&self, username: &str) -> Result<(), ServerError> {
+        let (directory, (next_device_id, devices)) = match (&self.directory, self.users.get(username)) {
+            (Some(directory), Some(user)) => (directory, user),
+            _ => return Ok(()),
+        };
+        let path: PathBuf = directory.join(KEYS_DIRECTORY).join(format!("{}.{}", encode_username(username), KEYS_EXTENSION));
+        let temporary_path: PathBuf = path.with_extension("tmp");
+        let bytes: Vec<u8> = devices_to_bytes(*next_device_id, devices)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error.to_string()))?;
+        let mut file: File = File::create(&temporary_path)?;
+        file.write_all(&bytes)?;
+        file.sync_all()?;
+        fs::rename(&temporary_path, &path)?;
+        Ok(())
+    }
+}
+
+/// Returns the encoding of the devices of a user: `next_device_id (4) || count (4) || (device_id (4) || keys (4 + len))*`
+fn devices_to_bytes(next_device_id: DeviceId, devices: &BTreeMap<DeviceId, ServerKeyCollection>) -> Result<Vec<u8>, ParseError> {
+    let mut bytes: Vec<u8> = Vec::new();
+    bytes.extend_from_slice(&next_device_id.to_be_bytes());
+    bytes.extend_from_slice(&(devices.len() as u32).to_be_bytes());
+    for (device_id, keys) in devices {
+        bytes.extend_from_slice(&device_id.to_be_bytes());
+        write_bytes(&mut bytes, &keys.to_bytes())?;
+    }
+    Ok(bytes)
+}
+
+fn devices_from_bytes(bytes: &[u8]) -> Result<(DeviceId, BTreeMap<DeviceId, ServerKeyCollection>), ParseError> {
+    let mut reader: Reader = Reader::new(bytes);
+    let next_device_id: DeviceId = u32::from_be_bytes(reader.read_array::<4>()?);
+    let count: u32 = u32::from_be_bytes(reader.read_array::<4>()?);
+    let mut devices: BTreeMap<DeviceId, ServerKeyCollection> = BTreeMap::new();
+    for _ in 0..count {
+        let device_id: DeviceId = u32::from_be_bytes(reader.read_array::<4>()?);
+        devices.insert(device_id, ServerKeyCollection::from_bytes(reader.read_bytes()?)?);
+    }
+    reader.finish()?;
+    Ok((next_device_id, devices))
+}
+
+/// Load the key signing the sender certificates, or create it on the first start
+fn open_certificate_key(path: &Path) -> Result<IdentityKey, ServerError> {
+    match fs::read(path) {
+        Ok(bytes) => {
+            let private_key: [u8; 32] = bytes.try_into()
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid certificate key"))?;
+            Ok(IdentityKey::from_bytes(private_key))
+        },
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {
+            let mut private_key: [u8; 32] = [0u8; 32];
+            OsRng.fill_bytes(&mut private_key);
+            let temporary_path: PathBuf = path.with_extension("tmp");
+            let mut file: File = File::create(&temporary_path)?;
+            file.write_all(&private_key)?;
+            file.sync_all()?;
+            fs::rename(&temporary_path, path)?;
+            Ok(IdentityKey::from_bytes(private_key))
+        },
+        Err(error) => Err(ServerError::Storage(error)),
+    }
+}
+
+/// In-memory relay
+impl Relay for Server {
+    fn publish_keys(&mut self, username: &str, keys: ServerKeyCollection) -> Result<(), RelayError> {
+        Ok(self.add_user(username.to_string(), keys)?)
+    }
+
+    fn replace_keys(&mut self, username: &str, session: &SessionToken, keys: ServerKeyCollection) -> Result<(), RelayError> {
+        Ok(self.replace_user_keys(username, session, keys)?)
+    }
+
+    fn add_device(&mut self, username: &str, session: &SessionToken, keys: ServerKeyCollection) -> Result<DeviceId, RelayError> {
+        Ok(Server::add_device(self, username, session, keys)?)
+    }
+
+    fn remove_device(&mut self, username: &str, session: &SessionToken, device_id: DeviceId) -> Result<(), RelayError> {
+        Ok(Server::remove_device(self, username, session, device_id)?)
+    }
+
+    fn devices(&mut self, username: &str) -> Result<Vec<DeviceId>, RelayError> {
+        Ok(self.get_devices(username)?)
+    }
+
+    fn challenge(&mut self, username: &str, device_id: DeviceId) -> Result<Challenge, RelayError> {
+        Ok(self.create_challenge(username, device_id)?)
+    }
+
+    fn login(&mut self, username: &str, device_id: DeviceId, signature: Signature) -> Result<SessionToken, RelayError> {
+        Ok(Server::login(self, username, device_id, signature)?)
+    }
+
+    fn certificate_key(&mut self) -> Result<PublicKey, RelayError> {
+        Ok(self.get_certificate_key())
+    }
+
+    fn sender_certificate(&mut self, username: &str, session: &SessionToken) -> Result<SenderCertificate, RelayError> {
+        Ok(self.issue_sender_certificate(username, session)?)
+    }
+
+    fn publish_spk(&mut self, username: &str, session: &SessionToken, spk_id: u32, spk: PublicKey, signature: Signature) -> Result<(), RelayError> {
+        Ok(self.update_user_spk(username, session, spk_id, spk, signature)?)
+    }
+
+    fn publish_pqspk(&mut self, username: &str, session: &SessionToken, pqspk_id: u32, pqspk: EncapsulationKey, pq_signature: Signature) -> Result<(), RelayError> {
+        Ok(self.update_user_pqspk(username, session, pqspk_id, pqspk, pq_signature)?)
+    }
+
+    fn publish_opks(&mut self, username: &str, session: &SessionToken, opks: Vec<(u32, PublicKey)>) -> Result<(), RelayError> {
+        Ok(self.add_user_opks(username, session, opks)?)
+    }
+
+    fn opk_count(&mut self, username: &str, device_id: DeviceId) -> Result<usize, RelayError> {
+        Ok(self.get_opk_count(username, device_id)?)
+    }
+
+    fn fetch_bundle(&mut self, username: &str, device_id: DeviceId) -> Result<ServerKeyCollection, RelayError> {
+        Ok(self.fetch_prekey_bundle(username, device_id)?)
+    }
+
+    fn identity_key(&mut self, username: &str, device_id: DeviceId) -> Result<PublicKey, RelayError> {
+        Ok(self.get_user_keys(username, device_id)?.get_ik())
+    }
+
+    fn enqueue(&mut self, username: &str, device_id: DeviceId, message: Envelope) -> Result<(), RelayError> {
+        Ok(self.add_message_to(username, device_id, message)?)
+    }
+
+    fn fetch(&mut self, username: &str, session: &SessionToken) -> Result<Vec<(u64, Envelope)>, RelayError> {
+        Ok(self.get_user_messages(username, session)?)
+    }
+
+    fn acknowledge(&mut self, username: &str, session: &SessionToken, ids: &[u64]) -> Result<(), RelayError> {
+        Ok(self.acknowledge_messages(username, session, ids)?)
+    }
 }
 
 impl fmt::Display for ServerError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             ServerError::UserDoesNotExist => write!(f, "User does not exist on the server"),
+            ServerError::UserAlreadyExists => write!(f, "User already exists on the server"),
+            ServerError::DeviceDoesNotExist => write!(f, "Device does not exist on the server"),
+            ServerError::PrimaryDeviceRemoval => write!(f, "The primary device of a user can't be removed"),
+            ServerError::DeviceIdsExhausted => write!(f, "No device id left for the user"),
+            ServerError::NotAuthenticated => write!(f, "The user is not authenticated on the server"),
+            ServerError::Storage(error) => write!(f, "Storage of the server failed: {}", error),
         }
     }
-}
\ No newline at end of file
+}
+
+impl From<io::Error> for ServerError {
+    fn from(error: io::Error) -> Self {
+        ServerError::Storage(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::communication::client::{Client, ClientError};
+    use x3dh::create_identity_signature;
+
+    #[test]
+    fn test_login() {
+        let bob_name: String = "Bob".to_string();
+        let mut bob: Client = Client::new(bob_name.clone());
+        let eve: Client = Client::new("Eve".to_string());
+        let mut server: Server = Server::new();
+        server.add_user(bob_name.clone(), bob.get_server_keys()).unwrap();
+
+        // The challenge must be signed with the identity key of the user
+        let challenge: Challenge = server.create_challenge(&bob_name, PRIMARY_DEVICE_ID).unwrap();
+        let signature: Signature = create_identity_signature(&eve.get_keys().get_ik(), &login_message(&bob_name, PRIMARY_DEVICE_ID, &challenge));
+        assert!(matches!(server.login(&bob_name, PRIMARY_DEVICE_ID, signature), Err(ServerError::NotAuthenticated)));
+
+        // A challenge can only be used once
+        let challenge: Challenge = server.create_challenge(&bob_name, PRIMARY_DEVICE_ID).unwrap();
+        let signature: Signature = create_identity_signature(&bob.get_keys().get_ik(), &login_message(&bob_name, PRIMARY_DEVICE_ID, &challenge));
+        let session: SessionToken = server.login(&bob_name, PRIMARY_DEVICE_ID, signature).unwrap();
+        assert!(matches!(server.login(&bob_name, PRIMARY_DEVICE_ID, signature), Err(ServerError::NotAuthenticated)));
+        assert!(server.get_user_messages(&bob_name, &session).unwrap().is_empty());
+        assert!(matches!(server.get_user_messages(&bob_name, &[0u8; 32]), Err(ServerError::NotAuthenticated)));
+
+        assert_ne!(bob.login(&mut server).unwrap(), session);
+        assert!(matches!(server.create_challenge("Eve", PRIMARY_DEVICE_ID), Err(ServerError::UserDoesNotExist)));
+    }
+
+    #[test]
+    fn test_replace_user_keys() {
+        let alice_name: String = "Alice".to_string();
+        let bob_name: String = "Bob".to_string();
+        let mut alice: Client = Client::new(alice_name.clone());
+        let mut bob: Client = Client::new(bob_name.clone());
+        let new_bob: Client = Client::new(bob_name.clone());
+        let mut server: Server = Server::new();
+        alice.register(&mut server).unwrap();
+        bob.register(&mut server).unwrap();
+        alice.send_to(&mut server, &bob_name, b"A1").unwrap();
+
+        assert!(matches!(server.add_user(bob_name.clone(), new_bob.get_server_keys()), Err(ServerError::UserAlreadyExists)));
+        let alice_session: SessionToken = alice.login(&mut server).unwrap();
+        assert!(matches!(server.replace_user_keys(&bob_name, &alice_session, new_bob.get_server_keys()), Err(ServerError::NotAuthenticated)));
+        assert_eq!(server.get_user_keys(&bob_name, PRIMARY_DEVICE_ID).unwrap().get_ik(), bob.get_server_keys().get_ik());
+
+        // The messages waiting for Bob are kept
+        let bob_session: SessionToken = bob.login(&mut server).unwrap();
+        server.replace_user_keys(&bob_name, &bob_session, new_bob.get_server_keys()).unwrap();
+        assert_eq!(server.get_user_keys(&bob_name, PRIMARY_DEVICE_ID).unwrap().get_ik(), new_bob.get_server_keys().get_ik());
+        assert!(matches!(server.get_user_messages(&bob_name, &bob_session), Err(ServerError::NotAuthenticated)));
+
+        // The old session is revoked, Bob logs in with his new identity key
+        let challenge: Challenge = server.create_challenge(&bob_name, PRIMARY_DEVICE_ID).unwrap();
+        let signature: Signature = create_identity_signature(&bob.get_keys().get_ik(), &login_message(&bob_name, PRIMARY_DEVICE_ID, &challenge));
+        assert!(matches!(server.login(&bob_name, PRIMARY_DEVICE_ID, signature), Err(ServerError::NotAuthenticated)));
+        let challenge: Challenge = server.create_challenge(&bob_name, PRIMARY_DEVICE_ID).unwrap();
+        let signature: Signature = create_identity_signature(&new_bob.get_keys().get_ik(), &login_message(&bob_name, PRIMARY_DEVICE_ID, &challenge));
+        let bob_session: SessionToken = server.login(&bob_name, PRIMARY_DEVICE_ID, signature).unwrap();
+        assert_eq!(server.get_user_messages(&bob_name, &bob_session).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_session_expiration() {
+        let bob_name: String = "Bob".to_string();
+        let mut bob: Client = Client::new(bob_name.clone());
+        let mut server: Server = Server::new();
+        bob.register(&mut server).unwrap();
+        let session: SessionToken = bob.login(&mut server).unwrap();
+        assert!(server.get_user_messages(&bob_name, &session).unwrap().is_empty());
+
+        // The session expires after SESSION_LIFETIME, and the expired sessions are dropped at the next login
+        server.sessions.get_mut(&session).unwrap().2 = unix_time() - 1;
+        assert!(matches!(server.get_user_messages(&bob_name, &session), Err(ServerError::NotAuthenticated)));
+        let new_session: SessionToken = bob.login(&mut server).unwrap();
+        assert!(!server.sessions.contains_key(&session));
+        assert!(server.get_user_messages(&bob_name, &new_session).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_devices() {
+        let directory: std::path::PathBuf = std::env::temp_dir().join(format!("double-ratchet-server-devices-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let alice_name: String = "Alice".to_string();
+        let mut alice: Client = Client::new(alice_name.clone());
+        let mut alice_phone: Client = Client::new(alice_name.clone());
+        let mut alice_laptop: Client = Client::new(alice_name.clone());
+        let mut bob: Client = Client::new("Bob".to_string());
+        let mut server: Server = Server::open(&directory).unwrap();
+        alice.register(&mut server).unwrap();
+        bob.register(&mut server).unwrap();
+
+        // Each device logs in with its own identity key
+        let phone_id: DeviceId = alice.add_device(&mut server, &mut alice_phone).unwrap();
+        assert_eq!(phone_id, PRIMARY_DEVICE_ID + 1);
+        assert_eq!(server.get_user_keys(&alice_name, phone_id).unwrap().get_ik(), alice_phone.get_server_keys().get_ik());
+        let challenge: Challenge = server.create_challenge(&alice_name, phone_id).unwrap();
+        let signature: Signature = create_identity_signature(&alice.get_keys().get_ik(), &login_message(&alice_name, phone_id, &challenge));
+        assert!(matches!(server.login(&alice_name, phone_id, signature), Err(ServerError::NotAuthenticated)));
+
+        // The id of a removed device is never given again
+        let phone_session: SessionToken = alice_phone.login(&mut server).unwrap();
+        bob.send_to(&mut server, &alice_name, b"B1").unwrap();
+        alice.remove_device(&mut server, phone_id).unwrap();
+        assert!(matches!(server.get_user_messages(&alice_name, &phone_session), Err(ServerError::NotAuthenticated)));
+        assert!(matches!(server.fetch_prekey_bundle(&alice_name, phone_id), Err(ServerError::DeviceDoesNotExist)));
+        assert!(matches!(alice.remove_device(&mut server, phone_id), Err(ClientError::Relay(RelayError::Server(ServerError::DeviceDoesNotExist)))));
+        assert!(matches!(alice.remove_device(&mut server, PRIMARY_DEVICE_ID), Err(ClientError::Relay(RelayError::Server(ServerError::PrimaryDeviceRemoval)))));
+        let laptop_id: DeviceId = alice.add_device(&mut server, &mut alice_laptop).unwrap();
+        assert_eq!(laptop_id, phone_id + 1);
+
+        // The devices are kept when the server restarts
+        drop(server);
+        let mut server: Server = Server::open(&directory).unwrap();
+        assert_eq!(server.get_devices(&alice_name).unwrap(), vec![PRIMARY_DEVICE_ID, laptop_id]);
+        let laptop_session: SessionToken = alice_laptop.login(&mut server).unwrap();
+        assert!(server.get_user_messages(&alice_name, &laptop_session).unwrap().is_empty());
+        assert_eq!(alice.add_device(&mut server, &mut alice_phone).unwrap(), laptop_id + 1);
+
+        // The device ids never wrap around to the id of another device
+        server.users.get_mut(&alice_name).unwrap().0 = DeviceId::MAX;
+        let alice_session: SessionToken = alice.login(&mut server).unwrap();
+        assert!(matches!(server.add_device(&alice_name, &alice_session, bob.get_server_keys()), Err(ServerError::DeviceIdsExhausted)));
+        assert_eq!(server.get_devices(&alice_name).unwrap(), vec![PRIMARY_DEVICE_ID, laptop_id, laptop_id + 1]);
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
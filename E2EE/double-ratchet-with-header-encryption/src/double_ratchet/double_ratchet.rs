@@ -1,30 +1,87 @@
 use crate::double_ratchet::state::State;
-use crate::double_ratchet::aead::{encrypt as aead_encrypt, decrypt as aead_decrypt, hencrypt, hdecrypt};
+use crate::double_ratchet::skipped_keys::SkippedKeys;
+use crate::double_ratchet::aead::{Aead, AeadAlgorithm, NonceMode, hencrypt, hdecrypt, hdecrypt_derived};
 use sha2::Sha256;
 use hmac::{Hmac, Mac};
 use hkdf::Hkdf;
 use rand_core::OsRng;
-use x25519_dalek::{ReusableSecret, PublicKey};
+use x25519_dalek::{StaticSecret, PublicKey};
 
 use super::aead::CryptoError;
 
 
-const MAX_SKIP: u16 = 1000;
+const MAX_SKIP: u32 = 1000;
 const BYTE_MESSAGE_KEY: &[u8] = &[0x01];
 const BYTE_NEXT_CHAIN_KEY: &[u8] = &[0x02];
 const INFO: &[u8] = &[0x73];
 type HmacSha256 = Hmac<Sha256>;
+/// Encrypted header and (ciphertext, nonce) of an encrypted message
+pub type EncryptedMessage = ((Vec<u8>, Vec<u8>), (Vec<u8>, Vec<u8>));
 
 #[derive(Clone)]
 pub struct DoubleRatchetHE {
     state: State,
 }
 
+impl Default for DoubleRatchetHE {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl DoubleRatchetHE {
     pub fn new() -> Self {
         DoubleRatchetHE { state: State::new() }
     }
 
+    /// Returns a Double Ratchet encrypting its messages with `aead` *(both parties have to use the same one)*
+    pub fn with_aead(aead: AeadAlgorithm) -> Self {
+        let mut state: State = State::new();
+        state.aead = aead;
+        DoubleRatchetHE { state }
+    }
+
+    pub fn get_aead(&self) -> AeadAlgorithm {
+        self.state.aead
+    }
+
+    /// Choose how the nonces of the next messages sent are chosen *(the other party reads both modes)*
+    pub fn set_nonce_mode(&mut self, nonce_mode: NonceMode) {
+        self.state.nonce_mode = nonce_mode;
+    }
+
+    pub fn get_nonce_mode(&self) -> NonceMode {
+        self.state.nonce_mode
+    }
+
+    /// Returns the serialization of the whole ratchet state *(contains secret keys, seal it before storing it)*
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.state.to_bytes()
+    }
+
+    /// Returns the store of skipped message keys, to inspect them
+    pub fn get_skipped_keys(&self) -> &SkippedKeys<[u8; 32]> {
+        &self.state.mkskipped
+    }
+
+    /// Returns the store of skipped message keys, to purge them
+    pub fn get_skipped_keys_mut(&mut self) -> &mut SkippedKeys<[u8; 32]> {
+        &mut self.state.mkskipped
+    }
+
+    /// Restore a Double Ratchet from the serialization of its state
+    /// 
+    /// # Arguments
+    /// 
+    /// * `bytes` (&\[u8\]): Serialized state
+    /// 
+    /// # Output
+    /// 
+    /// * `double_ratchet` (Result\<DoubleRatchetHE, CryptoError\>): Restored Double Ratchet
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CryptoError> {
+        Ok(DoubleRatchetHE { state: State::from_bytes(bytes)? })
+    }
+
     /// Initialize the sender Double Ratchet
     /// 
     /// # Arguments
@@ -33,7 +90,7 @@ impl DoubleRatchetHE {
     /// * `receiver_public_key` (PublicKey): Receiver public key
     /// * `shared_hk` (\[u8; 32\]): Shared Header Keys *(HKDF derivation of the shared secret)*
     /// * `shared_nhk` (\[u8; 32\]): Shared Next Header Keys *(HKDF derivation of the shared secret)*
-    pub fn init_sender_he(&mut self, sk: [u8; 32], receiver_public_key: PublicKey, shared_hk: [u8; 32], shared_nhk: [u8; 32]) -> () {
+    pub fn init_sender_he(&mut self, sk: [u8; 32], receiver_public_key: PublicKey, shared_hk: [u8; 32], shared_nhk: [u8; 32]) {
         self.generate_dh(); // Set dh_s
         self.state.dh_r = Some(receiver_public_key);
         let (rk_result, ck_r_result, nhk_s_result) = self.kdf_rk_he(sk, self.dh(self.state.dh_s.as_ref().unwrap(), self.state.dh_r.unwrap()));
@@ -47,10 +104,10 @@ impl DoubleRatchetHE {
     /// # Arguments
     /// 
     /// * `sk` (\[u8; 32\]): Shared Key *(X3DH shared secret)*
-    /// * `receiver_pair` (ReusableSecret, PublicKey): Receiver pair
+    /// * `receiver_pair` (StaticSecret, PublicKey): Receiver pair
     /// * `shared_hk` (\[u8; 32\]): Shared Header Keys *(HKDF derivation of the shared secret, info different from shared_nhk)*
     /// * `shared_nhk` (\[u8; 32\]): Shared Next Header Keys *(HKDF derivation of the shared secret, info different from shared_hk)*
-    pub fn init_receiver_he(&mut self, sk: [u8; 32], receiver_pair: (ReusableSecret, PublicKey), shared_hk: [u8; 32], shared_nhk: [u8; 32]) -> () {
+    pub fn init_receiver_he(&mut self, sk: [u8; 32], receiver_pair: (StaticSecret, PublicKey), shared_hk: [u8; 32], shared_nhk: [u8; 32]) {
         self.state.dh_s = Some(receiver_pair);
         self.state.rk = Some(sk);
         self.state.nhk_s = Some(shared_nhk);
@@ -58,8 +115,8 @@ impl DoubleRatchetHE {
     }
     
     /// Create and set a new Diffie-Hellman *(Curve25519)* key pair to `dh_s`
-    fn generate_dh(&mut self) -> () {
-        let private_key: ReusableSecret = ReusableSecret::random_from_rng(OsRng);
+    fn generate_dh(&mut self) {
+        let private_key: StaticSecret = StaticSecret::random_from_rng(OsRng);
         let public_key: PublicKey = PublicKey::from(&private_key);
         self.state.dh_s = Some((private_key, public_key));
     }
@@ -74,7 +131,7 @@ impl DoubleRatchetHE {
     /// # Output
     /// 
     /// * `dh_out` (SharedSecret): Diffie-Hellman output
-    fn dh(&self, dh_pair: &(ReusableSecret, PublicKey), dh_pub: PublicKey) -> [u8; 32] {
+    fn dh(&self, dh_pair: &(StaticSecret, PublicKey), dh_pub: PublicKey) -> [u8; 32] {
         *dh_pair.0.diffie_hellman(&dh_pub).as_bytes()
     }
 
@@ -138,7 +195,7 @@ impl DoubleRatchetHE {
         (Some(new_chain_key), new_message_key)
     }
     
-    /// Returns an AEAD (AES-GCM-SIV-256) encryption of plaintext with message key `mk`.
+    /// Returns an AEAD encryption of plaintext with message key `mk`.
     /// 
     /// # Arguments
     /// 
@@ -147,24 +204,24 @@ impl DoubleRatchetHE {
     /// 
     /// # Output
     /// 
-    /// * `(enc_header, res)` ((Vec<u8>, Vec<u8>), (Vec\<u8\>, Vec\<u8\>)): Encrypted header and ciphertext
-    pub fn encrypt_he(&mut self, plaintext: &[u8], ad: &[u8]) -> ((Vec<u8>, Vec<u8>), (Vec<u8>, Vec<u8>)) {
-        let mk: [u8; 32];
-        (self.state.ck_s, mk) = self.kdf_ck(self.state.ck_s.unwrap());
-        let header: (PublicKey, u8, u8) = self.header(self.state.dh_s.as_ref().unwrap(), self.state.pn, self.state.n_s);
-        let enc_header: (Vec<u8>, Vec<u8>) = match hencrypt(self.state.hk_s.unwrap(), header) {
-            Ok((encrypted_header, header_nonce)) => (encrypted_header, header_nonce),
-            Err(error) => panic!("Error header (AES-GCM-SIV): {:?}", error),
-        };
+    /// * `(enc_header, res)` (Result\<((Vec<u8>, Vec<u8>), (Vec\<u8\>, Vec\<u8\>)), CryptoError\>): Encrypted header and ciphertext
+    pub fn encrypt_he(&mut self, plaintext: &[u8], ad: &[u8]) -> Result<EncryptedMessage, CryptoError> {
+        let ck_s: [u8; 32] = self.state.ck_s.ok_or(CryptoError::NotInitialized)?;
+        let hk_s: [u8; 32] = self.state.hk_s.ok_or(CryptoError::NotInitialized)?;
+        let dh_s: &(StaticSecret, PublicKey) = self.state.dh_s.as_ref().ok_or(CryptoError::NotInitialized)?;
+        let (new_ck_s, mk): (Option<[u8; 32]>, [u8; 32]) = self.kdf_ck(ck_s);
+        let header: (PublicKey, u32, u32) = self.header(dh_s, self.state.pn, self.state.n_s);
+        let res: (Vec<u8>, Vec<u8>) = self.state.aead.encrypt(mk, plaintext, &self.concat(ad, header), self.state.nonce_mode)?;
+        let enc_header: (Vec<u8>, Vec<u8>) = hencrypt(hk_s, header, &res.0, self.state.nonce_mode)?;
+        // The state only moves forward once the message has been encrypted
+        self.state.ck_s = new_ck_s;
         self.state.n_s += 1;
-        let res = match aead_encrypt(mk, plaintext, &self.concat(ad, header)) {
-            Ok((ciphertext, nonce)) => (ciphertext, nonce),
-            Err(error) => panic!("Error (AES-GCM-SIV): {:?}", error),
-        };
-        (enc_header, res)
+        Ok((enc_header, res))
     }
     
-    /// Returns the AEAD (AES-GCM-SIV-256) decryption of ciphertext with message key mk.
+    /// Returns the AEAD decryption of ciphertext with message key mk.
+    /// 
+    /// The decryption is transactional: if it fails, the state is left exactly as it was before the call.
     /// 
     /// # Arguments
     /// 
@@ -175,39 +232,66 @@ impl DoubleRatchetHE {
     /// 
     /// # Output
     /// 
-    /// * `plaintext` (Vec\<u8\>): Plaintext
-    pub fn decrypt_he(&mut self, enc_header: (Vec<u8>, Vec<u8>), ciphertext: Vec<u8>, nonce: Vec<u8>, ad: &[u8]) -> Vec<u8> {
-        let plaintext: Option<Vec<u8>> = self.try_skipped_message_keys_he(enc_header.clone(), &ciphertext, &nonce, ad);
-        if plaintext.is_some() {
-            return plaintext.unwrap()
+    /// * `plaintext` (Result\<Vec\<u8\>, CryptoError\>): Plaintext
+    pub fn decrypt_he(&mut self, enc_header: (Vec<u8>, Vec<u8>), ciphertext: Vec<u8>, nonce: Vec<u8>, ad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let backup: State = self.state.clone();
+        let res: Result<Vec<u8>, CryptoError> = self.ratchet_decrypt_he(&enc_header, &ciphertext, &nonce, ad);
+        if res.is_err() {
+            // Discard every change made to the state while processing the message
+            self.state = backup;
+        }
+        res
+    }
+
+    /// Decrypt the message and update the state *(the state may be partially updated on error)*
+    /// 
+    /// # Arguments
+    /// 
+    /// * `enc_header` (&(Vec<u8>, Vec<u8>)): Encrypted Header
+    /// * `ciphertext` (&\[u8\]): Ciphertext
+    /// * `nonce` (&\[u8\]): Nonce
+    /// * `ad` (&\[u8\]): Associated Data
+    /// 
+    /// # Output
+    /// 
+    /// * `plaintext` (Result\<Vec\<u8\>, CryptoError\>): Plaintext
+    fn ratchet_decrypt_he(&mut self, enc_header: &(Vec<u8>, Vec<u8>), ciphertext: &[u8], nonce: &[u8], ad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        if self.state.rk.is_none() || self.state.dh_s.is_none() || self.state.nhk_r.is_none() {
+            return Err(CryptoError::NotInitialized)
+        }
+        if let Some(plaintext) = self.try_skipped_message_keys_he(enc_header, ciphertext, nonce, ad)? {
+            return Ok(plaintext)
         }
-        let (header, dh_ratchet): ((PublicKey, u8, u8), bool) = match self.decrypt_header(enc_header.clone()) {
-            Ok((current_header, current_dh_ratchet)) => (current_header, current_dh_ratchet),
-            Err(error) => panic!("Error header (AES-GCM-SIV): {:?}", error),
-        };
+        let (header, dh_ratchet): ((PublicKey, u32, u32), bool) = self.decrypt_header(enc_header, ciphertext)?;
         if dh_ratchet {
-            self.skip_message_keys_he(header.1);
-            self.state.pn = self.state.n_s;
-            (self.state.n_s, self.state.n_r) = (0, 0);
-            self.state.hk_s = self.state.nhk_s;
-            self.state.hk_r = self.state.nhk_r;
-            self.state.dh_r = Some(header.0);
-            let (rk_result, ck_r_result, nhk_r_result) = self.kdf_rk_he(self.state.rk.unwrap(), self.dh(self.state.dh_s.as_ref().unwrap(), self.state.dh_r.unwrap()));
-            (self.state.rk, self.state.ck_r, self.state.nhk_r) = (Some(rk_result), Some(ck_r_result), Some(nhk_r_result));
-            self.generate_dh(); // New dh_s
-            let (rk_result, ck_s_result, nhk_s_result) = self.kdf_rk_he(self.state.rk.unwrap(), self.dh(self.state.dh_s.as_ref().unwrap(), self.state.dh_r.unwrap()));
-            (self.state.rk, self.state.ck_s, self.state.nhk_s) = (Some(rk_result), Some(ck_s_result), Some(nhk_s_result));
+            self.skip_message_keys_he(header.1)?;
+            self.dh_ratchet_he(header.0);
         }
-        self.skip_message_keys_he(header.2);
+        self.skip_message_keys_he(header.2)?;
         let mk: [u8; 32];
-        (self.state.ck_r, mk) = self.kdf_ck(self.state.ck_r.unwrap());
+        (self.state.ck_r, mk) = self.kdf_ck(self.state.ck_r.ok_or(CryptoError::NotInitialized)?);
         self.state.n_r += 1;
         
-        let res = match aead_decrypt(mk, &ciphertext, &nonce, &self.concat(ad, header)) {
-            Ok(plaintext) => plaintext,
-            Err(error) => panic!("Error (AES-GCM-SIV): {:?}", error),
-        };
-        res
+        self.state.aead.decrypt(mk, ciphertext, nonce, &self.concat(ad, header))
+    }
+
+    /// Applies a DH ratchet step with the new ratchet public key of the other party
+    /// 
+    /// # Arguments
+    /// 
+    /// * `dh_pub` (PublicKey): Ratchet public key received in the header
+    fn dh_ratchet_he(&mut self, dh_pub: PublicKey) {
+        self.state.pn = self.state.n_s;
+        (self.state.n_s, self.state.n_r) = (0, 0);
+        self.state.hk_s = self.state.nhk_s;
+        self.state.hk_r = self.state.nhk_r;
+        self.state.dh_r = Some(dh_pub);
+        self.state.mkskipped.advance_step(); // Expire the skipped message keys that are too old
+        let (rk_result, ck_r_result, nhk_r_result) = self.kdf_rk_he(self.state.rk.unwrap(), self.dh(self.state.dh_s.as_ref().unwrap(), dh_pub));
+        (self.state.rk, self.state.ck_r, self.state.nhk_r) = (Some(rk_result), Some(ck_r_result), Some(nhk_r_result));
+        self.generate_dh(); // New dh_s
+        let (rk_result, ck_s_result, nhk_s_result) = self.kdf_rk_he(self.state.rk.unwrap(), self.dh(self.state.dh_s.as_ref().unwrap(), dh_pub));
+        (self.state.rk, self.state.ck_s, self.state.nhk_s) = (Some(rk_result), Some(ck_s_result), Some(nhk_s_result));
     }
     
     /// Check if the message corresponds to a skipped message key. 
@@ -215,79 +299,88 @@ impl DoubleRatchetHE {
     /// If it's a skipped message, this function decrypts the message, deletes the message key, and return the plaintext.
     /// 
     /// # Arguments
-    /// * `enc_header` ((Vec<u8>, Vec<u8>)): Encrypted Header
-    /// * `ciphertext` (&Vec\<u8\>): Ciphertext
-    /// * `nonce` (&Vec\<u8\>): Nonce
+    /// * `enc_header` (&(Vec<u8>, Vec<u8>)): Encrypted Header
+    /// * `ciphertext` (&\[u8\]): Ciphertext
+    /// * `nonce` (&\[u8\]): Nonce
     /// * `ad` (&\[u8\]): Associated Data
     /// 
     /// # Output
     /// 
-    /// `plaintext` (Option\<Vec\<u8\>\>): Optional plaintext
-    fn try_skipped_message_keys_he(&mut self, enc_header: (Vec<u8>, Vec<u8>), ciphertext: &Vec<u8>, nonce: &Vec<u8>,  ad: &[u8]) -> Option<Vec<u8>> {
-        for ((hk, n), mk) in self.state.mkskipped.clone().iter() {
-            let header: Option<(PublicKey, u8, u8)> = hdecrypt(*hk, &enc_header.0, &enc_header.1);
-            if header.is_some() && header.unwrap().2 == *n {
-                self.state.mkskipped.remove(&(*hk, *n));
-                let res = match aead_decrypt(*mk, ciphertext, &nonce, &self.concat(ad, header.unwrap())) {
-                    Ok(plaintext) => plaintext,
-                    Err(error) => panic!("Error (AES-GCM-SIV): {:?}", error),
-                };
-                return Some(res)
+    /// `plaintext` (Result\<Option\<Vec\<u8\>\>, CryptoError\>): Optional plaintext
+    fn try_skipped_message_keys_he(&mut self, enc_header: &(Vec<u8>, Vec<u8>), ciphertext: &[u8], nonce: &[u8],  ad: &[u8]) -> Result<Option<Vec<u8>>, CryptoError> {
+        // Only one trial decryption per header key, the message number then gives the message key directly
+        for hk in self.state.mkskipped.chains() {
+            if let Some(header) = Self::hdecrypt_any(hk, enc_header, ciphertext) {
+                return match self.state.mkskipped.remove(&hk, header.2) {
+                    Some(mk) => self.state.aead.decrypt(mk, ciphertext, nonce, &self.concat(ad, header)).map(Some),
+                    None => Ok(None),
+                }
             }
         }
-        None
+        Ok(None)
     }
 
     /// Decrypt the header and define if we need to applies a DH ratchet step
     /// 
     /// # Arguments
-    /// * `enc_header` ((Vec<u8>, Vec<u8>)): Encrypted Header
+    /// * `enc_header` (&(Vec<u8>, Vec<u8>)): Encrypted Header
+    /// * `ciphertext` (&\[u8\]): Ciphertext *(the nonce of a header sent without it is derived from the ciphertext)*
     /// 
     /// # Output
     /// 
-    /// `(header, dh_ratchet)` (Result\<((PublicKey, u8, u8), bool), CryptoError\>): Header and boolean to tell if we need to applies a DH ratchet step
-    fn decrypt_header(&self, enc_header: (Vec<u8>, Vec<u8>)) -> Result<((PublicKey, u8, u8), bool), CryptoError> {
-        let mut header: Option<(PublicKey, u8, u8)> = hdecrypt(self.state.hk_r.unwrap_or_default(), &enc_header.0, &enc_header.1);
-        if header.is_some() {
-            return Ok((header.unwrap(), false))
+    /// `(header, dh_ratchet)` (Result\<((PublicKey, u32, u32), bool), CryptoError\>): Header and boolean to tell if we need to applies a DH ratchet step
+    fn decrypt_header(&self, enc_header: &(Vec<u8>, Vec<u8>), ciphertext: &[u8]) -> Result<((PublicKey, u32, u32), bool), CryptoError> {
+        if let Some(hk_r) = self.state.hk_r {
+            if let Some(header) = Self::hdecrypt_any(hk_r, enc_header, ciphertext) {
+                return Ok((header, false))
+            }
+        }
+        let nhk_r: [u8; 32] = self.state.nhk_r.ok_or(CryptoError::NotInitialized)?;
+        if let Some(header) = Self::hdecrypt_any(nhk_r, enc_header, ciphertext) {
+            return Ok((header, true))
         }
-        header = hdecrypt(self.state.nhk_r.unwrap(), &enc_header.0, &enc_header.1);
-        if header.is_some() {
-            return Ok((header.unwrap(), true))
+        Err(CryptoError::HeaderUndecryptable)
+    }
+
+    /// Decrypt a header with the nonce sent with it, or with the nonce derived from `hk` and the ciphertext if none was sent
+    fn hdecrypt_any(hk: [u8; 32], enc_header: &(Vec<u8>, Vec<u8>), ciphertext: &[u8]) -> Option<(PublicKey, u32, u32)> {
+        if enc_header.1.is_empty() {
+            return hdecrypt_derived(hk, &enc_header.0, ciphertext)
         }
-        Err(CryptoError::DecryptionError)
+        hdecrypt(hk, &enc_header.0, &enc_header.1)
     }
     
     /// Stores any skipped message keys from the current receiving chain.
     /// 
     /// # Arguments
-    /// * `until` (u8)
-    fn skip_message_keys_he(&mut self, until: u8) -> () {
-        if self.state.n_r as u16 + MAX_SKIP < until as u16 {
-            panic!("Error: state.Nr + MAX_SKIP < until");
+    /// * `until` (u32)
+    fn skip_message_keys_he(&mut self, until: u32) -> Result<(), CryptoError> {
+        if self.state.n_r.saturating_add(MAX_SKIP) < until {
+            return Err(CryptoError::TooManySkippedMessages)
         }
-        if self.state.ck_r != None {
+        if self.state.ck_r.is_some() {
             while self.state.n_r < until {
                 let mk: [u8; 32];
                 (self.state.ck_r, mk) = self.kdf_ck(self.state.ck_r.unwrap());
-                self.state.mkskipped.insert((self.state.hk_r.unwrap(), self.state.n_r), mk);
+                self.state.mkskipped.insert(self.state.hk_r.unwrap(), self.state.n_r, mk);
                 self.state.n_r += 1;
             }
         }
+        Ok(())
     }
     
     /// Returns the output of applying a KDF keyed by a 32-byte chain key `ck` to some constant.
     /// 
     /// # Arguments
     /// 
-    /// * `dh_pair` (&(ReusableSecret, PublicKey)): Diffie-Hellman key pair
-    /// * `pn` (u8): Number of messages in previous sending chain
-    /// * `n` (u8): Message numbers for sending and receiving
+    /// * `dh_pair` (&(StaticSecret, PublicKey)): Diffie-Hellman key pair
+    /// * `pn` (u32): Number of messages in previous sending chain
+    /// * `n` (u32): Message numbers for sending and receiving
     /// 
     /// # Output
     /// 
-    /// * `header` ((PublicKey, u8, u8)): Header
-    fn header(&self, dh_pair: &(ReusableSecret, PublicKey), pn: u8, n: u8) -> (PublicKey, u8, u8) {
+    /// * `header` ((PublicKey, u32, u32)): Header
+    fn header(&self, dh_pair: &(StaticSecret, PublicKey), pn: u32, n: u32) -> (PublicKey, u32, u32) {
         (dh_pair.1, pn, n)
     }
      
@@ -296,16 +389,198 @@ impl DoubleRatchetHE {
     /// # Arguments
     /// 
     /// * `ad` (&\[u8\]): Associated Data
-    /// * `header` ((PublicKey, u8, u8)): Header
+    /// * `header` ((PublicKey, u32, u32)): Header
     /// 
     /// # Output
     /// 
     /// * `res` (Vec\<u8\>): Concatenation
-    fn concat(&self, ad: &[u8], header: (PublicKey, u8, u8)) -> Vec<u8> {
+    fn concat(&self, ad: &[u8], header: (PublicKey, u32, u32)) -> Vec<u8> {
         let public_key: &[u8; 32] = header.0.as_bytes();
-        let nb_messages_previous_chain: u8 = header.1;
-        let message_number: u8 = header.2;
+        let nb_messages_previous_chain: u32 = header.1;
+        let message_number: u32 = header.2;
 
         [ad, public_key, &nb_messages_previous_chain.to_be_bytes(), &message_number.to_be_bytes()].concat()
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::double_ratchet::skipped_keys::MAX_SKIPPED_AGE;
+    use crate::double_ratchet::aead::derive_header_nonce;
+
+    const SK: [u8; 32] = [0x42; 32];
+    const SHARED_HK: [u8; 32] = [0x43; 32];
+    const SHARED_NHK: [u8; 32] = [0x44; 32];
+    const AD: &[u8] = b"associated data";
+
+    fn init_pair() -> (DoubleRatchetHE, DoubleRatchetHE) {
+        let bob_private_key: StaticSecret = StaticSecret::random_from_rng(OsRng);
+        let bob_public_key: PublicKey = PublicKey::from(&bob_private_key);
+        let mut alice: DoubleRatchetHE = DoubleRatchetHE::new();
+        let mut bob: DoubleRatchetHE = DoubleRatchetHE::new();
+        alice.init_sender_he(SK, bob_public_key, SHARED_HK, SHARED_NHK);
+        bob.init_receiver_he(SK, (bob_private_key, bob_public_key), SHARED_HK, SHARED_NHK);
+        (alice, bob)
+    }
+
+    #[test]
+    fn test_decrypt_forged_message_keeps_state() {
+        let (mut alice, mut bob) = init_pair();
+        let (enc_header, (ciphertext, nonce)) = alice.encrypt_he(b"Message A1", AD).unwrap();
+        let mut forged_ciphertext: Vec<u8> = ciphertext.clone();
+        forged_ciphertext[0] ^= 0x01;
+
+        assert_eq!(bob.decrypt_he(enc_header.clone(), forged_ciphertext, nonce.clone(), AD), Err(CryptoError::DecryptionError));
+        assert_eq!(bob.decrypt_he(enc_header, ciphertext, nonce, AD), Ok(b"Message A1".to_vec()));
+    }
+
+    #[test]
+    fn test_decrypt_forged_header() {
+        let (mut alice, mut bob) = init_pair();
+        let (enc_header, (ciphertext, nonce)) = alice.encrypt_he(b"Message A1", AD).unwrap();
+        let mut forged_header: (Vec<u8>, Vec<u8>) = enc_header.clone();
+        forged_header.0[0] ^= 0x01;
+
+        assert_eq!(bob.decrypt_he(forged_header, ciphertext.clone(), nonce.clone(), AD), Err(CryptoError::HeaderUndecryptable));
+        assert_eq!(bob.decrypt_he(enc_header, ciphertext, nonce, AD), Ok(b"Message A1".to_vec()));
+    }
+
+    #[test]
+    fn test_decrypt_forged_skipped_message_keeps_key() {
+        let (mut alice, mut bob) = init_pair();
+        let (enc_header_1, (ciphertext_1, nonce_1)) = alice.encrypt_he(b"Message A1", AD).unwrap();
+        let (enc_header_2, (ciphertext_2, nonce_2)) = alice.encrypt_he(b"Message A2", AD).unwrap();
+
+        assert_eq!(bob.decrypt_he(enc_header_2, ciphertext_2, nonce_2, AD), Ok(b"Message A2".to_vec()));
+        assert_eq!(bob.decrypt_he(enc_header_1.clone(), ciphertext_1.clone(), nonce_1.clone(), b"wrong associated data"), Err(CryptoError::DecryptionError));
+        assert_eq!(bob.decrypt_he(enc_header_1, ciphertext_1, nonce_1, AD), Ok(b"Message A1".to_vec()));
+    }
+
+    #[test]
+    fn test_derived_nonces() {
+        let (mut alice, mut bob) = init_pair();
+        alice.set_nonce_mode(NonceMode::Derived);
+        let (enc_header_1, (ciphertext_1, nonce_1)) = alice.encrypt_he(b"Message A1", AD).unwrap();
+        let (enc_header_2, (ciphertext_2, nonce_2)) = alice.encrypt_he(b"Message A2", AD).unwrap();
+        let (enc_header_3, (ciphertext_3, nonce_3)) = alice.encrypt_he(b"Message A3", AD).unwrap();
+        assert!(enc_header_1.1.is_empty() && nonce_1.is_empty());
+
+        // Out of order: the nonces of the headers are derived from the ciphertexts
+        assert_eq!(bob.decrypt_he(enc_header_3, ciphertext_3, nonce_3, AD), Ok(b"Message A3".to_vec()));
+        assert_eq!(bob.decrypt_he(enc_header_1, ciphertext_1, nonce_1, AD), Ok(b"Message A1".to_vec()));
+        let (enc_header, (ciphertext, nonce)) = bob.encrypt_he(b"Message B1", AD).unwrap();
+        assert_eq!(enc_header.1.len(), 12);
+        assert_eq!(alice.decrypt_he(enc_header, ciphertext, nonce, AD), Ok(b"Message B1".to_vec()));
+
+        // New chain of Alice (next header key), read by Bob with a random-nonce chain of his own
+        let (enc_header, (ciphertext, nonce)) = alice.encrypt_he(b"Message A4", AD).unwrap();
+        assert_eq!(bob.decrypt_he(enc_header, ciphertext, nonce, AD), Ok(b"Message A4".to_vec()));
+        assert_eq!(bob.decrypt_he(enc_header_2, ciphertext_2, nonce_2, AD), Ok(b"Message A2".to_vec()));
+
+        // The mode is kept with the session
+        let mut restored_alice: DoubleRatchetHE = DoubleRatchetHE::from_bytes(&alice.to_bytes()).unwrap();
+        assert_eq!(restored_alice.get_nonce_mode(), NonceMode::Derived);
+        let (enc_header, (ciphertext, nonce)) = restored_alice.encrypt_he(b"Message A5", AD).unwrap();
+        assert!(enc_header.1.is_empty());
+        assert_eq!(bob.decrypt_he(enc_header, ciphertext, nonce, AD), Ok(b"Message A5".to_vec()));
+    }
+
+    #[test]
+    fn test_derived_header_nonce() {
+        let public_key: PublicKey = PublicKey::from(&StaticSecret::random_from_rng(OsRng));
+        let (enc_header, nonce) = hencrypt(SHARED_HK, (public_key, 3, 7), b"ciphertext", NonceMode::Derived).unwrap();
+        assert!(nonce.is_empty());
+
+        assert_eq!(hdecrypt_derived(SHARED_HK, &enc_header, b"ciphertext"), Some((public_key, 3, 7)));
+        assert_eq!(hdecrypt(SHARED_HK, &enc_header, derive_header_nonce(SHARED_HK, b"ciphertext").as_ref()), Some((public_key, 3, 7)));
+        // The header is bound to the ciphertext of its message and to its header key
+        assert_eq!(hdecrypt_derived(SHARED_HK, &enc_header, b"other ciphertext"), None);
+        assert_eq!(hdecrypt_derived(SHARED_NHK, &enc_header, b"ciphertext"), None);
+        assert_ne!(derive_header_nonce(SHARED_HK, b"ciphertext"), derive_header_nonce(SHARED_HK, b"other ciphertext"));
+    }
+
+    #[test]
+    fn test_not_initialized() {
+        let (mut alice, _) = init_pair();
+        let (enc_header, (ciphertext, nonce)) = alice.encrypt_he(b"Message A1", AD).unwrap();
+        let mut double_ratchet: DoubleRatchetHE = DoubleRatchetHE::new();
+
+        assert!(matches!(double_ratchet.encrypt_he(b"Message", AD), Err(CryptoError::NotInitialized)));
+        assert_eq!(double_ratchet.decrypt_he(enc_header, ciphertext, nonce, AD), Err(CryptoError::NotInitialized));
+    }
+
+    #[test]
+    fn test_more_than_256_messages_on_one_chain() {
+        let (mut alice, mut bob) = init_pair();
+        let mut messages: Vec<EncryptedMessage> = Vec::new();
+        for i in 0..300 {
+            messages.push(alice.encrypt_he(format!("Message A{}", i).as_bytes(), AD).unwrap());
+        }
+
+        // Deliver the last message first, then the rest of the chain from the skipped message keys
+        let (enc_header, (ciphertext, nonce)) = messages.pop().unwrap();
+        assert_eq!(bob.decrypt_he(enc_header, ciphertext, nonce, AD), Ok(b"Message A299".to_vec()));
+        for (i, (enc_header, (ciphertext, nonce))) in messages.into_iter().enumerate() {
+            assert_eq!(bob.decrypt_he(enc_header, ciphertext, nonce, AD), Ok(format!("Message A{}", i).into_bytes()));
+        }
+
+        // The reply still goes through a DH ratchet step on a long chain
+        let (enc_header, (ciphertext, nonce)) = bob.encrypt_he(b"Message B1", AD).unwrap();
+        assert_eq!(alice.decrypt_he(enc_header, ciphertext, nonce, AD), Ok(b"Message B1".to_vec()));
+    }
+
+    #[test]
+    fn test_header_counters_wider_than_u8() {
+        let public_key: PublicKey = PublicKey::from(&StaticSecret::random_from_rng(OsRng));
+        let (enc_header, nonce) = hencrypt(SHARED_HK, (public_key, 256, 70_000), &[], NonceMode::Random).unwrap();
+
+        assert_eq!(hdecrypt(SHARED_HK, &enc_header, &nonce), Some((public_key, 256, 70_000)));
+    }
+
+    #[test]
+    fn test_too_many_skipped_messages() {
+        let (mut alice, mut bob) = init_pair();
+        let mut messages: Vec<EncryptedMessage> = Vec::new();
+        for i in 0..(MAX_SKIP + 2) {
+            messages.push(alice.encrypt_he(format!("Message A{}", i).as_bytes(), AD).unwrap());
+        }
+
+        let (enc_header, (ciphertext, nonce)) = messages.pop().unwrap();
+        assert_eq!(bob.decrypt_he(enc_header, ciphertext, nonce, AD), Err(CryptoError::TooManySkippedMessages));
+        let (enc_header, (ciphertext, nonce)) = messages.remove(0);
+        assert_eq!(bob.decrypt_he(enc_header, ciphertext, nonce, AD), Ok(b"Message A0".to_vec()));
+    }
+    #[test]
+    fn test_skipped_keys_expire_after_ratchet_steps() {
+        let (mut alice, mut bob) = init_pair();
+        let (delayed_header, (delayed_ciphertext, delayed_nonce)) = alice.encrypt_he(b"Message A0", AD).unwrap();
+        let (enc_header, (ciphertext, nonce)) = alice.encrypt_he(b"Message A1", AD).unwrap();
+        bob.decrypt_he(enc_header, ciphertext, nonce, AD).unwrap();
+        let hk: [u8; 32] = bob.get_skipped_keys().entries()[0].0;
+
+        for i in 0..(MAX_SKIPPED_AGE + 1) {
+            assert!(bob.get_skipped_keys().contains(&hk, 0), "Expired after {} ratchet steps", i);
+            let (enc_header, (ciphertext, nonce)) = bob.encrypt_he(b"Ping", AD).unwrap();
+            alice.decrypt_he(enc_header, ciphertext, nonce, AD).unwrap();
+            let (enc_header, (ciphertext, nonce)) = alice.encrypt_he(b"Pong", AD).unwrap();
+            bob.decrypt_he(enc_header, ciphertext, nonce, AD).unwrap();
+        }
+
+        assert!(bob.get_skipped_keys().is_empty());
+        assert_eq!(bob.decrypt_he(delayed_header, delayed_ciphertext, delayed_nonce, AD), Err(CryptoError::HeaderUndecryptable));
+    }
+
+    #[test]
+    fn test_purge_skipped_keys() {
+        let (mut alice, mut bob) = init_pair();
+        let (delayed_header, (delayed_ciphertext, delayed_nonce)) = alice.encrypt_he(b"Message A0", AD).unwrap();
+        let (enc_header, (ciphertext, nonce)) = alice.encrypt_he(b"Message A1", AD).unwrap();
+        bob.decrypt_he(enc_header, ciphertext, nonce, AD).unwrap();
+        assert_eq!(bob.get_skipped_keys().len(), 1);
+
+        bob.get_skipped_keys_mut().purge();
+        assert!(bob.get_skipped_keys().is_empty());
+        assert!(bob.decrypt_he(delayed_header, delayed_ciphertext, delayed_nonce, AD).is_err());
+    }
 }
\ No newline at end of file
@@ -1,3 +1,5 @@
+#[allow(clippy::module_inception)]
 pub mod double_ratchet;
 pub mod state;
-pub mod aead;
\ No newline at end of file
+pub mod aead;
+pub mod skipped_keys;
\ No newline at end of file
@@ -1 +0,0 @@
-pub mod x3dh;
\ No newline at end of file
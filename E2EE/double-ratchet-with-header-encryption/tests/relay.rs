@@ -0,0 +1,273 @@
+use double_ratchet_algorithm::communication::client::{Client, ClientError};
+use double_ratchet_algorithm::communication::relay::RelayError;
+use double_ratchet_algorithm::communication::key_collection::ServerKeyCollection;
+use double_ratchet_algorithm::communication::message::{Envelope, Message};
+use double_ratchet_algorithm::communication::server::{login_message, Challenge, DeviceId, Server, ServerError, SessionToken, PRIMARY_DEVICE_ID};
+use double_ratchet_algorithm::communication::transport::{serve_tcp, serve_unix, RemoteServer, TransportError};
+use std::io::{BufRead, BufReader};
+use std::net::{SocketAddr, TcpListener};
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, RwLock};
+use std::{env, fs, process, thread};
+use x25519_dalek::PublicKey;
+use x3dh::{create_identity_signature, Signature};
+
+fn start_tcp_relay() -> SocketAddr {
+    let listener: TcpListener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address: SocketAddr = listener.local_addr().unwrap();
+    let server: Arc<RwLock<Server>> = Arc::new(RwLock::new(Server::new()));
+    thread::spawn(move || serve_tcp(listener, server));
+    address
+}
+
+/// Encrypt a message and queue it on the relay, the prekey bundle (and its one-time prekey) is only fetched for the first message
+fn send(relay: &mut RemoteServer, sender: &mut Client, receiver_name: &str, plaintext: &[u8], first_message: bool) {
+    let r_keys: ServerKeyCollection = if first_message {
+        relay.fetch_prekey_bundle(receiver_name, PRIMARY_DEVICE_ID).unwrap()
+    } else {
+        relay.get_user_keys(receiver_name, PRIMARY_DEVICE_ID).unwrap()
+    };
+    let (x3dh_keys, (header, ciphertext)) = sender.send_message(receiver_name, PRIMARY_DEVICE_ID, plaintext, &r_keys).unwrap();
+    let message: Message = match x3dh_keys {
+        Some((ek, spk_id, opk_used, kem_ciphertext)) => Message::new((sender.get_client_name(), sender.get_device_id()), (header, ciphertext), Some(ek), Some(spk_id), opk_used, kem_ciphertext, Some(sender.get_aead())),
+        None => Message::new((sender.get_client_name(), sender.get_device_id()), (header, ciphertext), None, None, None, None, None),
+    };
+    relay.add_message_to(receiver_name, PRIMARY_DEVICE_ID, Envelope::Plain(Box::new(message))).unwrap();
+}
+
+/// Fetch the messages waiting for a user and acknowledge them
+fn receive(relay: &mut RemoteServer, username: &str, session: &SessionToken) -> Vec<Message> {
+    let messages: Vec<(u64, Envelope)> = relay.get_user_messages(username, session).unwrap();
+    let ids: Vec<u64> = messages.iter().map(|(id, _)| *id).collect();
+    relay.acknowledge_messages(username, session, &ids).unwrap();
+    messages.into_iter().map(|(_, envelope)| match envelope {
+        Envelope::Plain(message) => *message,
+        _ => panic!("Unexpected sealed or group message"),
+    }).collect()
+}
+
+/// Unwrap the result of each message read and keep the texts
+fn texts(plaintext_received: Vec<Result<Option<Vec<u8>>, ClientError>>) -> Vec<Vec<u8>> {
+    plaintext_received.into_iter().filter_map(|plaintext| plaintext.unwrap()).collect()
+}
+
+/// Alice and Bob each have their own connection to the relay and exchange messages in both directions
+fn conversation(mut alice_relay: RemoteServer, mut bob_relay: RemoteServer) {
+    let alice_name: String = "Alice".to_string();
+    let bob_name: String = "Bob".to_string();
+    let mut alice: Client = Client::new(alice_name.clone());
+    let mut bob: Client = Client::new(bob_name.clone());
+    alice_relay.add_user(alice_name.clone(), alice.get_server_keys()).unwrap();
+    bob_relay.add_user(bob_name.clone(), bob.get_server_keys()).unwrap();
+    let alice_session: SessionToken = alice.login(&mut alice_relay).unwrap();
+    let bob_session: SessionToken = bob.login(&mut bob_relay).unwrap();
+    assert_eq!(alice_relay.get_users(alice_name.clone()).unwrap(), vec![bob_name.clone()]);
+    let opk_count: usize = alice_relay.get_opk_count(&bob_name, PRIMARY_DEVICE_ID).unwrap();
+
+    send(&mut alice_relay, &mut alice, &bob_name, b"A1", true);
+    assert_eq!(bob_relay.get_opk_count(&bob_name, PRIMARY_DEVICE_ID).unwrap(), opk_count - 1);
+
+    let alice_ik: PublicKey = bob_relay.get_user_keys(&alice_name, PRIMARY_DEVICE_ID).unwrap().get_ik();
+    let messages: Vec<Message> = receive(&mut bob_relay, &bob_name, &bob_session);
+    assert_eq!(texts(bob.read_messages(&alice_name, PRIMARY_DEVICE_ID, Some(alice_ik), messages)), vec![b"A1".to_vec()]);
+    assert!(bob_relay.get_user_messages(&bob_name, &bob_session).unwrap().is_empty());
+
+    send(&mut alice_relay, &mut alice, &bob_name, b"A2", false);
+    send(&mut alice_relay, &mut alice, &bob_name, b"A3", false);
+    let messages: Vec<Message> = receive(&mut bob_relay, &bob_name, &bob_session);
+    assert_eq!(texts(bob.read_messages(&alice_name, PRIMARY_DEVICE_ID, None, messages)), vec![b"A2".to_vec(), b"A3".to_vec()]);
+    assert_eq!(bob_relay.get_opk_count(&bob_name, PRIMARY_DEVICE_ID).unwrap(), opk_count - 1);
+
+    send(&mut bob_relay, &mut bob, &alice_name, b"B1", false);
+    let messages: Vec<Message> = receive(&mut alice_relay, &alice_name, &alice_session);
+    assert_eq!(texts(alice.read_messages(&bob_name, PRIMARY_DEVICE_ID, None, messages)), vec![b"B1".to_vec()]);
+}
+
+#[test]
+fn test_two_clients_over_tcp() {
+    let address: SocketAddr = start_tcp_relay();
+    conversation(RemoteServer::connect_tcp(address).unwrap(), RemoteServer::connect_tcp(address).unwrap());
+}
+
+#[test]
+fn test_two_clients_over_unix_socket() {
+    let path: PathBuf = env::temp_dir().join(format!("double-ratchet-relay-{}.sock", process::id()));
+    let _ = fs::remove_file(&path);
+    let listener: UnixListener = UnixListener::bind(&path).unwrap();
+    let server: Arc<RwLock<Server>> = Arc::new(RwLock::new(Server::new()));
+    thread::spawn(move || serve_unix(listener, server));
+
+    conversation(RemoteServer::connect_unix(&path).unwrap(), RemoteServer::connect_unix(&path).unwrap());
+    let _ = fs::remove_file(&path);
+}
+
+#[test]
+fn test_clients_drive_the_relay() {
+    let address: SocketAddr = start_tcp_relay();
+    let mut alice_relay: RemoteServer = RemoteServer::connect_tcp(address).unwrap();
+    let mut bob_relay: RemoteServer = RemoteServer::connect_tcp(address).unwrap();
+    let alice_name: String = "Alice".to_string();
+    let bob_name: String = "Bob".to_string();
+    let mut alice: Client = Client::new(alice_name.clone());
+    let mut bob: Client = Client::new(bob_name.clone());
+    alice.register(&mut alice_relay).unwrap();
+    bob.register(&mut bob_relay).unwrap();
+
+    alice.send_to(&mut alice_relay, &bob_name, b"A1").unwrap();
+    assert_eq!(bob.poll(&mut bob_relay).unwrap(), vec![(alice_name.clone(), b"A1".to_vec())]);
+    bob.send_to(&mut bob_relay, &alice_name, b"B1").unwrap();
+    bob.send_to(&mut bob_relay, &alice_name, b"B2").unwrap();
+    assert_eq!(alice.poll(&mut alice_relay).unwrap(), vec![(bob_name.clone(), b"B1".to_vec()), (bob_name, b"B2".to_vec())]);
+}
+
+#[test]
+fn test_unknown_user() {
+    let mut relay: RemoteServer = RemoteServer::connect_tcp(start_tcp_relay()).unwrap();
+    let bob_name: String = "Bob".to_string();
+
+    assert!(matches!(relay.fetch_prekey_bundle(&bob_name, PRIMARY_DEVICE_ID), Err(TransportError::Server(ServerError::UserDoesNotExist))));
+    assert!(matches!(relay.create_challenge(&bob_name, PRIMARY_DEVICE_ID), Err(TransportError::Server(ServerError::UserDoesNotExist))));
+    // The connection is still usable after an error
+    assert!(relay.get_users(bob_name).unwrap().is_empty());
+}
+
+#[test]
+fn test_mailbox_requires_session() {
+    let mut relay: RemoteServer = RemoteServer::connect_tcp(start_tcp_relay()).unwrap();
+    let alice_name: String = "Alice".to_string();
+    let bob_name: String = "Bob".to_string();
+    let mut alice: Client = Client::new(alice_name.clone());
+    let mut bob: Client = Client::new(bob_name.clone());
+    let mut eve: Client = Client::new("Eve".to_string());
+    for client in [&mut alice, &mut bob, &mut eve] {
+        client.register(&mut relay).unwrap();
+    }
+    alice.send_to(&mut relay, &bob_name, b"A1").unwrap();
+
+    // Eve can't use her own session, take Bob's name or log in with her identity key
+    let eve_session: SessionToken = eve.login(&mut relay).unwrap();
+    assert!(matches!(relay.get_user_messages(&bob_name, &eve_session), Err(TransportError::Server(ServerError::NotAuthenticated))));
+    assert!(matches!(relay.acknowledge_messages(&bob_name, &eve_session, &[0]), Err(TransportError::Server(ServerError::NotAuthenticated))));
+    assert!(matches!(relay.replace_user_keys(&bob_name, &eve_session, eve.get_server_keys()), Err(TransportError::Server(ServerError::NotAuthenticated))));
+    assert!(matches!(relay.add_user(bob_name.clone(), eve.get_server_keys()), Err(TransportError::Server(ServerError::UserAlreadyExists))));
+    let challenge: Challenge = relay.create_challenge(&bob_name, PRIMARY_DEVICE_ID).unwrap();
+    let signature: Signature = create_identity_signature(&eve.get_keys().get_ik(), &login_message(&bob_name, PRIMARY_DEVICE_ID, &challenge));
+    assert!(matches!(relay.login(&bob_name, PRIMARY_DEVICE_ID, signature), Err(TransportError::Server(ServerError::NotAuthenticated))));
+
+    assert_eq!(bob.poll(&mut relay).unwrap(), vec![(alice_name, b"A1".to_vec())]);
+}
+
+#[test]
+fn test_sealed_sender_over_tcp() {
+    let mut relay: RemoteServer = RemoteServer::connect_tcp(start_tcp_relay()).unwrap();
+    let alice_name: String = "Alice".to_string();
+    let bob_name: String = "Bob".to_string();
+    let mut alice: Client = Client::new(alice_name.clone());
+    let mut bob: Client = Client::new(bob_name.clone());
+    alice.register(&mut relay).unwrap();
+    bob.register(&mut relay).unwrap();
+    let certificate_key: PublicKey = relay.get_certificate_key().unwrap();
+    alice.enable_sealed_sender(certificate_key);
+    bob.enable_sealed_sender(certificate_key);
+
+    alice.send_to(&mut relay, &bob_name, b"A1").unwrap();
+    // The relay doesn't know who sent the message
+    let bob_session: SessionToken = bob.login(&mut relay).unwrap();
+    let queued_messages: Vec<(u64, Envelope)> = relay.get_user_messages(&bob_name, &bob_session).unwrap();
+    assert_eq!(queued_messages.len(), 1);
+    assert!(queued_messages.iter().all(|(_, envelope)| matches!(envelope, Envelope::Sealed(_))));
+
+    assert_eq!(bob.poll(&mut relay).unwrap(), vec![(alice_name.clone(), b"A1".to_vec())]);
+    bob.send_to(&mut relay, &alice_name, b"B1").unwrap();
+    assert_eq!(alice.poll(&mut relay).unwrap(), vec![(bob_name, b"B1".to_vec())]);
+}
+
+#[test]
+fn test_devices_over_tcp() {
+    let address: SocketAddr = start_tcp_relay();
+    let mut alice_relay: RemoteServer = RemoteServer::connect_tcp(address).unwrap();
+    let mut bob_relay: RemoteServer = RemoteServer::connect_tcp(address).unwrap();
+    let alice_name: String = "Alice".to_string();
+    let bob_name: String = "Bob".to_string();
+    let mut alice: Client = Client::new(alice_name.clone());
+    let mut alice_phone: Client = Client::new(alice_name.clone());
+    let mut bob: Client = Client::new(bob_name.clone());
+    alice.register(&mut alice_relay).unwrap();
+    bob.register(&mut bob_relay).unwrap();
+    let phone_id: DeviceId = alice.add_device(&mut alice_relay, &mut alice_phone).unwrap();
+    assert_eq!(bob_relay.get_devices(&alice_name).unwrap(), vec![PRIMARY_DEVICE_ID, phone_id]);
+
+    // Each device of Alice gets its own copy, her phone keeps her other device in sync
+    bob.send_to(&mut bob_relay, &alice_name, b"B1").unwrap();
+    assert_eq!(alice.poll(&mut alice_relay).unwrap(), vec![(bob_name.clone(), b"B1".to_vec())]);
+    assert_eq!(alice_phone.poll(&mut alice_relay).unwrap(), vec![(bob_name.clone(), b"B1".to_vec())]);
+    alice_phone.send_to(&mut alice_relay, &bob_name, b"A1").unwrap();
+    assert_eq!(bob.poll(&mut bob_relay).unwrap(), vec![(alice_name.clone(), b"A1".to_vec())]);
+    assert_eq!(alice.poll(&mut alice_relay).unwrap(), vec![(alice_name.clone(), b"A1".to_vec())]);
+
+    alice.remove_device(&mut alice_relay, phone_id).unwrap();
+    assert_eq!(bob_relay.get_devices(&alice_name).unwrap(), vec![PRIMARY_DEVICE_ID]);
+    assert!(matches!(alice.remove_device(&mut alice_relay, PRIMARY_DEVICE_ID), Err(ClientError::Relay(RelayError::Server(ServerError::PrimaryDeviceRemoval)))));
+}
+
+/// Start the relay binary and returns its address
+fn spawn_relay(arguments: &[&str]) -> (Child, SocketAddr) {
+    let mut relay: Child = Command::new(env!("CARGO_BIN_EXE_relay"))
+        .args(arguments)
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut line: String = String::new();
+    BufReader::new(relay.stdout.take().unwrap()).read_line(&mut line).unwrap();
+    let address: SocketAddr = line.trim().rsplit(' ').next().unwrap().parse().unwrap();
+    (relay, address)
+}
+
+#[test]
+fn test_relay_binary() {
+    let (mut relay, address): (Child, SocketAddr) = spawn_relay(&["tcp", "127.0.0.1:0"]);
+    let result: thread::Result<()> = std::panic::catch_unwind(|| conversation(RemoteServer::connect_tcp(address).unwrap(), RemoteServer::connect_tcp(address).unwrap()));
+    relay.kill().unwrap();
+    relay.wait().unwrap();
+    assert!(result.is_ok());
+}
+
+#[test]
+fn test_relay_crash_between_enqueue_and_fetch() {
+    let directory: PathBuf = env::temp_dir().join(format!("double-ratchet-relay-data-{}", process::id()));
+    let _ = fs::remove_dir_all(&directory);
+    let data_directory: &str = directory.to_str().unwrap();
+    let alice_name: String = "Alice".to_string();
+    let bob_name: String = "Bob".to_string();
+    let mut alice: Client = Client::new(alice_name.clone());
+    let mut bob: Client = Client::new(bob_name.clone());
+
+    let (mut relay, address): (Child, SocketAddr) = spawn_relay(&["tcp", "127.0.0.1:0", data_directory]);
+    let mut relay_connection: RemoteServer = RemoteServer::connect_tcp(address).unwrap();
+    alice.register(&mut relay_connection).unwrap();
+    bob.register(&mut relay_connection).unwrap();
+    let opk_count: usize = relay_connection.get_opk_count(&bob_name, PRIMARY_DEVICE_ID).unwrap();
+    alice.send_to(&mut relay_connection, &bob_name, b"A1").unwrap();
+    // The relay is killed without any chance to save its state
+    relay.kill().unwrap();
+    relay.wait().unwrap();
+
+    let (mut relay, address): (Child, SocketAddr) = spawn_relay(&["tcp", "127.0.0.1:0", data_directory]);
+    let result: thread::Result<()> = std::panic::catch_unwind(std::panic::AssertUnwindSafe(move || {
+        let mut relay_connection: RemoteServer = RemoteServer::connect_tcp(address).unwrap();
+        // The one-time prekey handed out before the crash is not handed out again
+        assert_eq!(relay_connection.get_opk_count(&bob_name, PRIMARY_DEVICE_ID).unwrap(), opk_count - 1);
+        assert_eq!(bob.poll(&mut relay_connection).unwrap(), vec![(alice_name.clone(), b"A1".to_vec())]);
+        // The message has been acknowledged *(Bob logged in again, the sessions don't survive a restart)*
+        let bob_session: SessionToken = bob.login(&mut relay_connection).unwrap();
+        assert!(relay_connection.get_user_messages(&bob_name, &bob_session).unwrap().is_empty());
+        bob.send_to(&mut relay_connection, &alice_name, b"B1").unwrap();
+        assert_eq!(alice.poll(&mut relay_connection).unwrap(), vec![(bob_name, b"B1".to_vec())]);
+    }));
+    relay.kill().unwrap();
+    relay.wait().unwrap();
+    let _ = fs::remove_dir_all(&directory);
+    assert!(result.is_ok());
+}